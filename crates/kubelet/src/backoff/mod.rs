@@ -2,6 +2,8 @@
 //! such as ImagePullBackoff and CrashLoopBackoff.
 use std::time::Duration;
 
+use rand::Rng;
+
 /// Determines how long to back off before performing a retry.
 #[async_trait::async_trait]
 pub trait BackoffStrategy: Send {
@@ -21,6 +23,7 @@ pub struct ExponentialBackoffStrategy {
     base_duration: Duration,
     cap: Duration,
     last_duration: Duration,
+    jitter: f64,
 }
 
 impl Default for ExponentialBackoffStrategy {
@@ -30,11 +33,31 @@ impl Default for ExponentialBackoffStrategy {
             base_duration: Duration::from_secs(10),
             cap: Duration::from_secs(300),
             last_duration: Duration::from_secs(0),
+            jitter: 0.0,
         }
     }
 }
 
 impl ExponentialBackoffStrategy {
+    /// Gets a backoff strategy that starts at the given duration and doubles it after each
+    /// retry, up to the cap.
+    pub fn new(base_duration: Duration, cap: Duration) -> Self {
+        Self {
+            base_duration,
+            cap,
+            last_duration: Duration::from_secs(0),
+            jitter: 0.0,
+        }
+    }
+
+    /// Randomly shortens each duration by up to the given fraction of it (clamped to between 0
+    /// and 1), so that many things failing at once don't all retry at the same time. The
+    /// exponential sequence itself is unaffected, so durations still never exceed the cap.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
     fn capped_next_duration(&self) -> Duration {
         let next_duration = if self.last_duration == Duration::from_secs(0) {
             self.base_duration
@@ -58,7 +81,12 @@ impl BackoffStrategy for ExponentialBackoffStrategy {
     fn next_duration(&mut self) -> Duration {
         let next_duration = self.capped_next_duration();
         self.last_duration = next_duration;
-        next_duration
+        if self.jitter > 0.0 {
+            let factor = 1.0 - rand::thread_rng().gen_range(0.0..=self.jitter);
+            next_duration.mul_f64(factor)
+        } else {
+            next_duration
+        }
     }
 }
 
@@ -103,4 +131,45 @@ mod test {
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
         assert_eq!(backoff.next_duration(), Duration::from_secs(300));
     }
+
+    #[test]
+    fn custom_backoff_starts_at_base_and_is_capped() {
+        let mut backoff =
+            ExponentialBackoffStrategy::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_duration(), Duration::from_secs(1));
+        assert_eq!(backoff.next_duration(), Duration::from_secs(2));
+        assert_eq!(backoff.next_duration(), Duration::from_secs(4));
+        assert_eq!(backoff.next_duration(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jittered_backoff_stays_within_bounds() {
+        let mut backoff = ExponentialBackoffStrategy::default().with_jitter(0.1);
+        for expected in [10, 20, 40, 80, 160, 300, 300].iter() {
+            let expected = Duration::from_secs(*expected);
+            let duration = backoff.next_duration();
+            assert!(
+                duration <= expected,
+                "{:?} exceeds {:?}",
+                duration,
+                expected
+            );
+            assert!(
+                duration >= expected.mul_f64(0.9),
+                "{:?} is more than 10% under {:?}",
+                duration,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn jitter_is_clamped() {
+        let mut backoff = ExponentialBackoffStrategy::default().with_jitter(5.0);
+        for _ in 0..20 {
+            assert!(backoff.next_duration() <= Duration::from_secs(300));
+        }
+        let mut backoff = ExponentialBackoffStrategy::default().with_jitter(-1.0);
+        assert_eq!(backoff.next_duration(), Duration::from_secs(10));
+    }
 }
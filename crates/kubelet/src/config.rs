@@ -24,8 +24,23 @@ use std::collections::HashMap;
 
 use serde::Deserialize;
 
+use crate::resources::quantity::{Quantity, QuantityType};
+
 const DEFAULT_PORT: u16 = 3000;
 const DEFAULT_MAX_PODS: u16 = 110;
+const DEFAULT_CONTAINER_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_CONTAINER_LOG_MAX_FILES: u32 = 5;
+const DEFAULT_CONTAINER_LOG_BUFFER_SIZE: u64 = 64 * 1024;
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS: u64 = 120;
+const DEFAULT_CONTAINER_START_TIMEOUT_SECONDS: u64 = 300;
+const DEFAULT_SYSTEM_RESERVED_MEMORY: u64 = 256 * 1024 * 1024;
+const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+const DEFAULT_MODULE_POOL_MEMORY_PAGES: u32 = 16384;
+const DEFAULT_MODULE_POOL_TABLE_ELEMENTS: u32 = 10_000;
+const DEFAULT_MAX_MODULE_SIZE: u64 = 256 * 1024 * 1024;
+const DEFAULT_NODE_LEASE_RENEW_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_NODE_STATUS_UPDATE_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_MAX_PREOPENED_DIRS: usize = 256;
 const BOOTSTRAP_FILE: &str = "/etc/kubernetes/bootstrap-kubelet.conf";
 
 /// The configuration needed for a kubelet to run properly.
@@ -66,6 +81,100 @@ pub struct Config {
     /// device plugins lives. This is also where device plugins
     /// should host their services.
     pub device_plugins_dir: PathBuf,
+    /// The size in bytes a container's log can grow to before it is rotated
+    pub container_log_max_size: u64,
+    /// The maximum number of log files kept for each container, including the current one
+    pub container_log_max_files: u32,
+    /// The size in bytes of the in-memory buffer of each container's most recent output, which
+    /// logs are served from ahead of the log file. Output isn't buffered if this is 0
+    pub container_log_buffer_size: u64,
+    /// The directory where pulled modules are cached
+    pub module_cache_dir: PathBuf,
+    /// The size in bytes the module cache can grow to before the least recently used modules
+    /// are evicted. The cache is unbounded if this is not set
+    pub module_cache_max_size: Option<u64>,
+    /// The path to the public key module signatures are verified with
+    pub module_verification_key: Option<PathBuf>,
+    /// Whether the modules of all pods must be signed, rather than only those of pods that
+    /// require it
+    pub require_signed_modules: bool,
+    /// Whether to skip verifying the TLS certificates of servers modules are downloaded from by
+    /// URL
+    pub insecure_skip_module_tls_verify: bool,
+    /// The port Prometheus metrics are served on. Metrics are not served if this is not set
+    pub metrics_port: Option<u16>,
+    /// The endpoint of the OpenTelemetry collector spans are exported to over OTLP. Spans are
+    /// not exported if this is not set
+    pub otlp_endpoint: Option<String>,
+    /// The maximum number of modules compiled at once. Containers wait to start until their
+    /// module can be compiled
+    pub max_concurrent_compilations: usize,
+    /// How long to wait for pods to be evicted when the node shuts down, before the node is
+    /// deregistered anyway
+    pub shutdown_grace_period: std::time::Duration,
+    /// The memory, in bytes, reserved for the system and the kubelet itself. This is subtracted
+    /// from the memory of the node to give the memory allocatable to pods
+    pub system_reserved_memory: u64,
+    /// Whether each line of container output is logged as a JSON object recording where it came
+    /// from, rather than as it was written
+    pub json_container_logs: bool,
+    /// The addresses of the cluster's DNS servers, which pods with the `ClusterFirst` DNS policy
+    /// resolve names with. Those pods fall back to the node's resolver if this is empty
+    pub cluster_dns: Vec<IpAddr>,
+    /// The DNS domain of the cluster, which names are searched in by pods with the `ClusterFirst`
+    /// DNS policy
+    pub cluster_domain: String,
+    /// The number of module instances the pooling allocator reserves space for, so modules are
+    /// instantiated without allocating. Each of the engines modules are run with reserves this
+    /// many, and modules are allocated on demand once they are all in use. Instances are always
+    /// allocated on demand if this is not set
+    pub module_pool_size: Option<u32>,
+    /// The most 64KiB pages of memory a module instantiated from the pool can have
+    pub module_pool_memory_pages: u32,
+    /// The most elements a table of a module instantiated from the pool can have
+    pub module_pool_table_elements: u32,
+    /// The size in bytes a module can be. Larger modules fail to start rather than being compiled
+    pub max_module_size: u64,
+    /// How often the node's lease is renewed, which is how Kubernetes knows the node is alive
+    pub node_lease_renew_interval: std::time::Duration,
+    /// How often the node's status, such as its capacity and readiness, is posted
+    pub node_status_update_interval: std::time::Duration,
+    /// The most directories that can be mounted into a container's module. Each is held open while
+    /// the module runs, so this stops a pod with a great many volume mounts exhausting the node's
+    /// file descriptors
+    pub max_preopened_dirs: usize,
+    /// How much Cranelift optimizes modules as it compiles them. Higher levels take longer to
+    /// compile in exchange for faster modules
+    pub module_opt_level: ModuleOptLevel,
+    /// Whether the functions of a module are compiled on several threads at once. Turning this off
+    /// makes each compilation slower, but leaves more CPU for running modules
+    pub parallel_compilation: bool,
+    /// The domains and network ranges modules may send HTTP requests to when neither their pod nor
+    /// its namespace gives an allow list. Modules can't send requests anywhere if this is empty
+    pub default_allowed_domains: Vec<String>,
+    /// Whether a pod's network policy falls back to the annotations on its namespace. Reading the
+    /// namespace takes permissions the node's credentials usually don't have, so this is off
+    /// unless the node has been granted them
+    pub namespace_network_defaults: bool,
+    /// Directories pods may have their container logs written under instead of the data directory,
+    /// through the log directory annotation. Pods can't move their logs anywhere else
+    pub allowed_log_dirs: Vec<PathBuf>,
+    /// Pods are evicted once the memory available on the node drops below this many bytes.
+    /// Nothing is evicted for lack of memory if this is `None`
+    pub eviction_memory_available: Option<u64>,
+    /// Pods are evicted once the disk space available to the data directory drops below this
+    /// many bytes. Nothing is evicted for lack of disk space if this is `None`
+    pub eviction_disk_available: Option<u64>,
+    /// The WebAssembly features modules may not be compiled with, such as `simd` or `threads`.
+    /// Pods that ask for one of these are failed rather than run without it
+    pub disabled_wasm_features: Vec<String>,
+    /// The wasi-nn backends installed on the node, such as `openvino`, which pods can run machine
+    /// learning inference with. Modules can't use wasi-nn if this is empty
+    pub wasi_nn_backends: Vec<String>,
+    /// How long a container's module may take to be set up and started before the container is
+    /// failed, so a start that hangs doesn't leave the container waiting forever. Time spent
+    /// waiting for other modules to finish compiling doesn't count towards it
+    pub container_start_timeout: std::time::Duration,
 }
 /// The configuration for the Kubelet server.
 #[derive(Clone, Debug)]
@@ -80,6 +189,39 @@ pub struct ServerConfig {
     pub private_key_file: PathBuf,
 }
 
+/// How much Cranelift optimizes the code it compiles modules to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModuleOptLevel {
+    /// No optimizations, for the fastest compiles
+    None,
+    /// Optimizations that make code faster, which is what wasmtime does by default
+    Speed,
+    /// Optimizations that make code faster and smaller
+    SpeedAndSize,
+}
+
+impl Default for ModuleOptLevel {
+    fn default() -> Self {
+        ModuleOptLevel::Speed
+    }
+}
+
+impl std::str::FromStr for ModuleOptLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "none" => Ok(ModuleOptLevel::None),
+            "speed" => Ok(ModuleOptLevel::Speed),
+            "speed-and-size" => Ok(ModuleOptLevel::SpeedAndSize),
+            _ => Err(anyhow::anyhow!(
+                "{:?} is not one of none, speed or speed-and-size",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Default, serde::Deserialize)]
 struct ConfigBuilder {
     // Some -> Ok(v) = it was present and the value parsed as v
@@ -127,6 +269,76 @@ struct ConfigBuilder {
     pub plugins_dir: Option<PathBuf>,
     #[serde(default, rename = "devicePluginsDir")]
     pub device_plugins_dir: Option<PathBuf>,
+    #[serde(default, rename = "containerLogMaxSize")]
+    pub container_log_max_size: Option<String>,
+    #[serde(default, rename = "containerLogMaxFiles")]
+    pub container_log_max_files: Option<u32>,
+    #[serde(default, rename = "containerLogBufferSize")]
+    pub container_log_buffer_size: Option<String>,
+    #[serde(default, rename = "moduleCacheDir")]
+    pub module_cache_dir: Option<PathBuf>,
+    #[serde(default, rename = "moduleCacheMaxSize")]
+    pub module_cache_max_size: Option<String>,
+    #[serde(default, rename = "moduleVerificationKey")]
+    pub module_verification_key: Option<PathBuf>,
+    #[serde(default, rename = "requireSignedModules")]
+    pub require_signed_modules: Option<bool>,
+    #[serde(default, rename = "insecureSkipModuleTlsVerify")]
+    pub insecure_skip_module_tls_verify: Option<bool>,
+    #[serde(
+        default,
+        rename = "metricsPort",
+        deserialize_with = "try_deserialize_u16"
+    )]
+    pub metrics_port: Option<anyhow::Result<u16>>,
+    #[serde(default, rename = "otlpEndpoint")]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default, rename = "maxConcurrentCompilations")]
+    pub max_concurrent_compilations: Option<usize>,
+    #[serde(default, rename = "shutdownGracePeriodSeconds")]
+    pub shutdown_grace_period: Option<u64>,
+    #[serde(default, rename = "systemReservedMemory")]
+    pub system_reserved_memory: Option<String>,
+    #[serde(default, rename = "jsonContainerLogs")]
+    pub json_container_logs: Option<bool>,
+    #[serde(default, rename = "clusterDNS")]
+    pub cluster_dns: Option<Vec<String>>,
+    #[serde(default, rename = "clusterDomain")]
+    pub cluster_domain: Option<String>,
+    #[serde(default, rename = "modulePoolSize")]
+    pub module_pool_size: Option<u32>,
+    #[serde(default, rename = "modulePoolMemoryPages")]
+    pub module_pool_memory_pages: Option<u32>,
+    #[serde(default, rename = "modulePoolTableElements")]
+    pub module_pool_table_elements: Option<u32>,
+    #[serde(default, rename = "maxModuleSize")]
+    pub max_module_size: Option<String>,
+    #[serde(default, rename = "nodeLeaseRenewIntervalSeconds")]
+    pub node_lease_renew_interval: Option<u64>,
+    #[serde(default, rename = "nodeStatusUpdateIntervalSeconds")]
+    pub node_status_update_interval: Option<u64>,
+    #[serde(default, rename = "maxPreopenedDirs")]
+    pub max_preopened_dirs: Option<usize>,
+    #[serde(default, rename = "moduleOptLevel")]
+    pub module_opt_level: Option<String>,
+    #[serde(default, rename = "parallelCompilation")]
+    pub parallel_compilation: Option<bool>,
+    #[serde(default, rename = "defaultAllowedDomains")]
+    pub default_allowed_domains: Option<Vec<String>>,
+    #[serde(default, rename = "namespaceNetworkDefaults")]
+    pub namespace_network_defaults: Option<bool>,
+    #[serde(default, rename = "allowedLogDirs")]
+    pub allowed_log_dirs: Option<Vec<PathBuf>>,
+    #[serde(default, rename = "evictionMemoryAvailable")]
+    pub eviction_memory_available: Option<String>,
+    #[serde(default, rename = "evictionDiskAvailable")]
+    pub eviction_disk_available: Option<String>,
+    #[serde(default, rename = "disabledWasmFeatures")]
+    pub disabled_wasm_features: Option<Vec<String>>,
+    #[serde(default, rename = "wasiNnBackends")]
+    pub wasi_nn_backends: Option<Vec<String>>,
+    #[serde(default, rename = "containerStartTimeoutSeconds")]
+    pub container_start_timeout: Option<u64>,
 }
 
 struct ConfigBuilderFallbacks {
@@ -137,6 +349,7 @@ struct ConfigBuilderFallbacks {
     key_path: fn(data_dir: &Path) -> PathBuf,
     plugins_dir: fn(data_dir: &Path) -> PathBuf,
     device_plugins_dir: fn(data_dir: &Path) -> PathBuf,
+    module_cache_dir: fn(data_dir: &Path) -> PathBuf,
     node_ip: fn(hostname: &mut String, preferred_ip_family: &IpAddr) -> IpAddr,
 }
 
@@ -153,6 +366,7 @@ impl Config {
         let private_key_file = default_key_path(&data_dir);
         let plugins_dir = default_plugins_path(&data_dir);
         let device_plugins_dir = default_device_plugins_path(&data_dir);
+        let module_cache_dir = default_module_cache_path(&data_dir);
         Ok(Config {
             node_ip: default_node_ip(&mut hostname.clone(), preferred_ip_family)?,
             node_name: sanitize_hostname(&hostname),
@@ -165,6 +379,47 @@ impl Config {
             insecure_registries: None,
             plugins_dir,
             device_plugins_dir,
+            container_log_max_size: DEFAULT_CONTAINER_LOG_MAX_SIZE,
+            container_log_max_files: DEFAULT_CONTAINER_LOG_MAX_FILES,
+            container_log_buffer_size: DEFAULT_CONTAINER_LOG_BUFFER_SIZE,
+            module_cache_dir,
+            module_cache_max_size: None,
+            module_verification_key: None,
+            require_signed_modules: false,
+            insecure_skip_module_tls_verify: false,
+            metrics_port: None,
+            otlp_endpoint: None,
+            max_concurrent_compilations: num_cpus::get(),
+            shutdown_grace_period: std::time::Duration::from_secs(
+                DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS,
+            ),
+            system_reserved_memory: DEFAULT_SYSTEM_RESERVED_MEMORY,
+            json_container_logs: false,
+            cluster_dns: Vec::new(),
+            cluster_domain: DEFAULT_CLUSTER_DOMAIN.to_owned(),
+            module_pool_size: None,
+            module_pool_memory_pages: DEFAULT_MODULE_POOL_MEMORY_PAGES,
+            module_pool_table_elements: DEFAULT_MODULE_POOL_TABLE_ELEMENTS,
+            max_module_size: DEFAULT_MAX_MODULE_SIZE,
+            node_lease_renew_interval: std::time::Duration::from_secs(
+                DEFAULT_NODE_LEASE_RENEW_INTERVAL_SECONDS,
+            ),
+            node_status_update_interval: std::time::Duration::from_secs(
+                DEFAULT_NODE_STATUS_UPDATE_INTERVAL_SECONDS,
+            ),
+            max_preopened_dirs: DEFAULT_MAX_PREOPENED_DIRS,
+            module_opt_level: ModuleOptLevel::default(),
+            parallel_compilation: true,
+            default_allowed_domains: Vec::new(),
+            namespace_network_defaults: false,
+            allowed_log_dirs: Vec::new(),
+            eviction_memory_available: None,
+            eviction_disk_available: None,
+            disabled_wasm_features: Vec::new(),
+            wasi_nn_backends: Vec::new(),
+            container_start_timeout: std::time::Duration::from_secs(
+                DEFAULT_CONTAINER_START_TIMEOUT_SECONDS,
+            ),
             server_config: ServerConfig {
                 addr: match preferred_ip_family {
                     IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
@@ -185,6 +440,7 @@ impl Config {
             key_path: default_key_path,
             plugins_dir: default_plugins_path,
             device_plugins_dir: default_device_plugins_path,
+            module_cache_dir: default_module_cache_path,
             node_ip: |hn, ip| default_node_ip(hn, ip).expect("unable to get default node IP"),
             bootstrap_file: || PathBuf::from(BOOTSTRAP_FILE),
         };
@@ -289,6 +545,44 @@ impl ConfigBuilder {
             insecure_registries: opts.insecure_registries.map(parse_comma_separated),
             plugins_dir: opts.plugins_dir,
             device_plugins_dir: opts.device_plugins_dir,
+            container_log_max_size: opts.container_log_max_size,
+            container_log_max_files: opts.container_log_max_files,
+            container_log_buffer_size: opts.container_log_buffer_size,
+            module_cache_dir: opts.module_cache_dir,
+            module_cache_max_size: opts.module_cache_max_size,
+            module_verification_key: opts.module_verification_key,
+            require_signed_modules: opts.require_signed_modules,
+            insecure_skip_module_tls_verify: opts.insecure_skip_module_tls_verify,
+            metrics_port: ok_result_of(opts.metrics_port),
+            otlp_endpoint: opts.otlp_endpoint,
+            max_concurrent_compilations: opts.max_concurrent_compilations,
+            shutdown_grace_period: opts.shutdown_grace_period,
+            system_reserved_memory: opts.system_reserved_memory,
+            json_container_logs: opts.json_container_logs,
+            cluster_dns: opts.cluster_dns.map(parse_comma_separated),
+            cluster_domain: opts.cluster_domain,
+            module_pool_size: opts.module_pool_size,
+            module_pool_memory_pages: opts.module_pool_memory_pages,
+            module_pool_table_elements: opts.module_pool_table_elements,
+            max_module_size: opts.max_module_size,
+            node_lease_renew_interval: opts.node_lease_renew_interval,
+            node_status_update_interval: opts.node_status_update_interval,
+            max_preopened_dirs: opts.max_preopened_dirs,
+            module_opt_level: opts.module_opt_level,
+            parallel_compilation: opts.parallel_compilation,
+            default_allowed_domains: opts.default_allowed_domains.map(parse_comma_separated),
+            namespace_network_defaults: opts.namespace_network_defaults,
+            allowed_log_dirs: opts.allowed_log_dirs.map(|dirs| {
+                parse_comma_separated(dirs)
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect()
+            }),
+            eviction_memory_available: opts.eviction_memory_available,
+            eviction_disk_available: opts.eviction_disk_available,
+            disabled_wasm_features: opts.disabled_wasm_features.map(parse_comma_separated),
+            wasi_nn_backends: opts.wasi_nn_backends.map(parse_comma_separated),
+            container_start_timeout: opts.container_start_timeout,
             server_addr: ok_result_of(opts.addr),
             server_port: ok_result_of(opts.port),
             server_tls_cert_file: opts.cert_file,
@@ -328,6 +622,67 @@ impl ConfigBuilder {
             insecure_registries: other.insecure_registries.or(self.insecure_registries),
             plugins_dir: other.plugins_dir.or(self.plugins_dir),
             device_plugins_dir: other.device_plugins_dir.or(self.device_plugins_dir),
+            container_log_max_size: other.container_log_max_size.or(self.container_log_max_size),
+            container_log_max_files: other
+                .container_log_max_files
+                .or(self.container_log_max_files),
+            container_log_buffer_size: other
+                .container_log_buffer_size
+                .or(self.container_log_buffer_size),
+            module_cache_dir: other.module_cache_dir.or(self.module_cache_dir),
+            module_cache_max_size: other.module_cache_max_size.or(self.module_cache_max_size),
+            module_verification_key: other
+                .module_verification_key
+                .or(self.module_verification_key),
+            require_signed_modules: other.require_signed_modules.or(self.require_signed_modules),
+            insecure_skip_module_tls_verify: other
+                .insecure_skip_module_tls_verify
+                .or(self.insecure_skip_module_tls_verify),
+            metrics_port: other.metrics_port.or(self.metrics_port),
+            otlp_endpoint: other.otlp_endpoint.or(self.otlp_endpoint),
+            max_concurrent_compilations: other
+                .max_concurrent_compilations
+                .or(self.max_concurrent_compilations),
+            shutdown_grace_period: other.shutdown_grace_period.or(self.shutdown_grace_period),
+            system_reserved_memory: other.system_reserved_memory.or(self.system_reserved_memory),
+            json_container_logs: other.json_container_logs.or(self.json_container_logs),
+            cluster_dns: other.cluster_dns.or(self.cluster_dns),
+            cluster_domain: other.cluster_domain.or(self.cluster_domain),
+            module_pool_size: other.module_pool_size.or(self.module_pool_size),
+            module_pool_memory_pages: other
+                .module_pool_memory_pages
+                .or(self.module_pool_memory_pages),
+            module_pool_table_elements: other
+                .module_pool_table_elements
+                .or(self.module_pool_table_elements),
+            max_module_size: other.max_module_size.or(self.max_module_size),
+            node_lease_renew_interval: other
+                .node_lease_renew_interval
+                .or(self.node_lease_renew_interval),
+            node_status_update_interval: other
+                .node_status_update_interval
+                .or(self.node_status_update_interval),
+            max_preopened_dirs: other.max_preopened_dirs.or(self.max_preopened_dirs),
+            module_opt_level: other.module_opt_level.or(self.module_opt_level),
+            parallel_compilation: other.parallel_compilation.or(self.parallel_compilation),
+            default_allowed_domains: other
+                .default_allowed_domains
+                .or(self.default_allowed_domains),
+            namespace_network_defaults: other
+                .namespace_network_defaults
+                .or(self.namespace_network_defaults),
+            allowed_log_dirs: other.allowed_log_dirs.or(self.allowed_log_dirs),
+            eviction_memory_available: other
+                .eviction_memory_available
+                .or(self.eviction_memory_available),
+            eviction_disk_available: other
+                .eviction_disk_available
+                .or(self.eviction_disk_available),
+            disabled_wasm_features: other.disabled_wasm_features.or(self.disabled_wasm_features),
+            wasi_nn_backends: other.wasi_nn_backends.or(self.wasi_nn_backends),
+            container_start_timeout: other
+                .container_start_timeout
+                .or(self.container_start_timeout),
             server_tls_private_key_file: other
                 .server_tls_private_key_file
                 .or(self.server_tls_private_key_file),
@@ -346,6 +701,9 @@ impl ConfigBuilder {
         let device_plugins_dir = self
             .device_plugins_dir
             .unwrap_or_else(|| (fallbacks.device_plugins_dir)(&data_dir));
+        let module_cache_dir = self
+            .module_cache_dir
+            .unwrap_or_else(|| (fallbacks.module_cache_dir)(&data_dir));
         let server_addr = self
             .server_addr
             .unwrap_or(Ok(empty_ip_addr))
@@ -371,6 +729,117 @@ impl ConfigBuilder {
             .max_pods
             .unwrap_or(Ok(DEFAULT_MAX_PODS))
             .map_err(|e| invalid_config_value_error(e, "maximum pods"))?;
+        let container_log_max_size = self
+            .container_log_max_size
+            .map(|size| parse_size(&size))
+            .unwrap_or(Ok(DEFAULT_CONTAINER_LOG_MAX_SIZE))
+            .map_err(|e| invalid_config_value_error(e, "container log maximum size"))?;
+        let container_log_max_files = self
+            .container_log_max_files
+            .unwrap_or(DEFAULT_CONTAINER_LOG_MAX_FILES);
+        if container_log_max_files < 2 {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 2, got {}", container_log_max_files),
+                "container log maximum files",
+            ));
+        }
+        let container_log_buffer_size = self
+            .container_log_buffer_size
+            .map(|size| parse_size(&size))
+            .unwrap_or(Ok(DEFAULT_CONTAINER_LOG_BUFFER_SIZE))
+            .map_err(|e| invalid_config_value_error(e, "container log buffer size"))?;
+        let module_cache_max_size = self
+            .module_cache_max_size
+            .map(|size| parse_size(&size))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "module cache maximum size"))?;
+        let metrics_port = self
+            .metrics_port
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "metrics port"))?;
+        let max_concurrent_compilations = self
+            .max_concurrent_compilations
+            .unwrap_or_else(num_cpus::get);
+        if max_concurrent_compilations < 1 {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 1, got {}", max_concurrent_compilations),
+                "maximum concurrent compilations",
+            ));
+        }
+        let require_signed_modules = self.require_signed_modules.unwrap_or(false);
+        if require_signed_modules && self.module_verification_key.is_none() {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("a module verification key must be set"),
+                "require signed modules",
+            ));
+        }
+        let system_reserved_memory = match self.system_reserved_memory.as_deref() {
+            None => DEFAULT_SYSTEM_RESERVED_MEMORY,
+            Some("0") => 0,
+            Some(size) => parse_size(size)
+                .map_err(|e| invalid_config_value_error(e, "system reserved memory"))?,
+        };
+        let cluster_dns = self
+            .cluster_dns
+            .unwrap_or_default()
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|e| invalid_config_value_error(e.into(), "cluster DNS"))?;
+        let module_pool_size = self.module_pool_size;
+        if module_pool_size == Some(0) {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 1"),
+                "module pool size",
+            ));
+        }
+        let max_module_size = self
+            .max_module_size
+            .map(|size| parse_size(&size))
+            .unwrap_or(Ok(DEFAULT_MAX_MODULE_SIZE))
+            .map_err(|e| invalid_config_value_error(e, "maximum module size"))?;
+        let node_lease_renew_interval = self
+            .node_lease_renew_interval
+            .unwrap_or(DEFAULT_NODE_LEASE_RENEW_INTERVAL_SECONDS);
+        if node_lease_renew_interval < 1 {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 1 second"),
+                "node lease renew interval",
+            ));
+        }
+        let node_status_update_interval = self
+            .node_status_update_interval
+            .unwrap_or(DEFAULT_NODE_STATUS_UPDATE_INTERVAL_SECONDS);
+        if node_status_update_interval < 1 {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 1 second"),
+                "node status update interval",
+            ));
+        }
+        let container_start_timeout = self
+            .container_start_timeout
+            .unwrap_or(DEFAULT_CONTAINER_START_TIMEOUT_SECONDS);
+        if container_start_timeout < 1 {
+            return Err(invalid_config_value_error(
+                anyhow::anyhow!("must be at least 1 second"),
+                "container start timeout",
+            ));
+        }
+        let module_opt_level = self
+            .module_opt_level
+            .map(|level| level.parse())
+            .unwrap_or_else(|| Ok(ModuleOptLevel::default()))
+            .map_err(|e| invalid_config_value_error(e, "module optimization level"))?;
+        let eviction_memory_available = self
+            .eviction_memory_available
+            .map(|size| parse_size(&size))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "eviction memory threshold"))?;
+        let eviction_disk_available = self
+            .eviction_disk_available
+            .map(|size| parse_size(&size))
+            .transpose()
+            .map_err(|e| invalid_config_value_error(e, "eviction disk threshold"))?;
 
         Ok(Config {
             node_ip,
@@ -384,6 +853,52 @@ impl ConfigBuilder {
             insecure_registries: self.insecure_registries,
             plugins_dir,
             device_plugins_dir,
+            container_log_max_size,
+            container_log_max_files,
+            container_log_buffer_size,
+            module_cache_dir,
+            module_cache_max_size,
+            module_verification_key: self.module_verification_key,
+            require_signed_modules,
+            insecure_skip_module_tls_verify: self.insecure_skip_module_tls_verify.unwrap_or(false),
+            metrics_port,
+            otlp_endpoint: self.otlp_endpoint,
+            max_concurrent_compilations,
+            shutdown_grace_period: std::time::Duration::from_secs(
+                self.shutdown_grace_period
+                    .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS),
+            ),
+            system_reserved_memory,
+            json_container_logs: self.json_container_logs.unwrap_or(false),
+            cluster_dns,
+            cluster_domain: self
+                .cluster_domain
+                .unwrap_or_else(|| DEFAULT_CLUSTER_DOMAIN.to_owned()),
+            module_pool_size,
+            module_pool_memory_pages: self
+                .module_pool_memory_pages
+                .unwrap_or(DEFAULT_MODULE_POOL_MEMORY_PAGES),
+            module_pool_table_elements: self
+                .module_pool_table_elements
+                .unwrap_or(DEFAULT_MODULE_POOL_TABLE_ELEMENTS),
+            max_module_size,
+            node_lease_renew_interval: std::time::Duration::from_secs(node_lease_renew_interval),
+            node_status_update_interval: std::time::Duration::from_secs(
+                node_status_update_interval,
+            ),
+            max_preopened_dirs: self
+                .max_preopened_dirs
+                .unwrap_or(DEFAULT_MAX_PREOPENED_DIRS),
+            module_opt_level,
+            parallel_compilation: self.parallel_compilation.unwrap_or(true),
+            default_allowed_domains: self.default_allowed_domains.unwrap_or_default(),
+            namespace_network_defaults: self.namespace_network_defaults.unwrap_or(false),
+            allowed_log_dirs: self.allowed_log_dirs.unwrap_or_default(),
+            eviction_memory_available,
+            eviction_disk_available,
+            disabled_wasm_features: self.disabled_wasm_features.unwrap_or_default(),
+            wasi_nn_backends: self.wasi_nn_backends.unwrap_or_default(),
+            container_start_timeout: std::time::Duration::from_secs(container_start_timeout),
             server_config: ServerConfig {
                 cert_file: server_tls_cert_file,
                 private_key_file: server_tls_private_key_file,
@@ -413,6 +928,15 @@ where
     Ok(Some(n))
 }
 
+/// Parses a size given as a Kubernetes quantity (e.g. `10Mi`) into a number of bytes
+fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let quantity = k8s_openapi::apimachinery::pkg::api::resource::Quantity(size.to_owned());
+    match Quantity::from_kube_quantity(QuantityType::Memory(&quantity))? {
+        Quantity::Memory(bytes) if bytes > 0 => Ok(bytes as u64),
+        _ => anyhow::bail!("size must be a positive number of bytes"),
+    }
+}
+
 /// CLI options that can be configured for Kubelet
 ///
 /// These can be parsed from args using `Opts::into_app()`
@@ -541,6 +1065,237 @@ pub struct Opts {
         help = "Registries that should be accessed over HTTP instead of HTTPS (comma separated)"
     )]
     insecure_registries: Option<String>,
+
+    #[structopt(
+        long = "container-log-max-size",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_SIZE",
+        help = "The size a container's log can grow to before it is rotated, as a quantity (e.g. 10Mi). Defaults to 10Mi"
+    )]
+    container_log_max_size: Option<String>,
+
+    #[structopt(
+        long = "container-log-max-files",
+        env = "KRUSTLET_CONTAINER_LOG_MAX_FILES",
+        help = "The maximum number of log files kept for each container, including the current one. Must be at least 2. Defaults to 5"
+    )]
+    container_log_max_files: Option<u32>,
+
+    #[structopt(
+        long = "container-log-buffer-size",
+        env = "KRUSTLET_CONTAINER_LOG_BUFFER_SIZE",
+        help = "The size of the in-memory buffer of each container's most recent output that logs are served from ahead of its log file, as a quantity (e.g. 64Ki). 0 turns the buffer off. Defaults to 64Ki"
+    )]
+    container_log_buffer_size: Option<String>,
+
+    #[structopt(
+        long = "module-cache-dir",
+        env = "KRUSTLET_MODULE_CACHE_DIR",
+        help = "The path to the directory where pulled modules are cached. Defaults to $KRUSTLET_DATA_DIR/.oci/modules"
+    )]
+    module_cache_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "module-cache-max-size",
+        env = "KRUSTLET_MODULE_CACHE_MAX_SIZE",
+        help = "The size the module cache can grow to before the least recently used modules are evicted, as a quantity (e.g. 1Gi). Defaults to unbounded"
+    )]
+    module_cache_max_size: Option<String>,
+
+    #[structopt(
+        long = "module-verification-key",
+        env = "KRUSTLET_MODULE_VERIFICATION_KEY",
+        help = "The path to the PEM encoded public key that cosign signatures of modules are verified with. Modules are only verified for pods annotated with krustlet.dev/require-signed-modules=true unless --require-signed-modules is set"
+    )]
+    module_verification_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "require-signed-modules",
+        env = "KRUSTLET_REQUIRE_SIGNED_MODULES",
+        help = "Whether the modules of all pods must have a valid signature. Requires --module-verification-key"
+    )]
+    require_signed_modules: Option<bool>,
+
+    #[structopt(
+        long = "insecure-skip-module-tls-verify",
+        env = "KRUSTLET_INSECURE_SKIP_MODULE_TLS_VERIFY",
+        help = "Whether to skip verifying TLS certificates when downloading modules given as https URLs, e.g. for servers with certificates from an internal CA"
+    )]
+    insecure_skip_module_tls_verify: Option<bool>,
+
+    #[structopt(
+        long = "metrics-port",
+        env = "KRUSTLET_METRICS_PORT",
+        help = "The port Prometheus metrics should be served on at /metrics. Metrics are not served by default"
+    )]
+    metrics_port: Option<u16>,
+
+    #[structopt(
+        long = "otlp-endpoint",
+        env = "KRUSTLET_OTLP_ENDPOINT",
+        help = "The endpoint of an OpenTelemetry collector to export spans to over OTLP, e.g. http://localhost:4317. Spans are filtered by RUST_LOG like logs are. Spans are not exported by default"
+    )]
+    otlp_endpoint: Option<String>,
+
+    #[structopt(
+        long = "max-concurrent-compilations",
+        env = "KRUSTLET_MAX_CONCURRENT_COMPILATIONS",
+        help = "The maximum number of modules compiled at once. Containers beyond the limit wait to start. Defaults to the number of CPUs"
+    )]
+    max_concurrent_compilations: Option<usize>,
+
+    #[structopt(
+        long = "shutdown-grace-period",
+        env = "KRUSTLET_SHUTDOWN_GRACE_PERIOD",
+        help = "The number of seconds to wait for pods to be evicted when the node shuts down, before it is deregistered anyway. Defaults to 120"
+    )]
+    shutdown_grace_period: Option<u64>,
+
+    #[structopt(
+        long = "system-reserved-memory",
+        env = "KRUSTLET_SYSTEM_RESERVED_MEMORY",
+        help = "The memory reserved for the system and the kubelet, as a quantity (e.g. 512Mi). This is not allocatable to pods. Defaults to 256Mi"
+    )]
+    system_reserved_memory: Option<String>,
+
+    #[structopt(
+        long = "json-container-logs",
+        env = "KRUSTLET_JSON_CONTAINER_LOGS",
+        help = "Whether to log each line of container output as a JSON object with its timestamp, pod, container and stream, rather than as it was written"
+    )]
+    json_container_logs: Option<bool>,
+
+    #[structopt(
+        long = "cluster-dns",
+        env = "KRUSTLET_CLUSTER_DNS",
+        help = "A comma separated list of the addresses of the cluster's DNS servers. Pods with the ClusterFirst DNS policy use the node's resolver if this is not set"
+    )]
+    cluster_dns: Option<String>,
+
+    #[structopt(
+        long = "cluster-domain",
+        env = "KRUSTLET_CLUSTER_DOMAIN",
+        help = "The DNS domain of the cluster. Defaults to cluster.local"
+    )]
+    cluster_domain: Option<String>,
+
+    #[structopt(
+        long = "module-pool-size",
+        env = "KRUSTLET_MODULE_POOL_SIZE",
+        help = "The number of module instances each engine reserves space for up front, which makes instantiating modules faster. Modules are allocated on demand once they are all in use, or if not set"
+    )]
+    module_pool_size: Option<u32>,
+
+    #[structopt(
+        long = "module-pool-memory-pages",
+        env = "KRUSTLET_MODULE_POOL_MEMORY_PAGES",
+        help = "The most 64KiB pages of memory a module instantiated from the pool can have. Defaults to 16384 (1GiB)"
+    )]
+    module_pool_memory_pages: Option<u32>,
+
+    #[structopt(
+        long = "module-pool-table-elements",
+        env = "KRUSTLET_MODULE_POOL_TABLE_ELEMENTS",
+        help = "The most elements a table of a module instantiated from the pool can have. Defaults to 10000"
+    )]
+    module_pool_table_elements: Option<u32>,
+
+    #[structopt(
+        long = "max-module-size",
+        env = "KRUSTLET_MAX_MODULE_SIZE",
+        help = "The largest a module can be, as a quantity (e.g. 64Mi). Larger modules are not downloaded or compiled. Defaults to 256Mi"
+    )]
+    max_module_size: Option<String>,
+
+    #[structopt(
+        long = "node-lease-renew-interval",
+        env = "KRUSTLET_NODE_LEASE_RENEW_INTERVAL",
+        help = "The number of seconds between renewals of the node's lease. Defaults to 10"
+    )]
+    node_lease_renew_interval: Option<u64>,
+
+    #[structopt(
+        long = "node-status-update-interval",
+        env = "KRUSTLET_NODE_STATUS_UPDATE_INTERVAL",
+        help = "The number of seconds between updates of the node's status. Defaults to 10"
+    )]
+    node_status_update_interval: Option<u64>,
+
+    #[structopt(
+        long = "max-preopened-dirs",
+        env = "KRUSTLET_MAX_PREOPENED_DIRS",
+        help = "The most directories, such as volumes, that can be mounted into a container. Containers with more fail to start. Defaults to 256"
+    )]
+    max_preopened_dirs: Option<usize>,
+
+    #[structopt(
+        long = "module-opt-level",
+        env = "KRUSTLET_MODULE_OPT_LEVEL",
+        help = "How much to optimize modules when compiling them: none, speed or speed-and-size. Less optimization compiles faster but runs slower. Defaults to speed"
+    )]
+    module_opt_level: Option<String>,
+
+    #[structopt(
+        long = "parallel-compilation",
+        env = "KRUSTLET_PARALLEL_COMPILATION",
+        help = "Whether to compile the functions of a module on several threads at once. Defaults to true"
+    )]
+    parallel_compilation: Option<bool>,
+
+    #[structopt(
+        long = "default-allowed-domains",
+        env = "KRUSTLET_DEFAULT_ALLOWED_DOMAINS",
+        help = "A comma separated list of the domains and network ranges modules may send HTTP requests to, for pods that don't give their own and whose namespace doesn't either"
+    )]
+    default_allowed_domains: Option<String>,
+
+    #[structopt(
+        long = "namespace-network-defaults",
+        env = "KRUSTLET_NAMESPACE_NETWORK_DEFAULTS",
+        help = "Whether pods that don't give their own network policy fall back to the annotations on their namespace. The node needs permission to read namespaces. Defaults to false"
+    )]
+    namespace_network_defaults: Option<bool>,
+
+    #[structopt(
+        long = "allowed-log-dirs",
+        env = "KRUSTLET_ALLOWED_LOG_DIRS",
+        help = "A comma separated list of directories pods may have their container logs written under instead of the data directory"
+    )]
+    allowed_log_dirs: Option<String>,
+
+    #[structopt(
+        long = "eviction-memory-available",
+        env = "KRUSTLET_EVICTION_MEMORY_AVAILABLE",
+        help = "Evict pods once the memory available on the node drops below this quantity (e.g. 100Mi). Defaults to never evicting"
+    )]
+    eviction_memory_available: Option<String>,
+
+    #[structopt(
+        long = "eviction-disk-available",
+        env = "KRUSTLET_EVICTION_DISK_AVAILABLE",
+        help = "Evict pods once the disk space available to the data directory drops below this quantity (e.g. 100Mi). Defaults to never evicting"
+    )]
+    eviction_disk_available: Option<String>,
+
+    #[structopt(
+        long = "disabled-wasm-features",
+        env = "KRUSTLET_DISABLED_WASM_FEATURES",
+        help = "A comma separated list of the WebAssembly features modules may not use: simd, bulk-memory, reference-types, multi-value or threads"
+    )]
+    disabled_wasm_features: Option<String>,
+
+    #[structopt(
+        long = "wasi-nn-backends",
+        env = "KRUSTLET_WASI_NN_BACKENDS",
+        help = "A comma separated list of the wasi-nn backends installed on the node, such as openvino. Defaults to none, which leaves wasi-nn disabled"
+    )]
+    wasi_nn_backends: Option<String>,
+
+    #[structopt(
+        long = "container-start-timeout",
+        env = "KRUSTLET_CONTAINER_START_TIMEOUT",
+        help = "The number of seconds a container's module may take to start before the container is failed, not counting time spent waiting for other modules to compile. Must be at least 1. Defaults to 300"
+    )]
+    container_start_timeout: Option<u64>,
 }
 
 fn default_hostname() -> anyhow::Result<String> {
@@ -607,6 +1362,10 @@ fn default_device_plugins_path(data_dir: &Path) -> PathBuf {
     data_dir.join("device_plugins")
 }
 
+fn default_module_cache_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(".oci").join("modules")
+}
+
 #[cfg(any(feature = "cli", feature = "docs"))]
 fn default_config_file_path() -> PathBuf {
     dirs::home_dir()
@@ -660,6 +1419,7 @@ mod test {
             key_path: |_| PathBuf::from("/fallback/key/path"),
             plugins_dir: |_| PathBuf::from("/fallback/plugins/dir"),
             device_plugins_dir: |_| PathBuf::from("/fallback/device_plugins/dir"),
+            module_cache_dir: |_| PathBuf::from("/fallback/module_cache/dir"),
             bootstrap_file: || PathBuf::from("/fallback/bootstrap_file.txt"),
         }
     }
@@ -687,7 +1447,40 @@ mod test {
                 "local",
                 "dev"
             ],
-            "pluginsDir": "/some/plugins"
+            "pluginsDir": "/some/plugins",
+            "containerLogMaxSize": "1Mi",
+            "containerLogMaxFiles": 3,
+            "containerLogBufferSize": "128Ki",
+            "moduleCacheDir": "/some/modules",
+            "moduleCacheMaxSize": "1Gi",
+            "moduleVerificationKey": "/some/cosign.pub",
+            "requireSignedModules": true,
+            "insecureSkipModuleTlsVerify": true,
+            "metricsPort": 9100,
+            "otlpEndpoint": "http://collector:4317",
+            "maxConcurrentCompilations": 3,
+            "shutdownGracePeriodSeconds": 30,
+            "systemReservedMemory": "1Gi",
+            "jsonContainerLogs": true,
+            "clusterDNS": ["10.96.0.10"],
+            "clusterDomain": "example.internal",
+            "modulePoolSize": 200,
+            "modulePoolMemoryPages": 1024,
+            "modulePoolTableElements": 5000,
+            "maxModuleSize": "64Mi",
+            "nodeLeaseRenewIntervalSeconds": 5,
+            "nodeStatusUpdateIntervalSeconds": 20,
+            "maxPreopenedDirs": 32,
+            "moduleOptLevel": "none",
+            "parallelCompilation": false,
+            "defaultAllowedDomains": ["https://api.example.com", "10.0.0.0/8"],
+            "namespaceNetworkDefaults": true,
+            "allowedLogDirs": ["/mnt/fast-logs", "/mnt/archive-logs"],
+            "evictionMemoryAvailable": "100Mi",
+            "evictionDiskAvailable": "1Gi",
+            "disabledWasmFeatures": ["simd", "threads"],
+            "wasiNnBackends": ["openvino"],
+            "containerStartTimeoutSeconds": 60
         }"#,
         );
         let config = config_builder.unwrap().build(fallbacks()).unwrap();
@@ -717,6 +1510,66 @@ mod test {
         assert_eq!(&config.insecure_registries.clone().unwrap()[0], "local");
         assert_eq!(&config.insecure_registries.unwrap()[1], "dev");
         assert_eq!(&config.plugins_dir.to_string_lossy(), "/some/plugins");
+        assert_eq!(config.container_log_max_size, 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 3);
+        assert_eq!(config.container_log_buffer_size, 128 * 1024);
+        assert_eq!(&config.module_cache_dir.to_string_lossy(), "/some/modules");
+        assert_eq!(config.module_cache_max_size, Some(1024 * 1024 * 1024));
+        assert_eq!(
+            &config.module_verification_key.unwrap().to_string_lossy(),
+            "/some/cosign.pub"
+        );
+        assert!(config.require_signed_modules);
+        assert!(config.insecure_skip_module_tls_verify);
+        assert_eq!(config.metrics_port, Some(9100));
+        assert_eq!(
+            config.otlp_endpoint.as_deref(),
+            Some("http://collector:4317")
+        );
+        assert_eq!(config.max_concurrent_compilations, 3);
+        assert_eq!(
+            config.shutdown_grace_period,
+            std::time::Duration::from_secs(30)
+        );
+        assert_eq!(config.system_reserved_memory, 1024 * 1024 * 1024);
+        assert!(config.json_container_logs);
+        assert_eq!(config.cluster_dns, vec![IpAddr::from([10, 96, 0, 10])]);
+        assert_eq!(config.cluster_domain, "example.internal");
+        assert_eq!(config.module_pool_size, Some(200));
+        assert_eq!(config.module_pool_memory_pages, 1024);
+        assert_eq!(config.module_pool_table_elements, 5000);
+        assert_eq!(config.max_module_size, 64 * 1024 * 1024);
+        assert_eq!(
+            config.node_lease_renew_interval,
+            std::time::Duration::from_secs(5)
+        );
+        assert_eq!(
+            config.node_status_update_interval,
+            std::time::Duration::from_secs(20)
+        );
+        assert_eq!(config.max_preopened_dirs, 32);
+        assert_eq!(config.module_opt_level, ModuleOptLevel::None);
+        assert!(!config.parallel_compilation);
+        assert_eq!(
+            config.default_allowed_domains,
+            vec!["https://api.example.com", "10.0.0.0/8"]
+        );
+        assert!(config.namespace_network_defaults);
+        assert_eq!(
+            config.allowed_log_dirs,
+            vec![
+                PathBuf::from("/mnt/fast-logs"),
+                PathBuf::from("/mnt/archive-logs")
+            ]
+        );
+        assert_eq!(config.eviction_memory_available, Some(100 * 1024 * 1024));
+        assert_eq!(config.eviction_disk_available, Some(1024 * 1024 * 1024));
+        assert_eq!(config.disabled_wasm_features, vec!["simd", "threads"]);
+        assert_eq!(config.wasi_nn_backends, vec!["openvino"]);
+        assert_eq!(
+            config.container_start_timeout,
+            std::time::Duration::from_secs(60)
+        );
     }
 
     #[test]
@@ -782,6 +1635,54 @@ mod test {
             &config.plugins_dir.to_string_lossy(),
             "/fallback/plugins/dir"
         );
+        assert_eq!(config.container_log_max_size, 10 * 1024 * 1024);
+        assert_eq!(config.container_log_max_files, 5);
+        assert_eq!(config.container_log_buffer_size, 64 * 1024);
+        assert_eq!(
+            &config.module_cache_dir.to_string_lossy(),
+            "/fallback/module_cache/dir"
+        );
+        assert_eq!(config.module_cache_max_size, None);
+        assert_eq!(config.module_verification_key, None);
+        assert!(!config.require_signed_modules);
+        assert!(!config.insecure_skip_module_tls_verify);
+        assert_eq!(config.metrics_port, None);
+        assert_eq!(config.otlp_endpoint, None);
+        assert_eq!(config.max_concurrent_compilations, num_cpus::get());
+        assert_eq!(
+            config.shutdown_grace_period,
+            std::time::Duration::from_secs(120)
+        );
+        assert_eq!(config.system_reserved_memory, 256 * 1024 * 1024);
+        assert!(!config.json_container_logs);
+        assert!(config.cluster_dns.is_empty());
+        assert_eq!(config.cluster_domain, "cluster.local");
+        assert_eq!(config.module_pool_size, None);
+        assert_eq!(config.module_pool_memory_pages, 16384);
+        assert_eq!(config.module_pool_table_elements, 10000);
+        assert_eq!(config.max_module_size, 256 * 1024 * 1024);
+        assert_eq!(
+            config.node_lease_renew_interval,
+            std::time::Duration::from_secs(10)
+        );
+        assert_eq!(
+            config.node_status_update_interval,
+            std::time::Duration::from_secs(10)
+        );
+        assert_eq!(config.max_preopened_dirs, 256);
+        assert_eq!(config.module_opt_level, ModuleOptLevel::Speed);
+        assert!(config.parallel_compilation);
+        assert!(config.default_allowed_domains.is_empty());
+        assert!(!config.namespace_network_defaults);
+        assert!(config.allowed_log_dirs.is_empty());
+        assert_eq!(config.eviction_memory_available, None);
+        assert_eq!(config.eviction_disk_available, None);
+        assert!(config.disabled_wasm_features.is_empty());
+        assert!(config.wasi_nn_backends.is_empty());
+        assert_eq!(
+            config.container_start_timeout,
+            std::time::Duration::from_secs(300)
+        );
     }
 
     #[test]
@@ -971,6 +1872,217 @@ mod test {
         assert!(error.to_string().contains("server port"), "{:?}", error);
     }
 
+    #[test]
+    fn invalid_container_log_limits_are_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "containerLogMaxSize": "500m"
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("container log maximum size"),
+            "{:?}",
+            error
+        );
+
+        let config_builder = builder_from_json_string(
+            r#"{
+            "containerLogMaxFiles": 1
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("container log maximum files"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn invalid_module_cache_size_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "moduleCacheMaxSize": "0"
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("module cache maximum size"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn invalid_system_reserved_memory_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "systemReservedMemory": "lots"
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("system reserved memory"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn invalid_cluster_dns_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "clusterDNS": ["kube-dns"]
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(error.to_string().contains("cluster DNS"), "{:?}", error);
+    }
+
+    #[test]
+    fn zero_module_pool_size_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "modulePoolSize": 0
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("module pool size"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn invalid_max_module_size_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "maxModuleSize": "huge"
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("maximum module size"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn invalid_module_opt_level_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "moduleOptLevel": "fastest"
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("module optimization level"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn zero_node_heartbeat_interval_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "nodeLeaseRenewIntervalSeconds": 0
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("node lease renew interval"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn zero_container_start_timeout_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "containerStartTimeoutSeconds": 0
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("container start timeout"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn zero_concurrent_compilations_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "maxConcurrentCompilations": 0
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error
+                .to_string()
+                .contains("maximum concurrent compilations"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn requiring_signed_modules_without_key_is_reported() {
+        let config_builder = builder_from_json_string(
+            r#"{
+            "requireSignedModules": true
+        }"#,
+        );
+        let error = config_builder
+            .unwrap()
+            .build(fallbacks())
+            .expect_err("Expected config error but was okay");
+        assert!(
+            error.to_string().contains("require signed modules"),
+            "{:?}",
+            error
+        );
+    }
+
     #[test]
     fn out_of_range_config_value_is_reported() {
         let config_builder = builder_from_json_string(
@@ -35,6 +35,39 @@ mod test {
             plugins_dir: std::path::PathBuf::from("/nope"),
             device_plugins_dir: std::path::PathBuf::from("/nope"),
             max_pods: 0,
+            container_log_max_size: 0,
+            container_log_max_files: 0,
+            container_log_buffer_size: 0,
+            module_cache_dir: std::path::PathBuf::from("/nope"),
+            module_cache_max_size: None,
+            module_verification_key: None,
+            require_signed_modules: false,
+            insecure_skip_module_tls_verify: false,
+            metrics_port: None,
+            otlp_endpoint: None,
+            max_concurrent_compilations: 1,
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            system_reserved_memory: 0,
+            json_container_logs: false,
+            cluster_dns: Vec::new(),
+            cluster_domain: "cluster.local".to_owned(),
+            module_pool_size: None,
+            module_pool_memory_pages: 16384,
+            module_pool_table_elements: 10000,
+            max_module_size: u64::MAX,
+            node_lease_renew_interval: std::time::Duration::from_secs(10),
+            node_status_update_interval: std::time::Duration::from_secs(10),
+            max_preopened_dirs: usize::MAX,
+            module_opt_level: crate::config::ModuleOptLevel::default(),
+            parallel_compilation: true,
+            default_allowed_domains: Vec::new(),
+            namespace_network_defaults: false,
+            allowed_log_dirs: Vec::new(),
+            eviction_memory_available: None,
+            eviction_disk_available: None,
+            disabled_wasm_features: Vec::new(),
+            wasi_nn_backends: Vec::new(),
+            container_start_timeout: std::time::Duration::from_secs(0),
             node_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
             node_labels: std::collections::HashMap::new(),
             node_name: "nope".to_owned(),
@@ -0,0 +1,83 @@
+//! Expansion of the `$(VAR)` references Kubernetes allows in a container's `command` and `args`.
+use std::collections::HashMap;
+
+/// Expands the `$(VAR)` references in `input` with the values of the variables in `env`, as
+/// Kubernetes does for a container's `command` and `args`. `$$` is an escaped `$`, so `$$(VAR)`
+/// becomes the literal `$(VAR)`. References to variables that aren't defined, and anything else
+/// that isn't a complete reference, are left as they are.
+pub fn expand_var_refs(input: &str, env: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        if let Some(after_escape) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after_escape;
+        } else if let Some((name, after_ref)) = after
+            .strip_prefix('(')
+            .and_then(|r| r.find(')').map(|end| (&r[..end], &r[end + 1..])))
+        {
+            match env.get(name) {
+                Some(value) => expanded.push_str(value),
+                None => {
+                    expanded.push_str("$(");
+                    expanded.push_str(name);
+                    expanded.push(')');
+                }
+            }
+            rest = after_ref;
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn env() -> HashMap<String, String> {
+        vec![
+            ("NAME".to_owned(), "world".to_owned()),
+            ("EMPTY".to_owned(), String::new()),
+            ("NESTED".to_owned(), "$(NAME)".to_owned()),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_expand_var_refs() {
+        let env = env();
+        let cases = vec![
+            ("hello", "hello"),
+            ("$(NAME)", "world"),
+            ("hello $(NAME)!", "hello world!"),
+            ("$(NAME)$(NAME)", "worldworld"),
+            ("[$(EMPTY)]", "[]"),
+            // Values aren't expanded again
+            ("$(NESTED)", "$(NAME)"),
+            // Undefined variables are left as they are
+            ("$(MISSING)", "$(MISSING)"),
+            ("$()", "$()"),
+            // Escaping
+            ("$$(NAME)", "$(NAME)"),
+            ("$$$(NAME)", "$world"),
+            ("$$$$(NAME)", "$$(NAME)"),
+            ("cost: $$5", "cost: $5"),
+            // Anything else is left as it is
+            ("$NAME", "$NAME"),
+            ("$(NAME", "$(NAME"),
+            ("$", "$"),
+            ("trailing $", "trailing $"),
+            ("$é", "$é"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(expand_var_refs(input, &env), expected, "input {:?}", input);
+        }
+    }
+}
@@ -1,10 +1,14 @@
 use std::io::SeekFrom;
+use std::time::Duration;
 
+use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt};
 
 use crate::container::ContainerMap;
-use crate::handle::StopHandler;
+use crate::exec::Output;
+use crate::handle::{ExecHandler, StopHandler, UsageHandler};
 use crate::log::{stream, HandleFactory, Sender};
+use crate::stats::Usage;
 
 /// Represents a handle to a running "container" (whatever that might be). This
 /// can be used on its own, however, it is generally better to use it as a part
@@ -37,6 +41,13 @@ impl<H: StopHandler, F> Handle<H, F> {
         self.handle.stop().await
     }
 
+    /// Gracefully stop the running instance, forcibly stopping it if it hasn't stopped by the end
+    /// of the grace period. This uses the underlying [`StopHandler`] implementation passed to the
+    /// constructor
+    pub async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()> {
+        self.handle.stop_gracefully(grace_period).await
+    }
+
     /// Streams output from the running process into the given sender.
     /// Optionally tails the output and/or continues to watch the file and stream changes.
     pub(crate) async fn output<R>(&mut self, sender: Sender) -> anyhow::Result<()>
@@ -45,11 +56,39 @@ impl<H: StopHandler, F> Handle<H, F> {
         F: HandleFactory<R>,
     {
         let mut handle = self.handle_factory.new_handle();
-        handle.seek(SeekFrom::Start(0)).await?;
-        tokio::spawn(stream(handle, sender));
+        let start = sender
+            .since_cutoff()
+            .and_then(|since| self.handle_factory.offset_since(since))
+            .unwrap_or(0);
+        handle.seek(SeekFrom::Start(start)).await?;
+        tokio::spawn(stream(
+            handle,
+            sender,
+            self.handle_factory.closed(),
+            self.handle_factory.line_format(),
+        ));
         Ok(())
     }
 
+    /// Runs a command against the running instance, returning its captured output. This uses the
+    /// underlying [`ExecHandler`] implementation passed to the constructor. The command is run
+    /// by the returned future, which doesn't borrow the handle
+    pub fn exec(&self, command: Vec<String>) -> BoxFuture<'static, anyhow::Result<Output>>
+    where
+        H: ExecHandler,
+    {
+        self.handle.exec(command)
+    }
+
+    /// The current resource usage of the running instance. This uses the underlying
+    /// [`UsageHandler`] implementation passed to the constructor
+    pub fn usage(&self) -> Usage
+    where
+        H: UsageHandler,
+    {
+        self.handle.usage()
+    }
+
     /// Wait for the running process to complete. Generally speaking,
     /// [`Handle::stop`] should be called first. This uses the underlying
     /// [`StopHandler`] implementation passed to the constructor
@@ -5,12 +5,22 @@ use oci_distribution::Reference;
 use std::convert::TryInto;
 use std::fmt::Display;
 
+use crate::resources::quantity::{Quantity, QuantityType};
+
+mod expansion;
 mod handle;
+pub mod probe;
 pub mod state;
 mod status;
 
+pub use expansion::expand_var_refs;
 pub use handle::{Handle, HandleMap};
-pub use status::{make_initial_container_status, patch_container_status, Status};
+pub use status::{
+    make_initial_container_status, patch_container_restart_count, patch_container_status, Status,
+};
+
+/// The URL schemes a container image can be given with instead of a registry reference
+const MODULE_URL_SCHEMES: &[&str] = &["http", "https", "file"];
 
 /// Specifies how the store should check for module updates
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -68,13 +78,15 @@ pub enum ContainerKey {
     Init(String),
     /// An application container with the given name
     App(String),
+    /// An ephemeral container with the given name, added to a running pod for debugging
+    Ephemeral(String),
 }
 
 impl ContainerKey {
     /// Gets the container name
     pub fn name(&self) -> String {
         match self {
-            Self::Init(name) | Self::App(name) => name.to_string(),
+            Self::Init(name) | Self::App(name) | Self::Ephemeral(name) => name.to_string(),
         }
     }
 
@@ -87,6 +99,11 @@ impl ContainerKey {
     pub fn is_init(&self) -> bool {
         matches!(self, Self::Init(_))
     }
+
+    /// Whether the key identifies an ephemeral container
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, Self::Ephemeral(_))
+    }
 }
 
 impl Display for ContainerKey {
@@ -100,6 +117,8 @@ pub type ContainerMap<V> = std::collections::HashMap<ContainerKey, V>;
 
 /// Provides methods for accessing `ContainerMap` elements by name.
 pub trait ContainerMapByName<V> {
+    /// Gets a reference to the value associated with the container with the given name.
+    fn get_by_name(&self, name: &str) -> Option<&V>;
     /// Gets a mutable reference to the value associated with the container
     /// with the given name.
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V>;
@@ -108,19 +127,29 @@ pub trait ContainerMapByName<V> {
 }
 
 impl<V> ContainerMapByName<V> for ContainerMap<V> {
+    fn get_by_name(&self, name: &str) -> Option<&V> {
+        self.get(&ContainerKey::App(name.to_owned()))
+            .or_else(|| self.get(&ContainerKey::Init(name.to_owned())))
+            .or_else(|| self.get(&ContainerKey::Ephemeral(name.to_owned())))
+    }
+
     fn get_mut_by_name(&mut self, name: String) -> Option<&mut V> {
         // TODO: borrow checker objected to any of the more natural forms
         let app_key = ContainerKey::App(name.clone());
+        let init_key = ContainerKey::Init(name.clone());
         if self.contains_key(&app_key) {
             self.get_mut(&app_key)
+        } else if self.contains_key(&init_key) {
+            self.get_mut(&init_key)
         } else {
-            self.get_mut(&ContainerKey::Init(name))
+            self.get_mut(&ContainerKey::Ephemeral(name))
         }
     }
 
     fn contains_key_name(&self, name: &str) -> bool {
         self.contains_key(&ContainerKey::App(name.to_owned()))
             || self.contains_key(&ContainerKey::Init(name.to_owned()))
+            || self.contains_key(&ContainerKey::Ephemeral(name.to_owned()))
     }
 }
 
@@ -157,14 +186,30 @@ impl Container {
         &self.0.env_from
     }
 
-    /// Get image of container as `oci_distribution::Reference`.
+    /// Get image of container as `oci_distribution::Reference`. This is `None` if the container
+    /// has no image or its image is a URL, see [`Container::image_url`].
     pub fn image(&self) -> anyhow::Result<Option<Reference>> {
         match self.0.image.as_ref() {
+            Some(_) if self.image_url().is_some() => Ok(None),
             Some(s) => Ok(Some(s.clone().try_into()?)),
             None => Ok(None),
         }
     }
 
+    /// Get image of container if it is an `http`, `https` or `file` URL that the module is
+    /// fetched from directly rather than from a registry.
+    pub fn image_url(&self) -> Option<url::Url> {
+        let image = self.0.image.as_deref()?;
+        let is_url = MODULE_URL_SCHEMES
+            .iter()
+            .any(|scheme| image.starts_with(&format!("{}://", scheme)));
+        if is_url {
+            url::Url::parse(image).ok()
+        } else {
+            None
+        }
+    }
+
     /// Get effective pull policy of container.
     pub fn effective_pull_policy(&self) -> anyhow::Result<PullPolicy> {
         PullPolicy::parse_effective(self.0.image_pull_policy.as_deref(), self.image()?)
@@ -200,6 +245,34 @@ impl Container {
         self.0.resources.as_ref()
     }
 
+    /// Get the memory limit of the container in bytes, if one was set. Returns an error if the
+    /// limit is not a valid memory quantity
+    pub fn memory_limit(&self) -> anyhow::Result<Option<u64>> {
+        let limit = match self.resources().and_then(|r| r.limits.get("memory")) {
+            Some(q) => q,
+            None => return Ok(None),
+        };
+        match Quantity::from_kube_quantity(QuantityType::Memory(limit))? {
+            Quantity::Memory(bytes) => Ok(Some(bytes as u64)),
+            // This can't happen as we explicitly asked for a memory quantity
+            Quantity::Cpu(_) => unreachable!(),
+        }
+    }
+
+    /// Get the CPU limit of the container in millicores, if one was set. Returns an error if the
+    /// limit is not a valid CPU quantity
+    pub fn cpu_limit(&self) -> anyhow::Result<Option<u64>> {
+        let limit = match self.resources().and_then(|r| r.limits.get("cpu")) {
+            Some(q) => q,
+            None => return Ok(None),
+        };
+        match Quantity::from_kube_quantity(QuantityType::Cpu(limit))? {
+            Quantity::Cpu(cores) => Ok(Some((cores * 1000.0).round() as u64)),
+            // This can't happen as we explicitly asked for a CPU quantity
+            Quantity::Memory(_) => unreachable!(),
+        }
+    }
+
     /// Get security context of container.
     pub fn security_context(&self) -> Option<&k8s_openapi::api::core::v1::SecurityContext> {
         self.0.security_context.as_ref()
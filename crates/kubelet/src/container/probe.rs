@@ -0,0 +1,329 @@
+//! Support for evaluating Kubernetes container probes. The network based probe actions (`httpGet`
+//! and `tcpSocket`) can be run directly by [`run_network_probe`]. What an `exec` action means
+//! depends on the provider, so providers are expected to handle those themselves and feed the
+//! result into a [`ProbeTracker`] like any other probe.
+//!
+//! Lifecycle hooks use the same actions as probes, so [`run_network_hook`] runs those the same way.
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{HTTPGetAction, Handler, Probe, TCPSocketAction};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use tracing::debug;
+
+use crate::container::Container;
+
+/// The Kubernetes default for how often a probe is performed
+const DEFAULT_PERIOD: Duration = Duration::from_secs(10);
+/// The Kubernetes default for how long a probe may take before it is considered failed
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// The Kubernetes default for how many consecutive failures mark a probe as failed
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+/// The Kubernetes default for how many consecutive successes mark a probe as passing
+const DEFAULT_SUCCESS_THRESHOLD: u32 = 1;
+
+/// The outcome of a single attempt at running a probe
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeOutcome {
+    /// The probe succeeded
+    Success,
+    /// The probe failed with the given reason
+    Failure(String),
+}
+
+/// The overall state of a probe once its thresholds have been applied
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProbeState {
+    /// The probe has passed `successThreshold` times in a row
+    Passing,
+    /// The probe has failed, but not yet `failureThreshold` times in a row. This is also the state
+    /// of a probe that hasn't passed enough times yet to be considered passing
+    Failing {
+        /// The number of consecutive failures so far
+        failures: u32,
+        /// The reason the last attempt failed, if it was a failure
+        reason: Option<String>,
+    },
+    /// The probe has failed `failureThreshold` times in a row
+    Failed {
+        /// The number of consecutive failures
+        failures: u32,
+        /// The reason the last attempt failed
+        reason: String,
+    },
+}
+
+/// Tracks the results of a probe over time, applying the thresholds configured on it
+#[derive(Clone, Debug)]
+pub struct ProbeTracker {
+    initial_delay: Duration,
+    period: Duration,
+    timeout: Duration,
+    success_threshold: u32,
+    failure_threshold: u32,
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+}
+
+impl ProbeTracker {
+    /// Creates a tracker for the given probe, filling in the Kubernetes defaults for any settings
+    /// that weren't given
+    pub fn new(probe: &Probe) -> Self {
+        let seconds = |value: Option<i32>, default: Duration| {
+            value
+                .filter(|v| *v > 0)
+                .map(|v| Duration::from_secs(v as u64))
+                .unwrap_or(default)
+        };
+        let threshold = |value: Option<i32>, default: u32| {
+            value
+                .filter(|v| *v > 0)
+                .map(|v| v as u32)
+                .unwrap_or(default)
+        };
+        ProbeTracker {
+            initial_delay: seconds(probe.initial_delay_seconds, Duration::from_secs(0)),
+            period: seconds(probe.period_seconds, DEFAULT_PERIOD),
+            timeout: seconds(probe.timeout_seconds, DEFAULT_TIMEOUT),
+            success_threshold: threshold(probe.success_threshold, DEFAULT_SUCCESS_THRESHOLD),
+            failure_threshold: threshold(probe.failure_threshold, DEFAULT_FAILURE_THRESHOLD),
+            consecutive_successes: 0,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// How long to wait after the container starts before running the probe for the first time
+    pub fn initial_delay(&self) -> Duration {
+        self.initial_delay
+    }
+
+    /// How often the probe should be run
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// How long a single attempt at the probe may take
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Records the outcome of an attempt and returns the resulting state of the probe
+    pub fn record(&mut self, outcome: ProbeOutcome) -> ProbeState {
+        match outcome {
+            ProbeOutcome::Success => {
+                self.consecutive_failures = 0;
+                self.consecutive_successes += 1;
+                if self.consecutive_successes >= self.success_threshold {
+                    ProbeState::Passing
+                } else {
+                    ProbeState::Failing {
+                        failures: 0,
+                        reason: None,
+                    }
+                }
+            }
+            ProbeOutcome::Failure(reason) => {
+                self.consecutive_successes = 0;
+                self.consecutive_failures += 1;
+                debug!(failures = self.consecutive_failures, %reason, "Probe failed");
+                if self.consecutive_failures >= self.failure_threshold {
+                    ProbeState::Failed {
+                        failures: self.consecutive_failures,
+                        reason,
+                    }
+                } else {
+                    ProbeState::Failing {
+                        failures: self.consecutive_failures,
+                        reason: Some(reason),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs the `httpGet` or `tcpSocket` action of a probe against the given default host (generally
+/// the pod IP), applying the probe's timeout. Returns `None` if the probe doesn't have a network
+/// action, in which case the caller is responsible for running it
+pub async fn run_network_probe(
+    probe: &Probe,
+    container: &Container,
+    default_host: &str,
+) -> Option<ProbeOutcome> {
+    let timeout = ProbeTracker::new(probe).timeout();
+    run_network_action(
+        probe.http_get.as_ref(),
+        probe.tcp_socket.as_ref(),
+        container,
+        default_host,
+        timeout,
+    )
+    .await
+}
+
+/// Runs the `httpGet` or `tcpSocket` action of a lifecycle hook against the given default host
+/// (generally the pod IP), giving up after the timeout. Returns `None` if the hook doesn't have a
+/// network action, in which case the caller is responsible for running it
+pub async fn run_network_hook(
+    handler: &Handler,
+    container: &Container,
+    default_host: &str,
+    timeout: Duration,
+) -> Option<ProbeOutcome> {
+    run_network_action(
+        handler.http_get.as_ref(),
+        handler.tcp_socket.as_ref(),
+        container,
+        default_host,
+        timeout,
+    )
+    .await
+}
+
+async fn run_network_action(
+    http_get: Option<&HTTPGetAction>,
+    tcp_socket: Option<&TCPSocketAction>,
+    container: &Container,
+    default_host: &str,
+    timeout: Duration,
+) -> Option<ProbeOutcome> {
+    let attempt = if let Some(action) = http_get {
+        let port = match resolve_port(&action.port, container) {
+            Ok(p) => p,
+            Err(e) => return Some(ProbeOutcome::Failure(e.to_string())),
+        };
+        let url = format!(
+            "{}://{}:{}{}",
+            action
+                .scheme
+                .as_deref()
+                .unwrap_or("HTTP")
+                .to_ascii_lowercase(),
+            action.host.as_deref().unwrap_or(default_host),
+            port,
+            action.path.as_deref().unwrap_or("/"),
+        );
+        let headers = action
+            .http_headers
+            .iter()
+            .map(|h| (h.name.clone(), h.value.clone()))
+            .collect();
+        futures::future::Either::Left(http_probe(url, headers))
+    } else if let Some(action) = tcp_socket {
+        let port = match resolve_port(&action.port, container) {
+            Ok(p) => p,
+            Err(e) => return Some(ProbeOutcome::Failure(e.to_string())),
+        };
+        let host = action.host.as_deref().unwrap_or(default_host).to_owned();
+        futures::future::Either::Right(tcp_connect(host, port))
+    } else {
+        return None;
+    };
+
+    Some(match tokio::time::timeout(timeout, attempt).await {
+        Ok(outcome) => outcome,
+        Err(_) => ProbeOutcome::Failure(format!("probe timed out after {:?}", timeout)),
+    })
+}
+
+async fn http_probe(url: String, headers: Vec<(String, String)>) -> ProbeOutcome {
+    let client = reqwest::Client::new();
+    let mut req = client.get(&url);
+    for (name, value) in headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    match req.send().await {
+        // Kubernetes considers any code in the range [200, 400) a success
+        Ok(resp) if resp.status().is_success() || resp.status().is_redirection() => {
+            ProbeOutcome::Success
+        }
+        Ok(resp) => ProbeOutcome::Failure(format!(
+            "HTTP probe to {} failed with status code {}",
+            url,
+            resp.status().as_u16()
+        )),
+        Err(e) => ProbeOutcome::Failure(format!("HTTP probe to {} failed: {}", url, e)),
+    }
+}
+
+async fn tcp_connect(host: String, port: u16) -> ProbeOutcome {
+    match tokio::net::TcpStream::connect((host.as_str(), port)).await {
+        Ok(_) => ProbeOutcome::Success,
+        Err(e) => ProbeOutcome::Failure(format!("TCP probe to {}:{} failed: {}", host, port, e)),
+    }
+}
+
+/// Resolves a probe port, looking up named ports in the container's port list
+fn resolve_port(port: &IntOrString, container: &Container) -> anyhow::Result<u16> {
+    match port {
+        IntOrString::Int(p) => Ok(*p as u16),
+        IntOrString::String(name) => container
+            .ports()
+            .iter()
+            .find(|p| p.name.as_deref() == Some(name.as_str()))
+            .map(|p| p.container_port as u16)
+            .ok_or_else(|| anyhow::anyhow!("container has no port named {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn probe(failure_threshold: i32, success_threshold: i32) -> Probe {
+        Probe {
+            failure_threshold: Some(failure_threshold),
+            success_threshold: Some(success_threshold),
+            ..Default::default()
+        }
+    }
+
+    fn failure() -> ProbeOutcome {
+        ProbeOutcome::Failure("nope".to_owned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let tracker = ProbeTracker::new(&Probe::default());
+        assert_eq!(tracker.initial_delay(), Duration::from_secs(0));
+        assert_eq!(tracker.period(), Duration::from_secs(10));
+        assert_eq!(tracker.timeout(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_fails_after_threshold() {
+        let mut tracker = ProbeTracker::new(&probe(3, 1));
+        assert!(matches!(
+            tracker.record(failure()),
+            ProbeState::Failing { failures: 1, .. }
+        ));
+        assert!(matches!(
+            tracker.record(failure()),
+            ProbeState::Failing { failures: 2, .. }
+        ));
+        assert!(matches!(
+            tracker.record(failure()),
+            ProbeState::Failed { failures: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut tracker = ProbeTracker::new(&probe(2, 1));
+        tracker.record(failure());
+        assert_eq!(tracker.record(ProbeOutcome::Success), ProbeState::Passing);
+        assert!(matches!(
+            tracker.record(failure()),
+            ProbeState::Failing { failures: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn test_success_threshold() {
+        let mut tracker = ProbeTracker::new(&probe(3, 2));
+        assert!(matches!(
+            tracker.record(ProbeOutcome::Success),
+            ProbeState::Failing { failures: 0, .. }
+        ));
+        assert_eq!(tracker.record(ProbeOutcome::Success), ProbeState::Passing);
+    }
+}
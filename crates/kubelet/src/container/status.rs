@@ -21,11 +21,19 @@ pub enum Status {
         timestamp: DateTime<Utc>,
         /// A human readable string describing the why it is in a waiting status
         message: String,
+        /// A brief CamelCase reason for waiting (e.g. `CrashLoopBackOff`), if there is one
+        reason: Option<String>,
     },
     /// The container is running
     Running {
         /// The timestamp of when this status was reported
         timestamp: DateTime<Utc>,
+        /// Whether the container is ready to serve requests. This should only be false while a
+        /// readiness probe is failing
+        ready: bool,
+        /// Whether the container has finished starting. This should only be false until a
+        /// startup probe passes
+        started: bool,
     },
     /// The container is terminated
     Terminated {
@@ -35,6 +43,12 @@ pub enum Status {
         message: String,
         /// Should be set to true if the process exited with an error
         failed: bool,
+        /// A brief CamelCase reason for the termination (e.g. `OOMKilled`), if there is a more
+        /// specific one than the process exiting
+        reason: Option<String>,
+        /// The code the process exited with, if it is known. Otherwise the code is reported as 1
+        /// if the process failed and 0 if it didn't
+        exit_code: Option<i32>,
     },
 }
 
@@ -44,13 +58,43 @@ impl Status {
         Status::Waiting {
             timestamp: Utc::now(),
             message: message.to_string(),
+            reason: None,
         }
     }
 
-    /// Create `Status::Running`.
+    /// Create `Status::Waiting` from a reason and message.
+    pub fn waiting_with_reason(reason: &str, message: &str) -> Self {
+        Status::Waiting {
+            timestamp: Utc::now(),
+            message: message.to_string(),
+            reason: Some(reason.to_string()),
+        }
+    }
+
+    /// Create a ready `Status::Running`.
     pub fn running() -> Self {
         Status::Running {
             timestamp: Utc::now(),
+            ready: true,
+            started: true,
+        }
+    }
+
+    /// Create a `Status::Running` for a container that hasn't finished starting yet.
+    pub fn starting() -> Self {
+        Status::Running {
+            timestamp: Utc::now(),
+            ready: false,
+            started: false,
+        }
+    }
+
+    /// Create `Status::Running` with the given readiness.
+    pub fn running_with_readiness(ready: bool) -> Self {
+        Status::Running {
+            timestamp: Utc::now(),
+            ready,
+            started: true,
         }
     }
 
@@ -60,6 +104,8 @@ impl Status {
             timestamp: Utc::now(),
             message: message.to_string(),
             failed,
+            reason: None,
+            exit_code: None,
         }
     }
 
@@ -67,13 +113,15 @@ impl Status {
     pub fn to_kubernetes(&self, container_name: &str) -> KubeContainerStatus {
         let mut state = ContainerState::default();
         match self {
-            Self::Waiting { message, .. } => {
+            Self::Waiting {
+                message, reason, ..
+            } => {
                 state.waiting.replace(ContainerStateWaiting {
                     message: Some(message.clone()),
-                    ..Default::default()
+                    reason: reason.clone(),
                 });
             }
-            Self::Running { timestamp } => {
+            Self::Running { timestamp, .. } => {
                 state.running.replace(ContainerStateRunning {
                     started_at: Some(Time(*timestamp)),
                 });
@@ -82,25 +130,26 @@ impl Status {
                 timestamp,
                 message,
                 failed,
+                reason,
+                exit_code,
             } => {
                 state.terminated.replace(ContainerStateTerminated {
                     finished_at: Some(Time(*timestamp)),
                     message: Some(message.clone()),
-                    exit_code: *failed as i32,
+                    reason: reason.clone(),
+                    exit_code: exit_code.unwrap_or(*failed as i32),
                     ..Default::default()
                 });
             }
         };
-        let ready = state.running.is_some();
+        let ready = matches!(self, Self::Running { ready: true, .. });
+        let started = !matches!(self, Self::Running { started: false, .. });
         KubeContainerStatus {
             state: Some(state),
             name: container_name.to_string(),
-            // Right now we don't have a way to probe, so just set to ready if
-            // in a running state
             ready,
-            // This is always true if startupProbe is not defined. When we
-            // handle probes, this should be updated accordingly
-            started: Some(true),
+            // This is always true unless a startupProbe has yet to pass
+            started: Some(started),
             // The rest of the items in status (see docs here:
             // https://kubernetes.io/docs/reference/generated/kubernetes-api/v1.17/#containerstatus-v1-core)
             // either don't matter for us or we have not implemented the
@@ -110,6 +159,64 @@ impl Status {
     }
 }
 
+/// The field of the pod status that the status of the container is kept in
+fn statuses_field(key: &ContainerKey) -> &'static str {
+    match key {
+        ContainerKey::Init(_) => "initContainerStatuses",
+        ContainerKey::App(_) => "containerStatuses",
+        ContainerKey::Ephemeral(_) => "ephemeralContainerStatuses",
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Held while a container's first status is added to its pod, so that concurrent adds don't
+    /// overwrite each other
+    static ref FIRST_STATUS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// The operations that set a container's status in the pod status, replacing the status it
+/// already has if there is one
+fn status_patches(
+    pod: &Pod,
+    key: &ContainerKey,
+    kube_status: KubeContainerStatus,
+) -> Vec<json_patch::PatchOperation> {
+    match pod.container_status_index(key) {
+        Some(idx) => {
+            let path_prefix = format!("/status/{}/{}", statuses_field(key), idx);
+
+            vec![
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/state", path_prefix),
+                    value: serde_json::json!(kube_status.state.unwrap()),
+                }),
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/ready", path_prefix),
+                    value: serde_json::json!(kube_status.ready),
+                }),
+                json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
+                    path: format!("{}/started", path_prefix),
+                    value: serde_json::json!(kube_status.started.unwrap_or(true)),
+                }),
+            ]
+        }
+        // Ephemeral containers are added to running pods, which have no statuses for
+        // them to append to until the first one is reported
+        None if key.is_ephemeral() && !pod.has_ephemeral_container_statuses() => {
+            vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
+                path: format!("/status/{}", statuses_field(key)),
+                value: serde_json::json!([kube_status]),
+            })]
+        }
+        None => {
+            vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
+                path: format!("/status/{}/-", statuses_field(key)),
+                value: serde_json::json!(kube_status),
+            })]
+        }
+    }
+}
+
 /// Patch a single container's status
 #[instrument(level = "info", skip(client, pod, key, status), fields(pod_name = %pod.name(), namespace = %pod.namespace(), container_name = %key))]
 pub async fn patch_container_status(
@@ -122,41 +229,20 @@ pub async fn patch_container_status(
         Some(container) => {
             let kube_status = status.to_kubernetes(container.name());
 
-            let patches = match pod.container_status_index(&key) {
-                Some(idx) => {
-                    let path_prefix = if key.is_init() {
-                        format!("/status/initContainerStatuses/{}", idx)
-                    } else {
-                        format!("/status/containerStatuses/{}", idx)
-                    };
-
-                    vec![
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/state", path_prefix),
-                            value: serde_json::json!(kube_status.state.unwrap()),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/ready", path_prefix),
-                            value: serde_json::json!(kube_status.ready),
-                        }),
-                        json_patch::PatchOperation::Replace(json_patch::ReplaceOperation {
-                            path: format!("{}/started", path_prefix),
-                            value: serde_json::json!(true),
-                        }),
-                    ]
-                }
-                None => {
-                    let path = if key.is_init() {
-                        "/status/initContainerStatuses/-".to_string()
-                    } else {
-                        "/status/containerStatuses/-".to_string()
-                    };
-
-                    vec![json_patch::PatchOperation::Add(json_patch::AddOperation {
-                        path,
-                        value: serde_json::json!(kube_status),
-                    })]
+            // A container with no status yet, such as an ephemeral container added to a running
+            // pod, may have had one added since the pod was read. Its status is worked out from
+            // the latest pod, one container at a time, so that it is added exactly once
+            let first_status = if pod.container_status_index(key).is_none() {
+                Some(FIRST_STATUS_LOCK.lock().await)
+            } else {
+                None
+            };
+            let patches = match first_status {
+                Some(_) => {
+                    let latest = Pod::from(client.get_status(pod.name()).await?);
+                    status_patches(&latest, key, kube_status)
                 }
+                None => status_patches(pod, key, kube_status),
             };
 
             let patch = json_patch::Patch(patches);
@@ -177,6 +263,49 @@ pub async fn patch_container_status(
     }
 }
 
+/// The patch that sets the number of times the container has been restarted, if it has a status
+/// to set it in
+fn restart_count_patch(
+    pod: &Pod,
+    key: &ContainerKey,
+    restart_count: u32,
+) -> Option<json_patch::Patch> {
+    let idx = pod.container_status_index(key)?;
+    Some(json_patch::Patch(vec![json_patch::PatchOperation::Add(
+        json_patch::AddOperation {
+            path: format!("/status/{}/{}/restartCount", statuses_field(key), idx),
+            value: serde_json::json!(restart_count),
+        },
+    )]))
+}
+
+/// Patch the number of times a single container has been restarted, which is kept alongside its
+/// status so that it shows in the pod's restarts
+#[instrument(level = "info", skip(client, pod, key), fields(pod_name = %pod.name(), namespace = %pod.namespace(), container_name = %key))]
+pub async fn patch_container_restart_count(
+    client: &kube::Api<KubePod>,
+    pod: &Pod,
+    key: &ContainerKey,
+    restart_count: u32,
+) -> anyhow::Result<()> {
+    let patch = match restart_count_patch(pod, key, restart_count) {
+        Some(patch) => patch,
+        None => {
+            warn!(
+                "Restart count update for container {} with no status.",
+                key.name()
+            );
+            return Ok(());
+        }
+    };
+    let params = kube::api::PatchParams::default();
+    debug!(?patch, "Patching container restart count");
+    client
+        .patch_status(pod.name(), &params, &kube::api::Patch::<()>::Json(patch))
+        .await?;
+    Ok(())
+}
+
 /// Create inital container status for registering pod.
 pub fn make_initial_container_status(container: &Container) -> KubeContainerStatus {
     let state = ContainerState {
@@ -194,3 +323,119 @@ pub fn make_initial_container_status(container: &Container) -> KubeContainerStat
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn terminated_exit_code(status: Status) -> i32 {
+        status
+            .to_kubernetes("test")
+            .state
+            .unwrap()
+            .terminated
+            .unwrap()
+            .exit_code
+    }
+
+    #[test]
+    fn test_terminated_exit_code() {
+        assert_eq!(terminated_exit_code(Status::terminated("done", false)), 0);
+        assert_eq!(terminated_exit_code(Status::terminated("broke", true)), 1);
+        assert_eq!(
+            terminated_exit_code(Status::Terminated {
+                timestamp: Utc::now(),
+                message: "module exited with status 3".to_string(),
+                failed: true,
+                reason: Some("Error".to_string()),
+                exit_code: Some(3),
+            }),
+            3
+        );
+    }
+
+    fn ephemeral_pod(statuses: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "containers": [{"name": "app"}],
+                "ephemeralContainers": [{"name": "first"}, {"name": "second"}]
+            },
+            "status": {"ephemeralContainerStatuses": statuses}
+        }))
+        .unwrap()
+    }
+
+    fn patch_paths(pod: &Pod, name: &str) -> Vec<String> {
+        let key = ContainerKey::Ephemeral(name.to_owned());
+        let status = Status::running().to_kubernetes(name);
+        status_patches(pod, &key, status)
+            .into_iter()
+            .map(|op| match op {
+                json_patch::PatchOperation::Add(op) => format!("add {}", op.path),
+                json_patch::PatchOperation::Replace(op) => format!("replace {}", op.path),
+                op => panic!("unexpected operation {:?}", op),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ephemeral_status_patches() {
+        // The first status creates the list, which later ones are appended to
+        let pod = ephemeral_pod(serde_json::json!([]));
+        assert_eq!(
+            vec!["add /status/ephemeralContainerStatuses"],
+            patch_paths(&pod, "first")
+        );
+        let pod = ephemeral_pod(serde_json::json!(
+            [Status::running().to_kubernetes("first")]
+        ));
+        assert_eq!(
+            vec!["add /status/ephemeralContainerStatuses/-"],
+            patch_paths(&pod, "second")
+        );
+        assert_eq!(
+            vec![
+                "replace /status/ephemeralContainerStatuses/0/state",
+                "replace /status/ephemeralContainerStatuses/0/ready",
+                "replace /status/ephemeralContainerStatuses/0/started"
+            ],
+            patch_paths(&pod, "first")
+        );
+    }
+
+    #[test]
+    fn test_restart_count_patch() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "initContainers": [{"name": "init"}],
+                "containers": [{"name": "first"}, {"name": "second"}]
+            },
+            "status": {
+                "initContainerStatuses": [Status::running().to_kubernetes("init")],
+                "containerStatuses": [
+                    Status::running().to_kubernetes("first"),
+                    Status::running().to_kubernetes("second")
+                ]
+            }
+        }))
+        .unwrap();
+        let patch = restart_count_patch(&pod, &ContainerKey::App("second".to_owned()), 3);
+        assert_eq!(
+            serde_json::json!([
+                {"op": "add", "path": "/status/containerStatuses/1/restartCount", "value": 3}
+            ]),
+            serde_json::to_value(patch.unwrap()).unwrap()
+        );
+        let patch = restart_count_patch(&pod, &ContainerKey::Init("init".to_owned()), 1);
+        assert_eq!(
+            serde_json::json!([
+                {"op": "add", "path": "/status/initContainerStatuses/0/restartCount", "value": 1}
+            ]),
+            serde_json::to_value(patch.unwrap()).unwrap()
+        );
+        // A container with no status has nowhere to keep its count
+        assert!(restart_count_patch(&pod, &ContainerKey::App("third".to_owned()), 1).is_none());
+    }
+}
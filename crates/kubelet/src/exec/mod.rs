@@ -0,0 +1,164 @@
+//! `exec` contains the types used to run commands in containers on behalf of the Kubernetes API.
+//!
+//! Only non-interactive commands are supported: the command is run to completion and its captured
+//! output and exit code are returned to the caller in one go.
+use serde_json::json;
+
+/// The subprotocol for the v4 Kubernetes channel protocol, which reports the outcome of the command
+/// as a `Status` object on the error channel
+pub(crate) const V4_CHANNEL_PROTOCOL: &str = "v4.channel.k8s.io";
+/// The subprotocol for the original Kubernetes channel protocol, which only reports failures on the
+/// error channel, as plain text
+pub(crate) const CHANNEL_PROTOCOL: &str = "channel.k8s.io";
+
+/// The channel standard output is streamed on
+pub(crate) const STDOUT_CHANNEL: u8 = 1;
+/// The channel standard error is streamed on
+pub(crate) const STDERR_CHANNEL: u8 = 2;
+/// The channel the outcome of the command is reported on
+pub(crate) const ERROR_CHANNEL: u8 = 3;
+
+/// Options for an exec request, as given in its query string
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    command: Vec<String>,
+    stdin: bool,
+    stdout: bool,
+    stderr: bool,
+    tty: bool,
+}
+
+impl Options {
+    /// Parses the options out of an exec request's query string. Each part of the command is
+    /// given as its own `command` parameter
+    pub fn from_query(query: &str) -> Self {
+        let mut opts = Options::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let flag = matches!(value.as_ref(), "1" | "true");
+            match key.as_ref() {
+                "command" => opts.command.push(value.into_owned()),
+                "stdin" => opts.stdin = flag,
+                "stdout" => opts.stdout = flag,
+                "stderr" => opts.stderr = flag,
+                "tty" => opts.tty = flag,
+                _ => (),
+            }
+        }
+        opts
+    }
+
+    /// The command to run
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    /// Whether the caller asked to attach standard input. Input is not supported, so commands
+    /// always see an empty standard input
+    pub fn stdin(&self) -> bool {
+        self.stdin
+    }
+
+    /// Whether the caller wants standard output
+    pub fn stdout(&self) -> bool {
+        self.stdout
+    }
+
+    /// Whether the caller wants standard error
+    pub fn stderr(&self) -> bool {
+        self.stderr
+    }
+
+    /// Whether the caller asked for a TTY. TTYs are not supported, so commands are always run
+    /// without one
+    pub fn tty(&self) -> bool {
+        self.tty
+    }
+}
+
+/// The captured result of running a command in a container
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Output {
+    /// Everything the command wrote to standard output
+    pub stdout: Vec<u8>,
+    /// Everything the command wrote to standard error
+    pub stderr: Vec<u8>,
+    /// The exit code of the command
+    pub exit_code: i32,
+}
+
+impl Output {
+    /// The Kubernetes `Status` that reports the outcome of the command on the v4 error channel
+    pub(crate) fn status(&self) -> serde_json::Value {
+        if self.exit_code == 0 {
+            return json!({
+                "metadata": {},
+                "status": "Success",
+            });
+        }
+        json!({
+            "metadata": {},
+            "status": "Failure",
+            "message": format!("command terminated with non-zero exit code: {}", self.exit_code),
+            "reason": "NonZeroExitCode",
+            "details": {
+                "causes": [{
+                    "reason": "ExitCode",
+                    "message": self.exit_code.to_string(),
+                }],
+            },
+        })
+    }
+}
+
+/// The Kubernetes `Status` that reports a command that couldn't be run on the v4 error channel
+pub(crate) fn error_status(message: &str) -> serde_json::Value {
+    json!({
+        "metadata": {},
+        "status": "Failure",
+        "message": message,
+    })
+}
+
+/// Frames data for sending on the given channel of a Kubernetes channel protocol stream
+pub(crate) fn frame(channel: u8, data: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(data.len() + 1);
+    framed.push(channel);
+    framed.extend_from_slice(data);
+    framed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options_from_query() {
+        let opts =
+            Options::from_query("command=ls&command=-l&command=%2Fdata&stdout=1&stderr=true");
+        assert_eq!(opts.command(), &["ls", "-l", "/data"]);
+        assert!(opts.stdout());
+        assert!(opts.stderr());
+        assert!(!opts.stdin());
+        assert!(!opts.tty());
+    }
+
+    #[test]
+    fn test_status() {
+        let success = Output::default().status();
+        assert_eq!(success["status"], "Success");
+
+        let failure = Output {
+            exit_code: 2,
+            ..Default::default()
+        }
+        .status();
+        assert_eq!(failure["status"], "Failure");
+        assert_eq!(failure["reason"], "NonZeroExitCode");
+        assert_eq!(failure["details"]["causes"][0]["message"], "2");
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(STDOUT_CHANNEL, b"hi"), vec![1, b'h', b'i']);
+    }
+}
@@ -0,0 +1,11 @@
+use futures::future::BoxFuture;
+
+use crate::exec::Output;
+
+/// An [`ExecHandler`] is used to run commands against running processes.
+pub trait ExecHandler: Send + Sync {
+    /// Runs the command to completion against whatever is running under the implementor,
+    /// returning its captured output and exit code. The returned future doesn't borrow the
+    /// implementor, so whatever holds it doesn't have to stay locked while the command runs.
+    fn exec(&self, command: Vec<String>) -> BoxFuture<'static, anyhow::Result<Output>>;
+}
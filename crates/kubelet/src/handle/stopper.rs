@@ -1,12 +1,21 @@
+use std::time::Duration;
+
 /// A [`StopHandler`] is used to handle stopping running processes.
 #[async_trait::async_trait]
-pub trait StopHandler {
+pub trait StopHandler: Send {
     /// Calling stop should sends a signal for anything running under the implementor to stop.
     ///
     /// This is considered an ungraceful stop, and the caller should not wait for the
     /// underlying handle to complete. Instead they should call wait() to wait for anything running
     /// to stop.
     async fn stop(&mut self) -> anyhow::Result<()>;
+    /// Gracefully stops anything running under the implementor, giving it up to the grace period
+    /// to clean up (e.g. by running a preStop hook) before it is forcibly stopped.
+    ///
+    /// The default implementation ignores the grace period and calls [`StopHandler::stop`].
+    async fn stop_gracefully(&mut self, _grace_period: Duration) -> anyhow::Result<()> {
+        self.stop().await
+    }
     /// Wait for the implementor to stop anything it considers in the running state.
     async fn wait(&mut self) -> anyhow::Result<()>;
 }
@@ -0,0 +1,7 @@
+use crate::stats::Usage;
+
+/// A [`UsageHandler`] is used to report the resource usage of running processes.
+pub trait UsageHandler: Send + Sync {
+    /// Returns the current resource usage of whatever is running under the implementor.
+    fn usage(&self) -> Usage;
+}
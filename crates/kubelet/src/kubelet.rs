@@ -1,6 +1,8 @@
 ///! This library contains code for running a kubelet. Use this to create a new
 ///! Kubelet with a specific handler (called a `Provider`)
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
 use crate::config::Config;
+use crate::metrics;
 use crate::node;
 use crate::operator::PodOperator;
 use crate::plugin_watcher::PluginRegistry;
@@ -11,6 +13,7 @@ use crate::webserver::start as start_webserver;
 use futures::future::{FutureExt, TryFutureExt};
 use kube::api::ListParams;
 use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::signal::ctrl_c;
@@ -19,6 +22,12 @@ use tracing::{error, info, warn};
 
 use krator::{ControllerBuilder, Manager};
 
+/// How soon a failed update of the node's lease or status is first retried
+const HEARTBEAT_RETRY_BASE: std::time::Duration = std::time::Duration::from_secs(1);
+/// The fraction retries of node updates are randomly shortened by, so nodes that lost the API
+/// server at the same time don't all come back at once
+const HEARTBEAT_RETRY_JITTER: f64 = 0.2;
+
 /// A Kubelet server backed by a given `Provider`.
 ///
 /// A Kubelet is a special kind of server that handles Kubernetes requests
@@ -89,15 +98,32 @@ impl<P: Provider> Kubelet<P> {
         .boxed();
 
         // Start the webserver
-        let webserver = start_webserver(self.provider.clone(), &self.config.server_config)
-            .fuse()
-            .boxed();
+        let (server_ready_tx, server_ready_rx) = tokio::sync::oneshot::channel();
+        let webserver = start_webserver(
+            self.provider.clone(),
+            &self.config.server_config,
+            &self.config.node_name,
+            server_ready_tx,
+        )
+        .fuse()
+        .boxed();
+
+        let metrics_server =
+            start_metrics_server(self.config.server_config.addr, self.config.metrics_port)
+                .fuse()
+                .boxed();
 
         // Start updating the node lease and status periodically
-        let node_updater = start_node_updater(client.clone(), self.config.node_name.clone())
+        let node_updater = start_node_updater(client.clone(), self.config.clone(), server_ready_rx)
             .fuse()
             .boxed();
 
+        // Report and relieve memory and disk pressure on the node
+        let pressure_monitor =
+            node::monitor_pressure(client.clone(), self.config.clone(), self.provider.clone())
+                .fuse()
+                .boxed();
+
         // If any of these tasks fail, we can initiate graceful shutdown.
         let services = Box::pin(async {
             tokio::select! {
@@ -105,6 +131,7 @@ impl<P: Provider> Kubelet<P> {
                     error!(error = %e, "Signal task completed with error");
                 },
                 res = webserver => error!(result = ?res, "Webserver task completed with result"),
+                res = metrics_server => error!(result = ?res, "Metrics server task completed with result"),
                 res = node_updater => if let Err(e) = res {
                     error!(error = %e, "Node updater task completed with error");
                 },
@@ -113,6 +140,9 @@ impl<P: Provider> Kubelet<P> {
                 },
                 res = device_manager => if let Err(e) = res {
                     error!(error = %e, "Device manager task completed with error");
+                },
+                res = pressure_monitor => if let Err(e) = res {
+                    error!(error = %e, "Pressure monitor task completed with error");
                 }
             };
             // Use relaxed ordering because we just need other tasks to eventually catch the signal.
@@ -135,16 +165,22 @@ impl<P: Provider> Kubelet<P> {
         manager.register_controller(controller_builder);
         let operator_task = manager.start().boxed();
 
+        // The provider shuts down once the signal is caught. The operator keeps running while it
+        // does, so pods that are evicted are terminated gracefully
+        let shutdown = async {
+            match signal_handler.await {
+                Ok(()) => self.provider.shutdown(&self.config.node_name).await,
+                Err(e) => {
+                    error!(error = %e, "Signal handler task joined with error");
+                    Err(e)
+                }
+            }
+        };
+
         // These must all be running for graceful shutdown. An error here exits ungracefully.
         let core = Box::pin(async {
             tokio::select! {
-                res = signal_handler => match res {
-                    Ok(()) => self.provider.shutdown(&self.config.node_name).await,
-                    Err(e) => {
-                        error!(error = %e, "Signal handler task joined with error");
-                        Err(e)
-                    }
-                },
+                res = shutdown => res,
                 _ = operator_task => {
                     warn!("Pod operator has completed");
                     Ok(())
@@ -172,14 +208,36 @@ impl<P> Clone for Kubelet<P> {
     }
 }
 
-/// Awaits SIGINT and sets graceful shutdown flag if detected.
+/// Awaits SIGINT or SIGTERM and sets graceful shutdown flag if detected.
 async fn start_signal_task(signal: Arc<AtomicBool>) -> anyhow::Result<()> {
-    ctrl_c().await?;
-    warn!("Caught keyboard interrupt.");
+    tokio::select! {
+        res = ctrl_c() => {
+            res?;
+            warn!("Caught keyboard interrupt.");
+        }
+        res = terminate() => {
+            res?;
+            warn!("Caught termination signal.");
+        }
+    }
     signal.store(true, Ordering::Relaxed);
     Ok(())
 }
 
+/// Awaits SIGTERM, which is how service managers and container runtimes stop the kubelet
+#[cfg(target_family = "unix")]
+async fn terminate() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+// There is no SIGTERM on Windows, so only keyboard interrupts start a graceful shutdown
+#[cfg(target_family = "windows")]
+async fn terminate() -> anyhow::Result<()> {
+    futures::future::pending().await
+}
+
 async fn start_plugin_registry(registrar: Option<Arc<PluginRegistry>>) -> anyhow::Result<()> {
     match registrar {
         Some(r) => r.run().await,
@@ -215,12 +273,75 @@ async fn start_device_manager(device_manager: Option<Arc<DeviceManager>>) -> any
     }
 }
 
+/// Serves Prometheus metrics if a metrics port is configured
+async fn start_metrics_server(addr: IpAddr, port: Option<u16>) -> anyhow::Result<()> {
+    match port {
+        Some(port) => metrics::start(addr, port).await,
+        // Do nothing; just poll forever and "pretend" that a metrics server is running
+        None => {
+            task::spawn(async {
+                loop {
+                    // We run a delay here so we don't waste time on NOOP CPU cycles
+                    tokio::time::sleep(tokio::time::Duration::from_secs(std::u64::MAX)).await;
+                }
+            })
+            .map_err(anyhow::Error::from)
+            .await
+        }
+    }
+}
+
 /// Periodically renew node lease and status. Exits if signal is caught.
-async fn start_node_updater(client: kube::Client, node_name: String) -> anyhow::Result<()> {
-    let sleep_interval = std::time::Duration::from_secs(10);
+///
+/// The control plane reaches the node through the kubelet server for logs, exec and health
+/// checks, so the node's status, which reports it as ready, isn't posted until the server is
+/// listening.
+async fn start_node_updater(
+    client: kube::Client,
+    config: Box<Config>,
+    server_ready: tokio::sync::oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let lease = heartbeat("lease", config.node_lease_renew_interval, || {
+        node::renew_lease(&client, &config)
+    });
+    let status = async {
+        if server_ready.await.is_err() {
+            // The server stopped before it was listening, which shuts the kubelet down
+            warn!("Kubelet server never started, not reporting the node as ready");
+            return;
+        }
+        heartbeat("status", config.node_status_update_interval, || {
+            node::post_status(&client, &config)
+        })
+        .await
+    };
+    futures::future::join(lease, status).await;
+    Ok(())
+}
+
+/// Runs `update` every `interval`, forever. An update that fails, such as when the API server
+/// can't be reached, is retried with a backoff that tops out at the interval, so the node keeps
+/// reporting itself alive once the API is back rather than giving up.
+async fn heartbeat<F, Fut>(name: &str, interval: std::time::Duration, update: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let mut backoff = ExponentialBackoffStrategy::new(HEARTBEAT_RETRY_BASE.min(interval), interval)
+        .with_jitter(HEARTBEAT_RETRY_JITTER);
     loop {
-        node::update(&client, &node_name).await;
-        tokio::time::sleep(sleep_interval).await;
+        let wait = match update().await {
+            Ok(()) => {
+                backoff.reset();
+                interval
+            }
+            Err(e) => {
+                let wait = backoff.next_duration();
+                warn!(error = %e, retry_in = ?wait, "Unable to update node {}", name);
+                wait
+            }
+        };
+        tokio::time::sleep(wait).await;
     }
 }
 
@@ -251,8 +372,9 @@ mod test {
     };
     use k8s_openapi::api::core::v1::{
         Container as KubeContainer, EnvVar, EnvVarSource, ObjectFieldSelector, Pod as KubePod,
-        PodSpec, PodStatus,
+        PodSpec, PodStatus, ResourceFieldSelector,
     };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
     use krator::ObjectState;
     use kube::api::ObjectMeta;
     use std::collections::BTreeMap;
@@ -397,6 +519,39 @@ mod test {
                     }),
                     ..Default::default()
                 },
+                EnvVar {
+                    name: "NODE_NAME".into(),
+                    value_from: Some(EnvVarSource {
+                        field_ref: Some(ObjectFieldSelector {
+                            field_path: "spec.nodeName".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "LABEL".into(),
+                    value_from: Some(EnvVarSource {
+                        field_ref: Some(ObjectFieldSelector {
+                            field_path: "metadata.labels['label']".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvVar {
+                    name: "MISSING_LABEL".into(),
+                    value_from: Some(EnvVarSource {
+                        field_ref: Some(ObjectFieldSelector {
+                            field_path: "metadata.labels['missing']".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
             ],
             ..Default::default()
         });
@@ -416,6 +571,7 @@ mod test {
             },
             spec: Some(PodSpec {
                 service_account_name: Some("svc".to_string()),
+                node_name: Some("krustlet".to_string()),
                 ..Default::default()
             }),
             status: Some(PodStatus {
@@ -424,7 +580,9 @@ mod test {
                 ..Default::default()
             }),
         });
-        let env = MockProvider::env_vars(&container, &pod, &mock_client()).await;
+        let env = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap();
 
         assert_eq!(
             "value",
@@ -448,5 +606,120 @@ mod test {
         );
         assert_eq!("10.21.77.2", env.get("POD_IP").expect("pod_ip").as_str());
         assert_eq!("10.21.77.1", env.get("HOST_IP").expect("host_ip").as_str());
+        assert_eq!(
+            "krustlet",
+            env.get("NODE_NAME").expect("spec.nodeName").as_str()
+        );
+        assert_eq!(
+            "value",
+            env.get("LABEL").expect("metadata.labels['label']").as_str()
+        );
+        assert_eq!(
+            "",
+            env.get("MISSING_LABEL")
+                .expect("metadata.labels['missing']")
+                .as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_field_ref_env_var() {
+        let container = Container::new(&KubeContainer {
+            env: vec![EnvVar {
+                name: "UNSUPPORTED".into(),
+                value_from: Some(EnvVarSource {
+                    field_ref: Some(ObjectFieldSelector {
+                        field_path: "spec.hostname".into(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("my-name".to_string()),
+                namespace: Some("my-namespace".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let err = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .expect_err("unsupported field paths should not resolve");
+        let message = format!("{:#}", err);
+        assert!(message.contains("UNSUPPORTED"), "{}", message);
+        assert!(message.contains("spec.hostname"), "{}", message);
+    }
+
+    fn resource_env_var(name: &str, resource: &str, divisor: Option<&str>) -> EnvVar {
+        EnvVar {
+            name: name.into(),
+            value_from: Some(EnvVarSource {
+                resource_field_ref: Some(ResourceFieldSelector {
+                    resource: resource.into(),
+                    divisor: divisor.map(|d| Quantity(d.into())),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resource_field_env_vars() {
+        let mut container: KubeContainer = serde_json::from_value(serde_json::json!({
+            "name": "app",
+            "resources": {
+                "limits": {
+                    "cpu": "1500m",
+                    "memory": "64Mi"
+                },
+                "requests": {
+                    "cpu": "250m",
+                    "memory": "1000"
+                }
+            }
+        }))
+        .unwrap();
+        container.env = vec![
+            resource_env_var("CPU_LIMIT", "limits.cpu", None),
+            resource_env_var("CPU_LIMIT_MILLIS", "limits.cpu", Some("1m")),
+            resource_env_var("CPU_REQUEST_MILLIS", "requests.cpu", Some("1m")),
+            resource_env_var("CPU_REQUEST", "requests.cpu", Some("1")),
+            resource_env_var("MEMORY_LIMIT", "limits.memory", None),
+            resource_env_var("MEMORY_LIMIT_MI", "limits.memory", Some("1Mi")),
+            resource_env_var("MEMORY_REQUEST_KI", "requests.memory", Some("1Ki")),
+            resource_env_var("STORAGE_REQUEST", "requests.ephemeral-storage", None),
+            resource_env_var("STORAGE_LIMIT_KI", "limits.ephemeral-storage", Some("1Ki")),
+        ];
+        let container = Container::new(&container);
+        let pod = Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("my-name".to_string()),
+                namespace: Some("my-namespace".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let env = MockProvider::env_vars(&container, &pod, &mock_client())
+            .await
+            .unwrap();
+
+        let value = |key: &str| env.get(key).expect(key).as_str();
+        // Values are rounded up to a whole number of divisors
+        assert_eq!("2", value("CPU_LIMIT"));
+        assert_eq!("1500", value("CPU_LIMIT_MILLIS"));
+        assert_eq!("250", value("CPU_REQUEST_MILLIS"));
+        assert_eq!("1", value("CPU_REQUEST"));
+        assert_eq!("67108864", value("MEMORY_LIMIT"));
+        assert_eq!("64", value("MEMORY_LIMIT_MI"));
+        assert_eq!("1", value("MEMORY_REQUEST_KI"));
+        // Unset requests are 0 and unset limits are the node's capacity
+        assert_eq!("0", value("STORAGE_REQUEST"));
+        assert_eq!("61255492", value("STORAGE_LIMIT_KI"));
     }
 }
@@ -113,15 +113,19 @@ pub(crate) mod mio_uds_windows;
 pub mod backoff;
 pub mod config;
 pub mod container;
+pub mod exec;
 pub mod handle;
 pub mod log;
+pub mod metrics;
 pub mod node;
 pub mod plugin_watcher;
 pub mod pod;
+pub mod port_forward;
 pub mod provider;
 pub mod resources;
 pub mod secret;
 pub mod state;
+pub mod stats;
 pub mod store;
 pub mod volume;
 
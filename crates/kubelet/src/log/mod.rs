@@ -2,8 +2,10 @@
 use anyhow::bail;
 use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use std::io::SeekFrom;
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncRead};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncSeek, AsyncSeekExt};
+use tokio::sync::watch;
 use tracing::{debug, error};
 
 /// Possible errors sending log data.
@@ -39,6 +41,102 @@ impl std::error::Error for SendError {
     }
 }
 
+/// The output streams of a container. Providers that write both streams to the same log can tag
+/// each line with the stream it came from, so clients can ask for only one of them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl Stream {
+    /// The tag that starts each line of output from this stream in a tagged log
+    pub fn tag(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout ",
+            Stream::Stderr => "stderr ",
+        }
+    }
+
+    /// Splits the tag off a line of a tagged log. Lines without a tag are returned as is
+    pub fn untag(line: &str) -> (Option<Stream>, &str) {
+        for stream in &[Stream::Stdout, Stream::Stderr] {
+            if let Some(rest) = line.strip_prefix(stream.tag()) {
+                return (Some(*stream), rest);
+            }
+        }
+        (None, line)
+    }
+}
+
+/// How the lines of a container's log record the stream they were written to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineFormat {
+    /// Lines are written as they are, without their stream
+    Plain,
+    /// Each line starts with the [`Stream::tag`] of its stream, which is removed before it is sent
+    Tagged,
+    /// Each line is a JSON object with a `stream` field of `stdout` or `stderr`. Lines are sent as
+    /// they are
+    Json,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        LineFormat::Plain
+    }
+}
+
+impl LineFormat {
+    /// Splits a line into the stream it was written to, if the format records it, and the line
+    /// to send for it
+    fn split<'a>(&self, line: &'a str) -> (Option<Stream>, &'a str) {
+        match self {
+            LineFormat::Plain => (None, line),
+            LineFormat::Tagged => Stream::untag(line),
+            LineFormat::Json => {
+                let stream = serde_json::from_str::<serde_json::Value>(line)
+                    .ok()
+                    .and_then(|record| match record.get("stream")?.as_str()? {
+                        "stdout" => Some(Stream::Stdout),
+                        "stderr" => Some(Stream::Stderr),
+                        _ => None,
+                    });
+                (stream, line)
+            }
+        }
+    }
+}
+
+/// Which streams of a container logs are fetched from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum StreamFilter {
+    /// Both standard output and standard error
+    All,
+    /// Only standard output
+    Stdout,
+    /// Only standard error
+    Stderr,
+}
+
+impl Default for StreamFilter {
+    fn default() -> Self {
+        StreamFilter::All
+    }
+}
+
+impl StreamFilter {
+    fn matches(&self, stream: Stream) -> bool {
+        match self {
+            StreamFilter::All => true,
+            StreamFilter::Stdout => stream == Stream::Stdout,
+            StreamFilter::Stderr => stream == Stream::Stderr,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 /// Client options for fetching logs.
 /// For more details on what the parameters mean please refer to
@@ -65,6 +163,10 @@ pub struct Options {
     /// specifies a size limit of how many logs should be returned in bytes
     #[serde(rename = "limitBytes")]
     pub limit_bytes: Option<u64>,
+    /// specifies which of the container's output streams to return. This only has an effect
+    /// for providers that tag the stream of each line in their logs
+    #[serde(default)]
+    pub stream: StreamFilter,
 }
 
 /// Sender for streaming logs to client.
@@ -109,11 +211,40 @@ impl Sender {
         self.opts.since_time
     }
 
+    /// The earliest time logs should be returned from, based on the `sinceSeconds` and `sinceTime`
+    /// indicated by the request. If both are given, the later of the two is used.
+    pub fn since_cutoff(&self) -> Option<DateTime<Utc>> {
+        let since = self
+            .since()
+            .and_then(|d| chrono::Duration::from_std(d).ok())
+            .map(|d| Utc::now() - d);
+        match (since, self.since_time()) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
     /// The limit_bytes indicated by the request, or `None` if absent.
     pub fn limit_bytes(&self) -> Option<u64> {
         self.opts.limit_bytes
     }
 
+    /// The streams indicated by the request, or all of them if absent.
+    pub fn stream(&self) -> StreamFilter {
+        self.opts.stream
+    }
+
+    /// Prepares a line read from the log for sending. Lines of a tagged log have their tag
+    /// removed, and lines of logs that record their stream are dropped if they are from a stream
+    /// the request didn't ask for.
+    fn filter(&self, line: String, format: LineFormat) -> Option<String> {
+        match format.split(&line) {
+            (Some(stream), _) if !self.stream().matches(stream) => None,
+            (Some(_), rest) => Some(rest.to_owned()),
+            (None, _) => Some(line),
+        }
+    }
+
     /// Async send some data to a client.
     pub async fn send(&mut self, data: String) -> Result<(), SendError> {
         let b: hyper::body::Bytes = data.into();
@@ -134,6 +265,7 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
     lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
     sender: &mut Sender,
     n: usize,
+    format: LineFormat,
 ) -> Result<(), SendError> {
     let mut line_buf = std::collections::VecDeque::with_capacity(n);
 
@@ -147,6 +279,10 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
+        let line = match sender.filter(line, format) {
+            Some(line) => line,
+            None => continue,
+        };
         if line_buf.len() == n {
             line_buf.pop_front();
         }
@@ -164,8 +300,9 @@ async fn tail<R: AsyncRead + std::marker::Unpin>(
 async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
     lines: &mut tokio::io::Lines<tokio::io::BufReader<R>>,
     sender: &mut Sender,
+    format: LineFormat,
 ) -> Result<(), SendError> {
-    while let Some(mut line) = match lines.next_line().await {
+    while let Some(line) = match lines.next_line().await {
         Ok(line) => line,
         Err(e) => {
             error!(error = %e, "Error reading from log");
@@ -175,28 +312,51 @@ async fn stream_to_end<R: AsyncRead + std::marker::Unpin>(
             return Err(e.into());
         }
     } {
-        line.push('\n');
-        sender.send(line).await?;
+        if let Some(mut line) = sender.filter(line, format) {
+            line.push('\n');
+            sender.send(line).await?;
+        }
     }
     Ok(())
 }
 
-/// Future that streams logs from provided `AsyncRead` to provided `Sender`.
-pub async fn stream<R: AsyncRead + std::marker::Unpin>(
+/// Checks whether the log has been truncated (e.g. by rotation) to before the current read
+/// position, rewinding to the start of the log if it has. Returns whether the reader was rewound
+async fn rewind_if_truncated<R: AsyncSeek + std::marker::Unpin>(
+    reader: &mut R,
+) -> std::io::Result<bool> {
+    let position = reader.seek(SeekFrom::Current(0)).await?;
+    let len = reader.seek(SeekFrom::End(0)).await?;
+    if len < position {
+        reader.seek(SeekFrom::Start(0)).await?;
+        Ok(true)
+    } else {
+        reader.seek(SeekFrom::Start(position)).await?;
+        Ok(false)
+    }
+}
+
+/// Future that streams logs from provided `AsyncRead` to provided `Sender`. When following the log,
+/// the stream ends once the `closed` receiver (if any) reports that its sender has been dropped.
+/// A followed log that is truncated underneath the stream is followed again from its start.
+/// Each line of the log is in the given `format`, which may record the stream it came from.
+pub async fn stream<R: AsyncRead + AsyncSeek + std::marker::Unpin>(
     handle: R,
     mut sender: Sender,
+    mut closed: Option<watch::Receiver<()>>,
+    format: LineFormat,
 ) -> anyhow::Result<()> {
     let buf = tokio::io::BufReader::new(handle);
     let mut lines = buf.lines();
 
     if let Some(n) = sender.tail() {
-        match tail(&mut lines, &mut sender, n).await {
+        match tail(&mut lines, &mut sender, n, format).await {
             Ok(_) => (),
             Err(SendError::ChannelClosed) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
         }
     } else {
-        match stream_to_end(&mut lines, &mut sender).await {
+        match stream_to_end(&mut lines, &mut sender, format).await {
             Ok(_) => (),
             Err(SendError::ChannelClosed) => return Ok(()),
             Err(SendError::Abnormal(e)) => bail!(e),
@@ -204,14 +364,37 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
     }
 
     if sender.follow() {
+        let mut done = false;
         loop {
-            match stream_to_end(&mut lines, &mut sender).await {
+            match stream_to_end(&mut lines, &mut sender, format).await {
                 Ok(_) => (),
                 Err(SendError::ChannelClosed) => return Ok(()),
                 Err(SendError::Abnormal(e)) => bail!(e),
             }
+            // Once the writer has gone away, the drain above picked up the
+            // last of its output
+            if done {
+                return Ok(());
+            }
 
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            let poll = tokio::time::sleep(std::time::Duration::from_millis(500));
+            match closed.as_mut() {
+                Some(rx) => tokio::select! {
+                    _ = poll => (),
+                    res = rx.changed() => {
+                        if res.is_err() {
+                            debug!("Log writer closed, finishing followed stream");
+                            done = true;
+                        }
+                    }
+                },
+                None => poll.await,
+            }
+            match rewind_if_truncated(lines.get_mut()).await {
+                Ok(true) => debug!("Log was truncated, following it from the start"),
+                Ok(false) => (),
+                Err(e) => bail!(e),
+            }
         }
     }
 
@@ -225,4 +408,187 @@ pub async fn stream<R: AsyncRead + std::marker::Unpin>(
 pub trait HandleFactory<R>: Sync + Send {
     /// Create new log reader.
     fn new_handle(&self) -> R;
+
+    /// Returns the offset in the log to start reading from to get everything written at or after
+    /// the given time. Implementors that don't keep track of when output was written can use the
+    /// default, which returns `None` and serves the whole log.
+    fn offset_since(&self, _since: DateTime<Utc>) -> Option<u64> {
+        None
+    }
+
+    /// Returns a receiver whose sender is dropped once nothing will write to the log any more,
+    /// which ends followed log streams. The default returns `None`, which keeps followed streams
+    /// open until the client disconnects.
+    fn closed(&self) -> Option<watch::Receiver<()>> {
+        None
+    }
+
+    /// How each line of the log records the stream it was written to. The default returns
+    /// [`LineFormat::Plain`], for logs that are written as they are.
+    fn line_format(&self) -> LineFormat {
+        LineFormat::Plain
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::body::HttpBody;
+
+    fn options(follow: bool, tail: Option<usize>) -> Options {
+        Options {
+            tail,
+            follow,
+            previous: false,
+            timestamps: false,
+            since: None,
+            since_time: None,
+            limit_bytes: None,
+            stream: StreamFilter::All,
+        }
+    }
+
+    async fn collect(mut body: hyper::Body) -> String {
+        let mut out = Vec::new();
+        while let Some(chunk) = body.data().await {
+            out.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tail() {
+        let (tx, body) = hyper::Body::channel();
+        let sender = Sender::new(tx, options(false, Some(2)));
+        let (res, out) = tokio::join!(
+            stream(
+                std::io::Cursor::new(b"one\ntwo\nthree\n"),
+                sender,
+                None,
+                LineFormat::Plain
+            ),
+            collect(body)
+        );
+        res.unwrap();
+        assert_eq!(out, "two\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn test_follow_ends_when_writer_closes() {
+        let (tx, body) = hyper::Body::channel();
+        let sender = Sender::new(tx, options(true, None));
+        let (closed_tx, closed_rx) = watch::channel(());
+        drop(closed_tx);
+        let (res, out) = tokio::join!(
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                stream(
+                    std::io::Cursor::new(b"one\ntwo\n"),
+                    sender,
+                    Some(closed_rx),
+                    LineFormat::Plain
+                ),
+            ),
+            collect(body)
+        );
+        res.expect("followed stream should end once the writer is closed")
+            .unwrap();
+        assert_eq!(out, "one\ntwo\n");
+    }
+
+    #[tokio::test]
+    async fn test_follow_rewinds_after_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let handle = tokio::fs::File::open(&path).await.unwrap();
+
+        let (tx, body) = hyper::Body::channel();
+        let sender = Sender::new(tx, options(true, None));
+        let (closed_tx, closed_rx) = watch::channel(());
+        let rotate = async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            std::fs::write(&path, "three\n").unwrap();
+            drop(closed_tx);
+        };
+        let (res, out, _) = tokio::join!(
+            tokio::time::timeout(
+                Duration::from_secs(5),
+                stream(handle, sender, Some(closed_rx), LineFormat::Plain),
+            ),
+            collect(body),
+            rotate
+        );
+        res.expect("followed stream should end once the writer is closed")
+            .unwrap();
+        assert_eq!(out, "one\ntwo\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn test_tagged_streams_are_filtered() {
+        let log = b"stdout one\nstderr oops\nstdout two\n";
+
+        let (tx, body) = hyper::Body::channel();
+        let sender = Sender::new(tx, options(false, None));
+        let (res, out) = tokio::join!(
+            stream(std::io::Cursor::new(log), sender, None, LineFormat::Tagged),
+            collect(body)
+        );
+        res.unwrap();
+        assert_eq!(out, "one\noops\ntwo\n");
+
+        let (tx, body) = hyper::Body::channel();
+        let mut opts = options(false, Some(1));
+        opts.stream = StreamFilter::Stderr;
+        let sender = Sender::new(tx, opts);
+        let (res, out) = tokio::join!(
+            stream(std::io::Cursor::new(log), sender, None, LineFormat::Tagged),
+            collect(body)
+        );
+        res.unwrap();
+        assert_eq!(out, "oops\n");
+    }
+
+    #[tokio::test]
+    async fn test_json_streams_are_filtered() {
+        let log = concat!(
+            "{\"stream\":\"stdout\",\"message\":\"one\"}\n",
+            "{\"stream\":\"stderr\",\"message\":\"oops\"}\n",
+            "not json\n",
+        );
+
+        let (tx, body) = hyper::Body::channel();
+        let sender = Sender::new(tx, options(false, None));
+        let (res, out) = tokio::join!(
+            stream(std::io::Cursor::new(log), sender, None, LineFormat::Json),
+            collect(body)
+        );
+        res.unwrap();
+        assert_eq!(out, log);
+
+        let (tx, body) = hyper::Body::channel();
+        let mut opts = options(false, None);
+        opts.stream = StreamFilter::Stdout;
+        let sender = Sender::new(tx, opts);
+        let (res, out) = tokio::join!(
+            stream(std::io::Cursor::new(log), sender, None, LineFormat::Json),
+            collect(body)
+        );
+        res.unwrap();
+        assert_eq!(
+            out,
+            "{\"stream\":\"stdout\",\"message\":\"one\"}\nnot json\n"
+        );
+    }
+
+    #[test]
+    fn test_since_cutoff() {
+        let (tx, _body) = hyper::Body::channel();
+        let mut opts = options(false, None);
+        let since_time = Utc::now() - chrono::Duration::seconds(10);
+        opts.since_time = Some(since_time);
+        opts.since = Some(60);
+        let sender = Sender::new(tx, opts);
+        assert_eq!(sender.since_cutoff(), Some(since_time));
+    }
 }
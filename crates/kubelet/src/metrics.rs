@@ -0,0 +1,104 @@
+//! Prometheus metrics for the kubelet and its provider.
+//!
+//! Metrics are registered with the default Prometheus registry, so providers can register their
+//! own metrics with it and have them served alongside these.
+
+use std::net::IpAddr;
+
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_gauge, Encoder, Histogram, IntCounter,
+    IntGauge, TextEncoder,
+};
+use tracing::error;
+use warp::{Filter, Reply};
+
+/// Buckets for the module pull duration, which can take much longer than the default buckets
+/// allow for when modules are large or registries are slow
+const PULL_DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+lazy_static! {
+    /// The number of containers that have been started, including restarts
+    pub static ref CONTAINERS_STARTED: IntCounter = register_int_counter!(
+        "krustlet_containers_started_total",
+        "The number of containers that have been started"
+    )
+    .expect("containers started metric can be registered");
+    /// The number of containers that failed to start or exited with an error
+    pub static ref CONTAINER_FAILURES: IntCounter = register_int_counter!(
+        "krustlet_container_failures_total",
+        "The number of containers that failed to start or exited with an error"
+    )
+    .expect("container failures metric can be registered");
+    /// The time taken to pull the modules of a pod
+    pub static ref MODULE_PULL_DURATION: Histogram = register_histogram!(
+        "krustlet_module_pull_duration_seconds",
+        "The time taken to pull the modules of a pod",
+        PULL_DURATION_BUCKETS.to_vec()
+    )
+    .expect("module pull duration metric can be registered");
+    /// The time taken to compile a module before it is run, or to load it if it was compiled
+    /// before
+    pub static ref MODULE_COMPILE_DURATION: Histogram = register_histogram!(
+        "krustlet_module_compile_duration_seconds",
+        "The time taken to compile a module before it is run, or to load it if it was compiled before"
+    )
+    .expect("module compile duration metric can be registered");
+    /// The number of pods scheduled to the node that haven't been deleted
+    pub static ref ACTIVE_PODS: IntGauge = register_int_gauge!(
+        "krustlet_active_pods",
+        "The number of pods scheduled to the node that haven't been deleted"
+    )
+    .expect("active pods metric can be registered");
+}
+
+/// Serves the metrics in the Prometheus text format at `/metrics` on the given address and port
+pub(crate) async fn start(addr: IpAddr, port: u16) -> anyhow::Result<()> {
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(|| match encode() {
+            Ok(text) => {
+                warp::reply::with_header(text, "content-type", TextEncoder::new().format_type())
+                    .into_response()
+            }
+            Err(e) => {
+                error!(error = %e, "Unable to encode metrics");
+                warp::reply::with_status(
+                    e.to_string(),
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into_response()
+            }
+        });
+    warp::serve(metrics).run((addr, port)).await;
+    Ok(())
+}
+
+/// Encodes all registered metrics in the Prometheus text format
+fn encode() -> anyhow::Result<String> {
+    // Make sure the metrics are registered even if nothing has used them yet
+    lazy_static::initialize(&CONTAINERS_STARTED);
+    lazy_static::initialize(&CONTAINER_FAILURES);
+    lazy_static::initialize(&MODULE_PULL_DURATION);
+    lazy_static::initialize(&MODULE_COMPILE_DURATION);
+    lazy_static::initialize(&ACTIVE_PODS);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_encoded() {
+        CONTAINERS_STARTED.inc();
+        let text = encode().unwrap();
+        assert!(text.contains("krustlet_containers_started_total"));
+        assert!(text.contains("krustlet_module_pull_duration_seconds_bucket"));
+        assert!(text.contains("krustlet_active_pods"));
+    }
+}
@@ -0,0 +1,119 @@
+//! Detection of the resources the node can offer to pods.
+use std::collections::BTreeMap;
+
+use super::{NODE_EPHEMERAL_STORAGE, NODE_MEMORY};
+use crate::config::Config;
+
+/// The resources of the node, as reported in the `capacity` and `allocatable` of its status
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Capacity {
+    cpu: usize,
+    /// The memory of the node in bytes, if it could be detected
+    memory: Option<u64>,
+    reserved_memory: u64,
+    pods: u16,
+}
+
+impl Capacity {
+    /// Detects the CPUs and memory of the host. Memory can only be detected on Linux, so other
+    /// hosts report a fixed amount
+    pub(crate) fn detect(config: &Config) -> Self {
+        let memory = match std::fs::read_to_string("/proc/meminfo") {
+            Ok(meminfo) => parse_mem_total(&meminfo),
+            Err(_) => None,
+        };
+        Capacity {
+            cpu: num_cpus::get(),
+            memory,
+            reserved_memory: config.system_reserved_memory,
+            pods: config.max_pods,
+        }
+    }
+
+    /// Whether the memory of the node could be detected
+    pub(crate) fn memory_detected(&self) -> bool {
+        self.memory.is_some()
+    }
+
+    /// The total resources of the node
+    pub(crate) fn capacity(&self) -> BTreeMap<String, String> {
+        let memory = match self.memory {
+            Some(memory) => kibibytes(memory),
+            None => NODE_MEMORY.to_owned(),
+        };
+        self.resources(memory)
+    }
+
+    /// The resources of the node that can be allocated to pods, which is everything but the
+    /// memory reserved for the system
+    pub(crate) fn allocatable(&self) -> BTreeMap<String, String> {
+        let memory = match self.memory {
+            Some(memory) => kibibytes(memory.saturating_sub(self.reserved_memory)),
+            None => NODE_MEMORY.to_owned(),
+        };
+        self.resources(memory)
+    }
+
+    fn resources(&self, memory: String) -> BTreeMap<String, String> {
+        let mut resources = BTreeMap::new();
+        resources.insert("cpu".to_owned(), self.cpu.to_string());
+        resources.insert(
+            "ephemeral-storage".to_owned(),
+            NODE_EPHEMERAL_STORAGE.to_owned(),
+        );
+        resources.insert("hugepages-1Gi".to_owned(), "0".to_owned());
+        resources.insert("hugepages-2Mi".to_owned(), "0".to_owned());
+        resources.insert("memory".to_owned(), memory);
+        resources.insert("pods".to_owned(), self.pods.to_string());
+        resources
+    }
+}
+
+/// Formats a number of bytes as a quantity in whole kibibytes, like the kubelet reports memory in
+fn kibibytes(bytes: u64) -> String {
+    format!("{}Ki", bytes / 1024)
+}
+
+/// Parses the total memory in bytes out of the contents of `/proc/meminfo`
+fn parse_mem_total(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let mut parts = line["MemTotal:".len()..].split_whitespace();
+    let value: u64 = parts.next()?.parse().ok()?;
+    match parts.next() {
+        Some("kB") => Some(value * 1024),
+        None => Some(value),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_mem_total() {
+        let meminfo = "MemTotal:       16316412 kB\nMemFree:         1171028 kB\n";
+        assert_eq!(parse_mem_total(meminfo), Some(16316412 * 1024));
+        assert_eq!(parse_mem_total("MemFree:         1171028 kB\n"), None);
+    }
+
+    #[test]
+    fn test_allocatable_excludes_reserved_memory() {
+        let capacity = Capacity {
+            cpu: 2,
+            memory: Some(2048 * 1024 * 1024),
+            reserved_memory: 512 * 1024 * 1024,
+            pods: 20,
+        };
+        assert_eq!(capacity.capacity()["memory"], "2097152Ki");
+        assert_eq!(capacity.allocatable()["memory"], "1572864Ki");
+        assert_eq!(capacity.allocatable()["cpu"], "2");
+        assert_eq!(capacity.allocatable()["pods"], "20");
+
+        let tiny = Capacity {
+            memory: Some(256 * 1024 * 1024),
+            ..capacity
+        };
+        assert_eq!(tiny.allocatable()["memory"], "0Ki");
+    }
+}
@@ -18,7 +18,26 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::{debug, error, info, instrument, trace, warn};
 
+mod capacity;
+mod pressure;
+
+use capacity::Capacity;
+pub use pressure::monitor_pressure;
+
 const KUBELET_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The CPUs containers without a CPU limit are limited to
+pub(crate) const NODE_CPU: &str = "4";
+/// The memory reported as the node's capacity if it can't be detected. This is also what
+/// containers without a memory limit are limited to
+pub(crate) const NODE_MEMORY: &str = "4032800Ki";
+/// The ephemeral storage reported as the node's capacity. This is also what containers without
+/// an ephemeral storage limit are limited to
+pub(crate) const NODE_EPHEMERAL_STORAGE: &str = "61255492Ki";
+/// The shortest time the node's lease is valid for
+const LEASE_DURATION_SECONDS: u64 = 300;
+/// How many times the lease should be renewed within its duration, so a few missed renewals
+/// don't cost the node its lease
+const LEASE_RENEWALS_PER_DURATION: u64 = 4;
 
 macro_rules! retry {
     ($action:expr, times: $num_times:expr, error: $on_err:expr) => {{
@@ -97,23 +116,29 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
 
     node_labels_definition(P::ARCH, &config, &mut builder);
 
-    // TODO Do we want to detect this?
-    builder.add_capacity("cpu", "4");
-    builder.add_capacity("ephemeral-storage", "61255492Ki");
-    builder.add_capacity("hugepages-1Gi", "0");
-    builder.add_capacity("hugepages-2Mi", "0");
-    builder.add_capacity("memory", "4032800Ki");
-    builder.add_capacity("pods", &config.max_pods.to_string());
-
-    builder.add_allocatable("cpu", "4");
-    builder.add_allocatable("ephemeral-storage", "61255492Ki");
-    builder.add_allocatable("hugepages-1Gi", "0");
-    builder.add_allocatable("hugepages-2Mi", "0");
-    builder.add_allocatable("memory", "4032800Ki");
-    builder.add_allocatable("pods", &config.max_pods.to_string());
+    let capacity = Capacity::detect(config);
+    if !capacity.memory_detected() {
+        warn!(
+            memory = NODE_MEMORY,
+            "Unable to detect the memory of the node, reporting a fixed amount"
+        );
+    }
+    for (resource, quantity) in capacity.capacity() {
+        builder.add_capacity(&resource, &quantity);
+    }
+    for (resource, quantity) in capacity.allocatable() {
+        builder.add_allocatable(&resource, &quantity);
+    }
 
     let ts = Utc::now();
-    builder.add_condition("Ready", "True", &ts, "KubeletReady", "kubelet is ready");
+    // The node is reported ready by the status updates, once the kubelet server is listening
+    builder.add_condition(
+        "Ready",
+        "False",
+        &ts,
+        "KubeletNotReady",
+        "kubelet server is starting",
+    );
     builder.add_condition(
         "OutOfDisk",
         "False",
@@ -121,6 +146,20 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
         "KubeletHasSufficientDisk",
         "kubelet has sufficient disk space available",
     );
+    builder.add_condition(
+        "MemoryPressure",
+        "False",
+        &ts,
+        "KubeletHasSufficientMemory",
+        "kubelet has sufficient memory available",
+    );
+    builder.add_condition(
+        "DiskPressure",
+        "False",
+        &ts,
+        "KubeletHasNoDiskPressure",
+        "kubelet has no disk pressure",
+    );
 
     builder.add_address("InternalIP", &format!("{}", config.node_ip));
     builder.add_address("Hostname", &config.hostname);
@@ -137,7 +176,8 @@ pub async fn create<P: Provider>(client: &kube::Client, config: &Config, provide
     match retry!(node_client.create(&PostParams::default(), &node).await, times: 4) {
         Ok(node) => {
             let node_uid = node.metadata.uid.unwrap();
-            if let Err(e) = create_lease(&node_uid, &config.node_name, &client).await {
+            let duration = lease_duration(config.node_lease_renew_interval);
+            if let Err(e) = create_lease(&node_uid, &config.node_name, duration, &client).await {
                 error!(error = %e, "Failed to create lease");
                 return;
             }
@@ -175,12 +215,64 @@ pub async fn uid(client: &kube::Client, node_name: &str) -> anyhow::Result<Strin
     }
 }
 
-/// Cordons node and evicts all pods.
-pub async fn drain(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
-    evict_pods(client, node_name).await?;
+/// Cordons the node, evicts all of its pods and deregisters it.
+///
+/// Pods are evicted one at a time, and each is given its termination grace period to stop. The
+/// node is deregistered once all pods are gone, or once `timeout` has elapsed if they take too
+/// long, so a node being shut down doesn't leave state behind in the cluster.
+pub async fn drain(
+    client: &kube::Client,
+    node_name: &str,
+    timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    cordon(client, node_name).await?;
+    match tokio::time::timeout(timeout, evict_pods(client, node_name)).await {
+        Ok(Ok(())) => info!("All pods evicted"),
+        Ok(Err(e)) => error!(error = %e, "Error evicting pods"),
+        Err(_) => warn!(?timeout, "Timed out waiting for pods to be evicted"),
+    }
+    deregister(client, node_name).await
+}
+
+/// Marks the node as unschedulable so no new pods are scheduled to it.
+#[instrument(level = "info", skip(client))]
+pub async fn cordon(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    let patch = serde_json::json!({
+        "spec": {
+            "unschedulable": true
+        }
+    });
+    retry!(
+        node_client
+            .patch(
+                node_name,
+                &PatchParams::default(),
+                &kube::api::Patch::Strategic(&patch)
+            )
+            .await,
+        times: 4
+    )
+    .map_err(|e| anyhow::anyhow!("Unable to cordon node: {}", e))?;
+    info!("Cordoned node");
     Ok(())
 }
 
+/// Deletes the node object, so the node no longer appears in the cluster.
+#[instrument(level = "info", skip(client))]
+async fn deregister(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    match node_client.delete(node_name, &Default::default()).await {
+        Ok(_) => {
+            info!("Deregistered node");
+            Ok(())
+        }
+        // Someone else got there first
+        Err(Error::Api(ErrorResponse { code: 404, .. })) => Ok(()),
+        Err(e) => Err(anyhow::anyhow!("Unable to deregister node: {}", e)),
+    }
+}
+
 /// Fetches list of pods on this node and deletes them.
 #[instrument(level = "info", skip(client))]
 pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Result<()> {
@@ -199,8 +291,11 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
 
     info!(num_pods = pods.len(), "Evicting pods");
 
+    // Evict critical pods last, as the other pods may depend on them while they shut down
+    let mut pods: Vec<Pod> = pods.into_iter().map(Pod::from).collect();
+    pods.sort_by_key(|pod| pod.is_critical());
+
     for pod in pods {
-        let pod = Pod::from(pod);
         if pod.is_daemonset() {
             info!(pod_name = pod.name(), "Skipping eviction of DaemonSet pod");
             continue;
@@ -218,7 +313,9 @@ pub async fn evict_pods(client: &kube::Client, node_name: &str) -> anyhow::Resul
                             ContainerStatus::Terminated {
                                 timestamp: Utc::now(),
                                 message: "Evicted on node shutdown".to_string(),
-                                failed: false
+                                failed: false,
+                                reason: None,
+                                exit_code: None,
                             }.to_kubernetes(container.name())
                         }).collect::<Vec<KubeContainerStatus>>()
                     }
@@ -283,27 +380,56 @@ async fn evict_pod(
     Ok(())
 }
 
-/// Update the timestamps on the Node object.
+/// Renew the node's lease.
 ///
-/// This is how we report liveness to the upstream.
-/// If we are unable to update the node after several retries we panic, as we could be in an
-/// inconsistent state
-#[instrument(level = "info", skip(client))]
-pub async fn update(client: &kube::Client, node_name: &str) {
-    debug!("Updating node");
-    if let Ok(uid) = uid(client, node_name).await {
-        trace!("Fetched current node object to update");
-        retry!(update_lease(&uid, node_name, client).await, times: 4)
-            .expect("Could not update lease");
-        retry!(update_status(node_name, client).await, times: 4)
-            .expect("Could not update node status");
-    }
+/// This is how we report liveness to the upstream. An error is returned if the lease could not
+/// be renewed after several retries, so the caller can try again later
+#[instrument(level = "info", skip(client, config), fields(node_name = %config.node_name))]
+pub async fn renew_lease(client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+    debug!("Renewing node lease");
+    let node_name = &config.node_name;
+    let uid = uid(client, node_name).await?;
+    trace!("Fetched current node object to renew lease");
+    let duration = lease_duration(config.node_lease_renew_interval);
+    retry!(update_lease(&uid, node_name, duration, client).await, times: 4)
+        .map_err(|e| anyhow::anyhow!("Unable to renew node lease: {}", e))?;
+    Ok(())
+}
+
+/// Update the readiness and resources on the Node object.
+///
+/// An error is returned if the status could not be updated after several retries, so the caller
+/// can try again later
+#[instrument(level = "info", skip(client, config), fields(node_name = %config.node_name))]
+pub async fn post_status(client: &kube::Client, config: &Config) -> anyhow::Result<()> {
+    debug!("Updating node status");
+    let capacity = Capacity::detect(config);
+    retry!(update_status(&config.node_name, &capacity, client).await, times: 4)
 }
 
-async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result<()> {
+/// How long the node's lease is valid for. Kubernetes considers the node lost once its lease
+/// expires, so it is kept long enough to be renewed several times over. Leases store the
+/// duration as a 32-bit integer, so it is capped at the longest they can hold
+fn lease_duration(renew_interval: std::time::Duration) -> u64 {
+    std::cmp::max(
+        LEASE_DURATION_SECONDS,
+        renew_interval
+            .as_secs()
+            .saturating_mul(LEASE_RENEWALS_PER_DURATION),
+    )
+    .min(i32::MAX as u64)
+}
+
+async fn update_status(
+    node_name: &str,
+    capacity: &Capacity,
+    client: &kube::Client,
+) -> anyhow::Result<()> {
     // TODO: Update the lastTransitionTime properly
     let status_patch = serde_json::json!({
         "status": {
+            "capacity": capacity.capacity(),
+            "allocatable": capacity.allocatable(),
             "conditions": [
                 {
                     "lastHeartbeatTime": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
@@ -336,11 +462,16 @@ async fn update_status(node_name: &str, client: &kube::Client) -> anyhow::Result
 /// As far as I can tell, leases ALWAYS go in the 'kube-node-lease'
 /// namespace, no exceptions.
 #[instrument(level = "info", err, skip(client))]
-async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) -> Result<(), Error> {
+async fn create_lease(
+    node_uid: &str,
+    node_name: &str,
+    duration_seconds: u64,
+    client: &kube::Client,
+) -> Result<(), Error> {
     debug!("Creating lease for node");
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let lease = lease_definition(node_uid, node_name, duration_seconds);
     let lease = serde_json::from_value(lease)
         .expect("failed to deserialize lease from lease definition JSON");
 
@@ -375,12 +506,13 @@ async fn create_lease(node_uid: &str, node_name: &str, client: &kube::Client) ->
 async fn update_lease(
     node_uid: &str,
     node_name: &str,
+    duration_seconds: u64,
     client: &kube::Client,
 ) -> Result<Lease, Error> {
     debug!("Updating lease for node");
     let leases: Api<Lease> = Api::namespaced(client.clone(), "kube-node-lease");
 
-    let lease = lease_definition(node_uid, node_name);
+    let lease = lease_definition(node_uid, node_name, duration_seconds);
 
     let resp = leases
         .patch(
@@ -401,7 +533,7 @@ async fn update_lease(
 /// The lease tells Kubernetes that we want to claim the node for a while
 /// longer. And then tells Kubernetes how long it should wait before
 /// expecting a new lease.
-fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
+fn lease_definition(node_uid: &str, node_name: &str, duration_seconds: u64) -> serde_json::Value {
     serde_json::json!(
         {
             "apiVersion": "coordination.k8s.io/v1",
@@ -417,7 +549,7 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
                     }
                 ]
             },
-            "spec": lease_spec_definition(node_name)
+            "spec": lease_spec_definition(node_name, duration_seconds)
         }
     )
 }
@@ -425,7 +557,7 @@ fn lease_definition(node_uid: &str, node_name: &str) -> serde_json::Value {
 /// Defines a new coordiation lease for Kubernetes
 ///
 /// We set the lease times, the lease duration, and the node name.
-fn lease_spec_definition(node_name: &str) -> serde_json::Value {
+fn lease_spec_definition(node_name: &str, duration_seconds: u64) -> serde_json::Value {
     // Workaround for https://github.com/deislabs/krustlet/issues/5
     // In the future, use LeaseSpec rather than a JSON value
     let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
@@ -435,7 +567,7 @@ fn lease_spec_definition(node_name: &str) -> serde_json::Value {
             "holderIdentity": node_name,
             "acquireTime": now,
             "renewTime": now,
-            "leaseDurationSeconds": 300
+            "leaseDurationSeconds": duration_seconds
         }
     )
 }
@@ -770,6 +902,39 @@ mod test {
             device_plugins_dir: PathBuf::new(),
             node_labels,
             max_pods: 110,
+            container_log_max_size: 0,
+            container_log_max_files: 0,
+            container_log_buffer_size: 0,
+            module_cache_dir: PathBuf::new(),
+            module_cache_max_size: None,
+            module_verification_key: None,
+            require_signed_modules: false,
+            insecure_skip_module_tls_verify: false,
+            metrics_port: None,
+            otlp_endpoint: None,
+            max_concurrent_compilations: 1,
+            shutdown_grace_period: std::time::Duration::from_secs(0),
+            system_reserved_memory: 0,
+            json_container_logs: false,
+            cluster_dns: Vec::new(),
+            cluster_domain: "cluster.local".to_owned(),
+            module_pool_size: None,
+            module_pool_memory_pages: 16384,
+            module_pool_table_elements: 10000,
+            max_module_size: u64::MAX,
+            node_lease_renew_interval: std::time::Duration::from_secs(10),
+            node_status_update_interval: std::time::Duration::from_secs(10),
+            max_preopened_dirs: usize::MAX,
+            module_opt_level: crate::config::ModuleOptLevel::default(),
+            parallel_compilation: true,
+            default_allowed_domains: Vec::new(),
+            namespace_network_defaults: false,
+            allowed_log_dirs: Vec::new(),
+            eviction_memory_available: None,
+            eviction_disk_available: None,
+            disabled_wasm_features: Vec::new(),
+            wasi_nn_backends: Vec::new(),
+            container_start_timeout: std::time::Duration::from_secs(0),
         };
 
         let mut builder = Node::builder();
@@ -784,4 +949,24 @@ mod test {
         assert!(!result.get("beta.kubernetes.io/os").unwrap().eq("managed"));
         assert!(result.get("beta.kubernetes.io/os").unwrap().eq("linux"));
     }
+
+    #[test]
+    fn test_lease_duration() {
+        use std::time::Duration;
+        assert_eq!(
+            LEASE_DURATION_SECONDS,
+            lease_duration(Duration::from_secs(10))
+        );
+        assert_eq!(
+            LEASE_DURATION_SECONDS,
+            lease_duration(Duration::from_secs(
+                LEASE_DURATION_SECONDS / LEASE_RENEWALS_PER_DURATION
+            ))
+        );
+        assert_eq!(
+            120 * LEASE_RENEWALS_PER_DURATION,
+            lease_duration(Duration::from_secs(120))
+        );
+        assert_eq!(i32::MAX as u64, lease_duration(Duration::MAX));
+    }
 }
@@ -0,0 +1,402 @@
+//! Watching the node for memory and disk pressure, and evicting pods to relieve it.
+//!
+//! Pressure is reported through the node's `MemoryPressure` and `DiskPressure` conditions, which
+//! the scheduler uses to keep new pods off the node. While the node is under pressure, pods are
+//! evicted one at a time until the pressure is relieved. Once a pod has been evicted, no other pod
+//! is until it is gone and the resource has been measured again, so a pod that takes a while to
+//! stop doesn't have more pods evicted in its place. Pods are evicted starting from those with the
+//! lowest quality of service class, then the lowest priority, then the ones using the most of the
+//! resource. Evicted pods are deleted with their grace period, so they are terminated gracefully
+//! and their controllers can replace them on another node.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, DeleteParams, ListParams, PatchParams};
+use kube::error::ErrorResponse;
+use tracing::{debug, info, instrument, warn};
+
+use crate::config::Config;
+use crate::pod::{record_event, Pod, QosClass, WARNING};
+use crate::provider::Provider;
+use crate::stats::{disk_available, memory_available};
+
+/// How often the node is checked for pressure
+const PRESSURE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The reason given to pods that are evicted
+const EVICTED_REASON: &str = "Evicted";
+
+/// The resources the node can run short of
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resource {
+    Memory,
+    Disk,
+}
+
+impl Resource {
+    /// The node condition that reports pressure on the resource
+    fn condition(self) -> &'static str {
+        match self {
+            Resource::Memory => "MemoryPressure",
+            Resource::Disk => "DiskPressure",
+        }
+    }
+
+    /// The reason and message of the node condition, by whether the node is under pressure
+    fn condition_reason(self, pressure: bool) -> (&'static str, &'static str) {
+        match (self, pressure) {
+            (Resource::Memory, false) => (
+                "KubeletHasSufficientMemory",
+                "kubelet has sufficient memory available",
+            ),
+            (Resource::Memory, true) => (
+                "KubeletHasInsufficientMemory",
+                "kubelet has insufficient memory available",
+            ),
+            (Resource::Disk, false) => ("KubeletHasNoDiskPressure", "kubelet has no disk pressure"),
+            (Resource::Disk, true) => ("KubeletHasDiskPressure", "kubelet has disk pressure"),
+        }
+    }
+
+    /// How much of the resource is available, in bytes, if it can be read
+    fn available(self, config: &Config) -> Option<u64> {
+        match self {
+            Resource::Memory => memory_available(),
+            Resource::Disk => disk_available(&config.data_dir),
+        }
+    }
+
+    /// The amount of the resource below which pods are evicted, if any
+    fn threshold(self, config: &Config) -> Option<u64> {
+        match self {
+            Resource::Memory => config.eviction_memory_available,
+            Resource::Disk => config.eviction_disk_available,
+        }
+    }
+}
+
+const RESOURCES: &[Resource] = &[Resource::Memory, Resource::Disk];
+
+/// A pod that was evicted to relieve pressure
+#[derive(Debug)]
+struct Eviction {
+    namespace: String,
+    name: String,
+    uid: String,
+}
+
+impl Eviction {
+    fn of(pod: &Pod) -> Self {
+        Eviction {
+            namespace: pod.namespace().to_owned(),
+            name: pod.name().to_owned(),
+            uid: pod.pod_uid().to_owned(),
+        }
+    }
+
+    /// Whether the evicted pod is gone from the API server. A pod with the same name but a
+    /// different uid is a replacement, so the evicted one is gone
+    async fn is_done(&self, client: &kube::Client) -> anyhow::Result<bool> {
+        let pod_client: Api<KubePod> = Api::namespaced(client.clone(), &self.namespace);
+        match pod_client.get(&self.name).await {
+            Ok(pod) => Ok(pod.metadata.uid.as_deref() != Some(self.uid.as_str())),
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => Ok(true),
+            Err(e) => Err(anyhow::anyhow!(
+                "Unable to check on evicted pod {}: {}",
+                self.name,
+                e
+            )),
+        }
+    }
+}
+
+/// Checks the node for pressure every few seconds, forever, reporting it in the node's conditions
+/// and evicting pods while it lasts
+pub async fn monitor_pressure<P: Provider>(
+    client: kube::Client,
+    config: Box<Config>,
+    provider: Arc<P>,
+) -> anyhow::Result<()> {
+    // What was last reported for each resource, so the node is only patched when it changes
+    let mut reported: Vec<Option<bool>> = vec![None; RESOURCES.len()];
+    // The last pod evicted, until it is gone
+    let mut in_flight: Option<Eviction> = None;
+    loop {
+        // Checked before the resources are measured, so the first measurement after the pod is
+        // gone decides whether another has to be evicted
+        if let Some(eviction) = &in_flight {
+            match eviction.is_done(&client).await {
+                Ok(true) => in_flight = None,
+                Ok(false) => debug!(
+                    pod_name = %eviction.name,
+                    pod_namespace = %eviction.namespace,
+                    "Waiting for evicted pod to be gone before evicting another"
+                ),
+                Err(e) => warn!(error = %e, "Unable to tell if evicted pod is gone"),
+            }
+        }
+        let mut under_pressure = Vec::new();
+        for (resource, reported) in RESOURCES.iter().zip(reported.iter_mut()) {
+            let pressure = match (resource.available(&config), resource.threshold(&config)) {
+                (Some(available), Some(threshold)) => available < threshold,
+                _ => false,
+            };
+            if *reported != Some(pressure) {
+                match update_condition(&client, &config.node_name, *resource, pressure).await {
+                    Ok(()) => *reported = Some(pressure),
+                    Err(e) => warn!(error = %e, "Unable to report node pressure"),
+                }
+            }
+            if pressure {
+                under_pressure.push(*resource);
+            }
+        }
+        if let (None, Some(resource)) = (&in_flight, under_pressure.first()) {
+            match evict_one(&client, &config.node_name, *resource, &*provider).await {
+                Ok(evicted) => in_flight = evicted,
+                Err(e) => warn!(error = %e, "Unable to evict pod to relieve node pressure"),
+            }
+        }
+        tokio::time::sleep(PRESSURE_CHECK_INTERVAL).await;
+    }
+}
+
+/// Sets the node's pressure condition for the resource
+async fn update_condition(
+    client: &kube::Client,
+    node_name: &str,
+    resource: Resource,
+    pressure: bool,
+) -> anyhow::Result<()> {
+    let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    let (reason, message) = resource.condition_reason(pressure);
+    let status_patch = serde_json::json!({
+        "status": {
+            "conditions": [
+                {
+                    "lastHeartbeatTime": now,
+                    "lastTransitionTime": now,
+                    "message": message,
+                    "reason": reason,
+                    "status": if pressure { "True" } else { "False" },
+                    "type": resource.condition()
+                }
+            ],
+        }
+    });
+    let node_client: Api<KubeNode> = Api::all(client.clone());
+    node_client
+        .patch_status(
+            node_name,
+            &PatchParams::default(),
+            &kube::api::Patch::Strategic(status_patch),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to patch node conditions: {}", e))?;
+    Ok(())
+}
+
+/// How much of the resource each of the provider's pods is using, keyed by namespace and name.
+/// Providers only report memory usage, so pods are never told apart by their disk usage
+async fn pod_usage<P: Provider>(
+    provider: &P,
+    resource: Resource,
+) -> HashMap<(String, String), u64> {
+    if resource != Resource::Memory {
+        return HashMap::new();
+    }
+    match provider.pod_stats().await {
+        Ok(stats) => stats
+            .into_iter()
+            .filter_map(|stats| {
+                let usage = stats.memory.working_set_bytes?;
+                Some(((stats.pod_ref.namespace, stats.pod_ref.name), usage))
+            })
+            .collect(),
+        Err(e) => {
+            debug!(error = %e, "Unable to get pod usage, evicting pods without regard to it");
+            HashMap::new()
+        }
+    }
+}
+
+/// The key pods are sorted by to pick the one to evict, the smallest first. Critical pods go last,
+/// and the rest are ordered by quality of service class, then priority, with the pods using more
+/// of the resource going first
+fn eviction_order(pod: &Pod, usage: u64) -> (bool, QosClass, i32, Reverse<u64>) {
+    (
+        pod.is_critical(),
+        pod.qos_class(),
+        pod.priority(),
+        Reverse(usage),
+    )
+}
+
+/// Whether a pod's containers have all stopped for good, so evicting it frees nothing
+fn has_finished(pod: &Pod) -> bool {
+    matches!(
+        pod.as_kube_pod()
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref()),
+        Some("Succeeded") | Some("Failed")
+    )
+}
+
+/// Evicts the pod on the node that should go first to relieve pressure on the resource, returning
+/// it if there was one. Pods that are already terminating or have finished, DaemonSet and static
+/// pods are never evicted, as removing them either frees nothing or has them come straight back
+#[instrument(level = "info", skip(client, provider))]
+async fn evict_one<P: Provider>(
+    client: &kube::Client,
+    node_name: &str,
+    resource: Resource,
+    provider: &P,
+) -> anyhow::Result<Option<Eviction>> {
+    let pod_client: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", node_name));
+    let pods = pod_client.list(&params).await?;
+    let mut candidates: Vec<Pod> = pods
+        .items
+        .into_iter()
+        .map(Pod::from)
+        .filter(|pod| {
+            pod.deletion_timestamp().is_none()
+                && !has_finished(pod)
+                && !pod.is_daemonset()
+                && !pod.is_static()
+        })
+        .collect();
+    let usage = pod_usage(provider, resource).await;
+    let usage_of = |pod: &Pod| {
+        usage
+            .get(&(pod.namespace().to_owned(), pod.name().to_owned()))
+            .copied()
+            .unwrap_or(0)
+    };
+    candidates.sort_by_key(|pod| eviction_order(pod, usage_of(pod)));
+    let pod = match candidates.into_iter().next() {
+        Some(pod) => pod,
+        None => {
+            warn!("Node is under pressure but there are no pods to evict");
+            return Ok(None);
+        }
+    };
+
+    let message = format!(
+        "The node was low on resource: {}.",
+        match resource {
+            Resource::Memory => "memory",
+            Resource::Disk => "ephemeral-storage",
+        }
+    );
+    info!(
+        pod_name = pod.name(),
+        pod_namespace = pod.namespace(),
+        qos_class = ?pod.qos_class(),
+        priority = pod.priority(),
+        usage = usage_of(&pod),
+        "Evicting pod to relieve node pressure"
+    );
+    if let Err(e) = record_event(client, &pod, WARNING, EVICTED_REASON, &message).await {
+        warn!(error = %e, "Unable to record eviction event");
+    }
+    let ns_client: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    ns_client
+        .delete(pod.name(), &DeleteParams::default())
+        .await
+        .map_err(|e| anyhow::anyhow!("Unable to evict pod {}: {}", pod.name(), e))?;
+    Ok(Some(Eviction::of(&pod)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(spec: serde_json::Value, status: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": spec,
+            "status": status
+        }))
+        .unwrap()
+    }
+
+    fn order(pod: &Pod, usage: u64) -> (bool, QosClass, i32, Reverse<u64>) {
+        eviction_order(pod, usage)
+    }
+
+    #[test]
+    fn test_eviction_order_by_qos_class() {
+        let best_effort = pod(
+            serde_json::json!({"containers": [{"name": "app"}]}),
+            serde_json::json!({"qosClass": "BestEffort"}),
+        );
+        let burstable = pod(
+            serde_json::json!({"containers": [{"name": "app"}]}),
+            serde_json::json!({"qosClass": "Burstable"}),
+        );
+        let guaranteed = pod(
+            serde_json::json!({"containers": [{"name": "app"}], "priority": -10}),
+            serde_json::json!({"qosClass": "Guaranteed"}),
+        );
+        // The class outweighs both usage and priority
+        assert!(order(&best_effort, 0) < order(&burstable, 1 << 30));
+        assert!(order(&burstable, 0) < order(&guaranteed, 1 << 30));
+    }
+
+    #[test]
+    fn test_eviction_order_by_priority_and_usage() {
+        let low = pod(
+            serde_json::json!({"containers": [{"name": "app"}], "priority": 10}),
+            serde_json::json!({"qosClass": "Burstable"}),
+        );
+        let high = pod(
+            serde_json::json!({"containers": [{"name": "app"}], "priority": 1000}),
+            serde_json::json!({"qosClass": "Burstable"}),
+        );
+        // Priority outweighs usage
+        assert!(order(&low, 0) < order(&high, 1 << 30));
+        // And pods of the same priority using more go first
+        assert!(order(&low, 2048) < order(&low, 1024));
+    }
+
+    #[test]
+    fn test_critical_pods_are_evicted_last() {
+        let critical = pod(
+            serde_json::json!({
+                "containers": [{"name": "app"}],
+                "priorityClassName": "system-node-critical",
+                "priority": 0
+            }),
+            serde_json::json!({"qosClass": "BestEffort"}),
+        );
+        let guaranteed = pod(
+            serde_json::json!({"containers": [{"name": "app"}], "priority": 1000}),
+            serde_json::json!({"qosClass": "Guaranteed"}),
+        );
+        assert!(order(&guaranteed, 0) < order(&critical, 1 << 30));
+    }
+
+    #[test]
+    fn test_finished_pods_are_not_evicted() {
+        let spec = serde_json::json!({"containers": [{"name": "app"}]});
+        assert!(has_finished(&pod(
+            spec.clone(),
+            serde_json::json!({"phase": "Succeeded"})
+        )));
+        assert!(has_finished(&pod(
+            spec.clone(),
+            serde_json::json!({"phase": "Failed"})
+        )));
+        assert!(!has_finished(&pod(
+            spec.clone(),
+            serde_json::json!({"phase": "Running"})
+        )));
+        assert!(!has_finished(&pod(spec, serde_json::json!({}))));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::pod::initialize_pod_container_statuses;
 use crate::pod::Pod;
 use crate::provider::Provider;
@@ -41,10 +42,12 @@ impl<P: Provider> Operator for PodOperator<P> {
         let name = initial_manifest.name().to_string();
         let api: Api<KubePod> = Api::namespaced(self.client.clone(), namespace);
 
+        metrics::ACTIVE_PODS.inc();
         initialize_pod_container_statuses(name, manifest, &api).await
     }
 
     async fn deregistration_hook(&self, _manifest: Manifest<Self::Manifest>) -> anyhow::Result<()> {
+        metrics::ACTIVE_PODS.dec();
         Ok(())
     }
 }
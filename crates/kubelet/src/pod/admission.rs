@@ -0,0 +1,107 @@
+//! Checks pods must pass before they are run on the node.
+//!
+//! Admission hooks are registered with the provider and run in process when a pod is registered,
+//! before any of its modules are pulled. A pod any hook rejects is failed, with the rejection
+//! recorded as an event on the pod, much as the kubelet does for pods a validating webhook or
+//! its own admit handlers turn away.
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::Pod;
+
+/// Why a pod may not run on the node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    /// A brief CamelCase reason for the rejection, such as `DisallowedRegistry`
+    pub reason: String,
+    /// A human readable explanation of the rejection
+    pub message: String,
+}
+
+impl Rejection {
+    /// Creates a rejection with the given reason and message
+    pub fn new(reason: impl Into<String>, message: impl Into<String>) -> Self {
+        Rejection {
+            reason: reason.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// A check that decides whether a pod may run on the node
+#[async_trait]
+pub trait AdmissionHook: Send + Sync {
+    /// Checks the pod, returning why it is rejected if it may not run
+    async fn admit(&self, pod: &Pod) -> Result<(), Rejection>;
+}
+
+/// The admission hooks registered with a provider, which are run in the order they were
+/// registered
+#[derive(Clone, Default)]
+pub struct AdmissionHooks {
+    hooks: Vec<Arc<dyn AdmissionHook>>,
+}
+
+impl AdmissionHooks {
+    /// Registers a hook to run on every pod
+    pub fn register(&mut self, hook: Arc<dyn AdmissionHook>) {
+        self.hooks.push(hook);
+    }
+
+    /// Runs the hooks on the pod, stopping at the first one to reject it
+    pub async fn admit(&self, pod: &Pod) -> Result<(), Rejection> {
+        for hook in &self.hooks {
+            hook.admit(pod).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Rejects pods with modules from anywhere but the allowed registry
+    struct AllowedRegistry(&'static str);
+
+    #[async_trait]
+    impl AdmissionHook for AllowedRegistry {
+        async fn admit(&self, pod: &Pod) -> Result<(), Rejection> {
+            for container in pod.all_containers() {
+                let image = container.image().ok().flatten();
+                if image.map(|i| i.registry() != self.0).unwrap_or(true) {
+                    return Err(Rejection::new(
+                        "DisallowedRegistry",
+                        format!("container {} is not from {}", container.name(), self.0),
+                    ));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn pod(image: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {"containers": [{"name": "app", "image": image}]}
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admission_hooks() {
+        let mut hooks = AdmissionHooks::default();
+        assert_eq!(hooks.admit(&pod("other.io/app:v1")).await, Ok(()));
+
+        hooks.register(Arc::new(AllowedRegistry("allowed.io")));
+        assert_eq!(hooks.admit(&pod("allowed.io/app:v1")).await, Ok(()));
+        assert_eq!(
+            hooks.admit(&pod("other.io/app:v1")).await,
+            Err(Rejection::new(
+                "DisallowedRegistry",
+                "container app is not from allowed.io"
+            ))
+        );
+    }
+}
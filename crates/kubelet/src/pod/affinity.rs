@@ -0,0 +1,171 @@
+//! Checks that a pod was placed on a node it can run on.
+//!
+//! The scheduler only places pods on nodes that match their node selector and required node
+//! affinity, but pods can also be bound to a node directly by setting `nodeName`. Like the
+//! kubelet, krustlet checks these again when a pod arrives, so a mis-targeted pod is rejected
+//! straight away rather than failing later in a less obvious way.
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{Node as KubeNode, NodeSelectorRequirement, NodeSelectorTerm};
+
+use super::Pod;
+
+/// The field node selector terms can match against with `matchFields`
+const NODE_NAME_FIELD: &str = "metadata.name";
+
+/// Checks the pod's node selector and required node affinity against the node, returning a
+/// description of what doesn't match if the pod can't run on it
+pub(crate) fn check_node_affinity(pod: &Pod, node: &KubeNode) -> anyhow::Result<()> {
+    let labels = &node.metadata.labels;
+    let node_name = node.metadata.name.as_deref().unwrap_or_default();
+
+    let mismatched: Vec<String> = pod
+        .node_selector()
+        .iter()
+        .filter(|(key, value)| labels.get(*key) != Some(value))
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    if !mismatched.is_empty() {
+        anyhow::bail!(
+            "node {} doesn't have the labels the pod's node selector requires: {}",
+            node_name,
+            mismatched.join(", ")
+        );
+    }
+
+    let required = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.affinity.as_ref())
+        .and_then(|affinity| affinity.node_affinity.as_ref())
+        .and_then(|affinity| {
+            affinity
+                .required_during_scheduling_ignored_during_execution
+                .as_ref()
+        });
+    if let Some(required) = required {
+        let terms = &required.node_selector_terms;
+        // The terms are alternatives, so the node only has to match one of them
+        if !terms.is_empty() && !terms.iter().any(|t| term_matches(t, labels, node_name)) {
+            anyhow::bail!(
+                "node {} doesn't match any of the pod's required node affinity terms",
+                node_name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether the node matches every requirement of a term. A term with no requirements matches no
+/// nodes
+fn term_matches(term: &NodeSelectorTerm, labels: &BTreeMap<String, String>, name: &str) -> bool {
+    if term.match_expressions.is_empty() && term.match_fields.is_empty() {
+        return false;
+    }
+    term.match_expressions
+        .iter()
+        .all(|r| requirement_matches(r, labels.get(&r.key).map(String::as_str)))
+        && term.match_fields.iter().all(|r| {
+            let field = if r.key == NODE_NAME_FIELD {
+                Some(name)
+            } else {
+                None
+            };
+            requirement_matches(r, field)
+        })
+}
+
+/// Whether a label or field, given its value if the node has it, meets a requirement
+fn requirement_matches(requirement: &NodeSelectorRequirement, value: Option<&str>) -> bool {
+    let values = &requirement.values;
+    match requirement.operator.as_str() {
+        "In" => value.map_or(false, |v| values.iter().any(|allowed| allowed == v)),
+        "NotIn" => value.map_or(true, |v| values.iter().all(|denied| denied != v)),
+        "Exists" => value.is_some(),
+        "DoesNotExist" => value.is_none(),
+        "Gt" | "Lt" => {
+            let parse = |v: &str| v.parse::<i64>().ok();
+            match (value.and_then(parse), values.first().and_then(|v| parse(v))) {
+                (Some(value), Some(bound)) if requirement.operator == "Gt" => value > bound,
+                (Some(value), Some(bound)) => value < bound,
+                _ => false,
+            }
+        }
+        // The API server rejects any other operator, so there is nothing a node could match
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(labels: serde_json::Value) -> KubeNode {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "krustlet", "labels": labels}
+        }))
+        .unwrap()
+    }
+
+    fn pod(spec: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": spec
+        }))
+        .unwrap()
+    }
+
+    fn required(terms: serde_json::Value) -> Pod {
+        pod(serde_json::json!({
+            "containers": [],
+            "affinity": {"nodeAffinity": {
+                "requiredDuringSchedulingIgnoredDuringExecution": {"nodeSelectorTerms": terms}
+            }}
+        }))
+    }
+
+    #[test]
+    fn test_node_selector() {
+        let node = node(serde_json::json!({"kubernetes.io/arch": "wasm32-wasi", "zone": "a"}));
+        let matching = pod(serde_json::json!({
+            "containers": [],
+            "nodeSelector": {"kubernetes.io/arch": "wasm32-wasi"}
+        }));
+        assert!(check_node_affinity(&matching, &node).is_ok());
+
+        let mismatched = pod(serde_json::json!({
+            "containers": [],
+            "nodeSelector": {"kubernetes.io/arch": "wasm32-wasi", "zone": "b", "gpu": "true"}
+        }));
+        let err = check_node_affinity(&mismatched, &node).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "node krustlet doesn't have the labels the pod's node selector requires: gpu=true, zone=b"
+        );
+    }
+
+    #[test]
+    fn test_required_node_affinity() {
+        let node = node(serde_json::json!({"zone": "a", "cores": "8"}));
+        let check = |terms| check_node_affinity(&required(terms), &node).is_ok();
+
+        assert!(check(serde_json::json!([
+            {"matchExpressions": [
+                {"key": "zone", "operator": "In", "values": ["a", "b"]},
+                {"key": "cores", "operator": "Gt", "values": ["4"]},
+                {"key": "gpu", "operator": "DoesNotExist"}
+            ]}
+        ])));
+        // Terms are alternatives
+        assert!(check(serde_json::json!([
+            {"matchExpressions": [{"key": "zone", "operator": "NotIn", "values": ["a"]}]},
+            {"matchFields": [{"key": "metadata.name", "operator": "In", "values": ["krustlet"]}]}
+        ])));
+        assert!(!check(serde_json::json!([
+            {"matchExpressions": [{"key": "cores", "operator": "Lt", "values": ["8"]}]},
+            {"matchExpressions": [{"key": "gpu", "operator": "Exists"}]}
+        ])));
+        assert!(!check(serde_json::json!([{}])));
+    }
+}
@@ -0,0 +1,272 @@
+//! Resolution of the DNS settings a pod's names should be resolved with, following its
+//! `dnsPolicy` and `dnsConfig`.
+use std::net::IpAddr;
+
+use super::Pod;
+
+/// The most nameservers a resolver can be configured with, as in Kubernetes
+const MAX_NAMESERVERS: usize = 3;
+/// The most search domains a resolver can be configured with, as in Kubernetes
+const MAX_SEARCHES: usize = 32;
+/// The `ndots` pods with the `ClusterFirst` policy use, so service names are searched first
+const CLUSTER_NDOTS: u32 = 5;
+/// The `ndots` resolvers use if nothing else sets it
+const DEFAULT_NDOTS: u32 = 1;
+/// The resolver configuration of the node, which the `Default` policy uses
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The cluster's DNS settings, which the `ClusterFirst` policies use
+#[derive(Clone, Debug, Default)]
+pub struct ClusterDns {
+    /// The addresses of the cluster's DNS servers
+    pub servers: Vec<IpAddr>,
+    /// The DNS domain of the cluster
+    pub domain: String,
+}
+
+/// The nameservers and search domains names should be resolved with
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolverConfig {
+    /// The servers to query, in order
+    pub nameservers: Vec<IpAddr>,
+    /// The domains to search for names with fewer than `ndots` dots
+    pub searches: Vec<String>,
+    /// The number of dots a name must have to be tried as an absolute name before it is searched
+    pub ndots: u32,
+}
+
+impl ResolverConfig {
+    /// Reads the resolver configuration of the node
+    pub fn from_host() -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(HOST_RESOLV_CONF)
+            .map_err(|e| anyhow::anyhow!("unable to read {}: {}", HOST_RESOLV_CONF, e))?;
+        Ok(parse_resolv_conf(&contents))
+    }
+
+    /// The resolver configuration for names resolved on behalf of the pod, or `None` if the pod
+    /// uses the node's resolver unchanged.
+    ///
+    /// The `ClusterFirst` policy uses the cluster's DNS servers and searches the pod's namespace
+    /// and the cluster domain, unless the pod uses the host network or no cluster DNS servers are
+    /// configured, in which case it behaves like `Default`. `ClusterFirstWithHostNet` does so even
+    /// for pods on the host network. `Default` uses the node's resolver, and `None` uses only what
+    /// the pod's `dnsConfig` gives. Any `dnsConfig` is merged into the result of the policy
+    pub fn for_pod(
+        pod: &Pod,
+        cluster: &ClusterDns,
+        host: impl FnOnce() -> anyhow::Result<ResolverConfig>,
+    ) -> anyhow::Result<Option<Self>> {
+        let spec = match pod.kube_pod.spec.as_ref() {
+            Some(spec) => spec,
+            None => return Ok(None),
+        };
+        let host_network = spec.host_network.unwrap_or(false);
+        let cluster_first = match spec.dns_policy.as_deref() {
+            None | Some("ClusterFirst") => !host_network && !cluster.servers.is_empty(),
+            Some("ClusterFirstWithHostNet") => !cluster.servers.is_empty(),
+            Some("Default") | Some("None") => false,
+            Some(policy) => anyhow::bail!("unsupported DNS policy {}", policy),
+        };
+        let dns_config = spec.dns_config.as_ref();
+
+        let mut config = if spec.dns_policy.as_deref() == Some("None") {
+            if dns_config.map(|c| c.nameservers.is_empty()).unwrap_or(true) {
+                anyhow::bail!("DNS policy None requires a dnsConfig with at least one nameserver");
+            }
+            ResolverConfig {
+                nameservers: Vec::new(),
+                searches: Vec::new(),
+                ndots: DEFAULT_NDOTS,
+            }
+        } else if cluster_first {
+            let host = host()?;
+            let mut searches = vec![
+                format!("{}.svc.{}", pod.namespace(), cluster.domain),
+                format!("svc.{}", cluster.domain),
+                cluster.domain.clone(),
+            ];
+            searches.extend(host.searches);
+            ResolverConfig {
+                nameservers: cluster.servers.clone(),
+                searches,
+                ndots: CLUSTER_NDOTS,
+            }
+        } else if dns_config.is_none() {
+            return Ok(None);
+        } else {
+            host()?
+        };
+
+        if let Some(dns_config) = dns_config {
+            for nameserver in &dns_config.nameservers {
+                let addr: IpAddr = nameserver
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid DNS nameserver {}", nameserver))?;
+                config.nameservers.push(addr);
+            }
+            config.searches.extend(dns_config.searches.iter().cloned());
+            for option in &dns_config.options {
+                if option.name.as_deref() == Some("ndots") {
+                    let value = option.value.as_deref().unwrap_or_default();
+                    config.ndots = value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid DNS ndots option {:?}", value))?;
+                }
+            }
+        }
+        config.nameservers = dedup(config.nameservers);
+        config.nameservers.truncate(MAX_NAMESERVERS);
+        config.searches = dedup(config.searches);
+        config.searches.truncate(MAX_SEARCHES);
+        Ok(Some(config))
+    }
+}
+
+/// Removes repeated items, keeping the first of each
+fn dedup<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+    let mut unique = Vec::with_capacity(items.len());
+    for item in items {
+        if !unique.contains(&item) {
+            unique.push(item);
+        }
+    }
+    unique
+}
+
+/// Parses the nameservers, search domains and `ndots` option out of a `resolv.conf` file.
+/// Anything it doesn't understand is ignored, as the system resolver does
+fn parse_resolv_conf(contents: &str) -> ResolverConfig {
+    let mut config = ResolverConfig {
+        nameservers: Vec::new(),
+        searches: Vec::new(),
+        ndots: DEFAULT_NDOTS,
+    };
+    for line in contents.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(Ok(addr)) = fields.next().map(|f| f.parse()) {
+                    config.nameservers.push(addr);
+                }
+            }
+            // The last search or domain line wins
+            Some("search") | Some("domain") => {
+                config.searches = fields.map(|f| f.to_owned()).collect();
+            }
+            Some("options") => {
+                for option in fields {
+                    if let Some(Ok(ndots)) = option.strip_prefix("ndots:").map(|n| n.parse()) {
+                        config.ndots = ndots;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(spec: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test", "namespace": "apps"},
+            "spec": spec,
+        }))
+        .unwrap()
+    }
+
+    fn cluster() -> ClusterDns {
+        ClusterDns {
+            servers: vec![IpAddr::from([10, 96, 0, 10])],
+            domain: "cluster.local".to_owned(),
+        }
+    }
+
+    fn host() -> anyhow::Result<ResolverConfig> {
+        Ok(parse_resolv_conf(
+            "nameserver 192.168.0.1\nsearch corp.example\n",
+        ))
+    }
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let config = parse_resolv_conf(
+            "# comment\nnameserver 1.1.1.1\nnameserver bogus\nsearch a.example b.example\noptions ndots:2 timeout:1\n",
+        );
+        assert_eq!(config.nameservers, vec![IpAddr::from([1, 1, 1, 1])]);
+        assert_eq!(config.searches, vec!["a.example", "b.example"]);
+        assert_eq!(config.ndots, 2);
+    }
+
+    #[test]
+    fn test_cluster_first_searches_namespace() {
+        let config = ResolverConfig::for_pod(
+            &pod(serde_json::json!({"containers": []})),
+            &cluster(),
+            host,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.nameservers, vec![IpAddr::from([10, 96, 0, 10])]);
+        assert_eq!(
+            config.searches,
+            vec![
+                "apps.svc.cluster.local",
+                "svc.cluster.local",
+                "cluster.local",
+                "corp.example"
+            ]
+        );
+        assert_eq!(config.ndots, 5);
+    }
+
+    #[test]
+    fn test_cluster_first_falls_back_to_host() {
+        let spec = serde_json::json!({"containers": []});
+        let config = ResolverConfig::for_pod(&pod(spec), &ClusterDns::default(), host).unwrap();
+        assert_eq!(config, None);
+
+        let host_network = serde_json::json!({"containers": [], "hostNetwork": true});
+        let config = ResolverConfig::for_pod(&pod(host_network), &cluster(), host).unwrap();
+        assert_eq!(config, None);
+    }
+
+    #[test]
+    fn test_none_policy_uses_dns_config() {
+        let spec = serde_json::json!({
+            "containers": [],
+            "dnsPolicy": "None",
+            "dnsConfig": {
+                "nameservers": ["8.8.8.8", "8.8.8.8"],
+                "searches": ["svc.example"],
+                "options": [{"name": "ndots", "value": "3"}]
+            }
+        });
+        let config = ResolverConfig::for_pod(&pod(spec), &cluster(), host)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.nameservers, vec![IpAddr::from([8, 8, 8, 8])]);
+        assert_eq!(config.searches, vec!["svc.example"]);
+        assert_eq!(config.ndots, 3);
+
+        let missing = serde_json::json!({"containers": [], "dnsPolicy": "None"});
+        assert!(ResolverConfig::for_pod(&pod(missing), &cluster(), host).is_err());
+    }
+
+    #[test]
+    fn test_default_policy_merges_dns_config() {
+        let spec = serde_json::json!({
+            "containers": [],
+            "dnsPolicy": "Default",
+            "dnsConfig": {"searches": ["extra.example"]}
+        });
+        let config = ResolverConfig::for_pod(&pod(spec), &cluster(), host)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.nameservers, vec![IpAddr::from([192, 168, 0, 1])]);
+        assert_eq!(config.searches, vec!["corp.example", "extra.example"]);
+    }
+}
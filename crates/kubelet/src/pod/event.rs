@@ -0,0 +1,53 @@
+//! Recording events about a pod, so they show up in `kubectl describe`.
+use chrono::Utc;
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PostParams};
+
+use super::Pod;
+
+/// The component events are reported as coming from, as the kubelet reports them
+const EVENT_SOURCE_COMPONENT: &str = "kubelet";
+
+/// Events about something the user should look into
+pub(crate) const WARNING: &str = "Warning";
+
+/// Records an event about the pod
+pub(crate) async fn record_event(
+    client: &kube::Client,
+    pod: &Pod,
+    type_: &str,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let now = Time(Utc::now());
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}.", pod.name())),
+            namespace: Some(pod.namespace().to_owned()),
+            ..Default::default()
+        },
+        involved_object: ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            name: Some(pod.name().to_owned()),
+            namespace: Some(pod.namespace().to_owned()),
+            uid: Some(pod.pod_uid().to_owned()),
+            ..Default::default()
+        },
+        type_: Some(type_.to_owned()),
+        reason: Some(reason.to_owned()),
+        message: Some(message.to_owned()),
+        source: Some(EventSource {
+            component: Some(EVENT_SOURCE_COMPONENT.to_owned()),
+            host: pod.node_name().map(str::to_owned),
+        }),
+        first_timestamp: Some(now.clone()),
+        last_timestamp: Some(now),
+        count: Some(1),
+        ..Default::default()
+    };
+    let events: Api<Event> = Api::namespaced(client.clone(), pod.namespace());
+    events.create(&PostParams::default(), &event).await?;
+    Ok(())
+}
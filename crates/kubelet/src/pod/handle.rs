@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use tokio::io::{AsyncRead, AsyncSeek};
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
@@ -5,10 +7,12 @@ use tracing::{debug, error, info};
 use crate::container::{
     ContainerKey, ContainerMapByName, Handle as ContainerHandle, HandleMap as ContainerHandleMap,
 };
-use crate::handle::StopHandler;
+use crate::exec::Output;
+use crate::handle::{ExecHandler, StopHandler, UsageHandler};
 use crate::log::{HandleFactory, Sender};
 use crate::pod::Pod;
 use crate::provider::ProviderError;
+use crate::stats::{ContainerStats, PodStats};
 
 /// Handle is the top level handle into managing a pod. It manages updating
 /// statuses for the containers in the pod and can be used to stop the pod and
@@ -60,6 +64,39 @@ impl<H: StopHandler, F> Handle<H, F> {
         handle.output(sender).await
     }
 
+    /// Runs a command in the specified container, returning its captured output
+    pub async fn exec(&self, container_name: &str, command: Vec<String>) -> anyhow::Result<Output>
+    where
+        H: ExecHandler,
+    {
+        // The command can run for a while, so the handles aren't kept locked while it does
+        let exec = {
+            let handles = self.container_handles.read().await;
+            let handle = handles.get_by_name(container_name).ok_or_else(|| {
+                ProviderError::ContainerNotFound {
+                    pod_name: self.pod.name().to_owned(),
+                    container_name: container_name.to_owned(),
+                }
+            })?;
+            handle.exec(command)
+        };
+        exec.await
+    }
+
+    /// The resource usage of the pod and each of its containers that has been started
+    pub async fn stats(&self) -> PodStats
+    where
+        H: UsageHandler,
+    {
+        let time = chrono::Utc::now();
+        let handles = self.container_handles.read().await;
+        let containers = handles
+            .iter()
+            .map(|(key, handle)| ContainerStats::new(key.name(), handle.usage(), time))
+            .collect();
+        PodStats::new(&self.pod, containers, time)
+    }
+
     /// Signal the pod and all its running containers to stop and wait for them
     /// to complete.
     pub async fn stop(&self) -> anyhow::Result<()> {
@@ -81,6 +118,43 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 
+    /// Gracefully stop all the containers in the pod at once, giving each of them up to the grace
+    /// period before they are forcibly stopped
+    pub async fn stop_gracefully(&self, grace_period: Duration) -> anyhow::Result<()> {
+        // The containers are taken out of the map while they stop, so that they don't hold it up
+        // for the whole grace period, and put back once they have stopped
+        let mut stopping: Vec<_> = self.container_handles.write().await.drain().collect();
+        let stops = stopping.iter_mut().map(|(key, handle)| async move {
+            info!(container_name = %key, ?grace_period, "Gracefully stopping container");
+            match handle.stop_gracefully(grace_period).await {
+                Ok(_) => debug!(container_name = %key, "Successfully stopped container"),
+                Err(e) => {
+                    error!(container_name = %key, error = %e, "Error while trying to stop pod")
+                }
+            }
+        });
+        futures::future::join_all(stops).await;
+        let mut handles = self.container_handles.write().await;
+        for (key, handle) in stopping {
+            handles.entry(key).or_insert(handle);
+        }
+        Ok(())
+    }
+
+    /// Signal a single container in the pod to stop. This does not wait for the container to
+    /// exit
+    pub async fn stop_container(&self, key: &ContainerKey) -> anyhow::Result<()> {
+        let mut handles = self.container_handles.write().await;
+        let handle = handles
+            .get_mut(key)
+            .ok_or_else(|| ProviderError::ContainerNotFound {
+                pod_name: self.pod.name().to_owned(),
+                container_name: key.name(),
+            })?;
+        info!(container_name = %key, "Stopping container");
+        handle.stop().await
+    }
+
     /// Wait for all containers in the pod to complete
     pub async fn wait(&mut self) -> anyhow::Result<()> {
         let mut handles = self.container_handles.write().await;
@@ -90,3 +164,89 @@ impl<H: StopHandler, F> Handle<H, F> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future::BoxFuture;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Notify};
+
+    /// A handle whose commands say when they have started, then run until they are told to finish
+    struct BlockingHandle {
+        started: mpsc::UnboundedSender<()>,
+        finish: Arc<Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl StopHandler for BlockingHandle {
+        async fn stop(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn wait(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ExecHandler for BlockingHandle {
+        fn exec(&self, command: Vec<String>) -> BoxFuture<'static, anyhow::Result<Output>> {
+            let (started, finish) = (self.started.clone(), self.finish.clone());
+            Box::pin(async move {
+                started.send(()).unwrap();
+                finish.notified().await;
+                Ok(Output {
+                    stdout: command.join(" ").into_bytes(),
+                    stderr: Vec::new(),
+                    exit_code: 0,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exec_does_not_hold_handles() {
+        let pod: Pod =
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {"name": "app", "namespace": "default"},
+                "spec": {"containers": [{"name": "module"}, {"name": "sidecar"}]}
+            }))
+            .unwrap()
+            .into();
+        let (started, mut command_started) = mpsc::unbounded_channel();
+        let finish = Arc::new(Notify::new());
+        let blocking_handle = || {
+            let handle = BlockingHandle {
+                started: started.clone(),
+                finish: finish.clone(),
+            };
+            ContainerHandle::new(handle, ())
+        };
+        let handle = Arc::new(Handle::new(HashMap::new(), pod));
+        handle
+            .insert_container_handle(ContainerKey::App("module".to_owned()), blocking_handle())
+            .await;
+
+        let exec = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.exec("module", vec!["echo".to_owned()]).await }
+        });
+        command_started.recv().await.unwrap();
+        // Other containers' handles can be added while the command runs
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            handle.insert_container_handle(
+                ContainerKey::App("sidecar".to_owned()),
+                blocking_handle(),
+            ),
+        )
+        .await
+        .unwrap();
+        finish.notify_one();
+        let output = exec.await.unwrap().unwrap();
+        assert_eq!(b"echo".to_vec(), output.stdout);
+
+        assert!(handle.exec("missing", Vec::new()).await.is_err());
+    }
+}
@@ -1,32 +1,102 @@
 //! `pod` is a collection of utilities surrounding the Kubernetes pod API.
+mod admission;
+mod affinity;
+pub mod dns;
+mod event;
 mod handle;
+mod qos;
 pub mod state;
 mod status;
 
+pub use admission::{AdmissionHook, AdmissionHooks, Rejection};
+pub(crate) use affinity::check_node_affinity;
+pub(crate) use event::{record_event, WARNING};
 pub use handle::Handle;
+pub use qos::QosClass;
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
+    make_condition, make_ready_conditions, make_registered_status, make_status,
+    make_status_with_containers, patch_status, Phase, Status, StatusBuilder, CONTAINERS_READY,
+    INITIALIZED, POD_SCHEDULED, READY,
 };
 
 use crate::container::{Container, ContainerKey};
 use chrono::{DateTime, Utc};
 use k8s_openapi::api::core::v1::{
-    Container as KubeContainer, Pod as KubePod, Volume as KubeVolume,
+    Container as KubeContainer, EphemeralContainer, Pod as KubePod, Volume as KubeVolume,
 };
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::{Resource, ResourceExt};
-use serde::Deserialize;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The grace period Kubernetes gives pods that don't specify one
+const DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS: i64 = 30;
+
+/// The container restart policy that makes an init container a sidecar
+const SIDECAR_RESTART_POLICY: &str = "Always";
+
+/// The container an ephemeral container runs as. Ephemeral containers have all the fields of a
+/// container, along with the container they target. Modules have no process namespace to share,
+/// so the target makes no difference to how they run
+fn ephemeral_container(c: &EphemeralContainer) -> KubeContainer {
+    KubeContainer {
+        args: c.args.clone(),
+        command: c.command.clone(),
+        env: c.env.clone(),
+        env_from: c.env_from.clone(),
+        image: c.image.clone(),
+        image_pull_policy: c.image_pull_policy.clone(),
+        lifecycle: c.lifecycle.clone(),
+        liveness_probe: c.liveness_probe.clone(),
+        name: c.name.clone(),
+        ports: c.ports.clone(),
+        readiness_probe: c.readiness_probe.clone(),
+        resources: c.resources.clone(),
+        security_context: c.security_context.clone(),
+        startup_probe: c.startup_probe.clone(),
+        stdin: c.stdin,
+        stdin_once: c.stdin_once,
+        termination_message_path: c.termination_message_path.clone(),
+        termination_message_policy: c.termination_message_policy.clone(),
+        tty: c.tty,
+        volume_devices: c.volume_devices.clone(),
+        volume_mounts: c.volume_mounts.clone(),
+        working_dir: c.working_dir.clone(),
+    }
+}
 
 /// A Kubernetes Pod
 ///
 /// This is a new type around the k8s_openapi Pod definition
 /// providing convenient accessor methods
-#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+#[derive(Default, Debug, Clone, Serialize)]
 pub struct Pod {
     #[serde(flatten)]
     kube_pod: KubePod,
+    /// The names of the init containers that are sidecars. Container `restartPolicy` is newer
+    /// than the Kubernetes API the pod is parsed with, so it is picked out when the pod is
+    /// deserialized
+    #[serde(skip)]
+    sidecars: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for Pod {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let sidecars = value
+            .pointer("/spec/initContainers")
+            .and_then(|containers| containers.as_array())
+            .map(|containers| {
+                containers
+                    .iter()
+                    .filter(|c| c["restartPolicy"] == SIDECAR_RESTART_POLICY)
+                    .filter_map(|c| c["name"].as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let kube_pod = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+        Ok(Pod { kube_pod, sidecars })
+    }
 }
 
 impl Pod {
@@ -66,6 +136,57 @@ impl Pod {
         spec.service_account_name.as_deref()
     }
 
+    /// Get the name of the node the pod is scheduled on
+    pub fn node_name(&self) -> Option<&str> {
+        let spec = self.kube_pod.spec.as_ref()?;
+        spec.node_name.as_deref()
+    }
+
+    /// Get the pod's restart policy
+    ///
+    /// Returns [`RestartPolicy::Always`] if no policy was explicitly set
+    pub fn restart_policy(&self) -> RestartPolicy {
+        match self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|s| s.restart_policy.as_deref())
+        {
+            Some("OnFailure") => RestartPolicy::OnFailure,
+            Some("Never") => RestartPolicy::Never,
+            _ => RestartPolicy::Always,
+        }
+    }
+
+    /// Get how long the pod's containers are given to shut down gracefully when it is deleted
+    ///
+    /// Returns the Kubernetes default of 30 seconds if no grace period was explicitly set
+    pub fn termination_grace_period(&self) -> std::time::Duration {
+        let seconds = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|s| s.termination_grace_period_seconds)
+            .unwrap_or(DEFAULT_TERMINATION_GRACE_PERIOD_SECONDS);
+        std::time::Duration::from_secs(seconds.max(0) as u64)
+    }
+
+    /// Get when the pod has to be done running by, if it sets `activeDeadlineSeconds`
+    ///
+    /// The deadline counts from the start time in the pod's status, or from `started` if the
+    /// status doesn't have one
+    pub fn active_deadline(&self, started: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let seconds = self.kube_pod.spec.as_ref()?.active_deadline_seconds?;
+        let start = self
+            .kube_pod
+            .status
+            .as_ref()
+            .and_then(|s| s.start_time.as_ref())
+            .map(|t| t.0)
+            .unwrap_or(started);
+        Some(start + chrono::Duration::seconds(seconds))
+    }
+
     /// Get the pod volumes
     pub fn volumes(&self) -> &Vec<KubeVolume> {
         self.kube_pod
@@ -134,6 +255,81 @@ impl Pod {
             .any(|owner| owner.kind == "DaemonSet")
     }
 
+    /// Indicate if this pod is critical to the node or cluster, as given by its priority class.
+    /// Critical pods are evicted last when the node is drained
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self.kube_pod
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.priority_class_name.as_deref()),
+            Some("system-node-critical") | Some("system-cluster-critical")
+        )
+    }
+
+    /// Get the priority of the pod, which the API server resolves from its priority class. Pods
+    /// without one have the default priority of 0
+    pub fn priority(&self) -> i32 {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.priority)
+            .unwrap_or(0)
+    }
+
+    /// Get the quality of service class of the pod
+    pub fn qos_class(&self) -> QosClass {
+        QosClass::of(self)
+    }
+
+    /// Indicate if the kubelet should mount the pod's service account token into its containers.
+    /// The API server adds a volume for the token itself unless it isn't set up to, so the token
+    /// is only mounted if no container already mounts something at the service account path
+    pub fn automounts_service_account_token(&self) -> bool {
+        let automount = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.automount_service_account_token)
+            .unwrap_or(true);
+        automount
+            && self.service_account_name().is_some()
+            && !self.all_containers().iter().any(|c| {
+                c.volume_mounts().iter().any(|vm| {
+                    vm.mount_path.trim_end_matches('/') == crate::volume::SERVICE_ACCOUNT_MOUNT_PATH
+                })
+            })
+    }
+
+    /// Get the group that should own the pod's volumes (`securityContext.fsGroup`), if the pod
+    /// has one
+    pub fn fs_group(&self) -> Option<i64> {
+        self.kube_pod
+            .spec
+            .as_ref()?
+            .security_context
+            .as_ref()?
+            .fs_group
+    }
+
+    /// Get the user and group the given container runs as. Each is taken from the container's
+    /// security context if it sets it, and from the pod's security context otherwise
+    pub fn run_as(&self, container: &Container) -> (Option<i64>, Option<i64>) {
+        let pod_context = self
+            .kube_pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.security_context.as_ref());
+        let container_context = container.security_context();
+        let user = container_context
+            .and_then(|c| c.run_as_user)
+            .or_else(|| pod_context.and_then(|p| p.run_as_user));
+        let group = container_context
+            .and_then(|c| c.run_as_group)
+            .or_else(|| pod_context.and_then(|p| p.run_as_group));
+        (user, group)
+    }
+
     ///  Get a specific annotation from the pod
     pub fn get_annotation(&self, key: &str) -> Option<&str> {
         Some(self.annotations().get(key)?.as_str())
@@ -150,10 +346,10 @@ impl Pod {
 
     /// Find container by `ContainerKey` and return it.
     pub fn find_container(&self, key: &ContainerKey) -> Option<Container> {
-        let containers: Vec<Container> = if key.is_init() {
-            self.init_containers()
-        } else {
-            self.containers()
+        let containers: Vec<Container> = match key {
+            ContainerKey::Init(_) => self.init_containers(),
+            ContainerKey::App(_) => self.containers(),
+            ContainerKey::Ephemeral(_) => self.ephemeral_containers(),
         };
         containers
             .into_iter()
@@ -164,10 +360,10 @@ impl Pod {
     pub fn container_status_index(&self, key: &ContainerKey) -> Option<usize> {
         match self.kube_pod.status.as_ref() {
             Some(status) => {
-                let statuses = if key.is_init() {
-                    &status.init_container_statuses
-                } else {
-                    &status.container_statuses
+                let statuses = match key {
+                    ContainerKey::Init(_) => &status.init_container_statuses,
+                    ContainerKey::App(_) => &status.container_statuses,
+                    ContainerKey::Ephemeral(_) => &status.ephemeral_container_statuses,
                 };
                 statuses
                     .iter()
@@ -179,6 +375,23 @@ impl Pod {
         }
     }
 
+    /// The number of times the container has been restarted, as last reported in the pod's
+    /// status. This outlives the kubelet, so counting can carry on from it after a restart
+    pub fn container_restart_count(&self, key: &ContainerKey) -> u32 {
+        self.kube_pod
+            .status
+            .as_ref()
+            .and_then(|status| {
+                let statuses = match key {
+                    ContainerKey::Init(_) => &status.init_container_statuses,
+                    ContainerKey::App(_) => &status.container_statuses,
+                    ContainerKey::Ephemeral(_) => &status.ephemeral_container_statuses,
+                };
+                statuses.iter().find(|status| status.name == key.name())
+            })
+            .map_or(0, |status| status.restart_count.max(0) as u32)
+    }
+
     /// Get a pod's containers
     pub fn containers(&self) -> Vec<Container> {
         self.kube_pod
@@ -203,6 +416,40 @@ impl Pod {
             .collect()
     }
 
+    /// Get the ephemeral containers that have been added to a pod to debug it
+    pub fn ephemeral_containers(&self) -> Vec<Container> {
+        self.kube_pod
+            .spec
+            .as_ref()
+            .map(|s| s.ephemeral_containers.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .map(|c| Container::new(&ephemeral_container(c)))
+            .collect()
+    }
+
+    /// Whether the status of any ephemeral container has been reported yet
+    pub(crate) fn has_ephemeral_container_statuses(&self) -> bool {
+        self.kube_pod
+            .status
+            .as_ref()
+            .map_or(false, |s| !s.ephemeral_container_statuses.is_empty())
+    }
+
+    /// Get a pod's sidecar containers, in the order they are started
+    pub fn sidecar_containers(&self) -> Vec<Container> {
+        self.init_containers()
+            .into_iter()
+            .filter(|c| self.sidecars.iter().any(|name| name == c.name()))
+            .collect()
+    }
+
+    /// Whether the container is a sidecar: an init container with a `restartPolicy` of `Always`,
+    /// which is started before the app containers and keeps running alongside them
+    pub fn is_sidecar(&self, key: &ContainerKey) -> bool {
+        key.is_init() && self.sidecars.contains(&key.name())
+    }
+
     /// Gets all of a pod's containers (init and application)
     pub fn all_containers(&self) -> Vec<Container> {
         let mut app_containers = self.containers();
@@ -245,7 +492,10 @@ impl k8s_openapi::Resource for Pod {
 
 impl std::convert::From<KubePod> for Pod {
     fn from(api_pod: KubePod) -> Self {
-        Self { kube_pod: api_pod }
+        Self {
+            kube_pod: api_pod,
+            sidecars: Vec::new(),
+        }
     }
 }
 
@@ -260,6 +510,31 @@ impl std::convert::From<Pod> for KubePod {
     }
 }
 
+/// The policy for restarting the containers of a pod once they exit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Containers are always restarted, no matter how they exited
+    Always,
+    /// Containers are only restarted if they exited with an error
+    OnFailure,
+    /// Containers are never restarted
+    Never,
+}
+
+impl RestartPolicy {
+    /// Returns whether a container that exited should be restarted. Init containers are treated
+    /// as `OnFailure` when the policy is `Always`, as they are expected to run to completion
+    pub fn should_restart(&self, key: &ContainerKey, failed: bool) -> bool {
+        match self {
+            // Ephemeral containers are only ever run once
+            _ if key.is_ephemeral() => false,
+            RestartPolicy::Always => failed || key.is_app(),
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        }
+    }
+}
+
 /// PodKey is a unique human readable key for storing a handle to a pod in a hash.
 #[derive(Hash, Ord, Eq, PartialOrd, PartialEq, Debug, Clone, Default)]
 pub struct PodKey {
@@ -329,3 +604,235 @@ lazy_static::lazy_static! {
     static ref EMPTY_VEC: Vec<KubeContainer> = Vec::new();
     static ref EMPTY_VOLUMES: Vec<KubeVolume> = Vec::new();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_container_restart_count() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "initContainers": [{"name": "setup"}],
+                "containers": [{"name": "app"}, {"name": "other"}]
+            },
+            "status": {
+                "initContainerStatuses": [{"name": "setup", "ready": false, "restartCount": 2, "image": "", "imageID": ""}],
+                "containerStatuses": [{"name": "app", "ready": true, "restartCount": 5, "image": "", "imageID": ""}]
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            pod.container_restart_count(&ContainerKey::Init("setup".to_owned())),
+            2
+        );
+        assert_eq!(
+            pod.container_restart_count(&ContainerKey::App("app".to_owned())),
+            5
+        );
+        assert_eq!(
+            pod.container_restart_count(&ContainerKey::App("other".to_owned())),
+            0
+        );
+        // Init and app containers are counted apart, even if they share a name
+        assert_eq!(
+            pod.container_restart_count(&ContainerKey::App("setup".to_owned())),
+            0
+        );
+        assert_eq!(
+            Pod::default().container_restart_count(&ContainerKey::App("app".to_owned())),
+            0
+        );
+    }
+
+    #[test]
+    fn test_sidecars() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "initContainers": [
+                    {"name": "setup"},
+                    {"name": "proxy", "restartPolicy": "Always"}
+                ],
+                "containers": [{"name": "app"}]
+            }
+        }))
+        .unwrap();
+        assert_eq!(pod.init_containers().len(), 2);
+        let sidecars: Vec<String> = pod
+            .sidecar_containers()
+            .iter()
+            .map(|c| c.name().to_owned())
+            .collect();
+        assert_eq!(sidecars, vec!["proxy"]);
+        assert!(pod.is_sidecar(&ContainerKey::Init("proxy".to_owned())));
+        assert!(!pod.is_sidecar(&ContainerKey::Init("setup".to_owned())));
+        assert!(!pod.is_sidecar(&ContainerKey::App("proxy".to_owned())));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let app = ContainerKey::App("app".to_owned());
+        let init = ContainerKey::Init("init".to_owned());
+
+        assert!(RestartPolicy::Always.should_restart(&app, false));
+        assert!(RestartPolicy::Always.should_restart(&app, true));
+        assert!(!RestartPolicy::Always.should_restart(&init, false));
+        assert!(RestartPolicy::Always.should_restart(&init, true));
+
+        assert!(!RestartPolicy::OnFailure.should_restart(&app, false));
+        assert!(RestartPolicy::OnFailure.should_restart(&app, true));
+
+        assert!(!RestartPolicy::Never.should_restart(&app, true));
+        assert!(!RestartPolicy::Never.should_restart(&init, true));
+
+        let ephemeral = ContainerKey::Ephemeral("debug".to_owned());
+        assert!(!RestartPolicy::Always.should_restart(&ephemeral, true));
+        assert!(!RestartPolicy::OnFailure.should_restart(&ephemeral, true));
+    }
+
+    #[test]
+    fn test_ephemeral_containers() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "containers": [{"name": "app", "image": "webassembly.azurecr.io/app:v1"}],
+                "ephemeralContainers": [{
+                    "name": "debugger",
+                    "image": "webassembly.azurecr.io/debug:v1",
+                    "args": ["--inspect"],
+                    "targetContainerName": "app"
+                }]
+            }
+        }))
+        .unwrap();
+        let key = ContainerKey::Ephemeral("debugger".to_owned());
+        let debugger = pod.find_container(&key).unwrap();
+        assert_eq!(debugger.args(), &vec!["--inspect".to_owned()]);
+        assert!(pod
+            .find_container(&ContainerKey::App("debugger".to_owned()))
+            .is_none());
+        assert_eq!(pod.all_containers().len(), 1);
+        assert!(!pod.has_ephemeral_container_statuses());
+    }
+
+    #[test]
+    fn test_active_deadline() {
+        let started = Utc::now();
+        assert_eq!(Pod::default().active_deadline(started), None);
+
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {"containers": [{"name": "app"}], "activeDeadlineSeconds": 30}
+        }))
+        .unwrap();
+        assert_eq!(
+            pod.active_deadline(started),
+            Some(started + chrono::Duration::seconds(30))
+        );
+
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {"containers": [{"name": "app"}], "activeDeadlineSeconds": 30},
+            "status": {"startTime": "2021-01-01T00:00:00Z"}
+        }))
+        .unwrap();
+        assert_eq!(
+            pod.active_deadline(started).unwrap().to_rfc3339(),
+            "2021-01-01T00:00:30+00:00"
+        );
+    }
+
+    #[test]
+    fn test_default_restart_policy() {
+        assert_eq!(Pod::default().restart_policy(), RestartPolicy::Always);
+    }
+
+    #[test]
+    fn test_is_critical() {
+        let pod = |priority_class: &str| {
+            Pod::from(KubePod {
+                spec: Some(k8s_openapi::api::core::v1::PodSpec {
+                    priority_class_name: Some(priority_class.to_owned()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+        assert!(pod("system-node-critical").is_critical());
+        assert!(pod("system-cluster-critical").is_critical());
+        assert!(!pod("high-priority").is_critical());
+        assert!(!Pod::default().is_critical());
+    }
+
+    #[test]
+    fn test_priority() {
+        let pod = Pod::from(KubePod {
+            spec: Some(k8s_openapi::api::core::v1::PodSpec {
+                priority_class_name: Some("high-priority".to_owned()),
+                priority: Some(1000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(pod.priority(), 1000);
+        assert_eq!(Pod::default().priority(), 0);
+    }
+
+    #[test]
+    fn test_automounts_service_account_token() {
+        let pod = |spec: serde_json::Value| -> Pod {
+            serde_json::from_value(serde_json::json!({
+                "metadata": {"name": "test"},
+                "spec": spec,
+            }))
+            .unwrap()
+        };
+        assert!(pod(serde_json::json!({
+            "serviceAccountName": "default",
+            "containers": [{"name": "app"}]
+        }))
+        .automounts_service_account_token());
+        assert!(!pod(serde_json::json!({
+            "serviceAccountName": "default",
+            "automountServiceAccountToken": false,
+            "containers": [{"name": "app"}]
+        }))
+        .automounts_service_account_token());
+        assert!(!pod(serde_json::json!({
+            "serviceAccountName": "default",
+            "containers": [{
+                "name": "app",
+                "volumeMounts": [{
+                    "name": "kube-api-access-abcde",
+                    "mountPath": "/var/run/secrets/kubernetes.io/serviceaccount"
+                }]
+            }]
+        }))
+        .automounts_service_account_token());
+        assert!(!pod(serde_json::json!({"containers": [{"name": "app"}]}))
+            .automounts_service_account_token());
+    }
+
+    #[test]
+    fn test_security_context_ids() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "securityContext": {"runAsUser": 1000, "runAsGroup": 3000, "fsGroup": 2000},
+                "containers": [
+                    {"name": "inherits"},
+                    {"name": "overrides", "securityContext": {"runAsUser": 1001}}
+                ]
+            },
+        }))
+        .unwrap();
+        let containers = pod.containers();
+        assert_eq!(pod.fs_group(), Some(2000));
+        assert_eq!(pod.run_as(&containers[0]), (Some(1000), Some(3000)));
+        assert_eq!(pod.run_as(&containers[1]), (Some(1001), Some(3000)));
+        assert_eq!(Pod::default().fs_group(), None);
+        assert_eq!(Pod::default().run_as(&containers[0]), (None, None));
+    }
+}
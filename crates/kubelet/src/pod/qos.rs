@@ -0,0 +1,138 @@
+//! The quality of service class of a pod, which decides which pods are evicted first when the
+//! node runs low on resources.
+use k8s_openapi::api::core::v1::ResourceRequirements;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity as KubeQuantity;
+
+use super::Pod;
+use crate::resources::quantity::{Quantity, QuantityType};
+
+/// The resources that make up a pod's quality of service class
+const QOS_RESOURCES: &[&str] = &["cpu", "memory"];
+
+/// The quality of service class of a pod. Classes are ordered from the first to be evicted to the
+/// last
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QosClass {
+    /// No container requests or limits CPU or memory
+    BestEffort,
+    /// Some container requests or limits CPU or memory, but the pod isn't guaranteed them
+    Burstable,
+    /// Every container has CPU and memory limits, and requests exactly its limits
+    Guaranteed,
+}
+
+impl QosClass {
+    /// The class the API server gave the pod, or the one worked out from its containers'
+    /// resources if it hasn't given one
+    pub(crate) fn of(pod: &Pod) -> Self {
+        let assigned = pod
+            .as_kube_pod()
+            .status
+            .as_ref()
+            .and_then(|status| status.qos_class.as_deref());
+        match assigned {
+            Some("BestEffort") => QosClass::BestEffort,
+            Some("Burstable") => QosClass::Burstable,
+            Some("Guaranteed") => QosClass::Guaranteed,
+            _ => Self::from_resources(pod),
+        }
+    }
+
+    /// Works out the class of a pod the way the API server does
+    fn from_resources(pod: &Pod) -> Self {
+        let containers = pod.all_containers();
+        let resources: Vec<Option<&ResourceRequirements>> =
+            containers.iter().map(|c| c.resources()).collect();
+        let sets_any = resources.iter().flatten().any(|r| {
+            QOS_RESOURCES
+                .iter()
+                .any(|name| r.requests.contains_key(*name) || r.limits.contains_key(*name))
+        });
+        if !sets_any {
+            return QosClass::BestEffort;
+        }
+        let guaranteed = resources.iter().all(|r| match r {
+            Some(r) => QOS_RESOURCES.iter().all(|name| {
+                match (r.limits.get(*name), r.requests.get(*name)) {
+                    (Some(_), None) => true,
+                    (Some(limit), Some(request)) => same_quantity(name, limit, request),
+                    (None, _) => false,
+                }
+            }),
+            None => false,
+        });
+        if guaranteed {
+            QosClass::Guaranteed
+        } else {
+            QosClass::Burstable
+        }
+    }
+}
+
+/// Whether two quantities of the named resource are the same amount, however they are written
+fn same_quantity(name: &str, a: &KubeQuantity, b: &KubeQuantity) -> bool {
+    let parse = |q: &KubeQuantity| {
+        let quantity = if name == "cpu" {
+            QuantityType::Cpu(q)
+        } else {
+            QuantityType::Memory(q)
+        };
+        Quantity::from_kube_quantity(quantity).ok()
+    };
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod(containers: serde_json::Value, status: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {"containers": containers},
+            "status": status
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_qos_class_from_resources() {
+        let best_effort = pod(serde_json::json!([{"name": "app"}]), serde_json::json!({}));
+        assert_eq!(QosClass::of(&best_effort), QosClass::BestEffort);
+
+        let burstable = pod(
+            serde_json::json!([
+                {"name": "app", "resources": {"requests": {"memory": "64Mi"}}},
+                {"name": "sidecar"}
+            ]),
+            serde_json::json!({}),
+        );
+        assert_eq!(QosClass::of(&burstable), QosClass::Burstable);
+
+        let guaranteed = pod(
+            serde_json::json!([{
+                "name": "app",
+                "resources": {
+                    "limits": {"cpu": "1", "memory": "64Mi"},
+                    "requests": {"cpu": "1000m"}
+                }
+            }]),
+            serde_json::json!({}),
+        );
+        assert_eq!(QosClass::of(&guaranteed), QosClass::Guaranteed);
+    }
+
+    #[test]
+    fn test_qos_class_from_status() {
+        let assigned = pod(
+            serde_json::json!([{"name": "app"}]),
+            serde_json::json!({"qosClass": "Guaranteed"}),
+        );
+        assert_eq!(QosClass::of(&assigned), QosClass::Guaranteed);
+        assert!(QosClass::BestEffort < QosClass::Burstable);
+        assert!(QosClass::Burstable < QosClass::Guaranteed);
+    }
+}
@@ -1,7 +1,7 @@
 //! Container statuses
 
 use super::Pod;
-use crate::container::make_initial_container_status;
+use crate::container::{make_initial_container_status, Container, ContainerKey};
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use k8s_openapi::api::core::v1::PodCondition as KubePodCondition;
@@ -32,6 +32,15 @@ pub async fn patch_status(api: &Api<KubePod>, name: &str, status: Status) {
 
 const MAX_STATUS_INIT_RETRIES: usize = 5;
 
+/// The Pod has been scheduled to a node
+pub const POD_SCHEDULED: &str = "PodScheduled";
+/// All of the Pod's init containers have completed
+pub const INITIALIZED: &str = "Initialized";
+/// All of the Pod's containers are ready
+pub const CONTAINERS_READY: &str = "ContainersReady";
+/// The Pod is able to serve requests
+pub const READY: &str = "Ready";
+
 /// Initializes Pod container status array and wait for Pod reflection to update.
 pub async fn initialize_pod_container_statuses(
     name: String,
@@ -92,22 +101,97 @@ pub async fn initialize_pod_container_statuses(
 /// This initializes Pod status to include containers in the correct order as expected by
 /// `patch_container_status`.
 pub fn make_registered_status(pod: &Pod) -> Status {
+    // Restart counts are carried over, as the pod may have been running before the kubelet was
+    let initial_status = |container: &Container, key: ContainerKey| KubeContainerStatus {
+        restart_count: pod.container_restart_count(&key) as i32,
+        ..make_initial_container_status(container)
+    };
     let init_container_statuses: Vec<KubeContainerStatus> = pod
         .init_containers()
         .iter()
-        .map(make_initial_container_status)
+        .map(|c| initial_status(c, ContainerKey::Init(c.name().to_owned())))
         .collect();
     let container_statuses: Vec<KubeContainerStatus> = pod
         .containers()
         .iter()
-        .map(make_initial_container_status)
+        .map(|c| initial_status(c, ContainerKey::App(c.name().to_owned())))
         .collect();
-    make_status_with_containers(
-        Phase::Pending,
-        "Registered",
-        container_statuses,
-        init_container_statuses,
-    )
+    let initialized = pod.init_containers().is_empty();
+    let conditions = vec![
+        make_condition(pod, POD_SCHEDULED, true, None),
+        make_condition(
+            pod,
+            INITIALIZED,
+            initialized,
+            if initialized {
+                None
+            } else {
+                Some("ContainersNotInitialized")
+            },
+        ),
+        make_condition(pod, CONTAINERS_READY, false, Some("ContainersNotReady")),
+        make_condition(pod, READY, false, Some("ContainersNotReady")),
+    ];
+    StatusBuilder::new()
+        .phase(Phase::Pending)
+        .reason("Registered")
+        .container_statuses(container_statuses)
+        .init_container_statuses(init_container_statuses)
+        .conditions(conditions)
+        .build()
+}
+
+/// Create the Pod `Ready` and `ContainersReady` conditions for the given readiness. A Pod is ready
+/// once all of its containers are. The conditions are stamped with the current time, so they
+/// should only be patched in when the readiness of the Pod changes.
+pub fn make_ready_conditions(ready: bool) -> Vec<KubePodCondition> {
+    let now = k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(chrono::Utc::now());
+    vec![READY, CONTAINERS_READY]
+        .into_iter()
+        .map(|type_| KubePodCondition {
+            type_: type_.to_string(),
+            status: condition_status(ready),
+            last_transition_time: Some(now.clone()),
+            reason: if ready {
+                None
+            } else {
+                Some("ContainersNotReady".to_string())
+            },
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Create a Pod condition of the given type. If the Pod already has the condition with the same
+/// status, its transition time is kept, so the time is always when the status last changed.
+pub fn make_condition(
+    pod: &Pod,
+    type_: &str,
+    status: bool,
+    reason: Option<&str>,
+) -> KubePodCondition {
+    let status = condition_status(status);
+    let last_transition_time = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.iter().find(|c| c.type_ == type_))
+        .filter(|c| c.status == status)
+        .and_then(|c| c.last_transition_time.clone())
+        .unwrap_or_else(
+            || k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(chrono::Utc::now()),
+        );
+    KubePodCondition {
+        type_: type_.to_string(),
+        status,
+        last_transition_time: Some(last_transition_time),
+        reason: reason.map(|r| r.to_string()),
+        ..Default::default()
+    }
+}
+
+fn condition_status(status: bool) -> String {
+    if status { "True" } else { "False" }.to_string()
 }
 
 /// Create basic Pod status patch.
@@ -143,6 +227,8 @@ pub struct Status {
     container_statuses: Option<Vec<KubeContainerStatus>>,
     init_container_statuses: Option<Vec<KubeContainerStatus>>,
     conditions: Option<Vec<KubePodCondition>>,
+    host_ip: Option<String>,
+    pod_ip: Option<String>,
 }
 
 #[derive(Default)]
@@ -154,6 +240,8 @@ pub struct StatusBuilder {
     container_statuses: Option<Vec<KubeContainerStatus>>,
     init_container_statuses: Option<Vec<KubeContainerStatus>>,
     conditions: Option<Vec<KubePodCondition>>,
+    host_ip: Option<String>,
+    pod_ip: Option<String>,
 }
 
 impl StatusBuilder {
@@ -204,6 +292,18 @@ impl StatusBuilder {
         self
     }
 
+    /// Set the IP of the node the Pod is running on.
+    pub fn host_ip(mut self, host_ip: &str) -> StatusBuilder {
+        self.host_ip = Some(host_ip.to_string());
+        self
+    }
+
+    /// Set the IP the Pod can be reached at.
+    pub fn pod_ip(mut self, pod_ip: &str) -> StatusBuilder {
+        self.pod_ip = Some(pod_ip.to_string());
+        self
+    }
+
     /// Finalize Pod Status from builder.
     pub fn build(self) -> Status {
         // NOTE: Right now this is basically the same as just implementing it on `Status` (i.e. they
@@ -216,6 +316,8 @@ impl StatusBuilder {
             container_statuses: self.container_statuses,
             init_container_statuses: self.init_container_statuses,
             conditions: self.conditions,
+            host_ip: self.host_ip,
+            pod_ip: self.pod_ip,
         }
     }
 }
@@ -278,6 +380,15 @@ impl ObjectStatus for Status {
             status.insert("conditions".to_string(), serde_json::json!(c));
         }
 
+        if let Some(ip) = self.host_ip.clone() {
+            status.insert("hostIP".to_string(), serde_json::Value::String(ip));
+        }
+
+        if let Some(ip) = self.pod_ip.clone() {
+            status.insert("podIPs".to_string(), serde_json::json!([{ "ip": ip }]));
+            status.insert("podIP".to_string(), serde_json::Value::String(ip));
+        }
+
         serde_json::json!(
             {
                 "metadata": {
@@ -296,3 +407,82 @@ impl ObjectStatus for Status {
             .build()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pod_with_conditions(conditions: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "initContainers": [{"name": "init"}],
+                "containers": [{"name": "app"}]
+            },
+            "status": {"conditions": conditions},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_make_condition_keeps_transition_time() {
+        let pod = pod_with_conditions(serde_json::json!([{
+            "type": "PodScheduled",
+            "status": "True",
+            "lastTransitionTime": "2021-07-01T00:00:00Z"
+        }]));
+        let scheduled = make_condition(&pod, POD_SCHEDULED, true, None);
+        assert_eq!(scheduled.status, "True");
+        assert_eq!(
+            scheduled.last_transition_time.unwrap().0.to_rfc3339(),
+            "2021-07-01T00:00:00+00:00"
+        );
+
+        let unscheduled = make_condition(&pod, POD_SCHEDULED, false, Some("Unschedulable"));
+        assert_eq!(unscheduled.status, "False");
+        assert_eq!(unscheduled.reason.as_deref(), Some("Unschedulable"));
+        assert!(unscheduled.last_transition_time.unwrap().0.timestamp() > 1625097600);
+    }
+
+    #[test]
+    fn test_registered_status_conditions() {
+        let pod = pod_with_conditions(serde_json::json!([]));
+        let patch = make_registered_status(&pod).json_patch();
+        let conditions = patch["status"]["conditions"].as_array().unwrap();
+        let status_of = |type_: &str| {
+            conditions
+                .iter()
+                .find(|c| c["type"] == type_)
+                .map(|c| c["status"].as_str().unwrap().to_owned())
+        };
+        assert_eq!(status_of(POD_SCHEDULED).as_deref(), Some("True"));
+        assert_eq!(status_of(INITIALIZED).as_deref(), Some("False"));
+        assert_eq!(status_of(CONTAINERS_READY).as_deref(), Some("False"));
+        assert_eq!(status_of(READY).as_deref(), Some("False"));
+    }
+
+    #[test]
+    fn test_registered_status_keeps_restart_counts() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "initContainers": [{"name": "init"}],
+                "containers": [{"name": "app"}, {"name": "new"}]
+            },
+            "status": {
+                "initContainerStatuses": [{"name": "init", "ready": false, "restartCount": 1, "image": "", "imageID": ""}],
+                "containerStatuses": [{"name": "app", "ready": true, "restartCount": 4, "image": "", "imageID": ""}]
+            },
+        }))
+        .unwrap();
+        let patch = make_registered_status(&pod).json_patch();
+        let status = &patch["status"];
+        assert_eq!(status["initContainerStatuses"][0]["restartCount"], 1);
+        assert_eq!(status["containerStatuses"][0]["restartCount"], 4);
+        assert_eq!(
+            status["containerStatuses"][0]["ready"],
+            serde_json::json!(false)
+        );
+        assert_eq!(status["containerStatuses"][1]["restartCount"], 0);
+    }
+}
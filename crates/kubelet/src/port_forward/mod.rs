@@ -0,0 +1,99 @@
+//! `port_forward` contains the types used to forward ports of pods on behalf of the Kubernetes API.
+//!
+//! Forwarded ports are multiplexed over a single websocket using the Kubernetes channel protocol.
+//! Each port gets a pair of channels, in the order the ports were requested: the data channel
+//! carries the bytes of the connection in both directions and the error channel reports failures
+//! to connect to the port.
+
+/// The most ports that can be forwarded over one stream. Channels are numbered with a single byte
+/// and each port uses two of them
+pub(crate) const MAX_PORTS: usize = 128;
+
+/// Options for a port forward request, as given in its query string
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+    ports: Vec<u16>,
+}
+
+impl Options {
+    /// Parses the options out of a port forward request's query string. Each port to forward is
+    /// given as its own `port` parameter
+    pub fn from_query(query: &str) -> anyhow::Result<Self> {
+        let mut opts = Options::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            if key == "port" {
+                let port: u16 = value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid port {:?}", value))?;
+                if port == 0 {
+                    anyhow::bail!("Port 0 cannot be forwarded");
+                }
+                opts.ports.push(port);
+            }
+        }
+        if opts.ports.len() > MAX_PORTS {
+            anyhow::bail!(
+                "At most {} ports can be forwarded at once, got {}",
+                MAX_PORTS,
+                opts.ports.len()
+            );
+        }
+        Ok(opts)
+    }
+
+    /// The ports to forward
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+}
+
+/// The channel that carries the data of the port at the given index of the request
+pub(crate) fn data_channel(index: usize) -> u8 {
+    (index * 2) as u8
+}
+
+/// The channel that failures to forward the port at the given index of the request are reported on
+pub(crate) fn error_channel(index: usize) -> u8 {
+    (index * 2 + 1) as u8
+}
+
+/// The first frame sent on each channel of a port, which tells the client which port the channel
+/// belongs to
+pub(crate) fn port_frame(channel: u8, port: u16) -> Vec<u8> {
+    crate::exec::frame(channel, &port.to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_options_from_query() {
+        let opts = Options::from_query("port=8080&port=9090").unwrap();
+        assert_eq!(opts.ports(), &[8080, 9090]);
+
+        assert!(Options::from_query("port=http").is_err());
+        assert!(Options::from_query("port=0").is_err());
+        assert!(Options::from_query("port=70000").is_err());
+    }
+
+    #[test]
+    fn test_too_many_ports_are_rejected() {
+        let query: Vec<String> = (1..=(MAX_PORTS as u16 + 1))
+            .map(|p| format!("port={}", p))
+            .collect();
+        assert!(Options::from_query(&query.join("&")).is_err());
+    }
+
+    #[test]
+    fn test_channels() {
+        assert_eq!((data_channel(0), error_channel(0)), (0, 1));
+        assert_eq!((data_channel(2), error_channel(2)), (4, 5));
+        assert_eq!(error_channel(MAX_PORTS - 1), 255);
+    }
+
+    #[test]
+    fn test_port_frame() {
+        assert_eq!(port_frame(2, 8080), vec![2, 0x90, 0x1f]);
+    }
+}
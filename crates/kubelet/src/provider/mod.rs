@@ -1,20 +1,26 @@
 //! Traits and types needed to create backend providers for a Kubelet
 use std::collections::HashMap;
 
+use anyhow::Context;
 use async_trait::async_trait;
-use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, Secret};
+use k8s_openapi::api::core::v1::{ConfigMap, EnvVarSource, ResourceFieldSelector, Secret};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity as KubeQuantity;
 use kube::api::Api;
+use kube::error::ErrorResponse;
 use std::sync::Arc;
 use thiserror::Error;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::container::Container;
+use crate::exec::Output as ExecOutput;
 use crate::log::Sender;
 use crate::node::Builder;
 use crate::plugin_watcher::PluginRegistry;
 use crate::pod::Pod;
 use crate::pod::Status as PodStatus;
+use crate::resources::quantity::{Quantity, QuantityType};
 use crate::resources::DeviceManager;
+use crate::stats::PodStats;
 use krator::{ObjectState, State};
 
 /// A back-end for a Kubelet.
@@ -148,11 +154,41 @@ pub trait Provider: Sized + Send + Sync + 'static {
         sender: Sender,
     ) -> anyhow::Result<()>;
 
-    /// Execute a given command on a workload and then return the result.
+    /// Execute a given command in a container of a workload and then return its captured output.
     ///
     /// The default implementation of this returns a message that this feature is
     /// not available. Override this only when there is an implementation.
-    async fn exec(&self, _pod: Pod, _command: String) -> anyhow::Result<Vec<String>> {
+    async fn exec(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _container: String,
+        _command: Vec<String>,
+    ) -> anyhow::Result<ExecOutput> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Connect to a port of a pod, for forwarding the port on behalf of the Kubernetes API.
+    /// Each forwarded port gets its own connection, and the connection is closed when the client
+    /// stops forwarding.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn port_forward(
+        &self,
+        _namespace: String,
+        _pod: String,
+        _port: u16,
+    ) -> anyhow::Result<tokio::net::TcpStream> {
+        Err(NotImplementedError.into())
+    }
+
+    /// Get the resource usage of every pod the provider is running, for serving to metrics
+    /// consumers such as metrics-server.
+    ///
+    /// The default implementation of this returns a message that this feature is
+    /// not available. Override this only when there is an implementation.
+    async fn pod_stats(&self) -> anyhow::Result<Vec<PodStats>> {
         Err(NotImplementedError.into())
     }
 
@@ -167,26 +203,8 @@ pub trait Provider: Sized + Send + Sync + 'static {
         container: &Container,
         pod: &Pod,
         client: &kube::Client,
-    ) -> HashMap<String, String> {
-        let mut env = HashMap::new();
-
-        for env_var in container.env().clone().into_iter() {
-            let key = env_var.name;
-            let value = match env_var.value {
-                Some(v) => v,
-                None => {
-                    on_missing_env_value(
-                        env_var.value_from,
-                        client,
-                        pod.namespace(),
-                        &field_map(pod),
-                    )
-                    .await
-                }
-            };
-            env.insert(key, value);
-        }
-        env
+    ) -> anyhow::Result<HashMap<String, String>> {
+        env_vars(container, pod, client).await
     }
 }
 
@@ -221,25 +239,115 @@ pub trait DevicePluginSupport {
 /// custom Downward API fields.
 ///
 /// It is safe to call from within your own providers.
+///
+/// Returns an error if a variable references a field that the Downward API
+/// does not support, or if a non-optional `envFrom` source can't be fetched,
+/// as the container can't be run without them.
 pub async fn env_vars(
     container: &Container,
     pod: &Pod,
     client: &kube::Client,
-) -> HashMap<String, String> {
-    let mut env = HashMap::new();
+) -> anyhow::Result<HashMap<String, String>> {
+    // Variables from `envFrom` come first so that `env` entries take precedence
+    let mut env = env_from_vars(container, pod, client).await?;
+    let fields = field_map(pod);
 
     for env_var in container.env().clone().into_iter() {
         let key = env_var.name;
         let value = match env_var.value {
             Some(v) => v,
-            None => {
-                on_missing_env_value(env_var.value_from, client, pod.namespace(), &field_map(pod))
-                    .await
-            }
+            None => on_missing_env_value(env_var.value_from, client, pod, container, &fields)
+                .await
+                .with_context(|| format!("unable to resolve environment variable {}", key))?,
         };
         env.insert(key, value);
     }
-    env
+    Ok(env)
+}
+
+/// Resolves the config maps and secrets a container pulls in whole through
+/// `envFrom`.
+///
+/// Later sources override earlier ones. A source that doesn't exist is an
+/// error unless it is marked as optional. Keys that aren't valid variable
+/// names once prefixed are skipped, as the API server would reject them in
+/// `env`.
+async fn env_from_vars(
+    container: &Container,
+    pod: &Pod,
+    client: &kube::Client,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    for source in container.env_from() {
+        let prefix = source.prefix.as_deref().unwrap_or_default();
+        if let Some(cm_ref) = source.config_map_ref.as_ref() {
+            let name = cm_ref.name.as_deref().unwrap_or_default();
+            let api = Api::<ConfigMap>::namespaced(client.clone(), pod.namespace());
+            match api.get(name).await {
+                Ok(cfgmap) => extend_env(&mut env, prefix, name, cfgmap.data),
+                Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                    if cm_ref.optional.unwrap_or(false) =>
+                {
+                    debug!(name, "Skipping missing optional config map in envFrom");
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("unable to fetch config map {}", name))
+                }
+            }
+        }
+        if let Some(secret_ref) = source.secret_ref.as_ref() {
+            let name = secret_ref.name.as_deref().unwrap_or_default();
+            let api = Api::<Secret>::namespaced(client.clone(), pod.namespace());
+            match api.get(name).await {
+                Ok(secret) => extend_env(
+                    &mut env,
+                    prefix,
+                    name,
+                    secret
+                        .data
+                        .into_iter()
+                        .map(|(k, v)| (k, String::from_utf8(v.0).unwrap_or_default())),
+                ),
+                Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                    if secret_ref.optional.unwrap_or(false) =>
+                {
+                    debug!(name, "Skipping missing optional secret in envFrom");
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("unable to fetch secret {}", name))
+                }
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// Adds the entries of an `envFrom` source to the environment, skipping those
+/// whose prefixed key isn't a valid variable name.
+fn extend_env(
+    env: &mut HashMap<String, String>,
+    prefix: &str,
+    source: &str,
+    data: impl IntoIterator<Item = (String, String)>,
+) {
+    for (key, value) in data {
+        let key = format!("{}{}", prefix, key);
+        if is_env_var_name(&key) {
+            env.insert(key, value);
+        } else {
+            warn!(%key, source, "Skipping invalid environment variable name in envFrom");
+        }
+    }
+}
+
+/// Whether a name is a valid environment variable name, following the rules
+/// the API server applies to `env` names.
+fn is_env_var_name(name: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_');
+    match name.chars().next() {
+        Some(first) => !first.is_ascii_digit() && name.chars().all(valid),
+        None => false,
+    }
 }
 
 /// Called when an env var does not have a value associated with.
@@ -249,12 +357,14 @@ pub async fn env_vars(
 async fn on_missing_env_value(
     env_var_source: Option<EnvVarSource>,
     client: &kube::Client,
-    ns: &str,
+    pod: &Pod,
+    container: &Container,
     fields: &HashMap<String, String>,
-) -> String {
+) -> anyhow::Result<String> {
+    let ns = pod.namespace();
     let env_src = match env_var_source {
         Some(env_src) => env_src,
-        None => return String::new(),
+        None => return Ok(String::new()),
     };
 
     // ConfigMaps
@@ -268,11 +378,11 @@ async fn on_missing_env_value(
                 // I am not totally clear on what the outcome should
                 // be of a cfgmap key miss. So for now just return an
                 // empty default.
-                return cfgmap.data.get(&cfkey.key).cloned().unwrap_or_default();
+                return Ok(cfgmap.data.get(&cfkey.key).cloned().unwrap_or_default());
             }
             Err(e) => {
                 error!(error = %e, name, "Error fetching config map");
-                return "".to_string();
+                return Ok("".to_string());
             }
         }
     }
@@ -287,25 +397,146 @@ async fn on_missing_env_value(
                 // I am not totally clear on what the outcome should
                 // be of a secret key miss. So for now just return an
                 // empty default.
-                return secret
+                return Ok(secret
                     .data
                     .remove(&seckey.key)
                     .map(|s| String::from_utf8(s.0).unwrap_or_default())
-                    .unwrap_or_default();
+                    .unwrap_or_default());
             }
             Err(e) => {
                 error!(error = %e, name, "Error fetching secret");
-                return String::new();
+                return Ok(String::new());
             }
         }
     }
     // Downward API (Field Refs)
     if let Some(cfkey) = env_src.field_ref.as_ref() {
-        return fields.get(&cfkey.field_path).cloned().unwrap_or_default();
+        return field_value(&cfkey.field_path, fields);
+    }
+    // Resource Fields
+    if let Some(selector) = env_src.resource_field_ref.as_ref() {
+        return match resource_field_value(selector, pod, container) {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                error!(error = %e, resource = %selector.resource, "Error resolving resource field");
+                Ok(String::new())
+            }
+        };
+    }
+
+    Ok(String::new())
+}
+
+/// Looks up a Downward API field path in the map built by [`field_map`].
+///
+/// Labels and annotations that aren't set resolve to an empty string, as they
+/// do in Kubernetes, but any other path that isn't in the map is unsupported.
+fn field_value(path: &str, fields: &HashMap<String, String>) -> anyhow::Result<String> {
+    let path = normalize_field_path(path);
+    if let Some(value) = fields.get(&path) {
+        return Ok(value.clone());
+    }
+    if path.starts_with("metadata.labels.") || path.starts_with("metadata.annotations.") {
+        return Ok(String::new());
+    }
+    anyhow::bail!("unsupported field path {}", path)
+}
+
+/// Converts the subscript form Kubernetes uses for label and annotation keys
+/// (`metadata.labels['app']`) into the dotted form used by [`field_map`]
+fn normalize_field_path(path: &str) -> String {
+    for prefix in &["metadata.labels", "metadata.annotations"] {
+        if let Some(key) = path
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix("['"))
+            .and_then(|rest| rest.strip_suffix("']"))
+        {
+            return format!("{}.{}", prefix, key);
+        }
+    }
+    path.to_owned()
+}
+
+/// Resolves a resource field reference the way Kubernetes does: the value of the resource is
+/// divided by the divisor (1 by default) and rounded up to a whole number. Limits that aren't set
+/// default to the capacity of the node, and requests that aren't set are 0
+fn resource_field_value(
+    selector: &ResourceFieldSelector,
+    pod: &Pod,
+    container: &Container,
+) -> anyhow::Result<String> {
+    let container = match selector.container_name.as_deref() {
+        Some(name) if !name.is_empty() && name != container.name() => pod
+            .all_containers()
+            .into_iter()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Container {} does not exist in the pod", name))?,
+        _ => container.clone(),
+    };
+    let resources = container.resources().cloned().unwrap_or_default();
+    let (values, resource, default_to_capacity) = match selector.resource.split_once('.') {
+        Some(("limits", resource)) => (resources.limits, resource, true),
+        Some(("requests", resource)) => (resources.requests, resource, false),
+        _ => anyhow::bail!("Unsupported resource {}", selector.resource),
+    };
+    let value = |capacity: &str| match values.get(resource) {
+        Some(q) => q.clone(),
+        None if default_to_capacity => KubeQuantity(capacity.to_owned()),
+        None => KubeQuantity("0".to_owned()),
+    };
+    let divisor = selector
+        .divisor
+        .clone()
+        .unwrap_or_else(|| KubeQuantity("1".to_owned()));
+
+    match resource {
+        "cpu" => {
+            let value = value(crate::node::NODE_CPU);
+            // Parsing rounds anything under a millicore up to one, so a zero
+            // value has to be caught first
+            let value = if value.0 == "0" {
+                0
+            } else {
+                millicores(&value)?
+            };
+            Ok(div_ceil(value, millicores(&divisor)?)?.to_string())
+        }
+        "memory" => {
+            let value = value(crate::node::NODE_MEMORY);
+            Ok(div_ceil(bytes(&value)?, bytes(&divisor)?)?.to_string())
+        }
+        "ephemeral-storage" => {
+            let value = value(crate::node::NODE_EPHEMERAL_STORAGE);
+            Ok(div_ceil(bytes(&value)?, bytes(&divisor)?)?.to_string())
+        }
+        _ => anyhow::bail!("Unsupported resource {}", selector.resource),
+    }
+}
+
+/// Parses a CPU quantity into a whole number of millicores
+fn millicores(q: &KubeQuantity) -> anyhow::Result<u128> {
+    match Quantity::from_kube_quantity(QuantityType::Cpu(q))? {
+        Quantity::Cpu(cores) => Ok((cores * 1000.0).round() as u128),
+        // This can't happen as we explicitly asked for a CPU quantity
+        Quantity::Memory(_) => unreachable!(),
+    }
+}
+
+/// Parses a memory or storage quantity into a number of bytes
+fn bytes(q: &KubeQuantity) -> anyhow::Result<u128> {
+    match Quantity::from_kube_quantity(QuantityType::Memory(q))? {
+        Quantity::Memory(bytes) => Ok(bytes),
+        // This can't happen as we explicitly asked for a memory quantity
+        Quantity::Cpu(_) => unreachable!(),
     }
-    // Reource Fields (Not implementable just yet... need more of a model.)
+}
 
-    String::new()
+/// Divides `value` by `divisor`, rounding up
+fn div_ceil(value: u128, divisor: u128) -> anyhow::Result<u128> {
+    if divisor == 0 {
+        anyhow::bail!("Divisor must be greater than 0");
+    }
+    Ok((value + divisor - 1) / divisor)
 }
 
 /// Build the map of allowable field_ref values.
@@ -316,6 +547,14 @@ fn field_map(pod: &Pod) -> HashMap<String, String> {
     let mut map: HashMap<String, String> = HashMap::new();
     map.insert("metadata.name".into(), pod.name().to_owned());
     map.insert("metadata.namespace".into(), pod.namespace().to_owned());
+    map.insert(
+        "metadata.uid".into(),
+        pod.as_kube_pod().metadata.uid.clone().unwrap_or_default(),
+    );
+    map.insert(
+        "spec.nodeName".into(),
+        pod.node_name().unwrap_or_default().to_owned(),
+    );
     map.insert(
         "spec.serviceAccountName".into(),
         pod.service_account_name().unwrap_or_default().to_owned(),
@@ -361,3 +600,138 @@ pub enum ProviderError {
 #[derive(Error, Debug)]
 #[error("Operation not supported")]
 pub struct NotImplementedError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{Body, Request, Response};
+
+    /// A client whose API server has the `app` config map and secret in the
+    /// `default` namespace, and nothing else
+    fn client() -> kube::Client {
+        let (service, mut handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        tokio::spawn(async move {
+            while let Some((request, send)) = handle.next_request().await {
+                let (status, body) = match request.uri().path() {
+                    "/api/v1/namespaces/default/configmaps/app" => (
+                        200,
+                        serde_json::json!({
+                            "apiVersion": "v1",
+                            "kind": "ConfigMap",
+                            "metadata": { "name": "app" },
+                            "data": { "MODE": "debug", "log.level": "info", "1ST": "no" },
+                        }),
+                    ),
+                    "/api/v1/namespaces/default/secrets/app" => (
+                        200,
+                        serde_json::json!({
+                            "apiVersion": "v1",
+                            "kind": "Secret",
+                            "metadata": { "name": "app" },
+                            // "hunter2"
+                            "data": { "TOKEN": "aHVudGVyMg==", "bad=key": "aHVudGVyMg==" },
+                        }),
+                    ),
+                    _ => (
+                        404,
+                        serde_json::json!({
+                            "apiVersion": "v1",
+                            "kind": "Status",
+                            "status": "Failure",
+                            "message": "not found",
+                            "reason": "NotFound",
+                            "code": 404,
+                        }),
+                    ),
+                };
+                send.send_response(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(body.to_string()))
+                        .unwrap(),
+                );
+            }
+        });
+        kube::Client::new(service, "default")
+    }
+
+    fn pod(env_from: serde_json::Value) -> Pod {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "app", "namespace": "default" },
+            "spec": {
+                "containers": [{ "name": "module", "image": "app:v1", "envFrom": env_from }],
+            },
+        }))
+        .unwrap();
+        Pod::from(pod)
+    }
+
+    async fn env_from(env_from: serde_json::Value) -> anyhow::Result<HashMap<String, String>> {
+        let pod = pod(env_from);
+        let container = pod.containers().remove(0);
+        env_from_vars(&container, &pod, &client()).await
+    }
+
+    #[tokio::test]
+    async fn test_env_from_prefix() {
+        let env = env_from(serde_json::json!([
+            { "configMapRef": { "name": "app" }, "prefix": "APP_" },
+            { "secretRef": { "name": "app" } },
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(Some("debug"), env.get("APP_MODE").map(String::as_str));
+        assert_eq!(Some("info"), env.get("APP_log.level").map(String::as_str));
+        assert_eq!(Some("no"), env.get("APP_1ST").map(String::as_str));
+        assert_eq!(Some("hunter2"), env.get("TOKEN").map(String::as_str));
+        assert!(!env.contains_key("MODE"));
+    }
+
+    #[tokio::test]
+    async fn test_env_from_skips_invalid_keys() {
+        let env = env_from(serde_json::json!([
+            { "configMapRef": { "name": "app" } },
+            { "secretRef": { "name": "app" } },
+        ]))
+        .await
+        .unwrap();
+        let mut keys = env.keys().map(String::as_str).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(vec!["MODE", "TOKEN", "log.level"], keys);
+    }
+
+    #[tokio::test]
+    async fn test_env_from_missing_source() {
+        let env = env_from(serde_json::json!([
+            { "configMapRef": { "name": "missing", "optional": true } },
+            { "secretRef": { "name": "missing", "optional": true } },
+            { "configMapRef": { "name": "app" } },
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(Some("debug"), env.get("MODE").map(String::as_str));
+
+        let err = env_from(serde_json::json!([{ "configMapRef": { "name": "missing" } }]))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("config map missing"));
+        let err = env_from(serde_json::json!([
+            { "secretRef": { "name": "missing", "optional": false } },
+        ]))
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("secret missing"));
+    }
+
+    #[test]
+    fn test_is_env_var_name() {
+        for name in &["PATH", "_private", "my-var", "log.level", "a1"] {
+            assert!(is_env_var_name(name), "{} should be valid", name);
+        }
+        for name in &["", "1ST", "bad=key", "has space", "ünicode"] {
+            assert!(!is_env_var_name(name), "{} should be invalid", name);
+        }
+    }
+}
@@ -3,6 +3,7 @@
 use k8s_openapi::api::core::v1::Secret;
 use kube::api::Api;
 use oci_distribution::secrets::RegistryAuth;
+use tracing::warn;
 
 /// Resolves registry authentication from image pull secrets
 pub struct RegistryAuthResolver {
@@ -38,9 +39,11 @@ impl RegistryAuthResolver {
             .collect();
         let secret_results = futures::future::join_all(secret_futures).await;
 
-        for secret_result in secret_results {
+        for (name, secret_result) in self.image_pull_secret_names.iter().zip(secret_results) {
             match secret_result {
-                Err(e) => return Err(e.into()),
+                // Like Kubernetes, a pull secret that can't be fetched doesn't stop the image
+                // from being pulled, as it may not need the secret at all
+                Err(e) => warn!(error = %e, secret = %name, "Unable to fetch image pull secret"),
                 Ok(secret) => {
                     if let Some(auth) = parse_auth(&secret, reference.registry()) {
                         return Ok(auth);
@@ -81,6 +84,7 @@ fn parse_auth_from_secret_value(
     //     "reg2": { ... }
     //   }
     // }
+    // or, for legacy `.dockercfg` secrets, the same map of registries without the `auths` key
     parse_byte_string_json(secret_value)
         .and_then(|value| parse_auth_from_json_value(&value, registry_name))
 }
@@ -93,20 +97,90 @@ fn parse_auth_from_json_value(
     json_value: &serde_json::Value,
     registry_name: &str,
 ) -> Option<RegistryAuth> {
-    json_value
-        .get("auths")
-        .and_then(|auths| auths.get(registry_name))
-        .and_then(|creds| parse_auth_from_json_creds(creds))
+    let auths = json_value.get("auths").unwrap_or(json_value).as_object()?;
+    let registry_name = normalize_registry(registry_name);
+    auths
+        .iter()
+        .filter(|(key, _)| normalize_registry(key) == registry_name)
+        .find_map(|(_, creds)| parse_auth_from_json_creds(creds))
+}
+
+/// Reduces a registry key from a docker config to its host, so that keys such as
+/// `https://index.docker.io/v1/` match the registry of an image reference
+fn normalize_registry(registry: &str) -> &str {
+    let registry = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    match registry.split('/').next().unwrap_or_default() {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io",
+        host => host,
+    }
 }
 
 fn parse_auth_from_json_creds(json_creds: &serde_json::Value) -> Option<RegistryAuth> {
     let username = json_creds.get("username");
     let password = json_creds.get("password");
-    // TODO: my test creds also included an entry "auth" - should we return this? (e.g. bearer auth?)
-    match (username, password) {
-        (Some(serde_json::Value::String(u)), Some(serde_json::Value::String(p))) => {
-            Some(RegistryAuth::Basic(u.to_owned(), p.to_owned()))
+    if let (Some(serde_json::Value::String(u)), Some(serde_json::Value::String(p))) =
+        (username, password)
+    {
+        return Some(RegistryAuth::Basic(u.to_owned(), p.to_owned()));
+    }
+    // Otherwise the credentials are a base64 encoded `username:password` pair
+    let auth = json_creds.get("auth")?.as_str()?;
+    let auth = String::from_utf8(base64::decode(auth).ok()?).ok()?;
+    let (username, password) = auth.split_once(':')?;
+    Some(RegistryAuth::Basic(
+        username.to_owned(),
+        password.to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn basic(auth: Option<RegistryAuth>) -> Option<(String, String)> {
+        match auth {
+            Some(RegistryAuth::Basic(u, p)) => Some((u, p)),
+            _ => None,
         }
-        _ => None,
+    }
+
+    #[test]
+    fn test_username_and_password() {
+        let config = serde_json::json!({
+            "auths": {
+                "myregistry.io": { "username": "user", "password": "pass" }
+            }
+        });
+        assert_eq!(
+            Some(("user".to_owned(), "pass".to_owned())),
+            basic(parse_auth_from_json_value(&config, "myregistry.io"))
+        );
+        assert!(parse_auth_from_json_value(&config, "otherregistry.io").is_none());
+    }
+
+    #[test]
+    fn test_auth_blob() {
+        let config = serde_json::json!({
+            "auths": {
+                "https://index.docker.io/v1/": { "auth": base64::encode("user:pa:ss") }
+            }
+        });
+        assert_eq!(
+            Some(("user".to_owned(), "pa:ss".to_owned())),
+            basic(parse_auth_from_json_value(&config, "docker.io"))
+        );
+    }
+
+    #[test]
+    fn test_legacy_dockercfg() {
+        let config = serde_json::json!({
+            "myregistry.io:5000": { "auth": base64::encode("user:pass") }
+        });
+        assert_eq!(
+            Some(("user".to_owned(), "pass".to_owned())),
+            basic(parse_auth_from_json_value(&config, "myregistry.io:5000"))
+        );
     }
 }
@@ -49,7 +49,11 @@ impl<P: GenericProvider> State<P::PodState> for ImagePull<P> {
             (state_reader.client(), state_reader.store())
         };
         let auth_resolver = crate::secret::RegistryAuthResolver::new(client, &pod);
-        let modules = match store.fetch_pod_modules(&pod, &auth_resolver).await {
+        let pull_result = {
+            let _timer = crate::metrics::MODULE_PULL_DURATION.start_timer();
+            store.fetch_pod_modules(&pod, &auth_resolver).await
+        };
+        let modules = match pull_result {
             Ok(m) => m,
             Err(e) => {
                 error!(error = %e);
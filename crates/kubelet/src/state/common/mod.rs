@@ -46,6 +46,11 @@ pub trait GenericProviderState: 'static + Send + Sync {
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Gets the admission hooks pods must pass before they are run. The
+    /// default is to have none.
+    fn admission_hooks(&self) -> crate::pod::AdmissionHooks {
+        crate::pod::AdmissionHooks::default()
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -1,11 +1,17 @@
 //! The Kubelet is aware of the Pod.
 
 use crate::pod::state::prelude::*;
-use tracing::{debug, error, info, instrument};
+use crate::pod::{check_node_affinity, record_event, WARNING};
+use k8s_openapi::api::core::v1::Node as KubeNode;
+use kube::api::Api;
+use tracing::{debug, error, info, instrument, warn};
 
 use super::error::Error;
 use super::resources::Resources;
-use super::GenericProvider;
+use super::{GenericProvider, GenericProviderState};
+
+/// The reason given when a pod is rejected for not matching the node, as the kubelet gives it
+const NODE_AFFINITY_REASON: &str = "NodeAffinity";
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -30,12 +36,12 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     #[instrument(
         level = "info",
-        skip(self, _provider_state, _pod_state, pod),
+        skip(self, provider_state, _pod_state, pod),
         fields(pod_name)
     )]
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         _pod_state: &mut P::PodState,
         pod: Manifest<Pod>,
     ) -> Transition<P::PodState> {
@@ -52,6 +58,30 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
                 return Transition::next(self, next);
             }
         }
+
+        // A pod that can't run here is failed for good, as retrying won't change the node
+        let client = provider_state.read().await.client();
+        if let Err(e) = check_node(&client, &pod).await {
+            let message = format!("Pod Predicate {} failed: {}", NODE_AFFINITY_REASON, e);
+            error!(%message, "Rejecting pod");
+            if let Err(e) =
+                record_event(&client, &pod, WARNING, NODE_AFFINITY_REASON, &message).await
+            {
+                warn!(error = %e, "Unable to record event for rejected pod");
+            }
+            return Transition::Complete(Err(anyhow::anyhow!(message)));
+        }
+        // As are pods an admission hook turns away
+        let admission_hooks = provider_state.read().await.admission_hooks();
+        if let Err(rejection) = admission_hooks.admit(&pod).await {
+            let message = format!("Pod rejected: {}", rejection.message);
+            error!(reason = %rejection.reason, %message, "Rejecting pod");
+            if let Err(e) = record_event(&client, &pod, WARNING, &rejection.reason, &message).await
+            {
+                warn!(error = %e, "Unable to record event for rejected pod");
+            }
+            return Transition::Complete(Err(anyhow::anyhow!(message)));
+        }
         info!("Pod registered");
         let next = Resources::<P>::default();
         Transition::next(self, next)
@@ -62,5 +92,22 @@ impl<P: GenericProvider> State<P::PodState> for Registered<P> {
     }
 }
 
+/// Checks the pod against the labels of the node it was placed on. If the node can't be fetched
+/// the pod is let through, as the scheduler has already checked it in all but rare cases
+async fn check_node(client: &kube::Client, pod: &Pod) -> anyhow::Result<()> {
+    let node_name = match pod.node_name() {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let nodes: Api<KubeNode> = Api::all(client.clone());
+    match nodes.get(node_name).await {
+        Ok(node) => check_node_affinity(pod, &node),
+        Err(e) => {
+            warn!(error = %e, node_name, "Unable to fetch node to check pod's node affinity");
+            Ok(())
+        }
+    }
+}
+
 impl<P: GenericProvider> TransitionTo<Error<P>> for Registered<P> {}
 impl<P: GenericProvider> TransitionTo<Resources<P>> for Registered<P> {}
@@ -1,5 +1,7 @@
 //! Kubelet is pulling container images.
 
+use std::convert::TryFrom;
+
 use tracing::{error, info, instrument};
 
 use super::{GenericPodState, GenericProvider, GenericProviderState};
@@ -88,6 +90,29 @@ impl<P: GenericProvider> State<P::PodState> for VolumeMount<P> {
             let next = Error::<P>::new(e.to_string());
             return Transition::next(self, next);
         }
+        if let Some(fs_group) = pod.fs_group() {
+            let fs_group = match u32::try_from(fs_group) {
+                Ok(g) => g,
+                Err(_) => {
+                    let next = Error::<P>::new(format!("Invalid fsGroup {}", fs_group));
+                    return Transition::next(self, next);
+                }
+            };
+            let ownership = volumes.iter().map(|(k, v)| async move {
+                v.set_fs_group(fs_group)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Unable to set the group of volume {}: {}", k, e))
+            });
+            if let Err(e) = futures::future::join_all(ownership)
+                .await
+                .into_iter()
+                .collect::<anyhow::Result<()>>()
+            {
+                error!(error = %e);
+                let next = Error::<P>::new(e.to_string());
+                return Transition::next(self, next);
+            }
+        }
         pod_state.set_volumes(volumes).await;
         Transition::next_unchecked(self, P::RunState::default())
     }
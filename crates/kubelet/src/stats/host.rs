@@ -0,0 +1,179 @@
+//! The resource usage of the host krustlet runs on, as the node's stats.
+//!
+//! On Linux usage is read from `/proc`. Other platforms report zero usage, so the node still has
+//! the stats metrics consumers expect of it.
+use chrono::{DateTime, TimeZone, Utc};
+use lazy_static::lazy_static;
+
+use super::{CpuStats, MemoryStats, NodeStats};
+
+#[cfg(target_os = "linux")]
+const PROC_STAT: &str = "/proc/stat";
+#[cfg(target_os = "linux")]
+const PROC_MEMINFO: &str = "/proc/meminfo";
+
+lazy_static! {
+    /// When stats were first asked for, which stands in for the node's start time when the boot
+    /// time of the host isn't known
+    static ref FIRST_SAMPLE: DateTime<Utc> = Utc::now();
+}
+
+/// The CPU and memory usage of the host, sampled at `time`
+pub(crate) fn node_stats(node_name: &str, time: DateTime<Utc>) -> NodeStats {
+    let (start_time, cpu, memory) = sample();
+    NodeStats {
+        node_name: node_name.to_owned(),
+        start_time: start_time.unwrap_or(*FIRST_SAMPLE),
+        cpu: CpuStats {
+            time: Some(time),
+            usage_nano_cores: None,
+            usage_core_nano_seconds: Some(cpu.unwrap_or(0)),
+        },
+        memory: MemoryStats {
+            time: Some(time),
+            available_bytes: memory.map(|m| m.available),
+            usage_bytes: Some(memory.map_or(0, |m| m.total.saturating_sub(m.free))),
+            working_set_bytes: Some(memory.map_or(0, |m| m.total.saturating_sub(m.available))),
+        },
+    }
+}
+
+/// The memory available to start new work on the host without swapping, in bytes, if it can be
+/// read
+pub(crate) fn memory_available() -> Option<u64> {
+    sample().2.map(|m| m.available)
+}
+
+/// The disk space available to unprivileged users on the filesystem holding `path`, in bytes, if
+/// it can be read
+#[cfg(target_family = "unix")]
+pub(crate) fn disk_available(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn disk_available(_path: &std::path::Path) -> Option<u64> {
+    None
+}
+
+/// The memory of the host, in bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Memory {
+    total: u64,
+    free: u64,
+    available: u64,
+}
+
+/// Reads the boot time, CPU time used and memory of the host, whichever can be read
+#[cfg(target_os = "linux")]
+fn sample() -> (Option<DateTime<Utc>>, Option<u64>, Option<Memory>) {
+    let stat = std::fs::read_to_string(PROC_STAT).ok();
+    let meminfo = std::fs::read_to_string(PROC_MEMINFO).ok();
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let ticks_per_second = if ticks_per_second > 0 {
+        ticks_per_second as u64
+    } else {
+        100
+    };
+    (
+        stat.as_deref().and_then(boot_time),
+        stat.as_deref()
+            .and_then(cpu_ticks)
+            .map(|ticks| ticks * (1_000_000_000 / ticks_per_second)),
+        meminfo.as_deref().and_then(memory),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sample() -> (Option<DateTime<Utc>>, Option<u64>, Option<Memory>) {
+    (None, None, None)
+}
+
+/// The boot time of the host from the contents of `/proc/stat`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn boot_time(stat: &str) -> Option<DateTime<Utc>> {
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+        .map(|seconds| Utc.timestamp(seconds, 0))
+}
+
+/// The clock ticks every CPU of the host has spent busy, from the contents of `/proc/stat`. Time
+/// spent idle or waiting on IO isn't counted
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn cpu_ticks(stat: &str) -> Option<u64> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let ticks = line
+        .split_whitespace()
+        .skip(1)
+        .map(|field| field.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    // The fields are user, nice, system, idle, iowait, irq, softirq, steal, then guest time,
+    // which is already counted in user and nice
+    let total: u64 = ticks.iter().take(8).sum();
+    let idle = ticks.get(3).copied().unwrap_or(0) + ticks.get(4).copied().unwrap_or(0);
+    Some(total.saturating_sub(idle))
+}
+
+/// The memory of the host from the contents of `/proc/meminfo`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn memory(meminfo: &str) -> Option<Memory> {
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| {
+                value
+                    .trim()
+                    .trim_end_matches("kB")
+                    .trim()
+                    .parse::<u64>()
+                    .ok()
+            })
+            .map(|kb| kb * 1024)
+    };
+    let total = field("MemTotal")?;
+    let free = field("MemFree")?;
+    Some(Memory {
+        total,
+        free,
+        available: field("MemAvailable").unwrap_or(free),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_proc_stat() {
+        let stat = "cpu  100 20 30 1000 50 5 5 0 10 0\n\
+                    cpu0 50 10 15 500 25 2 3 0 5 0\n\
+                    btime 1600000000\n";
+        assert_eq!(cpu_ticks(stat), Some(160));
+        assert_eq!(boot_time(stat), Some(Utc.timestamp(1_600_000_000, 0)));
+        assert_eq!(cpu_ticks("intr 1 2 3\n"), None);
+    }
+
+    #[test]
+    fn test_proc_meminfo() {
+        let meminfo = "MemTotal:        2048 kB\n\
+                       MemFree:          512 kB\n\
+                       MemAvailable:    1024 kB\n";
+        assert_eq!(
+            memory(meminfo),
+            Some(Memory {
+                total: 2048 * 1024,
+                free: 512 * 1024,
+                available: 1024 * 1024,
+            })
+        );
+        assert_eq!(memory("MemFree: 512 kB\n"), None);
+    }
+}
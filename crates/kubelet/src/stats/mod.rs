@@ -0,0 +1,224 @@
+//! `stats` contains the types providers report the resource usage of their pods and containers
+//! with.
+//!
+//! The types follow the pod and container stats of the kubelet's summary API, which is what
+//! metrics-server reads, so the usage a provider reports can be served as is. Usage is
+//! cumulative where the summary API is: CPU is reported as the CPU time used since the container
+//! started, and consumers work out the rate from two samples.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::pod::Pod;
+
+mod host;
+
+pub(crate) use host::{disk_available, memory_available, node_stats};
+
+/// The resource usage of a single running container, as reported by its handle
+#[derive(Clone, Debug, PartialEq)]
+pub struct Usage {
+    /// When the container started
+    pub start_time: DateTime<Utc>,
+    /// The CPU time the container has used since it started
+    pub cpu_time: std::time::Duration,
+    /// The memory the container is currently using, in bytes
+    pub memory_bytes: u64,
+}
+
+/// The CPU usage of a pod or container
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuStats {
+    /// When the usage was sampled
+    pub time: Option<DateTime<Utc>>,
+    /// The average CPU usage since the previous sample, in billionths of a core
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_nano_cores: Option<u64>,
+    /// The CPU time used since the container started, in nanoseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_core_nano_seconds: Option<u64>,
+}
+
+/// The memory usage of a pod or container
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryStats {
+    /// When the usage was sampled
+    pub time: Option<DateTime<Utc>>,
+    /// The memory that is still available, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_bytes: Option<u64>,
+    /// The memory in use, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_bytes: Option<u64>,
+    /// The memory that can't be reclaimed under pressure, in bytes. This is what memory limits
+    /// and `kubectl top` go by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub working_set_bytes: Option<u64>,
+}
+
+/// The resource usage of a container
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStats {
+    /// The name of the container
+    pub name: String,
+    /// When the container started
+    pub start_time: DateTime<Utc>,
+    /// The container's CPU usage
+    pub cpu: CpuStats,
+    /// The container's memory usage
+    pub memory: MemoryStats,
+}
+
+impl ContainerStats {
+    /// Stats for the named container from the usage its handle reports, sampled at `time`
+    pub fn new(name: String, usage: Usage, time: DateTime<Utc>) -> Self {
+        ContainerStats {
+            name,
+            start_time: usage.start_time,
+            cpu: CpuStats {
+                time: Some(time),
+                usage_nano_cores: None,
+                usage_core_nano_seconds: Some(usage.cpu_time.as_nanos() as u64),
+            },
+            memory: MemoryStats {
+                time: Some(time),
+                available_bytes: None,
+                usage_bytes: Some(usage.memory_bytes),
+                working_set_bytes: Some(usage.memory_bytes),
+            },
+        }
+    }
+}
+
+/// Identifies the pod stats are for
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PodReference {
+    /// The name of the pod
+    pub name: String,
+    /// The namespace of the pod
+    pub namespace: String,
+    /// The UID of the pod
+    pub uid: String,
+}
+
+/// The resource usage of a pod and each of its running containers
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodStats {
+    /// The pod the stats are for
+    pub pod_ref: PodReference,
+    /// When the pod started
+    pub start_time: DateTime<Utc>,
+    /// The usage of the pod's running containers
+    pub containers: Vec<ContainerStats>,
+    /// The CPU usage of the pod, which is the sum of its containers' usage
+    pub cpu: CpuStats,
+    /// The memory usage of the pod, which is the sum of its containers' usage
+    pub memory: MemoryStats,
+}
+
+impl PodStats {
+    /// Stats for the pod made up of the stats of its running containers, sampled at `time`
+    pub fn new(pod: &Pod, containers: Vec<ContainerStats>, time: DateTime<Utc>) -> Self {
+        let start_time = pod
+            .as_kube_pod()
+            .status
+            .as_ref()
+            .and_then(|status| status.start_time.as_ref())
+            .map(|start_time| start_time.0)
+            .or_else(|| containers.iter().map(|c| c.start_time).min())
+            .unwrap_or(time);
+        let sum = |f: fn(&ContainerStats) -> Option<u64>| {
+            containers
+                .iter()
+                .filter_map(f)
+                .fold(None, |total, value| Some(total.unwrap_or(0) + value))
+        };
+        let cpu = CpuStats {
+            time: Some(time),
+            usage_nano_cores: sum(|c| c.cpu.usage_nano_cores),
+            usage_core_nano_seconds: sum(|c| c.cpu.usage_core_nano_seconds),
+        };
+        let memory = MemoryStats {
+            time: Some(time),
+            available_bytes: None,
+            usage_bytes: sum(|c| c.memory.usage_bytes),
+            working_set_bytes: sum(|c| c.memory.working_set_bytes),
+        };
+        PodStats {
+            pod_ref: PodReference {
+                name: pod.name().to_owned(),
+                namespace: pod.namespace().to_owned(),
+                uid: pod.pod_uid().to_owned(),
+            },
+            start_time,
+            containers,
+            cpu,
+            memory,
+        }
+    }
+}
+
+/// The resource usage of the node
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeStats {
+    /// The name of the node
+    pub node_name: String,
+    /// When the node started
+    pub start_time: DateTime<Utc>,
+    /// The CPU usage of the node
+    pub cpu: CpuStats,
+    /// The memory usage of the node
+    pub memory: MemoryStats,
+}
+
+/// The resource usage of the node and the pods running on it, as served at `/stats/summary`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// The usage of the node
+    pub node: NodeStats,
+    /// The usage of each pod running on the node
+    pub pods: Vec<PodStats>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pod_stats_sum_containers() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test", "namespace": "default", "uid": "1234"},
+            "spec": {"containers": []}
+        }))
+        .unwrap();
+        let time = Utc::now();
+        let start_time = time - chrono::Duration::seconds(10);
+        let usage = |cpu_ms, memory_bytes| Usage {
+            start_time,
+            cpu_time: Duration::from_millis(cpu_ms),
+            memory_bytes,
+        };
+        let stats = PodStats::new(
+            &pod,
+            vec![
+                ContainerStats::new("a".to_owned(), usage(250, 65536), time),
+                ContainerStats::new("b".to_owned(), usage(750, 131072), time),
+            ],
+            time,
+        );
+        assert_eq!(stats.start_time, start_time);
+        assert_eq!(stats.cpu.usage_core_nano_seconds, Some(1_000_000_000));
+        assert_eq!(stats.cpu.usage_nano_cores, None);
+        assert_eq!(stats.memory.working_set_bytes, Some(196608));
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["podRef"]["uid"], "1234");
+        assert_eq!(json["containers"][1]["memory"]["workingSetBytes"], 131072);
+        assert!(json["cpu"].get("usageNanoCores").is_none());
+    }
+}
@@ -73,6 +73,29 @@ impl Store for CompositeStore {
             self.base.get(image_ref, pull_policy, auth).await
         }
     }
+
+    async fn get_url(&self, url: &url::Url, pull_policy: PullPolicy) -> anyhow::Result<Vec<u8>> {
+        self.base.get_url(url, pull_policy).await
+    }
+
+    async fn get_data_path(&self, image_ref: &Reference) -> Option<(String, std::path::PathBuf)> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.get_data_path(image_ref).await
+        } else {
+            self.base.get_data_path(image_ref).await
+        }
+    }
+
+    async fn get_annotations(
+        &self,
+        image_ref: &Reference,
+    ) -> std::collections::HashMap<String, String> {
+        if self.interceptor.intercepts(image_ref) {
+            self.interceptor.get_annotations(image_ref).await
+        } else {
+            self.base.get_annotations(image_ref).await
+        }
+    }
 }
 
 #[cfg(test)]
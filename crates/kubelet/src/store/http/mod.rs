@@ -0,0 +1,228 @@
+//! `http` implements fetching modules directly from the URLs they are served at.
+
+use crate::config::Config;
+use crate::store::{PullPolicy, Store};
+use async_trait::async_trait;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use std::sync::Arc;
+use tracing::{debug, instrument};
+use url::Url;
+
+/// A `Store` which fetches modules from `http` and `https` URLs, for modules
+/// served by a plain file server rather than a registry. Modules given as
+/// `file` URLs are read from the local filesystem if local modules are
+/// allowed. Modules given as image references are fetched from the
+/// store it wraps.
+///
+/// Modules fetched from URLs are not cached, so every pull downloads the
+/// module again. Modules larger than the maximum module size are rejected
+/// before they are read in full.
+pub struct HttpStore {
+    base: Arc<dyn Store + Send + Sync>,
+    client: reqwest::Client,
+    allow_file_urls: bool,
+    max_module_size: u64,
+}
+
+impl HttpStore {
+    /// Creates a store that fetches modules from URLs, and modules given as
+    /// image references from `base`. TLS certificates are verified unless the
+    /// config skips verification.
+    pub fn from_config(
+        base: Arc<dyn Store + Send + Sync>,
+        config: &Config,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(config.insecure_skip_module_tls_verify)
+            .build()?;
+        Ok(Self::new(
+            base,
+            client,
+            config.allow_local_modules,
+            config.max_module_size,
+        ))
+    }
+
+    fn new(
+        base: Arc<dyn Store + Send + Sync>,
+        client: reqwest::Client,
+        allow_file_urls: bool,
+        max_module_size: u64,
+    ) -> Self {
+        HttpStore {
+            base,
+            client,
+            allow_file_urls,
+            max_module_size,
+        }
+    }
+
+    fn check_size(&self, url: &Url, size: u64) -> anyhow::Result<()> {
+        if size > self.max_module_size {
+            anyhow::bail!(
+                "Unable to fetch {}: module is larger than the maximum module size of {} bytes",
+                url,
+                self.max_module_size
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for HttpStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.base.get(image_ref, pull_policy, auth).await
+    }
+
+    async fn get_data_path(&self, image_ref: &Reference) -> Option<(String, std::path::PathBuf)> {
+        self.base.get_data_path(image_ref).await
+    }
+
+    async fn get_annotations(
+        &self,
+        image_ref: &Reference,
+    ) -> std::collections::HashMap<String, String> {
+        self.base.get_annotations(image_ref).await
+    }
+
+    #[instrument(level = "info", skip(self))]
+    async fn get_url(&self, url: &Url, pull_policy: PullPolicy) -> anyhow::Result<Vec<u8>> {
+        match url.scheme() {
+            "file" => {
+                if !self.allow_file_urls {
+                    anyhow::bail!("Unable to fetch {}: local modules are not allowed", url);
+                }
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| anyhow::anyhow!("Unable to fetch {}: invalid file path", url))?;
+                self.check_size(url, tokio::fs::metadata(&path).await?.len())?;
+                Ok(tokio::fs::read(&path).await?)
+            }
+            "http" | "https" => {
+                if pull_policy == PullPolicy::Never {
+                    anyhow::bail!(
+                        "Unable to fetch {}: modules served at URLs are not cached locally and the pull policy is Never",
+                        url
+                    );
+                }
+                debug!("Downloading module");
+                let mut response = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                // The length the server claims can't be trusted, so keep counting as the module
+                // is read
+                if let Some(length) = response.content_length() {
+                    self.check_size(url, length)?;
+                }
+                let mut module = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    self.check_size(url, (module.len() + chunk.len()) as u64)?;
+                    module.extend_from_slice(&chunk);
+                }
+                Ok(module)
+            }
+            scheme => anyhow::bail!("Unable to fetch {}: unsupported scheme {}", url, scheme),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    struct FakeBase {}
+
+    #[async_trait]
+    impl Store for FakeBase {
+        async fn get(
+            &self,
+            _image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            Ok(vec![11, 10, 5, 14])
+        }
+    }
+
+    fn store(allow_file_urls: bool) -> HttpStore {
+        HttpStore::new(
+            Arc::new(FakeBase {}),
+            reqwest::Client::new(),
+            allow_file_urls,
+            u64::MAX,
+        )
+    }
+
+    #[tokio::test]
+    async fn http_store_passes_references_to_base() {
+        let image_ref = Reference::try_from("foo/bar:1.0").unwrap();
+        let module = store(false)
+            .get(
+                &image_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![11, 10, 5, 14], module);
+    }
+
+    #[tokio::test]
+    async fn http_store_reads_file_urls_if_allowed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("module.wasm");
+        tokio::fs::write(&path, vec![1, 2, 3]).await.unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+
+        let module = store(true)
+            .get_url(&url, PullPolicy::IfNotPresent)
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 2, 3], module);
+
+        store(false)
+            .get_url(&url, PullPolicy::IfNotPresent)
+            .await
+            .expect_err("file URLs should not be read unless local modules are allowed");
+    }
+
+    #[tokio::test]
+    async fn http_store_rejects_modules_larger_than_the_maximum() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("module.wasm");
+        tokio::fs::write(&path, vec![1, 2, 3, 4]).await.unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+
+        let store = HttpStore::new(Arc::new(FakeBase {}), reqwest::Client::new(), true, 3);
+        let error = store
+            .get_url(&url, PullPolicy::IfNotPresent)
+            .await
+            .expect_err("modules larger than the maximum should be rejected");
+        assert!(
+            error.to_string().contains("maximum module size"),
+            "{:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn http_store_does_not_download_if_pull_policy_is_never() {
+        let url = Url::parse("https://example.com/module.wasm").unwrap();
+        let error = store(false)
+            .get_url(&url, PullPolicy::Never)
+            .await
+            .expect_err("modules should not be downloaded with a Never pull policy");
+        assert!(error.to_string().contains("Never"), "{:?}", error);
+    }
+}
@@ -1,26 +1,36 @@
 //! `store` contains logic around fetching and storing modules.
 pub mod composite;
 pub mod fs;
+pub mod http;
 pub mod oci;
 
 use oci_distribution::client::ImageData;
 use oci_distribution::secrets::RegistryAuth;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
 use async_trait::async_trait;
 use oci_distribution::Reference;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 
-use crate::container::PullPolicy;
+use crate::container::{Container, PullPolicy};
 use crate::pod::Pod;
 use crate::store::oci::Client;
 
 /// A store of container modules.
 ///
-/// This provides the ability to get a module's bytes given an image [`Reference`].
+/// This provides the ability to get a module's bytes given an image [`Reference`]. Providers hold
+/// the store they are built with as a trait object, so modules can be served from any backend,
+/// such as an object store or an internal artifact service in an air-gapped cluster, by
+/// implementing this trait and handing it to the provider in place of the OCI backed
+/// [`FileStore`](oci::FileStore). Only [`get`](Store::get) has to be implemented.
+///
+/// Stores only ever hand out module bytes, never modules compiled ahead of time. Compiled modules
+/// are native code that is loaded without being verified, so providers only load the ones they
+/// compiled and cached themselves.
 ///
 /// # Example
 ///  ```rust
@@ -59,6 +69,56 @@ pub trait Store: Sync {
         auth: &RegistryAuth,
     ) -> anyhow::Result<Vec<u8>>;
 
+    /// Get a module's data given the URL it is served at. Stores that can't fetch modules from
+    /// URLs return an error, which is the default.
+    async fn get_url(&self, url: &url::Url, _pull_policy: PullPolicy) -> anyhow::Result<Vec<u8>> {
+        Err(anyhow::anyhow!(
+            "Unable to fetch {}: modules can't be fetched from URLs",
+            url
+        ))
+    }
+
+    /// Get the digest of the image manifest the data files shipped alongside a module came from,
+    /// and the directory they were extracted into, given its image `Reference`. Returns `None` if
+    /// the image has no data layers, or the store doesn't keep them, which is the default.
+    async fn get_data_path(&self, _image_ref: &Reference) -> Option<(String, PathBuf)> {
+        None
+    }
+
+    /// Get the annotations of the manifest of a module's image given its image `Reference`, which
+    /// can describe how the module is meant to be run. Returns none if the image has none, or the
+    /// store doesn't keep them, which is the default.
+    async fn get_annotations(&self, _image_ref: &Reference) -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    /// Fetch the module of a single container, resolving the registry credentials to pull it
+    /// with from the pod's image pull secrets.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the container does not have an image associated with it
+    async fn fetch_container_module(
+        &self,
+        container: &Container,
+        auth: &crate::secret::RegistryAuthResolver,
+    ) -> anyhow::Result<Vec<u8>> {
+        let url = container.image_url();
+        let reference = container.image().expect("Could not parse image.");
+        let pull_policy = container
+            .effective_pull_policy()
+            .expect("Could not identify pull policy.");
+        match (url, reference) {
+            (Some(url), _) => self.get_url(&url, pull_policy).await,
+            (None, Some(reference)) => {
+                let registry_authentication = auth.resolve_registry_auth(&reference).await?;
+                self.get(&reference, pull_policy, &registry_authentication)
+                    .await
+            }
+            (None, None) => panic!("FATAL ERROR: container must have an image"),
+        }
+    }
+
     /// Fetch all container modules for a given `Pod` storing the name of the
     /// container and the module's data as key/value pairs in a hashmap.
     ///
@@ -76,22 +136,9 @@ pub trait Store: Sync {
         debug!("Fetching all the container modules for pod");
         // Fetch all of the container modules in parallel
         let all_containers = pod.all_containers();
-        let container_module_futures = all_containers.iter().map(move |container| {
-            let reference = container
-                .image()
-                .expect("Could not parse image.")
-                .expect("FATAL ERROR: container must have an image");
-            let pull_policy = container
-                .effective_pull_policy()
-                .expect("Could not identify pull policy.");
-            async move {
-                let registry_authentication = auth.resolve_registry_auth(&reference).await?;
-                Ok((
-                    container.name().to_string(),
-                    self.get(&reference, pull_policy, &registry_authentication)
-                        .await?,
-                ))
-            }
+        let container_module_futures = all_containers.iter().map(move |container| async move {
+            let module = self.fetch_container_module(container, auth).await?;
+            Ok((container.name().to_string(), module))
         });
 
         // Collect the container modules into a HashMap for quick lookup
@@ -110,10 +157,28 @@ pub struct LocalStore<S: Storer, C: Client> {
 }
 
 impl<S: Storer, C: Client> LocalStore<S, C> {
+    /// Pulls the image ref and stores it. If the registry reported the digest the ref should have,
+    /// an image pulled with a different one, such as when the tag moved during the pull, is
+    /// rejected rather than stored
     #[instrument(level = "info", skip(self, auth))]
-    async fn pull(&self, image_ref: &Reference, auth: &RegistryAuth) -> anyhow::Result<()> {
+    async fn pull(
+        &self,
+        image_ref: &Reference,
+        auth: &RegistryAuth,
+        expected_digest: Option<&str>,
+    ) -> anyhow::Result<()> {
         debug!("Pulling image ref from registry");
         let image_data = self.client.lock().await.pull(image_ref, auth).await?;
+        if let (Some(expected), Some(pulled)) = (expected_digest, image_data.digest.as_deref()) {
+            if expected != pulled {
+                anyhow::bail!(
+                    "Pulled image ref {} with digest {}, but the registry reported {}",
+                    image_ref,
+                    pulled,
+                    expected
+                );
+            }
+        }
         self.storer
             .write()
             .await
@@ -134,7 +199,7 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
         match pull_policy {
             PullPolicy::IfNotPresent => {
                 if !self.storer.read().await.is_present(image_ref).await {
-                    self.pull(image_ref, auth).await?
+                    self.pull(image_ref, auth, None).await?
                 }
             }
             PullPolicy::Always => {
@@ -148,16 +213,40 @@ impl<S: Storer + Sync + Send, C: Client + Sync + Send> Store for LocalStore<S, C
                     .storer
                     .read()
                     .await
-                    .is_present_with_digest(image_ref, digest)
+                    .is_present_with_digest(image_ref, digest.clone())
                     .await;
                 if !already_got_with_digest {
-                    self.pull(image_ref, auth).await?
+                    self.pull(image_ref, auth, Some(&digest)).await?
+                }
+            }
+            PullPolicy::Never => {
+                if !self.storer.read().await.is_present(image_ref).await {
+                    anyhow::bail!(
+                        "Image ref {} is not present locally and its pull policy is Never",
+                        image_ref
+                    );
                 }
             }
-            PullPolicy::Never => (),
         };
 
-        self.storer.read().await.get_local(image_ref).await
+        let local = self.storer.read().await.get_local(image_ref).await;
+        match local {
+            // The local copy may be missing or corrupt, so pull it again if the policy allows
+            Err(e) if pull_policy != PullPolicy::Never => {
+                warn!(error = %e, "Unable to read image ref from local store, pulling it again");
+                self.pull(image_ref, auth, None).await?;
+                self.storer.read().await.get_local(image_ref).await
+            }
+            local => local,
+        }
+    }
+
+    async fn get_data_path(&self, image_ref: &Reference) -> Option<(String, PathBuf)> {
+        self.storer.read().await.data_path(image_ref).await
+    }
+
+    async fn get_annotations(&self, image_ref: &Reference) -> HashMap<String, String> {
+        self.storer.read().await.annotations(image_ref).await
     }
 }
 
@@ -180,4 +269,16 @@ pub trait Storer {
 
     /// Whether the specified module is already present in the backing store with the specified digest.
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool;
+
+    /// The digest of the manifest the data files shipped alongside the module came from, and the
+    /// directory they were extracted into, if the image has any. The default is that images have
+    /// none
+    async fn data_path(&self, _image_ref: &Reference) -> Option<(String, PathBuf)> {
+        None
+    }
+
+    /// The annotations of the image's manifest. The default is that images have none
+    async fn annotations(&self, _image_ref: &Reference) -> HashMap<String, String> {
+        HashMap::new()
+    }
 }
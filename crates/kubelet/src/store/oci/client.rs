@@ -1,11 +1,37 @@
 //! Client for fetching container modules from OCI
+use std::io::Read;
+
+use anyhow::Context;
 use async_trait::async_trait;
-use oci_distribution::client::ImageData;
-use oci_distribution::manifest;
+use oci_distribution::client::{ImageData, ImageLayer};
+use oci_distribution::manifest::{
+    WASM_DATA_LAYER_GZIP_MEDIA_TYPE, WASM_DATA_LAYER_MEDIA_TYPE, WASM_DATA_LAYER_ZSTD_MEDIA_TYPE,
+    WASM_LAYER_GZIP_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE, WASM_LAYER_ZSTD_MEDIA_TYPE,
+};
 use oci_distribution::secrets::RegistryAuth;
 
 use oci_distribution::Reference;
 
+/// The most bytes a layer may decompress to. Layers are small enough to pull once compressed,
+/// but could otherwise expand to far more than the node has memory for
+const MAX_DECOMPRESSED_LAYER_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The media types of the module layers that are pulled. Compressed layers are decompressed once
+/// they have been pulled
+pub(crate) const MODULE_LAYER_MEDIA_TYPES: &[&str] = &[
+    WASM_LAYER_MEDIA_TYPE,
+    WASM_LAYER_GZIP_MEDIA_TYPE,
+    WASM_LAYER_ZSTD_MEDIA_TYPE,
+];
+
+/// The media types of the layers of data files shipped alongside the module, which are pulled
+/// along with it. Compressed layers are decompressed into tar archives once they have been pulled
+pub(crate) const DATA_LAYER_MEDIA_TYPES: &[&str] = &[
+    WASM_DATA_LAYER_MEDIA_TYPE,
+    WASM_DATA_LAYER_GZIP_MEDIA_TYPE,
+    WASM_DATA_LAYER_ZSTD_MEDIA_TYPE,
+];
+
 /// An image client capable of fetching images from a storage location
 #[async_trait]
 pub trait Client {
@@ -59,8 +85,28 @@ pub trait Client {
 #[async_trait]
 impl Client for oci_distribution::Client {
     async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
-        self.pull(image, auth, vec![manifest::WASM_LAYER_MEDIA_TYPE])
-            .await
+        let media_types = MODULE_LAYER_MEDIA_TYPES
+            .iter()
+            .chain(DATA_LAYER_MEDIA_TYPES)
+            .copied()
+            .collect();
+        let ImageData {
+            layers,
+            digest,
+            annotations,
+        } = self.pull(image, auth, media_types).await?;
+        let layers = tokio::task::spawn_blocking(move || {
+            layers
+                .into_iter()
+                .map(decompress_layer)
+                .collect::<anyhow::Result<_>>()
+        })
+        .await??;
+        Ok(ImageData {
+            layers,
+            digest,
+            annotations,
+        })
     }
 
     async fn fetch_digest(
@@ -71,3 +117,182 @@ impl Client for oci_distribution::Client {
         self.fetch_manifest_digest(image, auth).await
     }
 }
+
+/// Decompresses a module or data layer according to its media type, giving a layer holding the
+/// module itself or the tar archive of the data files. Decompression is CPU bound, so this
+/// should be run on a blocking thread
+pub(crate) fn decompress_layer(layer: ImageLayer) -> anyhow::Result<ImageLayer> {
+    decompress_layer_up_to(layer, MAX_DECOMPRESSED_LAYER_SIZE)
+}
+
+fn decompress_layer_up_to(layer: ImageLayer, limit: u64) -> anyhow::Result<ImageLayer> {
+    let (data, media_type) = match layer.media_type.as_str() {
+        WASM_LAYER_MEDIA_TYPE | WASM_DATA_LAYER_MEDIA_TYPE => return Ok(layer),
+        WASM_LAYER_GZIP_MEDIA_TYPE => {
+            (gunzip(&layer.data, "module", limit)?, WASM_LAYER_MEDIA_TYPE)
+        }
+        WASM_LAYER_ZSTD_MEDIA_TYPE => {
+            (unzstd(&layer.data, "module", limit)?, WASM_LAYER_MEDIA_TYPE)
+        }
+        WASM_DATA_LAYER_GZIP_MEDIA_TYPE => (
+            gunzip(&layer.data, "data", limit)?,
+            WASM_DATA_LAYER_MEDIA_TYPE,
+        ),
+        WASM_DATA_LAYER_ZSTD_MEDIA_TYPE => (
+            unzstd(&layer.data, "data", limit)?,
+            WASM_DATA_LAYER_MEDIA_TYPE,
+        ),
+        other => anyhow::bail!("unsupported module layer media type {}", other),
+    };
+    Ok(ImageLayer::new(data, media_type.to_owned()))
+}
+
+fn gunzip(data: &[u8], kind: &str, limit: u64) -> anyhow::Result<Vec<u8>> {
+    read_up_to(flate2::read::GzDecoder::new(data), limit)
+        .with_context(|| format!("unable to decompress gzip {} layer", kind))
+}
+
+fn unzstd(data: &[u8], kind: &str, limit: u64) -> anyhow::Result<Vec<u8>> {
+    zstd::stream::read::Decoder::new(data)
+        .map_err(anyhow::Error::from)
+        .and_then(|decoder| read_up_to(decoder, limit))
+        .with_context(|| format!("unable to decompress zstd {} layer", kind))
+}
+
+/// Reads everything from a decoder, failing once more than `limit` bytes have been read
+fn read_up_to(decoder: impl Read, limit: u64) -> anyhow::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    decoder
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() as u64 > limit {
+        anyhow::bail!("layer decompresses to more than {} bytes", limit);
+    }
+    Ok(decompressed)
+}
+
+/// Whether the layer holds data files rather than the module
+pub(crate) fn is_data_layer(layer: &ImageLayer) -> bool {
+    DATA_LAYER_MEDIA_TYPES.contains(&layer.media_type.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn test_uncompressed_layer_is_unchanged() {
+        let layer = decompress_layer(ImageLayer::new(
+            MODULE.to_vec(),
+            WASM_LAYER_MEDIA_TYPE.to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(layer.data, MODULE);
+        assert_eq!(layer.media_type, WASM_LAYER_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_gzip_layer_is_decompressed() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(MODULE).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let layer = decompress_layer(ImageLayer::new(
+            compressed,
+            WASM_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(layer.data, MODULE);
+        assert_eq!(layer.media_type, WASM_LAYER_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_zstd_layer_is_decompressed() {
+        let compressed = zstd::stream::encode_all(MODULE, 0).unwrap();
+
+        let layer = decompress_layer(ImageLayer::new(
+            compressed,
+            WASM_LAYER_ZSTD_MEDIA_TYPE.to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(layer.data, MODULE);
+        assert_eq!(layer.media_type, WASM_LAYER_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn test_data_layer_is_decompressed_to_tar() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"archive").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let layer = decompress_layer(ImageLayer::new(
+            compressed,
+            WASM_DATA_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(layer.data, b"archive");
+        assert_eq!(layer.media_type, WASM_DATA_LAYER_MEDIA_TYPE);
+        assert!(is_data_layer(&layer));
+    }
+
+    #[test]
+    fn test_decompression_is_limited() {
+        let data = vec![0; 4096];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&data).unwrap();
+        let gzip = ImageLayer::new(
+            encoder.finish().unwrap(),
+            WASM_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+        );
+        let zstd = ImageLayer::new(
+            zstd::stream::encode_all(data.as_slice(), 0).unwrap(),
+            WASM_DATA_LAYER_ZSTD_MEDIA_TYPE.to_owned(),
+        );
+
+        for layer in vec![gzip, zstd] {
+            let media_type = layer.media_type.clone();
+            let error = decompress_layer_up_to(layer.clone(), 4095)
+                .err()
+                .expect("layer over the limit should not decompress");
+            assert!(
+                format!("{:#}", error).contains("more than 4095 bytes"),
+                "{}: {:#}",
+                media_type,
+                error
+            );
+            let decompressed = decompress_layer_up_to(layer, 4096).unwrap();
+            assert_eq!(decompressed.data, data, "{}", media_type);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_layer_is_reported() {
+        let error = decompress_layer(ImageLayer::new(
+            MODULE.to_vec(),
+            WASM_LAYER_ZSTD_MEDIA_TYPE.to_owned(),
+        ))
+        .err()
+        .expect("corrupt layer should not decompress");
+        assert!(error.to_string().contains("zstd"), "{}", error);
+    }
+
+    #[test]
+    fn test_unknown_media_type_is_reported() {
+        let error = decompress_layer(ImageLayer::new(
+            MODULE.to_vec(),
+            "application/vnd.wasm.content.layer.v1+wasm+lz4".to_owned(),
+        ))
+        .err()
+        .expect("layer with an unknown media type should not decompress");
+        assert!(
+            error
+                .to_string()
+                .contains("application/vnd.wasm.content.layer.v1+wasm+lz4"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,15 +1,18 @@
 use crate::store::Storer;
-use oci_distribution::client::ImageData;
+use oci_distribution::client::{ImageData, ImageLayer};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use oci_distribution::Reference;
+use sha2::Digest;
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use super::client::Client;
+use super::client::{is_data_layer, Client};
 use crate::store::LocalStore;
 
 /// A module store that keeps modules cached on the file system
@@ -22,17 +25,33 @@ pub type FileStore<C> = LocalStore<FileStorer, C>;
 impl<C: Client + Send> FileStore<C> {
     /// Create a new `FileStore`
     pub fn new<T: AsRef<Path>>(client: C, root_dir: T) -> Self {
+        Self::from_storer(client, FileStorer::new(root_dir))
+    }
+
+    /// Create a new `FileStore` that evicts the least recently used modules once the modules it
+    /// holds take up more than `max_size` bytes
+    pub fn with_max_size<T: AsRef<Path>>(client: C, root_dir: T, max_size: u64) -> Self {
+        let mut storer = FileStorer::new(root_dir);
+        storer.max_size = Some(max_size);
+        Self::from_storer(client, storer)
+    }
+
+    fn from_storer(client: C, storer: FileStorer) -> Self {
         Self {
-            storer: Arc::new(RwLock::new(FileStorer {
-                root_dir: root_dir.as_ref().into(),
-            })),
+            storer: Arc::new(RwLock::new(storer)),
             client: Arc::new(Mutex::new(client)),
         }
     }
 }
 
+/// Stores modules on disk, content addressed by their SHA-256 digest so that images sharing a
+/// module share a single copy of it. Each image reference records the digest of its module,
+/// which is checked whenever the module is read back
 pub struct FileStorer {
     root_dir: PathBuf,
+    max_size: Option<u64>,
+    /// When each module was last used, so that the least recently used are evicted first
+    last_used: std::sync::Mutex<HashMap<PathBuf, SystemTime>>,
 }
 
 impl FileStorer {
@@ -40,38 +59,189 @@ impl FileStorer {
     pub fn new<T: AsRef<Path>>(root_dir: T) -> Self {
         Self {
             root_dir: root_dir.as_ref().into(),
+            max_size: None,
+            last_used: Default::default(),
         }
     }
 
     fn pull_path(&self, r: &Reference) -> PathBuf {
         let mut path = self.root_dir.join(r.registry());
         path.push(r.repository());
-        path.push(r.tag().unwrap_or("latest"));
+        match r.digest() {
+            // Colons aren't allowed in paths on Windows
+            Some(digest) => path.push(digest.replace(':', "-")),
+            None => path.push(r.tag().unwrap_or("latest")),
+        }
         path
     }
 
-    fn pull_file_path(&self, r: &Reference) -> PathBuf {
-        self.pull_path(r).join("module.wasm")
+    fn module_digest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("module.txt")
     }
 
     fn digest_file_path(&self, r: &Reference) -> PathBuf {
         self.pull_path(r).join("digest.txt")
     }
+
+    fn data_digest_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("data.txt")
+    }
+
+    /// The directory the data layers of the image with the given manifest digest are extracted
+    /// into. Returns `None` if the digest isn't a valid SHA-256 digest
+    fn data_dir_path(&self, digest: &str) -> Option<PathBuf> {
+        Some(self.root_dir.join("data").join(sha256_hex(digest)?))
+    }
+
+    fn annotations_file_path(&self, r: &Reference) -> PathBuf {
+        self.pull_path(r).join("annotations.json")
+    }
+
+    /// Extracts the image's data layers into a directory named by the digest of its manifest, and
+    /// records that digest for the image reference. The layers are extracted in order, so files in
+    /// later layers replace those in earlier ones. A directory, once extracted, is never changed or
+    /// removed, as containers may still have it mounted when the image is pulled again
+    async fn store_data_layers(
+        &self,
+        image_ref: &Reference,
+        digest: Option<&str>,
+        layers: Vec<ImageLayer>,
+    ) -> anyhow::Result<()> {
+        let data_digest_path = self.data_digest_file_path(image_ref);
+        if layers.is_empty() {
+            match tokio::fs::remove_file(&data_digest_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => return Ok(()),
+            }
+        }
+        let digest = digest.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Image ref {} has data layers but no manifest digest",
+                image_ref
+            )
+        })?;
+        let data_dir = self.data_dir_path(digest).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid manifest digest {} for image ref {}",
+                digest,
+                image_ref
+            )
+        })?;
+        if !data_dir.exists() {
+            // Extract into a temporary directory first so a partially extracted image is never used
+            let partial_dir = data_dir.with_extension("partial");
+            match tokio::fs::remove_dir_all(&partial_dir).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            tokio::fs::create_dir_all(&partial_dir).await?;
+            let extract_dir = partial_dir.clone();
+            tokio::task::spawn_blocking(move || extract_data_layers(&layers, &extract_dir))
+                .await??;
+            tokio::fs::rename(&partial_dir, &data_dir).await?;
+        }
+        tokio::fs::write(&data_digest_path, digest).await?;
+        Ok(())
+    }
+
+    fn blobs_dir(&self) -> PathBuf {
+        self.root_dir.join("blobs").join("sha256")
+    }
+
+    /// The path of the module with the given digest. Returns `None` if the digest isn't a valid
+    /// SHA-256 digest, so a tampered digest can't point outside the store
+    fn blob_path(&self, digest: &str) -> Option<PathBuf> {
+        Some(self.blobs_dir().join(sha256_hex(digest)?))
+    }
+
+    /// The digest and path of the module the given image reference was stored with, if any
+    async fn local_module(&self, r: &Reference) -> Option<(String, PathBuf)> {
+        let digest = tokio::fs::read_to_string(self.module_digest_file_path(r))
+            .await
+            .ok()?;
+        let digest = digest.trim().to_owned();
+        let path = self.blob_path(&digest)?;
+        Some((digest, path))
+    }
+
+    fn touch(&self, path: &Path) {
+        self.last_used
+            .lock()
+            .expect("module cache access times lock poisoned")
+            .insert(path.to_owned(), SystemTime::now());
+    }
+
+    /// Removes the least recently used modules, other than `keep`, until the store is within its
+    /// maximum size. Modules that haven't been used since the store was created are ordered by
+    /// when they were written
+    async fn evict(&self, keep: &Path) -> anyhow::Result<()> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(()),
+        };
+        let mut total = 0;
+        let mut blobs = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.blobs_dir()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let last_used = self
+                .last_used
+                .lock()
+                .expect("module cache access times lock poisoned")
+                .get(&path)
+                .copied()
+                .or_else(|| metadata.modified().ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            total += metadata.len();
+            blobs.push((last_used, metadata.len(), path));
+        }
+        blobs.sort();
+
+        for (_, len, path) in blobs {
+            if total <= max_size {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            debug!(path = %path.display(), "Evicting least recently used module from cache");
+            tokio::fs::remove_file(&path).await?;
+            self.last_used
+                .lock()
+                .expect("module cache access times lock poisoned")
+                .remove(&path);
+            total -= len;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl Storer for FileStorer {
     async fn get_local(&self, image_ref: &Reference) -> anyhow::Result<Vec<u8>> {
-        let path = self.pull_file_path(image_ref);
-        if !path.exists() {
+        let not_available = || anyhow::anyhow!("Image ref {} not available locally", image_ref);
+        let (digest, path) = self
+            .local_module(image_ref)
+            .await
+            .ok_or_else(not_available)?;
+        debug!(?image_ref, "Fetching image ref from disk");
+        let data = tokio::fs::read(&path).await.map_err(|_| not_available())?;
+
+        if sha256_digest(&data) != digest {
+            // Remove the corrupt module so that it gets pulled again
+            warn!(?image_ref, %digest, "Cached module does not match its digest, removing it");
+            tokio::fs::remove_file(&path).await?;
             return Err(anyhow::anyhow!(
-                "Image ref {} not available locally",
+                "Cached module for image ref {} does not match its digest",
                 image_ref
             ));
         }
-
-        debug!(?image_ref, "Fetching image ref from disk");
-        Ok(tokio::fs::read(path).await?)
+        self.touch(&path);
+        Ok(data)
     }
     async fn store(&mut self, image_ref: &Reference, image_data: ImageData) -> anyhow::Result<()> {
         tokio::fs::create_dir_all(self.pull_path(image_ref)).await?;
@@ -84,26 +254,77 @@ impl Storer for FileStorer {
         if digest_path.exists() {
             tokio::fs::remove_file(&digest_path).await?;
         }
-        // FIXME: we need to determine the proper file path for each layer rather than assuming it's a single-layer image.
-        let module_path = self.pull_file_path(image_ref);
-        if image_data.layers.is_empty() {
+        // Data layers are extracted for the module to read, and the first other layer is the module
+        let (data_layers, mut module_layers): (Vec<_>, Vec<_>) =
+            image_data.layers.into_iter().partition(is_data_layer);
+        if module_layers.is_empty() {
             return Err(anyhow::anyhow!("No module layer present in image data"));
         }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
+        let module = &module_layers.remove(0).data;
+        let module_digest = sha256_digest(module);
+        let module_path = self
+            .blob_path(&module_digest)
+            .expect("computed digests are always valid");
+        if !module_path.exists() {
+            tokio::fs::create_dir_all(self.blobs_dir()).await?;
+            // Write to a temporary file first so a partially written module is never picked up
+            let partial_path = module_path.with_extension("partial");
+            tokio::fs::write(&partial_path, module).await?;
+            tokio::fs::rename(&partial_path, &module_path).await?;
+        }
+        self.store_data_layers(image_ref, image_data.digest.as_deref(), data_layers)
+            .await?;
+        // Annotations left from an earlier pull of the ref would describe a different image
+        let annotations_path = self.annotations_file_path(image_ref);
+        match image_data.annotations.filter(|a| !a.is_empty()) {
+            Some(annotations) => {
+                tokio::fs::write(&annotations_path, serde_json::to_vec(&annotations)?).await?
+            }
+            None if annotations_path.exists() => tokio::fs::remove_file(&annotations_path).await?,
+            None => (),
+        }
+        tokio::fs::write(self.module_digest_file_path(image_ref), &module_digest).await?;
         if let Some(d) = image_data.digest {
             tokio::fs::write(&digest_path, d).await?;
         }
-        Ok(())
+        self.touch(&module_path);
+        self.evict(&module_path).await
     }
 
     async fn is_present(&self, image_ref: &Reference) -> bool {
-        let path = self.pull_file_path(image_ref);
-        path.exists()
+        // The module may have been evicted even though the image reference is still recorded
+        match self.local_module(image_ref).await {
+            Some((_, path)) => path.exists(),
+            None => false,
+        }
     }
 
     async fn is_present_with_digest(&self, image_ref: &Reference, digest: String) -> bool {
         let path = self.digest_file_path(image_ref);
-        path.exists() && file_content_is(path, digest).await
+        path.exists() && file_content_is(path, digest).await && self.is_present(image_ref).await
+    }
+
+    async fn data_path(&self, image_ref: &Reference) -> Option<(String, PathBuf)> {
+        let digest = tokio::fs::read_to_string(self.data_digest_file_path(image_ref))
+            .await
+            .ok()?;
+        let digest = digest.trim().to_owned();
+        let path = self.data_dir_path(&digest)?;
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => Some((digest, path)),
+            _ => None,
+        }
+    }
+
+    async fn annotations(&self, image_ref: &Reference) -> HashMap<String, String> {
+        let data = match tokio::fs::read(self.annotations_file_path(image_ref)).await {
+            Ok(data) => data,
+            Err(_) => return HashMap::new(),
+        };
+        serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!(?image_ref, error = %e, "Unable to read stored image annotations");
+            HashMap::new()
+        })
     }
 }
 
@@ -116,6 +337,56 @@ impl<C: Client + Send> Clone for FileStore<C> {
     }
 }
 
+/// The hex part of a SHA-256 digest, or `None` if it isn't one, so that a tampered digest can't
+/// point outside the store
+fn sha256_hex(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:")?;
+    if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(hex)
+}
+
+/// The most entries all the data layers of an image may hold between them
+const MAX_DATA_ENTRIES: usize = 10_000;
+
+/// The most bytes the files in all the data layers of an image may add up to
+const MAX_DATA_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Extracts the given data layers into a directory, refusing images with more than
+/// [`MAX_DATA_ENTRIES`] entries or [`MAX_DATA_SIZE`] bytes of files
+fn extract_data_layers(layers: &[ImageLayer], dir: &Path) -> anyhow::Result<()> {
+    extract_data_layers_up_to(layers, dir, MAX_DATA_ENTRIES, MAX_DATA_SIZE)
+}
+
+fn extract_data_layers_up_to(
+    layers: &[ImageLayer],
+    dir: &Path,
+    max_entries: usize,
+    max_size: u64,
+) -> anyhow::Result<()> {
+    let unable = |e: std::io::Error| anyhow::anyhow!("unable to extract data layer: {}", e);
+    let mut entries = 0;
+    let mut size: u64 = 0;
+    for layer in layers {
+        let mut archive = tar::Archive::new(layer.data.as_slice());
+        for entry in archive.entries().map_err(unable)? {
+            let mut entry = entry.map_err(unable)?;
+            entries += 1;
+            if entries > max_entries {
+                anyhow::bail!("data layers hold more than {} entries", max_entries);
+            }
+            size = size.saturating_add(entry.header().size().map_err(unable)?);
+            if size > max_size {
+                anyhow::bail!("data layers hold more than {} bytes", max_size);
+            }
+            // Entries that would be written outside of the directory are skipped
+            entry.unpack_in(dir).map_err(unable)?;
+        }
+    }
+    Ok(())
+}
+
 async fn file_content_is(path: PathBuf, text: String) -> bool {
     match tokio::fs::read(path).await {
         Err(_) => false,
@@ -126,6 +397,10 @@ async fn file_content_is(path: PathBuf, text: String) -> bool {
     }
 }
 
+pub(crate) fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", sha2::Sha256::digest(bytes))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -178,6 +453,7 @@ mod test {
                     ImageData {
                         layers: vec![ImageLayer::oci_v1(content)],
                         digest: Some(digest.to_owned()),
+                        annotations: None,
                     },
                 );
             }
@@ -194,6 +470,7 @@ mod test {
                 ImageData {
                     layers: vec![ImageLayer::oci_v1(content)],
                     digest: Some(digest.to_owned()),
+                    annotations: None,
                 },
             );
         }
@@ -216,6 +493,29 @@ mod test {
         }
     }
 
+    /// A client whose registry reports a different digest to the image it serves, as when a tag
+    /// is moved while it is being pulled
+    struct MovedTagClient(FakeImageClient);
+
+    #[async_trait]
+    impl Client for MovedTagClient {
+        async fn pull(
+            &mut self,
+            image_ref: &Reference,
+            auth: &RegistryAuth,
+        ) -> anyhow::Result<ImageData> {
+            self.0.pull(image_ref, auth).await
+        }
+
+        async fn fetch_digest(
+            &mut self,
+            _image_ref: &Reference,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<String> {
+            Ok("sha256:456".to_owned())
+        }
+    }
+
     struct TemporaryDirectory {
         path: PathBuf,
     }
@@ -282,6 +582,190 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn file_module_store_rejects_moved_tag_if_policy_always() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(MovedTagClient(fake_client), &scratch_dir.path);
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await;
+        assert!(
+            module_bytes.is_err(),
+            "expected get of an image with an unexpected digest to fail but it worked"
+        );
+        let cached = store
+            .get(&fake_ref, PullPolicy::Never, &RegistryAuth::Anonymous)
+            .await;
+        assert!(cached.is_err(), "expected the image not to be stored");
+        Ok(())
+    }
+
+    fn data_layer(files: &[(&str, &[u8])]) -> anyhow::Result<ImageLayer> {
+        let mut archive = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, path, *content)?;
+        }
+        Ok(ImageLayer::new(
+            archive.into_inner()?,
+            oci_distribution::manifest::WASM_DATA_LAYER_MEDIA_TYPE.to_owned(),
+        ))
+    }
+
+    fn image_with_data(data_layer: ImageLayer, digest: &str) -> ImageData {
+        ImageData {
+            layers: vec![data_layer, ImageLayer::oci_v1(vec![1, 2, 3])],
+            digest: Some(digest.to_owned()),
+            annotations: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn file_module_store_extracts_data_layers() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![]);
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            image_with_data(data_layer(&[("assets/model.bin", b"model")])?, "sha256:123"),
+        );
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        let module_bytes = store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        let (digest, data_dir) = store
+            .get_data_path(&fake_ref)
+            .await
+            .expect("expected the data layer to be extracted");
+        assert_eq!("sha256:123", digest);
+        assert_eq!(
+            b"model".to_vec(),
+            std::fs::read(data_dir.join("assets").join("model.bin"))?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_keeps_data_in_use_when_pulling_again() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![]);
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            image_with_data(data_layer(&[("model.bin", b"old")])?, "sha256:123"),
+        );
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        let (_, old_dir) = store.get_data_path(&fake_ref).await.unwrap();
+
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            image_with_data(data_layer(&[("model.bin", b"new")])?, "sha256:456"),
+        );
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        let (digest, new_dir) = store.get_data_path(&fake_ref).await.unwrap();
+        assert_eq!("sha256:456", digest);
+        assert_ne!(old_dir, new_dir);
+        assert_eq!(b"new".to_vec(), std::fs::read(new_dir.join("model.bin"))?);
+        // A container started from the first pull may still be reading its files
+        assert_eq!(b"old".to_vec(), std::fs::read(old_dir.join("model.bin"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_forgets_data_of_image_without_data_layers() -> anyhow::Result<()> {
+        let mut fake_client = FakeImageClient::new(vec![]);
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            image_with_data(data_layer(&[("model.bin", b"model")])?, "sha256:123"),
+        );
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        fake_client.update("foo/bar:1.0", vec![4, 5, 6], "sha256:456");
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert!(store.get_data_path(&fake_ref).await.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn data_layer_extraction_is_limited() -> anyhow::Result<()> {
+        let layers = vec![
+            data_layer(&[("a", b"12345"), ("b", b"12345")])?,
+            data_layer(&[("c", b"12345")])?,
+        ];
+        let scratch_dir = create_temp_dir();
+
+        let dir = scratch_dir.path.join("entries");
+        let err = extract_data_layers_up_to(&layers, &dir, 2, 1024).unwrap_err();
+        assert!(err.to_string().contains("more than 2 entries"), "{}", err);
+
+        let dir = scratch_dir.path.join("size");
+        let err = extract_data_layers_up_to(&layers, &dir, 10, 12).unwrap_err();
+        assert!(err.to_string().contains("more than 12 bytes"), "{}", err);
+
+        let dir = scratch_dir.path.join("ok");
+        extract_data_layers_up_to(&layers, &dir, 3, 15)?;
+        assert_eq!(b"12345".to_vec(), std::fs::read(dir.join("c"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_keeps_image_annotations() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![]);
+        let annotations: HashMap<String, String> = vec![(
+            "alpha.wasi.krustlet.dev/entrypoint".to_owned(),
+            "run".to_owned(),
+        )]
+        .into_iter()
+        .collect();
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            ImageData {
+                layers: vec![ImageLayer::oci_v1(vec![1, 2, 3])],
+                digest: Some("sha256:123".to_owned()),
+                annotations: Some(annotations.clone()),
+            },
+        );
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client.clone(), &scratch_dir.path);
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert_eq!(annotations, store.get_annotations(&fake_ref).await);
+
+        // Pulling a new image for the ref drops the annotations of the old one
+        fake_client.images.write().unwrap().insert(
+            "foo/bar:1.0".to_owned(),
+            ImageData {
+                layers: vec![ImageLayer::oci_v1(vec![4, 5, 6])],
+                digest: Some("sha256:456".to_owned()),
+                annotations: None,
+            },
+        );
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        assert!(store.get_annotations(&fake_ref).await.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn file_module_store_can_reuse_cached_if_policy_never() -> anyhow::Result<()> {
         let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
@@ -434,4 +918,85 @@ mod test {
         assert_eq!(6, module_bytes_after[1]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn file_module_store_pulls_again_if_module_corrupted() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![("foo/bar:1.0", vec![1, 2, 3], "sha256:123")]);
+        let fake_ref = Reference::try_from("foo/bar:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        store
+            .get(&fake_ref, PullPolicy::Always, &RegistryAuth::Anonymous)
+            .await?;
+        let module_path = scratch_dir
+            .path
+            .join("blobs")
+            .join("sha256")
+            .join(sha256_digest(&[1, 2, 3]).trim_start_matches("sha256:"));
+        std::fs::write(&module_path, vec![9, 9])?;
+        let module_bytes = store
+            .get(
+                &fake_ref,
+                PullPolicy::IfNotPresent,
+                &RegistryAuth::Anonymous,
+            )
+            .await?;
+        assert_eq!(vec![1, 2, 3], module_bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_shares_modules_between_refs() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:1.0", vec![1, 2, 3], "sha256:123"),
+        ]);
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::new(fake_client, &scratch_dir.path);
+        for image in &["foo/bar:1.0", "foo/baz:1.0"] {
+            store
+                .get(
+                    &Reference::try_from(*image)?,
+                    PullPolicy::IfNotPresent,
+                    &RegistryAuth::Anonymous,
+                )
+                .await?;
+        }
+        let modules = std::fs::read_dir(scratch_dir.path.join("blobs").join("sha256"))?;
+        assert_eq!(1, modules.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_module_store_evicts_least_recently_used() -> anyhow::Result<()> {
+        let fake_client = FakeImageClient::new(vec![
+            ("foo/bar:1.0", vec![1, 2, 3], "sha256:123"),
+            ("foo/baz:1.0", vec![4, 5, 6], "sha256:456"),
+            ("foo/qux:1.0", vec![7, 8, 9], "sha256:789"),
+        ]);
+        let bar = Reference::try_from("foo/bar:1.0")?;
+        let baz = Reference::try_from("foo/baz:1.0")?;
+        let qux = Reference::try_from("foo/qux:1.0")?;
+        let scratch_dir = create_temp_dir();
+        let store = FileStore::with_max_size(fake_client, &scratch_dir.path, 6);
+        store
+            .get(&bar, PullPolicy::IfNotPresent, &RegistryAuth::Anonymous)
+            .await?;
+        store
+            .get(&baz, PullPolicy::IfNotPresent, &RegistryAuth::Anonymous)
+            .await?;
+        // Use bar again so baz becomes the least recently used
+        store
+            .get(&bar, PullPolicy::IfNotPresent, &RegistryAuth::Anonymous)
+            .await?;
+        store
+            .get(&qux, PullPolicy::IfNotPresent, &RegistryAuth::Anonymous)
+            .await?;
+
+        let storer = store.storer.read().await;
+        assert!(storer.is_present(&bar).await);
+        assert!(!storer.is_present(&baz).await);
+        assert!(storer.is_present(&qux).await);
+        Ok(())
+    }
 }
@@ -1,6 +1,8 @@
 //! `oci` implements different storage methods for fetching modules from an OCI registry.
 mod client;
 mod file;
+mod signature;
 
 pub use client::Client;
 pub use file::FileStore;
+pub use signature::{SignatureVerifier, REQUIRE_SIGNED_MODULES_ANNOTATION};
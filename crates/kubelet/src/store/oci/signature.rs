@@ -0,0 +1,335 @@
+//! Verification of module signatures made with [cosign](https://github.com/sigstore/cosign).
+use std::convert::TryFrom;
+
+use anyhow::Context;
+use oci_distribution::client::{ClientConfig, ClientConfigSource, ImageLayer};
+use oci_distribution::manifest::{OciDescriptor, OciManifest, WASM_LAYER_MEDIA_TYPE};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use tracing::debug;
+
+use super::client::{decompress_layer, MODULE_LAYER_MEDIA_TYPES};
+use super::file::sha256_digest;
+use crate::config::Config;
+use crate::pod::Pod;
+
+/// The pod annotation that requires the modules of the pod to be signed
+pub const REQUIRE_SIGNED_MODULES_ANNOTATION: &str = "krustlet.dev/require-signed-modules";
+
+/// The media type of the layers of a cosign signature
+const SIMPLE_SIGNING_MEDIA_TYPE: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+/// The layer annotation holding the base64 encoded signature of the layer
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+/// The DER prefix of a P-256 public key in SubjectPublicKeyInfo form, which is followed by the
+/// 65 byte uncompressed point
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// Verifies that modules were signed with cosign before they are run.
+///
+/// Signatures are checked against a P-256 public key, as generated by `cosign generate-key-pair`.
+/// Keyless signatures, which are checked against a Fulcio identity, are not supported.
+pub struct SignatureVerifier {
+    /// The config registry clients are created with. Each verification gets a client of its own,
+    /// so verifying the modules of one pod doesn't wait on another
+    client_config: ClientConfig,
+    public_key: Option<Vec<u8>>,
+    require_all: bool,
+}
+
+impl SignatureVerifier {
+    /// Creates a verifier from the kubelet config, reading the configured public key
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let public_key = config
+            .module_verification_key
+            .as_ref()
+            .map(|path| -> anyhow::Result<Vec<u8>> {
+                let pem = std::fs::read_to_string(path).with_context(|| {
+                    format!("unable to read verification key {}", path.display())
+                })?;
+                parse_public_key(&pem)
+            })
+            .transpose()?;
+        Ok(SignatureVerifier {
+            client_config: config.client_config(),
+            public_key,
+            require_all: config.require_signed_modules,
+        })
+    }
+
+    /// Whether the modules of the given pod must be signed. They must be if the node requires all
+    /// modules to be signed, or if the pod has the [`REQUIRE_SIGNED_MODULES_ANNOTATION`]
+    /// annotation set to `true`. A pod that requires signed modules on a node without a
+    /// verification key can't have them verified, so its modules are refused rather than run
+    pub fn is_required(&self, pod: &Pod) -> bool {
+        self.require_all
+            || pod
+                .annotations()
+                .get(REQUIRE_SIGNED_MODULES_ANNOTATION)
+                .map(|v| v == "true")
+                .unwrap_or(false)
+    }
+
+    /// Verifies that the module was pulled from the given image and that the image has a valid
+    /// signature, returning the digest of the manifest the signature was verified for
+    pub async fn verify(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        module: &[u8],
+    ) -> anyhow::Result<String> {
+        let public_key = self.public_key.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("signed modules are required but no verification key is configured")
+        })?;
+        let mut client = oci_distribution::Client::new(self.client_config.clone());
+
+        // The signature covers the image manifest, so make sure the module is the one the
+        // manifest refers to
+        let (manifest, digest) = client.pull_manifest(image, auth).await?;
+        let module_digest = sha256_digest(module);
+        if !manifest.layers.iter().any(|l| l.digest == module_digest)
+            && !compressed_layers_match(&client, image, &manifest, module).await?
+        {
+            anyhow::bail!(
+                "module does not match the current manifest of image {}",
+                image
+            );
+        }
+
+        let signature_ref = signature_reference(image, &digest)?;
+        let (signature_manifest, _) = client
+            .pull_manifest(&signature_ref, auth)
+            .await
+            .with_context(|| format!("no signature found for image {}", image))?;
+        let payloads = client
+            .pull(&signature_ref, auth, vec![SIMPLE_SIGNING_MEDIA_TYPE])
+            .await?;
+        for (descriptor, payload) in signature_manifest.layers.iter().zip(payloads.layers) {
+            match verify_layer(public_key, descriptor, &payload.data, &digest) {
+                Ok(()) => return Ok(digest),
+                Err(e) => debug!(error = %e, %image, "Skipping signature that did not verify"),
+            }
+        }
+        anyhow::bail!("no valid signature found for image {}", image)
+    }
+}
+
+/// Checks whether the module was decompressed from one of the compressed module layers of the
+/// manifest. The manifest records the digests of compressed layers as they are stored, so each
+/// layer is pulled again by its digest and checked against it before it is decompressed
+async fn compressed_layers_match(
+    client: &oci_distribution::Client,
+    image: &Reference,
+    manifest: &OciManifest,
+    module: &[u8],
+) -> anyhow::Result<bool> {
+    let compressed = manifest.layers.iter().filter(|l| {
+        l.media_type != WASM_LAYER_MEDIA_TYPE
+            && MODULE_LAYER_MEDIA_TYPES.contains(&l.media_type.as_str())
+    });
+    for descriptor in compressed {
+        let mut data = Vec::new();
+        client
+            .pull_layer(image, &descriptor.digest, &mut data)
+            .await?;
+        if sha256_digest(&data) != descriptor.digest {
+            continue;
+        }
+        let layer = ImageLayer::new(data, descriptor.media_type.clone());
+        let layer = tokio::task::spawn_blocking(move || decompress_layer(layer)).await??;
+        if layer.data == module {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The reference cosign stores the signature of the image with the given manifest digest at
+fn signature_reference(image: &Reference, digest: &str) -> anyhow::Result<Reference> {
+    Ok(Reference::try_from(format!(
+        "{}/{}:{}.sig",
+        image.registry(),
+        image.repository(),
+        digest.replace(':', "-")
+    ))?)
+}
+
+/// Parses a PEM encoded P-256 public key into the form ring verifies signatures with
+fn parse_public_key(pem: &str) -> anyhow::Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = base64::decode(body.trim()).context("verification key is not valid PEM")?;
+    match der.strip_prefix(P256_SPKI_PREFIX) {
+        Some(point) if point.len() == 65 => Ok(point.to_vec()),
+        _ => anyhow::bail!("verification key is not a P-256 public key"),
+    }
+}
+
+/// Verifies a single signature layer: the signature must be valid for the payload and the
+/// payload must be for the image with the given manifest digest
+fn verify_layer(
+    public_key: &[u8],
+    descriptor: &OciDescriptor,
+    payload: &[u8],
+    digest: &str,
+) -> anyhow::Result<()> {
+    let signature = descriptor
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(SIGNATURE_ANNOTATION))
+        .ok_or_else(|| anyhow::anyhow!("layer has no signature"))?;
+    let signature = base64::decode(signature)?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+        .verify(payload, &signature)
+        .map_err(|_| anyhow::anyhow!("signature is not valid"))?;
+
+    let payload: serde_json::Value = serde_json::from_slice(payload)?;
+    let signed_digest = payload
+        .pointer("/critical/image/docker-manifest-digest")
+        .and_then(|d| d.as_str());
+    if signed_digest != Some(digest) {
+        anyhow::bail!("signature is for a different image");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use std::collections::HashMap;
+
+    const DIGEST: &str = "sha256:0123456789abcdef";
+
+    fn generate_key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn signed_layer(key_pair: &EcdsaKeyPair, digest: &str) -> (OciDescriptor, Vec<u8>) {
+        let payload = serde_json::to_vec(&serde_json::json!({
+            "critical": {
+                "identity": { "docker-reference": "myregistry.io/module" },
+                "image": { "docker-manifest-digest": digest },
+                "type": "cosign container image signature"
+            },
+            "optional": null
+        }))
+        .unwrap();
+        let signature = key_pair.sign(&SystemRandom::new(), &payload).unwrap();
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            SIGNATURE_ANNOTATION.to_owned(),
+            base64::encode(signature.as_ref()),
+        );
+        let descriptor = OciDescriptor {
+            media_type: SIMPLE_SIGNING_MEDIA_TYPE.to_owned(),
+            annotations: Some(annotations),
+            ..Default::default()
+        };
+        (descriptor, payload)
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key_pair = generate_key_pair();
+        let mut der = P256_SPKI_PREFIX.to_vec();
+        der.extend_from_slice(key_pair.public_key().as_ref());
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            base64::encode(&der)
+        );
+        assert_eq!(
+            key_pair.public_key().as_ref(),
+            parse_public_key(&pem).unwrap().as_slice()
+        );
+        assert!(
+            parse_public_key("-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----").is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_layer() {
+        let key_pair = generate_key_pair();
+        let public_key = key_pair.public_key().as_ref();
+        let (descriptor, payload) = signed_layer(&key_pair, DIGEST);
+        verify_layer(public_key, &descriptor, &payload, DIGEST).unwrap();
+
+        // The signature must be for the image being run
+        verify_layer(public_key, &descriptor, &payload, "sha256:fedcba9876543210")
+            .expect_err("signature for another image should not verify");
+
+        // And made with the configured key
+        let other_key_pair = generate_key_pair();
+        verify_layer(
+            other_key_pair.public_key().as_ref(),
+            &descriptor,
+            &payload,
+            DIGEST,
+        )
+        .expect_err("signature made with another key should not verify");
+
+        // And cover the payload
+        let mut tampered = payload;
+        tampered.push(b' ');
+        verify_layer(public_key, &descriptor, &tampered, DIGEST)
+            .expect_err("tampered payload should not verify");
+    }
+
+    fn verifier(public_key: Option<Vec<u8>>, require_all: bool) -> SignatureVerifier {
+        SignatureVerifier {
+            client_config: ClientConfig::default(),
+            public_key,
+            require_all,
+        }
+    }
+
+    fn pod(annotations: serde_json::Value) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test", "annotations": annotations}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_is_required() {
+        let key = Some(generate_key_pair().public_key().as_ref().to_vec());
+        let unsigned = pod(serde_json::json!({}));
+        let signed = pod(serde_json::json!({ REQUIRE_SIGNED_MODULES_ANNOTATION: "true" }));
+        let not_signed = pod(serde_json::json!({ REQUIRE_SIGNED_MODULES_ANNOTATION: "false" }));
+
+        assert!(!verifier(key.clone(), false).is_required(&unsigned));
+        assert!(!verifier(key.clone(), false).is_required(&not_signed));
+        assert!(verifier(key.clone(), false).is_required(&signed));
+        assert!(verifier(key, true).is_required(&unsigned));
+        // A pod asking for signed modules isn't let off because the node can't verify them
+        assert!(verifier(None, false).is_required(&signed));
+    }
+
+    #[tokio::test]
+    async fn test_verify_without_key() {
+        let image = Reference::try_from("myregistry.io/module:v1").unwrap();
+        let err = verifier(None, false)
+            .verify(&image, &RegistryAuth::Anonymous, b"module")
+            .await
+            .expect_err("modules can't be verified without a key");
+        assert!(err.to_string().contains("no verification key"), "{}", err);
+    }
+
+    #[test]
+    fn test_signature_reference() {
+        let image = Reference::try_from("myregistry.io/module:v1").unwrap();
+        let signature_ref = signature_reference(&image, DIGEST).unwrap();
+        assert_eq!(
+            "myregistry.io/module:sha256-0123456789abcdef.sig",
+            signature_ref.whole()
+        );
+    }
+}
@@ -11,6 +11,10 @@ pub struct ConfigMapVolume {
     cm_name: String,
     client: kube::Api<ConfigMap>,
     items: Vec<KeyToPath>,
+    default_mode: i32,
+    /// Whether the volume is left empty, rather than failing to mount, if the ConfigMap doesn't
+    /// exist
+    optional: bool,
     mounted_path: Option<PathBuf>,
 }
 
@@ -29,6 +33,8 @@ impl ConfigMapVolume {
                 .ok_or_else(|| anyhow::anyhow!("no ConfigMap name was given"))?,
             client: Api::namespaced(client, namespace),
             items: cm_source.items.clone(),
+            default_mode: cm_source.default_mode.unwrap_or(DEFAULT_MODE),
+            optional: cm_source.optional.unwrap_or(false),
             mounted_path: None,
         })
     }
@@ -39,6 +45,21 @@ impl ConfigMapVolume {
         self.mounted_path.as_deref()
     }
 
+    /// Returns the name of the ConfigMap the volume holds the data of
+    pub fn config_map_name(&self) -> &str {
+        &self.cm_name
+    }
+
+    /// Returns the path on the host the given key of the ConfigMap is mounted at. Will return `None`
+    /// if the volume hasn't been mounted yet, or its items don't include the key
+    pub fn key_path(&self, key: &str) -> Option<PathBuf> {
+        let path = self.mounted_path.as_ref()?;
+        match mount_setting_for(key, &self.items) {
+            ItemMount::MountAt(key_path) => Some(path.join(key_path)),
+            ItemMount::DoNotMount => None,
+        }
+    }
+
     /// Mounts the ConfigMap volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
@@ -57,30 +78,53 @@ impl ConfigMapVolume {
 
     /// A function for mounting the file(s) at the given path. It mainly exists to allow the
     /// projected volumes to mount everything at the same level. The given path must be a directory
-    /// and already exist. This method will not set any permissions, so the caller is responsible
-    /// for setting permissions on the directory
+    /// and already exist. This method only sets the modes of the files it writes, so the caller is
+    /// responsible for setting permissions on the directory
     pub(crate) async fn mount_at(&mut self, path: PathBuf) -> anyhow::Result<()> {
-        let config_map = self.client.get(&self.cm_name).await?;
+        let config_map = match self.client.get(&self.cm_name).await {
+            Ok(config_map) => config_map,
+            Err(kube::Error::Api(kube::error::ErrorResponse { code: 404, .. }))
+                if self.optional =>
+            {
+                self.mounted_path = Some(path);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
         let binary_data = config_map.binary_data;
         let binary_data = binary_data
             .into_iter()
             .filter_map(
                 |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
+                    ItemMount::MountAt(mount_path) => Some((
+                        path.join(mount_path),
+                        file_mode(&key, &self.items, self.default_mode),
+                        data,
+                    )),
                     ItemMount::DoNotMount => None,
                 },
             )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
+            .map(|(file_path, mode, data)| async move {
+                tokio::fs::write(&file_path, &data).await?;
+                set_mode(&file_path, mode).await
+            });
         let binary_data = futures::future::join_all(binary_data);
 
         let data = config_map.data;
         let data = data
             .into_iter()
             .filter_map(|(key, data)| match mount_setting_for(&key, &self.items) {
-                ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
+                ItemMount::MountAt(mount_path) => Some((
+                    path.join(mount_path),
+                    file_mode(&key, &self.items, self.default_mode),
+                    data,
+                )),
                 ItemMount::DoNotMount => None,
             })
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
+            .map(|(file_path, mode, data)| async move {
+                tokio::fs::write(&file_path, &data).await?;
+                set_mode(&file_path, mode).await
+            });
         let data = futures::future::join_all(data);
 
         let (binary_data, data) = futures::future::join(binary_data, data).await;
@@ -18,6 +18,7 @@ pub struct DownwardApiVolume {
     vol_name: String,
     pod: Pod,
     items: Vec<DownwardAPIVolumeFile>,
+    default_mode: i32,
     mounted_path: Option<PathBuf>,
 }
 
@@ -34,6 +35,7 @@ impl DownwardApiVolume {
             vol_name: vol.name.clone(),
             pod,
             items: da_source.items.clone(),
+            default_mode: da_source.default_mode.unwrap_or(DEFAULT_MODE),
             mounted_path: None,
         })
     }
@@ -61,21 +63,27 @@ impl DownwardApiVolume {
 
     /// A function for mounting the file(s) at the given path. It mainly exists to allow the
     /// projected volumes to mount everything at the same level. The given path must be a directory
-    /// and already exist. This method will not set any permissions, so the caller is responsible
-    /// for setting permissions on the directory
+    /// and already exist. This method only sets the modes of the files it writes, so the caller is
+    /// responsible for setting permissions on the directory
     pub(crate) async fn mount_at(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        let default_mode = self.default_mode;
         // Mount field refs
         let field_refs = self
             .items
             .iter()
             .filter_map(|d| {
-                d.field_ref
-                    .as_ref()
-                    .map(|f| (path.join(&d.path), data_from_field_ref(f, &self.pod)))
+                d.field_ref.as_ref().map(|f| {
+                    (
+                        path.join(&d.path),
+                        d.mode.unwrap_or(default_mode),
+                        data_from_field_ref(f, &self.pod),
+                    )
+                })
             })
-            .map(|(p, res)| async move {
+            .map(|(p, mode, res)| async move {
                 let data = res?;
-                tokio::fs::write(p, &data).await.map_err(|e| e.into())
+                tokio::fs::write(&p, &data).await?;
+                set_mode(&p, mode).await.map_err(|e| e.into())
             });
         let field_refs = futures::future::join_all(field_refs);
 
@@ -84,13 +92,18 @@ impl DownwardApiVolume {
             .items
             .iter()
             .filter_map(|d| {
-                d.resource_field_ref
-                    .as_ref()
-                    .map(|f| (path.join(&d.path), data_from_resource_ref(f, &containers)))
+                d.resource_field_ref.as_ref().map(|f| {
+                    (
+                        path.join(&d.path),
+                        d.mode.unwrap_or(default_mode),
+                        data_from_resource_ref(f, &containers),
+                    )
+                })
             })
-            .map(|(p, res)| async move {
+            .map(|(p, mode, res)| async move {
                 let data = res?;
-                tokio::fs::write(p, &data).await.map_err(|e| e.into())
+                tokio::fs::write(&p, &data).await?;
+                set_mode(&p, mode).await.map_err(|e| e.into())
             });
         let resource_refs = futures::future::join_all(resource_refs);
 
@@ -670,6 +683,46 @@ mycustomannotation="{}""#,
             .expect_err("A valid path outside of metadata should fail");
     }
 
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_file_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let fake_pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test-pod", "namespace": "test"},
+            "spec": {
+                "containers": [{"name": "test-container"}],
+                "volumes": [{
+                    "name": "podinfo",
+                    "downwardAPI": {
+                        "defaultMode": 0o600,
+                        "items": [
+                            {"path": "pod_name", "fieldRef": {"fieldPath": "metadata.name"}},
+                            {
+                                "path": "pod_namespace",
+                                "fieldRef": {"fieldPath": "metadata.namespace"},
+                                "mode": 0o400
+                            }
+                        ]
+                    }
+                }]
+            }
+        }))
+        .unwrap();
+        let vol = fake_pod.volumes()[0].clone();
+        let mut downward = DownwardApiVolume::new(&vol, fake_pod).unwrap();
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().to_owned();
+        downward.mount_at(path.clone()).await.unwrap();
+        let mode = |name: &str| {
+            std::fs::metadata(path.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_eq!(mode("pod_name") & 0o777, 0o600);
+        assert_eq!(mode("pod_namespace") & 0o777, 0o400);
+    }
+
     async fn assert_content(path: PathBuf, expected: &str, message: &str) {
         let content = tokio::fs::read_to_string(path)
             .await
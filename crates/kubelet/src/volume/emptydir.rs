@@ -0,0 +1,208 @@
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::Volume as KubeVolume;
+use tracing::warn;
+
+use super::*;
+use crate::resources::quantity::{Quantity, QuantityType};
+
+/// The medium of EmptyDir volumes kept on the node's disk, the default
+const DISK_MEDIUM: &str = "";
+
+/// A type that can manage an EmptyDir volume with mounting and unmounting support. An EmptyDir is
+/// a scratch directory that lives for as long as the pod does
+pub struct EmptyDirVolume {
+    vol_name: String,
+    size_limit: Option<u64>,
+    mounted_path: Option<PathBuf>,
+}
+
+impl EmptyDirVolume {
+    /// Creates a new EmptyDir volume from a Kubernetes volume object. Passing a non-EmptyDir
+    /// volume type, a malformed `sizeLimit` or a `medium` other than the node's disk will result
+    /// in an error
+    pub fn new(vol: &KubeVolume) -> anyhow::Result<Self> {
+        let source = vol.empty_dir.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Called an EmptyDir volume constructor with a non-EmptyDir volume")
+        })?;
+        // Backing a volume with memory takes mounting a tmpfs, which the kubelet isn't always
+        // allowed to do. Writing the files to disk instead would quietly break what the pod
+        // asked for, such as keeping them off the node's storage
+        match source.medium.as_deref() {
+            None | Some(DISK_MEDIUM) => (),
+            Some(medium) => anyhow::bail!(
+                "EmptyDir volume {} has medium {:?}, but only the node's disk is supported",
+                vol.name,
+                medium
+            ),
+        }
+        let size_limit = match source.size_limit.as_ref() {
+            Some(q) => match Quantity::from_kube_quantity(QuantityType::Memory(q))? {
+                Quantity::Memory(bytes) => Some(bytes as u64),
+                // This can't happen as we explicitly asked for a memory quantity
+                Quantity::Cpu(_) => unreachable!(),
+            },
+            None => None,
+        };
+        Ok(EmptyDirVolume {
+            vol_name: vol.name.clone(),
+            size_limit,
+            mounted_path: None,
+        })
+    }
+
+    /// Returns the path where the volume is mounted on the host. Will return `None` if the volume
+    /// hasn't been mounted yet
+    pub fn get_path(&self) -> Option<&Path> {
+        self.mounted_path.as_deref()
+    }
+
+    /// Returns the maximum number of bytes the volume may hold, if a `sizeLimit` was set
+    pub fn size_limit(&self) -> Option<u64> {
+        self.size_limit
+    }
+
+    /// Mounts the EmptyDir volume in the given directory. The actual path will be
+    /// $BASE_PATH/$VOLUME_NAME
+    pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = base_path.as_ref().join(&self.vol_name);
+        tokio::fs::create_dir_all(&path).await?;
+        self.mounted_path = Some(path);
+        Ok(())
+    }
+
+    /// Returns the number of bytes currently stored in the volume. Returns 0 if the volume hasn't
+    /// been mounted yet
+    pub async fn usage(&self) -> anyhow::Result<u64> {
+        match self.mounted_path.clone() {
+            Some(p) => Ok(tokio::task::spawn_blocking(move || dir_size(&p)).await??),
+            None => Ok(0),
+        }
+    }
+
+    /// Checks the current usage of the volume against its `sizeLimit`, returning an error
+    /// describing the overage if the limit has been exceeded. Volumes without a limit always pass
+    pub async fn check_size_limit(&self) -> anyhow::Result<()> {
+        let limit = match self.size_limit {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        let usage = self.usage().await?;
+        if usage > limit {
+            anyhow::bail!(
+                "EmptyDir volume {} is using {} bytes, which exceeds its sizeLimit of {} bytes",
+                self.vol_name,
+                usage,
+                limit
+            );
+        }
+        Ok(())
+    }
+
+    /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
+    /// hasn't been mounted will log a warning, but otherwise not error
+    pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        match self.mounted_path.take() {
+            Some(p) => {
+                //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
+                #[cfg(target_family = "windows")]
+                tokio::task::spawn_blocking(|| remove_dir_all::remove_dir_all(p)).await??;
+
+                #[cfg(target_family = "unix")]
+                tokio::fs::remove_dir_all(p).await?;
+            }
+            None => {
+                warn!("Attempted to unmount EmptyDir directory that wasn't mounted, this generally shouldn't happen");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Recursively sums the size of all files under the given directory. Symlinks are not followed so
+/// a guest can't inflate (or hide) usage by pointing at files outside of the volume
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_medium() {
+        let volume = |medium: &str| -> KubeVolume {
+            serde_json::from_value(serde_json::json!({
+                "name": "scratch",
+                "emptyDir": {"medium": medium}
+            }))
+            .unwrap()
+        };
+        assert!(EmptyDirVolume::new(&volume("")).is_ok());
+        let err = EmptyDirVolume::new(&volume("Memory")).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "EmptyDir volume scratch has medium \"Memory\", but only the node's disk is supported"
+        );
+        assert!(EmptyDirVolume::new(&volume("HugePages")).is_err());
+    }
+
+    fn empty_dir_volume(size_limit: Option<&str>) -> KubeVolume {
+        serde_json::from_value(serde_json::json!({
+            "name": "scratch",
+            "emptyDir": {
+                "sizeLimit": size_limit,
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_size_limit_parsing() {
+        let vol = EmptyDirVolume::new(&empty_dir_volume(Some("1Ki"))).unwrap();
+        assert_eq!(vol.size_limit(), Some(1024));
+
+        let vol = EmptyDirVolume::new(&empty_dir_volume(None)).unwrap();
+        assert_eq!(vol.size_limit(), None);
+
+        assert!(EmptyDirVolume::new(&empty_dir_volume(Some("500m"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_past_size_limit() {
+        let base = tempfile::tempdir().unwrap();
+        let mut vol = EmptyDirVolume::new(&empty_dir_volume(Some("1Ki"))).unwrap();
+        vol.mount(base.path()).await.unwrap();
+        let path = vol.get_path().unwrap().to_owned();
+
+        tokio::fs::write(path.join("small"), vec![0u8; 512])
+            .await
+            .unwrap();
+        vol.check_size_limit()
+            .await
+            .expect("usage under the limit should pass");
+
+        tokio::fs::create_dir(path.join("nested")).await.unwrap();
+        tokio::fs::write(path.join("nested").join("big"), vec![0u8; 1024])
+            .await
+            .unwrap();
+        assert_eq!(vol.usage().await.unwrap(), 1536);
+        assert!(
+            vol.check_size_limit().await.is_err(),
+            "usage over the limit should fail"
+        );
+
+        vol.unmount().await.unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -1,21 +1,64 @@
+use std::str::FromStr;
+
 use k8s_openapi::api::core::v1::Volume as KubeVolume;
 
 use super::*;
 
+/// The checks to run on a host path before it is mounted, as given by the `type` of the volume
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostPathType {
+    /// No type was given, so the path only has to exist
+    Unset,
+    /// A directory is created at the path if nothing exists there
+    DirectoryOrCreate,
+    /// A directory must exist at the path
+    Directory,
+    /// An empty file is created at the path if nothing exists there
+    FileOrCreate,
+    /// A file must exist at the path
+    File,
+    /// A UNIX socket must exist at the path
+    Socket,
+    /// A character device must exist at the path
+    CharDevice,
+    /// A block device must exist at the path
+    BlockDevice,
+}
+
+impl FromStr for HostPathType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Ok(HostPathType::Unset),
+            "DirectoryOrCreate" => Ok(HostPathType::DirectoryOrCreate),
+            "Directory" => Ok(HostPathType::Directory),
+            "FileOrCreate" => Ok(HostPathType::FileOrCreate),
+            "File" => Ok(HostPathType::File),
+            "Socket" => Ok(HostPathType::Socket),
+            "CharDevice" => Ok(HostPathType::CharDevice),
+            "BlockDevice" => Ok(HostPathType::BlockDevice),
+            _ => Err(anyhow::anyhow!("Unknown hostPath type {}", s)),
+        }
+    }
+}
+
 /// A type that can manage a HostPath volume with mounting and unmounting support
 pub struct HostPathVolume {
     host_path: PathBuf,
+    path_type: HostPathType,
 }
 
 impl HostPathVolume {
     /// Creates a new HostPath volume from a Kubernetes volume object. Passing a non-HostPath volume
-    /// type will result in an error
+    /// type or one with an unknown `type` will result in an error
     pub fn new(vol: &KubeVolume) -> anyhow::Result<Self> {
         let source = vol.host_path.as_ref().ok_or_else(|| {
             anyhow::anyhow!("Called a HostPath volume constructor with a non-HostPath volume")
         })?;
         Ok(HostPathVolume {
             host_path: PathBuf::from(&source.path),
+            path_type: source.type_.as_deref().unwrap_or_default().parse()?,
         })
     }
 
@@ -24,10 +67,125 @@ impl HostPathVolume {
         Some(self.host_path.as_path())
     }
 
-    /// Mounts the configured host path volume. This just checks that the directory exists
+    /// Mounts the configured host path volume. This checks that the path matches the type of the
+    /// volume, creating it first for the `OrCreate` types
     pub async fn mount(&mut self) -> anyhow::Result<()> {
-        // Check the the directory exists on the host
-        tokio::fs::metadata(&self.host_path).await?;
-        Ok(())
+        let metadata = match tokio::fs::metadata(&self.host_path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match self.path_type {
+                    HostPathType::DirectoryOrCreate => {
+                        tokio::fs::create_dir_all(&self.host_path).await?
+                    }
+                    // Like Kubernetes, only the file is created and not its parent directories
+                    HostPathType::FileOrCreate => {
+                        tokio::fs::OpenOptions::new()
+                            .write(true)
+                            .create(true)
+                            .open(&self.host_path)
+                            .await?;
+                    }
+                    _ => anyhow::bail!("Host path {} does not exist", self.host_path.display()),
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        check_type(&self.host_path, &metadata.file_type(), self.path_type)
+    }
+}
+
+fn check_type(
+    path: &Path,
+    file_type: &std::fs::FileType,
+    path_type: HostPathType,
+) -> anyhow::Result<()> {
+    let matches = match path_type {
+        HostPathType::Unset => true,
+        HostPathType::DirectoryOrCreate | HostPathType::Directory => file_type.is_dir(),
+        HostPathType::FileOrCreate | HostPathType::File => file_type.is_file(),
+        HostPathType::Socket | HostPathType::CharDevice | HostPathType::BlockDevice => {
+            is_special_file(file_type, path_type)
+        }
+    };
+    if !matches {
+        anyhow::bail!(
+            "Host path {} is not of type {:?}",
+            path.display(),
+            path_type
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+fn is_special_file(file_type: &std::fs::FileType, path_type: HostPathType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match path_type {
+        HostPathType::Socket => file_type.is_socket(),
+        HostPathType::CharDevice => file_type.is_char_device(),
+        HostPathType::BlockDevice => file_type.is_block_device(),
+        _ => false,
+    }
+}
+
+// Sockets and devices can't be checked for on Windows, so they never match
+#[cfg(target_family = "windows")]
+fn is_special_file(_file_type: &std::fs::FileType, _path_type: HostPathType) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn volume(path: &Path, path_type: &str) -> HostPathVolume {
+        HostPathVolume {
+            host_path: path.to_owned(),
+            path_type: path_type.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_unknown_type_is_rejected() {
+        assert!("Directory".parse::<HostPathType>().is_ok());
+        assert!("Directroy".parse::<HostPathType>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_directory_or_create_creates_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        volume(&path, "DirectoryOrCreate").mount().await.unwrap();
+        assert!(path.is_dir());
+    }
+
+    #[tokio::test]
+    async fn test_file_or_create_creates_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data.txt");
+        volume(&path, "FileOrCreate").mount().await.unwrap();
+        assert!(path.is_file());
+    }
+
+    #[tokio::test]
+    async fn test_missing_directory_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        volume(&path, "Directory")
+            .mount()
+            .await
+            .expect_err("a missing directory should not mount");
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_type_mismatch_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        volume(tmp.path(), "File")
+            .mount()
+            .await
+            .expect_err("a directory should not mount as a file");
+        volume(tmp.path(), "Directory").mount().await.unwrap();
     }
 }
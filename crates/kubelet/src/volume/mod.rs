@@ -15,18 +15,28 @@ use crate::pod::Pod;
 
 mod configmap;
 mod downward;
+mod emptydir;
 mod hostpath;
+mod ownership;
 mod persistentvolumeclaim;
 mod projected;
 mod secret;
 
 pub use configmap::ConfigMapVolume;
 pub use downward::DownwardApiVolume;
-pub use hostpath::HostPathVolume;
+pub use emptydir::EmptyDirVolume;
+pub use hostpath::{HostPathType, HostPathVolume};
 pub use persistentvolumeclaim::PvcVolume;
 pub use projected::ProjectedVolume;
 pub use secret::SecretVolume;
 
+/// The path the service account token, the cluster's CA certificate and the pod's namespace are
+/// mounted at in each container
+pub const SERVICE_ACCOUNT_MOUNT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// The name of the volume the kubelet mounts the service account token in, for pods the API server
+/// hasn't added one to. Volume names can't contain dots, so this never clashes with the pod's own
+pub const SERVICE_ACCOUNT_VOLUME_NAME: &str = "kube-api-access.krustlet";
+
 /// A reference to a volume that can be mounted and unmounted. A `VolumeRef` should be stored
 /// alongside a pod handle as a way to manage the lifecycle of a Pod's volume. Each embedded type
 /// can be used separately as well
@@ -45,10 +55,13 @@ pub enum VolumeRef {
     /// Projected volume, a new volume type used for all projected data types (ConfigMap, Secret,
     /// and Downward API)
     Projected(ProjectedVolume),
+    /// EmptyDir volume, a scratch directory that lives for as long as the pod does
+    EmptyDir(EmptyDirVolume),
 }
 
 impl VolumeRef {
-    /// Resolves the volumes for a pod.
+    /// Resolves the volumes for a pod. This includes a volume for the pod's service account token,
+    /// named [`SERVICE_ACCOUNT_VOLUME_NAME`], if the kubelet should mount it
     pub async fn volumes_from_pod(
         pod: &Pod,
         client: &kube::Client,
@@ -61,7 +74,22 @@ impl VolumeRef {
             .map(|(vol, pr)| async move {
                 Ok((vol.name.clone(), to_volume_ref(vol, pod, client, pr).await?))
             });
-        futures::future::join_all(vols).await.into_iter().collect()
+        let mut volumes: HashMap<String, Self> = futures::future::join_all(vols)
+            .await
+            .into_iter()
+            .collect::<anyhow::Result<_>>()?;
+        if pod.automounts_service_account_token() {
+            let volume = ProjectedVolume::new(
+                &projected::service_account_volume(),
+                pod.to_owned(),
+                client.clone(),
+            )?;
+            volumes.insert(
+                SERVICE_ACCOUNT_VOLUME_NAME.to_owned(),
+                VolumeRef::Projected(volume),
+            );
+        }
+        Ok(volumes)
     }
 
     /// A convenience wrapper that calls the correct get_path method for the variant. Returns the
@@ -74,6 +102,7 @@ impl VolumeRef {
             VolumeRef::HostPath(host) => host.get_path(),
             VolumeRef::DownwardApi(d) => d.get_path(),
             VolumeRef::Projected(p) => p.get_path(),
+            VolumeRef::EmptyDir(e) => e.get_path(),
         }
     }
 
@@ -88,9 +117,29 @@ impl VolumeRef {
             // We need to clone the path here so we are sure that it is owned since this mount call
             // results in recursion
             VolumeRef::Projected(p) => p.mount(path.as_ref().to_owned()).await,
+            VolumeRef::EmptyDir(e) => e.mount(path).await,
         }
     }
 
+    /// Gives the pod's `fsGroup` ownership of the mounted volume. Host path volumes belong to the
+    /// node, so they are left as they are
+    pub async fn set_fs_group(&self, fs_group: u32) -> anyhow::Result<()> {
+        let read_only = match self {
+            VolumeRef::HostPath(_) => return Ok(()),
+            VolumeRef::PersistentVolumeClaim(_) | VolumeRef::EmptyDir(_) => false,
+            VolumeRef::ConfigMap(_)
+            | VolumeRef::Secret(_)
+            | VolumeRef::DownwardApi(_)
+            | VolumeRef::Projected(_) => true,
+        };
+        let path = match self.get_path() {
+            Some(p) => p.to_owned(),
+            None => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || ownership::set_fs_group(&path, fs_group, read_only))
+            .await?
+    }
+
     /// A convenience wrapper that calls the correct unmount function for the variant
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
         match self {
@@ -101,6 +150,7 @@ impl VolumeRef {
             VolumeRef::HostPath(_) => Ok(()),
             VolumeRef::DownwardApi(d) => d.unmount().await,
             VolumeRef::Projected(p) => p.unmount().await,
+            VolumeRef::EmptyDir(e) => e.unmount().await,
         }
     }
 }
@@ -118,6 +168,32 @@ fn mount_setting_for(key: &str, items_to_mount: &[KeyToPath]) -> ItemMount {
     }
 }
 
+/// The mode the files of ConfigMap, Secret, Downward API and Projected volumes are given if the
+/// volume doesn't specify one, matching Kubernetes
+const DEFAULT_MODE: i32 = 0o644;
+
+/// Returns the mode for the file the given key is mounted as. A mode set on the item takes
+/// precedence over the default mode for the volume
+fn file_mode(key: &str, items: &[KeyToPath], default_mode: i32) -> i32 {
+    items
+        .iter()
+        .find(|kp| kp.key == key)
+        .and_then(|kp| kp.mode)
+        .unwrap_or(default_mode)
+}
+
+#[cfg(target_family = "unix")]
+async fn set_mode(path: &Path, mode: i32) -> tokio::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode as u32 & 0o777)).await
+}
+
+// Windows has no notion of file modes, so there is nothing to set
+#[cfg(target_family = "windows")]
+async fn set_mode(_path: &Path, _mode: i32) -> tokio::io::Result<()> {
+    Ok(())
+}
+
 enum ItemMount {
     MountAt(String),
     DoNotMount,
@@ -167,9 +243,11 @@ async fn to_volume_ref(
             pod.to_owned(),
             client.clone(),
         )?))
+    } else if vol.empty_dir.is_some() {
+        Ok(VolumeRef::EmptyDir(EmptyDirVolume::new(vol)?))
     } else {
         Err(anyhow::anyhow!(
-            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, and DownwardAPI"
+            "Unsupported volume type. Currently supported types: ConfigMap, Secret, PersistentVolumeClaim, HostPath, DownwardAPI, Projected, and EmptyDir"
         ))
     }
 }
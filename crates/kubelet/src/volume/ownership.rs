@@ -0,0 +1,212 @@
+//! Handing the files of a volume to the pod's `fsGroup`, the way the kubelet does.
+use std::path::Path;
+
+/// The permissions the group is given on the files of volumes the pod can write to
+#[cfg(target_family = "unix")]
+const READ_WRITE_MASK: u32 = 0o660;
+/// The permissions the group is given on the files of volumes holding read only data
+#[cfg(target_family = "unix")]
+const READ_ONLY_MASK: u32 = 0o440;
+/// Lets members of the group list directories and makes new files inherit the group
+#[cfg(target_family = "unix")]
+const DIRECTORY_MASK: u32 = 0o2110;
+
+/// Changes the group of everything under `path` to `fs_group` and gives the group access to it.
+/// Symlinks are left alone, so the change can't reach outside of the volume. Each file is opened
+/// without following symlinks and changed through its descriptor, and the files in a directory
+/// are opened relative to it, so a file swapped for a symlink part way through is never followed
+#[cfg(target_family = "unix")]
+pub(crate) fn set_fs_group(path: &Path, fs_group: u32, read_only: bool) -> anyhow::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(NO_FOLLOW_FLAGS)
+        .open(path);
+    match file {
+        Ok(file) => set_fs_group_of(file, path, fs_group, read_only),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => Ok(()),
+        Err(e) => Err(anyhow::anyhow!("unable to open {}: {}", path.display(), e)),
+    }
+}
+
+/// Opens files without following a symlink, or blocking on a FIFO
+#[cfg(target_family = "unix")]
+const NO_FOLLOW_FLAGS: libc::c_int = libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC;
+
+#[cfg(target_family = "unix")]
+fn set_fs_group_of(
+    file: std::fs::File,
+    path: &Path,
+    fs_group: u32,
+    read_only: bool,
+) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let unable = |what: &str, e: std::io::Error| {
+        anyhow::anyhow!("unable to change the {} of {}: {}", what, path.display(), e)
+    };
+    let metadata = file.metadata()?;
+    // An owner of -1 leaves the owner as it is
+    if unsafe { libc::fchown(file.as_raw_fd(), libc::uid_t::MAX, fs_group as libc::gid_t) } != 0 {
+        return Err(unable("group", std::io::Error::last_os_error()));
+    }
+    let mut mode = metadata.permissions().mode()
+        | if read_only {
+            READ_ONLY_MASK
+        } else {
+            READ_WRITE_MASK
+        };
+    if metadata.is_dir() {
+        mode |= DIRECTORY_MASK;
+    }
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .map_err(|e| unable("permissions", e))?;
+
+    if metadata.is_dir() {
+        for name in dir_entries(&file).map_err(|e| unable("group of the files", e))? {
+            let child_path = path.join(std::ffi::OsStr::from_bytes(name.to_bytes()));
+            match open_at(&file, &name) {
+                Ok(child) => set_fs_group_of(child, &child_path, fs_group, read_only)?,
+                Err(e) if e.raw_os_error() == Some(libc::ELOOP) => (),
+                // Sockets can't be opened, but their group can be changed all the same
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    let result = unsafe {
+                        libc::fchownat(
+                            file.as_raw_fd(),
+                            name.as_ptr(),
+                            libc::uid_t::MAX,
+                            fs_group as libc::gid_t,
+                            libc::AT_SYMLINK_NOFOLLOW,
+                        )
+                    };
+                    if result != 0 {
+                        return Err(anyhow::anyhow!(
+                            "unable to change the group of {}: {}",
+                            child_path.display(),
+                            std::io::Error::last_os_error()
+                        ));
+                    }
+                }
+                Err(e) => {
+                    return Err(anyhow::anyhow!(
+                        "unable to open {}: {}",
+                        child_path.display(),
+                        e
+                    ))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens the named entry of a directory without following a symlink
+#[cfg(target_family = "unix")]
+fn open_at(dir: &std::fs::File, name: &std::ffi::CStr) -> std::io::Result<std::fs::File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_RDONLY | NO_FOLLOW_FLAGS,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+}
+
+/// The names of the entries of an open directory, other than `.` and `..`
+#[cfg(target_family = "unix")]
+fn dir_entries(dir: &std::fs::File) -> std::io::Result<Vec<std::ffi::CString>> {
+    use std::os::unix::io::AsRawFd;
+
+    // The directory stream takes ownership of the descriptor it is given, so give it a copy
+    let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let e = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe { libc::closedir(stream) };
+    Ok(names)
+}
+
+/// File groups aren't supported on this platform, so the volume is left as it is
+#[cfg(not(target_family = "unix"))]
+pub(crate) fn set_fs_group(path: &Path, _fs_group: u32, _read_only: bool) -> anyhow::Result<()> {
+    tracing::warn!(
+        path = %path.display(),
+        "fsGroup is not supported on this platform, leaving volume ownership unchanged"
+    );
+    Ok(())
+}
+
+#[cfg(all(test, target_family = "unix"))]
+mod test {
+    use super::*;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn test_set_fs_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        let file = nested.join("file");
+        std::fs::write(&file, "data").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        // Every user can change a file to a group it is in
+        let gid = std::fs::metadata(dir.path()).unwrap().gid();
+        set_fs_group(dir.path(), gid, false).unwrap();
+
+        let file_metadata = std::fs::metadata(&file).unwrap();
+        assert_eq!(file_metadata.gid(), gid);
+        assert_eq!(file_metadata.permissions().mode() & 0o7777, 0o660);
+        let dir_metadata = std::fs::metadata(&nested).unwrap();
+        assert_eq!(dir_metadata.permissions().mode() & 0o2070, 0o2070);
+    }
+
+    #[test]
+    fn test_set_fs_group_leaves_symlinks_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let volume = dir.path().join("volume");
+        std::fs::create_dir(&volume).unwrap();
+        let outside = dir.path().join("outside");
+        std::fs::write(&outside, "data").unwrap();
+        std::fs::set_permissions(&outside, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&outside, volume.join("link")).unwrap();
+
+        let gid = std::fs::metadata(dir.path()).unwrap().gid();
+        set_fs_group(&volume, gid, false).unwrap();
+        let outside_metadata = std::fs::metadata(&outside).unwrap();
+        assert_eq!(outside_metadata.permissions().mode() & 0o7777, 0o600);
+
+        // Nor is a volume that is itself a symlink followed
+        let link = dir.path().join("volume-link");
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+        set_fs_group(&link, gid, false).unwrap();
+        let outside_metadata = std::fs::metadata(&outside).unwrap();
+        assert_eq!(outside_metadata.permissions().mode() & 0o7777, 0o600);
+    }
+}
@@ -3,15 +3,17 @@ use std::path::Path;
 use either::Either;
 use k8s_openapi::api::authentication::v1::{BoundObjectReference, TokenRequest, TokenRequestSpec};
 use k8s_openapi::api::core::v1::{
-    ConfigMapVolumeSource, DownwardAPIVolumeSource, Pod as KubePod, SecretVolumeSource,
-    Volume as KubeVolume, VolumeProjection,
+    ConfigMapProjection, ConfigMapVolumeSource, DownwardAPIProjection, DownwardAPIVolumeFile,
+    DownwardAPIVolumeSource, ObjectFieldSelector, Pod as KubePod, ProjectedVolumeSource,
+    SecretVolumeSource, ServiceAccountTokenProjection, Volume as KubeVolume, VolumeProjection,
 };
 use k8s_openapi::Resource;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use super::*;
+use crate::backoff::{BackoffStrategy, ExponentialBackoffStrategy};
 
-/// A type that can manage a Secret volume with mounting and unmounting support
+/// A type that can manage a Projected volume with mounting and unmounting support
 pub struct ProjectedVolume {
     vol_name: String,
     volumes: Vec<super::VolumeRef>,
@@ -21,24 +23,36 @@ pub struct ProjectedVolume {
 
 struct ServiceAccountSource {
     file_name: String,
+    /// The mode the token file is given each time it is written
+    mode: i32,
+    issuer: TokenIssuer,
+    /// Rewrites the token before it expires, for as long as the volume is mounted
+    refresher: Option<tokio::task::JoinHandle<()>>,
+}
+
+/// Requests bound tokens for a pod's service account
+#[derive(Clone)]
+struct TokenIssuer {
     service_account_name: String,
     namespace: String,
     client: kube::Client,
-    audience: String,
+    /// The audience of the token. The API server's own audience is used if this is not set
+    audience: Option<String>,
     expiration_time: i64,
     pod_name: String,
     pod_uid: String,
 }
 
-impl ServiceAccountSource {
-    async fn mount_at(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+impl TokenIssuer {
+    /// Requests a new token, returning it along with how long until it should be refreshed
+    async fn issue(&self) -> anyhow::Result<(String, std::time::Duration)> {
         // As far as I can tell, this is the only way to access the token subresource on service accounts
         let (req, _) = TokenRequest::create_namespaced_service_account_token(
             &self.service_account_name,
             &self.namespace,
             &TokenRequest {
                 spec: TokenRequestSpec {
-                    audiences: vec![self.audience.clone()],
+                    audiences: self.audience.iter().cloned().collect(),
                     expiration_seconds: Some(self.expiration_time),
                     bound_object_ref: Some(BoundObjectReference {
                         api_version: Some(KubePod::API_VERSION.to_owned()),
@@ -53,20 +67,98 @@ impl ServiceAccountSource {
         )?;
         // Get the token from the API
         let token_resp: TokenRequest = self.client.request(req).await?;
-        let mount_path = path.as_ref().join(&self.file_name);
-
-        let token = token_resp
+        let status = token_resp
             .status
-            .ok_or_else(|| anyhow::anyhow!("Service account token was not issued"))?
-            .token;
-        tokio::fs::write(&mount_path, token).await?;
+            .ok_or_else(|| anyhow::anyhow!("Service account token was not issued"))?;
+        // The API server can issue tokens that live longer than requested, so refresh based on
+        // when the token actually expires
+        let lifetime = (status.expiration_timestamp.0 - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        Ok((status.token, refresh_after(lifetime)))
+    }
+}
+
+/// How long to wait before refreshing a token with the given lifetime. Like the kubelet, tokens
+/// are refreshed once 80% of their lifetime has passed, or after a day if that is sooner
+fn refresh_after(lifetime: std::time::Duration) -> std::time::Duration {
+    lifetime
+        .mul_f64(TOKEN_REFRESH_FRACTION)
+        .min(MAX_TOKEN_REFRESH_INTERVAL)
+}
+
+/// Writes the token to a temporary file next to the path and moves it into place, so a module
+/// reading the token never sees it half written. The file has its mode set before it is moved, so
+/// the token never has the wrong mode either
+async fn write_token(path: &Path, token: &str, mode: i32) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid token path {}", path.display()))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    tokio::fs::write(&temp_path, token).await?;
+    set_mode(&temp_path, mode).await?;
+    tokio::fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+impl ServiceAccountSource {
+    async fn mount_at(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let (token, refresh_in) = self.issuer.issue().await?;
+        let mount_path = path.as_ref().join(&self.file_name);
+        // The token can be mounted at a nested path, so make sure the parent exists
+        if let Some(parent) = mount_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_token(&mount_path, &token, self.mode).await?;
 
-        // TODO(thomastaylor312): Right now we don't automatically rotate the token. We should
-        // probably spawn a task as part of this VolumeRef to auto-rotate the token that drops along
-        // with the rest of the ProjectedVolume type
+        if let Some(refresher) = self.refresher.take() {
+            refresher.abort();
+        }
+        let issuer = self.issuer.clone();
+        let mode = self.mode;
+        self.refresher = Some(tokio::spawn(async move {
+            let mut refresh_in = refresh_in;
+            let mut backoff = ExponentialBackoffStrategy::default().with_jitter(0.2);
+            loop {
+                tokio::time::sleep(refresh_in).await;
+                match issuer.issue().await {
+                    Ok((token, next)) => match write_token(&mount_path, &token, mode).await {
+                        Ok(()) => {
+                            debug!(path = %mount_path.display(), "Refreshed service account token");
+                            backoff.reset();
+                            refresh_in = next;
+                        }
+                        Err(e) => {
+                            warn!(error = %e, path = %mount_path.display(), "Unable to write refreshed service account token");
+                            refresh_in = backoff.next_duration();
+                        }
+                    },
+                    Err(e) => {
+                        warn!(error = %e, service_account = %issuer.service_account_name, "Unable to refresh service account token");
+                        refresh_in = backoff.next_duration();
+                    }
+                }
+            }
+        }));
 
         Ok(())
     }
+
+    /// Stops refreshing the token
+    fn stop_refreshing(&mut self) {
+        if let Some(refresher) = self.refresher.take() {
+            refresher.abort();
+        }
+    }
+}
+
+impl Drop for ServiceAccountSource {
+    fn drop(&mut self) {
+        self.stop_refreshing();
+    }
 }
 
 impl ProjectedVolume {
@@ -82,7 +174,7 @@ impl ProjectedVolume {
             .sources
             .iter()
             .map(|proj| (client.clone(), proj))
-            .map(|(c, proj)| to_volume_ref(c, &pod, proj))
+            .map(|(c, proj)| to_volume_ref(c, &pod, proj, source.default_mode))
             .collect::<anyhow::Result<Vec<Either<_, _>>>>()?
             .into_iter()
         {
@@ -105,7 +197,7 @@ impl ProjectedVolume {
         self.mounted_path.as_deref()
     }
 
-    /// Mounts the Secret volume in the given directory. The actual path will be
+    /// Mounts the Projected volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     #[async_recursion::async_recursion]
     pub async fn mount<P: AsRef<Path> + Send + 'static>(
@@ -156,6 +248,9 @@ impl ProjectedVolume {
     /// Unmounts the directory, which removes all files. Calling `unmount` on a directory that
     /// hasn't been mounted will log a warning, but otherwise not error
     pub async fn unmount(&mut self) -> anyhow::Result<()> {
+        for sa in self.service_accounts.iter_mut() {
+            sa.stop_refreshing();
+        }
         match self.mounted_path.take() {
             Some(p) => {
                 //although remove_dir_all crate could default to std::fs::remove_dir_all for unix family, we still prefer std::fs implemetation for unix
@@ -173,17 +268,72 @@ impl ProjectedVolume {
     }
 }
 
-// Default audience should be the apiserver as specified here:
-// https://kubernetes.io/docs/reference/kubernetes-api/config-and-storage-resources/volume/#projections
-const DEFAULT_AUDIENCE: &str = "api";
 // Default expiration time for a token is 1 hour as specified here:
 // https://kubernetes.io/docs/reference/kubernetes-api/config-and-storage-resources/volume/#projections
 const DEFAULT_EXPIRATION_SECONDS: i64 = 3600;
+/// The fraction of a token's lifetime after which it is refreshed
+const TOKEN_REFRESH_FRACTION: f64 = 0.8;
+/// The longest a token goes without being refreshed, however long it lives
+const MAX_TOKEN_REFRESH_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(24 * 60 * 60);
+
+/// The expiration the API server gives the service account tokens it adds to pods, which is a
+/// little over an hour so tokens aren't all refreshed at the same moment
+const SERVICE_ACCOUNT_EXPIRATION_SECONDS: i64 = 3607;
+
+/// A volume equivalent to the one the API server adds to pods for their service account, holding
+/// the token, the cluster's CA certificate and the pod's namespace
+pub(crate) fn service_account_volume() -> KubeVolume {
+    KubeVolume {
+        name: SERVICE_ACCOUNT_VOLUME_NAME.to_owned(),
+        projected: Some(ProjectedVolumeSource {
+            default_mode: Some(0o644),
+            sources: vec![
+                VolumeProjection {
+                    service_account_token: Some(ServiceAccountTokenProjection {
+                        path: "token".to_owned(),
+                        expiration_seconds: Some(SERVICE_ACCOUNT_EXPIRATION_SECONDS),
+                        audience: None,
+                    }),
+                    ..Default::default()
+                },
+                VolumeProjection {
+                    config_map: Some(ConfigMapProjection {
+                        name: Some("kube-root-ca.crt".to_owned()),
+                        items: vec![KeyToPath {
+                            key: "ca.crt".to_owned(),
+                            path: "ca.crt".to_owned(),
+                            mode: None,
+                        }],
+                        // Clusters older than 1.20 don't publish their CA certificate this way
+                        optional: Some(true),
+                    }),
+                    ..Default::default()
+                },
+                VolumeProjection {
+                    downward_api: Some(DownwardAPIProjection {
+                        items: vec![DownwardAPIVolumeFile {
+                            path: "namespace".to_owned(),
+                            field_ref: Some(ObjectFieldSelector {
+                                api_version: Some("v1".to_owned()),
+                                field_path: "metadata.namespace".to_owned(),
+                            }),
+                            ..Default::default()
+                        }],
+                    }),
+                    ..Default::default()
+                },
+            ],
+        }),
+        ..Default::default()
+    }
+}
 
 fn to_volume_ref(
     client: kube::Client,
     pod: &Pod, // take a borrowed reference to the pod so we only clone when needed
     proj: &VolumeProjection,
+    default_mode: Option<i32>,
 ) -> anyhow::Result<Either<super::VolumeRef, ServiceAccountSource>> {
     // Assemble a volume type to use in constructing each of our VolumeRefs
     if let Some(s) = proj.secret.as_ref() {
@@ -194,7 +344,7 @@ fn to_volume_ref(
                 items: s.items.to_owned(),
                 secret_name: s.name.to_owned(),
                 optional: s.optional.to_owned(),
-                ..Default::default()
+                default_mode,
             }),
             ..Default::default()
         };
@@ -211,7 +361,7 @@ fn to_volume_ref(
                 items: cm.items.to_owned(),
                 name: cm.name.to_owned(),
                 optional: cm.optional.to_owned(),
-                ..Default::default()
+                default_mode,
             }),
             ..Default::default()
         };
@@ -226,7 +376,7 @@ fn to_volume_ref(
             name: "downwardapi-projection".into(),
             downward_api: Some(DownwardAPIVolumeSource {
                 items: d.items.to_owned(),
-                ..Default::default()
+                default_mode,
             }),
             ..Default::default()
         };
@@ -234,17 +384,67 @@ fn to_volume_ref(
             DownwardApiVolume::new(&vol, pod.to_owned())?,
         )))
     } else if let Some(sa) = proj.service_account_token.as_ref() {
-        Ok(Either::Right(ServiceAccountSource{
+        Ok(Either::Right(ServiceAccountSource {
             file_name: sa.path.to_owned(),
-            service_account_name: pod.service_account_name().ok_or_else(|| anyhow::anyhow!("Unable to create a service account token projection. The pod is missing a service account"))?.to_owned(),
-            namespace: pod.namespace().to_owned(),
-            client,
-            audience: sa.audience.to_owned().unwrap_or_else(|| String::from(DEFAULT_AUDIENCE)),
-            expiration_time: sa.expiration_seconds.unwrap_or(DEFAULT_EXPIRATION_SECONDS),
-            pod_name: pod.name().to_owned(),
-            pod_uid: pod.pod_uid().to_owned(),
+            mode: default_mode.unwrap_or(DEFAULT_MODE),
+            issuer: TokenIssuer {
+                service_account_name: pod.service_account_name().ok_or_else(|| anyhow::anyhow!("Unable to create a service account token projection. The pod is missing a service account"))?.to_owned(),
+                namespace: pod.namespace().to_owned(),
+                client,
+                // An empty audience means the API server's, as specified here:
+                // https://kubernetes.io/docs/reference/kubernetes-api/config-and-storage-resources/volume/#projections
+                audience: sa.audience.to_owned().filter(|a| !a.is_empty()),
+                expiration_time: sa.expiration_seconds.unwrap_or(DEFAULT_EXPIRATION_SECONDS),
+                pod_name: pod.name().to_owned(),
+                pod_uid: pod.pod_uid().to_owned(),
+            },
+            refresher: None,
         }))
     } else {
         Err(anyhow::anyhow!("No source specified in projected source"))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_refresh_after() {
+        assert_eq!(
+            refresh_after(Duration::from_secs(3600)),
+            Duration::from_secs(2880)
+        );
+        assert_eq!(
+            refresh_after(Duration::from_secs(7 * 24 * 60 * 60)),
+            MAX_TOKEN_REFRESH_INTERVAL
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_token_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        write_token(&path, "first", 0o644).await.unwrap();
+        write_token(&path, "second", 0o644).await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "second");
+        let entries = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(entries, 1);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_write_token_sets_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        write_token(&path, "first", 0o400).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o400);
+        // The mode is set again on every rewrite, even though the file is replaced
+        write_token(&path, "second", 0o440).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o440);
+    }
+}
@@ -2,7 +2,7 @@ use std::path::Path;
 
 use k8s_openapi::api::core::v1::{KeyToPath, Secret, Volume as KubeVolume};
 use k8s_openapi::ByteString;
-use tracing::warn;
+use tracing::{debug, warn};
 
 use super::*;
 
@@ -12,6 +12,8 @@ pub struct SecretVolume {
     sec_name: String,
     client: kube::Api<Secret>,
     items: Vec<KeyToPath>,
+    default_mode: i32,
+    optional: bool,
     mounted_path: Option<PathBuf>,
 }
 
@@ -30,6 +32,8 @@ impl SecretVolume {
                 .ok_or_else(|| anyhow::anyhow!("Secret volume does not have a name"))?,
             client: Api::namespaced(client, namespace),
             items: sec_source.items.clone(),
+            default_mode: sec_source.default_mode.unwrap_or(DEFAULT_MODE),
+            optional: sec_source.optional.unwrap_or(false),
             mounted_path: None,
         })
     }
@@ -40,6 +44,21 @@ impl SecretVolume {
         self.mounted_path.as_deref()
     }
 
+    /// Returns the name of the Secret the volume holds the data of
+    pub fn secret_name(&self) -> &str {
+        &self.sec_name
+    }
+
+    /// Returns the path on the host the given key of the Secret is mounted at. Will return `None`
+    /// if the volume hasn't been mounted yet, or its items don't include the key
+    pub fn key_path(&self, key: &str) -> Option<PathBuf> {
+        let path = self.mounted_path.as_ref()?;
+        match mount_setting_for(key, &self.items) {
+            ItemMount::MountAt(key_path) => Some(path.join(key_path)),
+            ItemMount::DoNotMount => None,
+        }
+    }
+
     /// Mounts the Secret volume in the given directory. The actual path will be
     /// $BASE_PATH/$VOLUME_NAME
     pub async fn mount(&mut self, base_path: impl AsRef<Path>) -> anyhow::Result<()> {
@@ -58,21 +77,42 @@ impl SecretVolume {
 
     /// A function for mounting the file(s) at the given path. It mainly exists to allow the
     /// projected volumes to mount everything at the same level. The given path must be a directory
-    /// and already exist. This method will not set any permissions, so the caller is responsible
-    /// for setting permissions on the directory
+    /// and already exist. This method only sets the modes of the files it writes, so the caller is
+    /// responsible for setting permissions on the directory
     pub(crate) async fn mount_at(&mut self, path: PathBuf) -> anyhow::Result<()> {
-        let secret = self.client.get(&self.sec_name).await?;
+        let secret = match self.client.get(&self.sec_name).await {
+            Ok(secret) => secret,
+            Err(kube::Error::Api(kube::error::ErrorResponse { code: 404, .. }))
+                if self.optional =>
+            {
+                debug!(secret = %self.sec_name, "Optional secret not found, mounting an empty volume");
+                self.mounted_path = Some(path);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         let data = secret.data;
         let data = data
             .into_iter()
             .filter_map(
                 |(key, ByteString(data))| match mount_setting_for(&key, &self.items) {
-                    ItemMount::MountAt(mount_path) => Some((path.join(mount_path), data)),
+                    ItemMount::MountAt(mount_path) => Some((
+                        path.join(mount_path),
+                        file_mode(&key, &self.items, self.default_mode),
+                        data,
+                    )),
                     ItemMount::DoNotMount => None,
                 },
             )
-            .map(|(file_path, data)| async move { tokio::fs::write(file_path, &data).await });
+            .map(|(file_path, mode, data)| async move {
+                // Items can be mounted at nested paths, so make sure the parent exists
+                if let Some(parent) = file_path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(&file_path, &data).await?;
+                set_mode(&file_path, mode).await
+            });
         futures::future::join_all(data)
             .await
             .into_iter()
@@ -108,3 +148,54 @@ impl SecretVolume {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_file_mode() {
+        let items = vec![
+            KeyToPath {
+                key: "tls.crt".into(),
+                path: "certs/tls.crt".into(),
+                mode: None,
+            },
+            KeyToPath {
+                key: "tls.key".into(),
+                path: "certs/tls.key".into(),
+                mode: Some(0o400),
+            },
+        ];
+        assert_eq!(0o400, file_mode("tls.key", &items, DEFAULT_MODE));
+        assert_eq!(0o440, file_mode("tls.crt", &items, 0o440));
+        assert_eq!(DEFAULT_MODE, file_mode("ca.crt", &[], DEFAULT_MODE));
+    }
+
+    #[tokio::test]
+    async fn test_key_path() {
+        let vol: KubeVolume = serde_json::from_value(serde_json::json!({
+            "name": "certs",
+            "secret": {
+                "secretName": "client-cert",
+                "items": [{"key": "tls.crt", "path": "certs/tls.crt"}]
+            }
+        }))
+        .unwrap();
+        let client =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:8080".parse().unwrap()))
+                .unwrap();
+        let mut volume = SecretVolume::new(&vol, "default", client).unwrap();
+        assert_eq!("client-cert", volume.secret_name());
+        assert_eq!(None, volume.key_path("tls.crt"));
+
+        volume.mounted_path = Some(PathBuf::from("/volumes/certs"));
+        assert_eq!(
+            Some(PathBuf::from("/volumes/certs/certs/tls.crt")),
+            volume.key_path("tls.crt")
+        );
+        // Keys not among the items aren't mounted
+        assert_eq!(None, volume.key_path("tls.key"));
+    }
+}
@@ -1,26 +1,39 @@
 //! Server is an HTTP(S) server for answering Kubelet callbacks.
 //!
-//! Logs and exec calls are the main things that a server should handle.
+//! Logs, exec and port forward calls are the main things that a server should handle, along with
+//! the stats summary metrics-server scrapes.
 
 use crate::config::ServerConfig;
+use crate::exec::{self, Options as ExecOptions, Output as ExecOutput};
 use crate::log::{Options, Sender};
+use crate::port_forward::{self, Options as PortForwardOptions};
 use crate::provider::{NotImplementedError, Provider};
+use crate::stats::{self, Summary};
+use futures::{SinkExt, StreamExt};
+use http::header::{HeaderValue, CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL};
 use http::status::StatusCode;
 use http::Response;
 use hyper::Body;
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
-use tracing::{debug, error, instrument};
-use warp::Filter;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, instrument};
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Reply};
 
 const PING: &str = "this is the Krustlet HTTP server";
 
 /// Start the Krustlet HTTP(S) server
 ///
-/// This is a primitive implementation of an HTTP provider for the internal API.
+/// This is a primitive implementation of an HTTP provider for the internal API. `ready` is sent
+/// once the server is bound and accepting connections.
 pub(crate) async fn start<T: Provider>(
     provider: Arc<T>,
     config: &ServerConfig,
+    node_name: &str,
+    ready: oneshot::Sender<()>,
 ) -> anyhow::Result<()> {
     let health = warp::get().and(warp::path("healthz")).map(|| PING);
     let ping = warp::get().and(warp::path::end()).map(|| PING);
@@ -35,21 +48,59 @@ pub(crate) async fn start<T: Provider>(
         });
 
     let exec_provider = provider.clone();
-    let exec = warp::post()
+    let exec = warp::get()
+        .or(warp::post())
+        .unify()
         .and(warp::path!("exec" / String / String / String))
-        .and_then(move |namespace, pod, container| {
-            let provider = exec_provider.clone();
-            post_exec(provider, namespace, pod, container)
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::ws().map(Some).or(warp::any().map(|| None)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and_then(
+            move |namespace, pod, container, query: String, ws, protocols| {
+                let provider = exec_provider.clone();
+                let opts = ExecOptions::from_query(&query);
+                post_exec(provider, namespace, pod, container, opts, ws, protocols)
+            },
+        );
+
+    let port_forward_provider = provider.clone();
+    let port_forward = warp::get()
+        .or(warp::post())
+        .unify()
+        .and(warp::path!("portForward" / String / String))
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::ws().map(Some).or(warp::any().map(|| None)).unify())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and_then(move |namespace, pod, query: String, ws, protocols| {
+            let provider = port_forward_provider.clone();
+            post_port_forward(provider, namespace, pod, query, ws, protocols)
+        });
+
+    let stats_provider = provider.clone();
+    let node_name = node_name.to_owned();
+    let stats_summary = warp::get()
+        .and(warp::path!("stats" / "summary"))
+        .and_then(move || {
+            let provider = stats_provider.clone();
+            get_stats_summary(provider, node_name.clone())
         });
 
-    let routes = ping.or(health).or(logs).or(exec);
+    let routes = ping
+        .or(health)
+        .or(logs)
+        .or(exec)
+        .or(port_forward)
+        .or(stats_summary);
 
-    warp::serve(routes)
+    let (addr, server) = warp::serve(routes)
         .tls()
         .cert_path(&config.cert_file)
         .key_path(&config.private_key_file)
-        .run((config.addr, config.port))
-        .await;
+        .bind_ephemeral((config.addr, config.port));
+    info!(%addr, "Kubelet server is listening");
+    // Nothing is waiting for the server if the kubelet is already shutting down
+    let _ = ready.send(());
+    server.await;
     Ok(())
 }
 
@@ -87,19 +138,344 @@ async fn get_container_logs<T: Provider>(
     }
 }
 
+/// Get the resource usage of the node and the pods running on it.
+///
+/// Implements the kubelet path /stats/summary. The node's usage is that of the host, and the
+/// usage of each pod is what the provider reports
+#[instrument(level = "debug", skip(provider))]
+async fn get_stats_summary<T: Provider>(
+    provider: Arc<T>,
+    node_name: String,
+) -> Result<Response<Body>, Infallible> {
+    debug!("Got stats summary request");
+    let pods = match provider.pod_stats().await {
+        Ok(pods) => pods,
+        Err(e) if e.is::<NotImplementedError>() => {
+            return Ok(return_with_code(
+                StatusCode::NOT_IMPLEMENTED,
+                "Pod stats not implemented in provider.".to_owned(),
+            ))
+        }
+        Err(e) => {
+            error!(error = %e, "Error fetching pod stats");
+            return Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ));
+        }
+    };
+    let summary = Summary {
+        node: stats::node_stats(&node_name, chrono::Utc::now()),
+        pods,
+    };
+    let body = match serde_json::to_string(&summary) {
+        Ok(body) => body,
+        Err(e) => {
+            return Ok(return_with_code(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Server error: {}", e),
+            ))
+        }
+    };
+    let mut response = Response::new(Body::from(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(response)
+}
+
 /// Run a pod exec command and get the output
 ///
-/// Implements the kubelet path /exec/{namespace}/{pod}/{container}
+/// Implements the kubelet path /exec/{namespace}/{pod}/{container}. Only non-interactive commands
+/// are supported. Requests that ask for a websocket get the output back over the Kubernetes channel
+/// protocol once the command has finished. Plain requests get a JSON object with the output and
+/// exit code of the command.
+#[instrument(level = "info", skip(provider, opts, ws, protocols))]
 async fn post_exec<T: Provider>(
-    _provider: Arc<T>,
-    _namespace: String,
-    _pod: String,
-    _container: String,
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    container: String,
+    opts: ExecOptions,
+    ws: Option<Ws>,
+    protocols: Option<String>,
+) -> Result<Response<Body>, Infallible> {
+    debug!(command = ?opts.command(), "Got container exec request");
+    if opts.command().is_empty() {
+        return Ok(return_with_code(
+            StatusCode::BAD_REQUEST,
+            "No command given to exec.".to_owned(),
+        ));
+    }
+    if opts.stdin() || opts.tty() {
+        debug!("Interactive exec is not supported, running command without input or a TTY");
+    }
+    let command = opts.command().to_vec();
+
+    let ws = match ws {
+        Some(ws) => ws,
+        None => {
+            let result = provider.exec(namespace, pod, container, command).await;
+            return Ok(exec_response(result));
+        }
+    };
+    let protocol = channel_protocol(protocols.as_deref());
+    let mut response = ws
+        .on_upgrade(move |socket| async move {
+            let result = provider.exec(namespace, pod, container, command).await;
+            send_exec_output(socket, protocol, &opts, result).await
+        })
+        .into_response();
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+    Ok(response)
+}
+
+/// Picks the channel protocol to use from those offered by the client, preferring v4 as it can
+/// report the exit code of exec commands
+fn channel_protocol(offered: Option<&str>) -> &'static str {
+    let v4_offered = offered
+        .map(|o| o.split(',').any(|p| p.trim() == exec::V4_CHANNEL_PROTOCOL))
+        .unwrap_or(false);
+    if v4_offered {
+        exec::V4_CHANNEL_PROTOCOL
+    } else {
+        exec::CHANNEL_PROTOCOL
+    }
+}
+
+/// Builds the response to a plain (non-websocket) exec request
+fn exec_response(result: anyhow::Result<ExecOutput>) -> Response<Body> {
+    match result {
+        Ok(output) => {
+            let body = serde_json::json!({
+                "stdout": String::from_utf8_lossy(&output.stdout),
+                "stderr": String::from_utf8_lossy(&output.stderr),
+                "exitCode": output.exit_code,
+            });
+            let mut response = Response::new(Body::from(body.to_string()));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => {
+            error!(error = %e, "Error running exec command");
+            if e.is::<NotImplementedError>() {
+                return_with_code(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "Exec not implemented in provider.".to_owned(),
+                )
+            } else {
+                return_with_code(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Server error: {}", e),
+                )
+            }
+        }
+    }
+}
+
+/// Sends the output of an exec command over a websocket using the given channel protocol, then
+/// closes the socket
+async fn send_exec_output(
+    mut socket: WebSocket,
+    protocol: &str,
+    opts: &ExecOptions,
+    result: anyhow::Result<ExecOutput>,
+) {
+    let v4 = protocol == exec::V4_CHANNEL_PROTOCOL;
+    let mut frames = Vec::new();
+    match result {
+        Ok(output) => {
+            if opts.stdout() && !output.stdout.is_empty() {
+                frames.push(exec::frame(exec::STDOUT_CHANNEL, &output.stdout));
+            }
+            if opts.stderr() && !output.stderr.is_empty() {
+                frames.push(exec::frame(exec::STDERR_CHANNEL, &output.stderr));
+            }
+            if v4 {
+                let status = output.status().to_string();
+                frames.push(exec::frame(exec::ERROR_CHANNEL, status.as_bytes()));
+            } else if output.exit_code != 0 {
+                let message = format!(
+                    "command terminated with non-zero exit code: {}",
+                    output.exit_code
+                );
+                frames.push(exec::frame(exec::ERROR_CHANNEL, message.as_bytes()));
+            }
+        }
+        Err(e) => {
+            error!(error = %e, "Error running exec command");
+            let message = format!("Unable to run command in container: {}", e);
+            let message = if v4 {
+                exec::error_status(&message).to_string()
+            } else {
+                message
+            };
+            frames.push(exec::frame(exec::ERROR_CHANNEL, message.as_bytes()));
+        }
+    }
+
+    for frame in frames {
+        if let Err(e) = socket.send(Message::binary(frame)).await {
+            debug!(error = %e, "Exec stream closed before all output was sent");
+            return;
+        }
+    }
+    if let Err(e) = socket.close().await {
+        debug!(error = %e, "Unable to close exec stream");
+    }
+}
+
+/// Forward ports of a pod
+///
+/// Implements the kubelet path /portForward/{namespace}/{pod}. Only websocket requests are
+/// supported. Each port given in the query string is connected to when the websocket opens, and
+/// the connections are multiplexed over the websocket until it is closed.
+#[instrument(level = "info", skip(provider, query, ws, protocols))]
+async fn post_port_forward<T: Provider>(
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    query: String,
+    ws: Option<Ws>,
+    protocols: Option<String>,
 ) -> Result<Response<Body>, Infallible> {
-    Ok(return_with_code(
-        StatusCode::NOT_IMPLEMENTED,
-        "Exec not implemented.".to_string(),
-    ))
+    let opts = match PortForwardOptions::from_query(&query) {
+        Ok(opts) => opts,
+        Err(e) => return Ok(return_with_code(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+    debug!(ports = ?opts.ports(), "Got port forward request");
+    if opts.ports().is_empty() {
+        return Ok(return_with_code(
+            StatusCode::BAD_REQUEST,
+            "No ports given to forward.".to_owned(),
+        ));
+    }
+    let ws = match ws {
+        Some(ws) => ws,
+        None => {
+            return Ok(return_with_code(
+                StatusCode::BAD_REQUEST,
+                "Port forwarding requires a websocket.".to_owned(),
+            ))
+        }
+    };
+
+    let protocol = channel_protocol(protocols.as_deref());
+    let mut response = ws
+        .on_upgrade(move |socket| forward_ports(socket, provider, namespace, pod, opts))
+        .into_response();
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+    Ok(response)
+}
+
+/// Multiplexes connections to the requested ports of a pod over a websocket until the client
+/// closes it. Ports that can't be connected to are reported on their error channel, without
+/// affecting the other ports
+async fn forward_ports<T: Provider>(
+    socket: WebSocket,
+    provider: Arc<T>,
+    namespace: String,
+    pod: String,
+    opts: PortForwardOptions,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (frames, mut outgoing) = mpsc::channel::<Vec<u8>>(32);
+    let sender = tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if let Err(e) = sink.send(Message::binary(frame)).await {
+                debug!(error = %e, "Port forward stream closed");
+                return;
+            }
+        }
+        if let Err(e) = sink.close().await {
+            debug!(error = %e, "Unable to close port forward stream");
+        }
+    });
+
+    let mut writers = HashMap::new();
+    let mut readers = Vec::new();
+    for (index, port) in opts.ports().iter().copied().enumerate() {
+        let data_channel = port_forward::data_channel(index);
+        let error_channel = port_forward::error_channel(index);
+        for channel in &[data_channel, error_channel] {
+            // The receiver only goes away if the stream is closed, which ends forwarding below
+            let _ = frames.send(port_forward::port_frame(*channel, port)).await;
+        }
+
+        let connection = match provider
+            .port_forward(namespace.clone(), pod.clone(), port)
+            .await
+        {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!(error = %e, port, "Unable to forward port");
+                let message = format!("Unable to forward port {}: {}", port, e);
+                let _ = frames
+                    .send(exec::frame(error_channel, message.as_bytes()))
+                    .await;
+                continue;
+            }
+        };
+        let (mut read, write) = connection.into_split();
+        writers.insert(data_channel, (port, write));
+        let frames = frames.clone();
+        readers.push(tokio::spawn(async move {
+            let mut buf = vec![0; 8192];
+            loop {
+                match read.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if frames
+                            .send(exec::frame(data_channel, &buf[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!(error = %e, port, "Forwarded connection closed");
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+
+    while let Some(message) = stream.next().await {
+        let message = match message {
+            Ok(message) if message.is_close() => break,
+            Ok(message) => message,
+            Err(e) => {
+                debug!(error = %e, "Port forward stream closed");
+                break;
+            }
+        };
+        let (channel, data) = match message.as_bytes().split_first() {
+            Some((channel, data)) if !data.is_empty() => (*channel, data),
+            _ => continue,
+        };
+        if let Some((port, write)) = writers.get_mut(&channel) {
+            if let Err(e) = write.write_all(data).await {
+                debug!(error = %e, port = *port, "Unable to write to forwarded connection");
+                writers.remove(&channel);
+            }
+        }
+    }
+
+    // Closing the websocket stops forwarding, so close every connection that is still open
+    drop(writers);
+    for reader in readers {
+        reader.abort();
+    }
+    drop(frames);
+    let _ = sender.await;
 }
 
 fn return_with_code(code: StatusCode, body: String) -> Response<Body> {
@@ -32,6 +32,8 @@ pub struct ImageData {
     pub layers: Vec<ImageLayer>,
     /// The digest of the image or module.
     pub digest: Option<String>,
+    /// The annotations of the image's manifest, if it has any.
+    pub annotations: Option<HashMap<String, String>>,
 }
 
 impl ImageData {
@@ -189,6 +191,7 @@ impl Client {
         self.validate_layers(&manifest, accepted_media_types)
             .await?;
 
+        let annotations = manifest.annotations;
         let layers = manifest.layers.into_iter().map(|layer| {
             // This avoids moving `self` which is &mut Self
             // into the async block. We only want to capture
@@ -198,6 +201,8 @@ impl Client {
                 let mut out: Vec<u8> = Vec::new();
                 debug!("Pulling image layer");
                 this.pull_layer(image, &layer.digest, &mut out).await?;
+                // The layers are only as trustworthy as the manifest if they match its digests
+                check_digest(&layer.digest, &out)?;
                 Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
             }
         });
@@ -207,6 +212,7 @@ impl Client {
         Ok(ImageData {
             layers,
             digest: Some(digest),
+            annotations,
         })
     }
 
@@ -439,6 +445,8 @@ impl Client {
             reqwest::StatusCode::OK => {
                 let digest = digest_header_value(&res)?;
                 let text = res.text().await?;
+                // The digest is what image signatures sign, so it must be the manifest's own
+                check_digest(&digest, text.as_bytes())?;
 
                 self.validate_image_manifest(&text).await?;
 
@@ -527,7 +535,7 @@ impl Client {
     /// repository and the registry, but it is not used to verify that
     /// the digest is a layer inside of the image. (The manifest is
     /// used for that.)
-    async fn pull_layer<T: AsyncWrite + Unpin>(
+    pub async fn pull_layer<T: AsyncWrite + Unpin>(
         &self,
         image: &Reference,
         digest: &str,
@@ -963,6 +971,18 @@ fn digest_header_value(response: &reqwest::Response) -> anyhow::Result<String> {
     }
 }
 
+/// Checks that content pulled by digest matches that digest. Only SHA-256 digests can be checked,
+/// so content with digests of other algorithms is accepted as it is
+fn check_digest(digest: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    if digest.starts_with("sha256:") && sha256_digest(bytes) != digest {
+        return Err(anyhow::anyhow!(
+            "content does not match its digest {}",
+            digest
+        ));
+    }
+    Ok(())
+}
+
 /// Computes the SHA256 digest of a byte vector
 fn sha256_digest(bytes: &[u8]) -> String {
     format!("sha256:{:x}", sha2::Sha256::digest(bytes))
@@ -989,6 +1009,14 @@ mod test {
     ];
     const DOCKER_IO_IMAGE: &str = "docker.io/library/hello-world:latest";
 
+    #[test]
+    fn test_check_digest() {
+        let digest = sha256_digest(b"layer");
+        assert!(check_digest(&digest, b"layer").is_ok());
+        assert!(check_digest(&digest, b"tampered").is_err());
+        assert!(check_digest("sha512:abc", b"layer").is_ok());
+    }
+
     #[test]
     fn test_to_v2_blob_url() {
         let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
@@ -1414,6 +1442,7 @@ mod test {
             let mut image_data = ImageData {
                 layers: Vec::with_capacity(0),
                 digest: None,
+                annotations: None,
             };
             for i in 1..6 {
                 match Client::default()
@@ -3,6 +3,16 @@ use std::collections::HashMap;
 
 /// The mediatype for WASM layers.
 pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
+/// The mediatype for WASM layers that are gzipped.
+pub const WASM_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm+gzip";
+/// The mediatype for WASM layers that are compressed with zstd.
+pub const WASM_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm+zstd";
+/// The mediatype for layers of data files shipped alongside a WASM module, as a tar archive.
+pub const WASM_DATA_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.data.layer.v1.tar";
+/// The mediatype for WASM data layers that are gzipped.
+pub const WASM_DATA_LAYER_GZIP_MEDIA_TYPE: &str = "application/vnd.wasm.data.layer.v1.tar+gzip";
+/// The mediatype for WASM data layers that are compressed with zstd.
+pub const WASM_DATA_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.wasm.data.layer.v1.tar+zstd";
 /// The mediatype for a WASM image config.
 pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
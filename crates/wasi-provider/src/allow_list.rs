@@ -0,0 +1,159 @@
+//! Outbound HTTP allow lists of domains and network ranges.
+//!
+//! A request is allowed if its host is one of the listed domains, or an address inside one of
+//! the listed networks. A domain that isn't listed is allowed if every address it resolves to is
+//! inside the networks. The HTTP functions connect to an address that was checked, rather than
+//! resolving the domain again, so it can't resolve somewhere else by the time the connection is
+//! made.
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use url::Url;
+
+/// Splits the raw entries of an allow list into domains and network ranges. Entries that parse as
+/// a CIDR range or a bare IP address are treated as networks, everything else is left as a domain
+pub(crate) fn split_allow_list(entries: Vec<String>) -> (Vec<String>, Vec<IpNet>) {
+    let mut domains = Vec::new();
+    let mut networks = Vec::new();
+    for entry in entries {
+        let trimmed = entry.trim();
+        if let Ok(net) = trimmed.parse::<IpNet>() {
+            networks.push(net);
+        } else if let Ok(addr) = trimmed.parse::<IpAddr>() {
+            networks.push(IpNet::from(addr));
+        } else {
+            domains.push(entry);
+        }
+    }
+    (domains, networks)
+}
+
+/// A list of domains and network ranges a module is allowed to send requests to. An empty list
+/// doesn't allow requests anywhere
+#[derive(Debug, Default)]
+pub(crate) struct AllowList {
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+}
+
+impl AllowList {
+    pub(crate) fn new(domains: Vec<String>, networks: Vec<IpNet>) -> Self {
+        // Domains are given as URLs (e.g. https://example.com), so match against their host.
+        // Anything that isn't a URL is used as is
+        let hosts = domains
+            .into_iter()
+            .map(|d| match Url::parse(&d) {
+                Ok(u) => u.host_str().map(|h| h.to_owned()).unwrap_or(d),
+                Err(_) => d,
+            })
+            .collect();
+        AllowList { hosts, networks }
+    }
+
+    /// Whether the host of a URL is explicitly listed
+    pub(crate) fn lists(&self, host: &str) -> bool {
+        self.hosts.iter().any(|h| h == host)
+    }
+
+    /// Whether the list has any network ranges, so that domains which aren't listed may still be
+    /// allowed by the addresses they resolve to
+    pub(crate) fn has_networks(&self) -> bool {
+        !self.networks.is_empty()
+    }
+
+    /// Whether an address is inside one of the allowed networks
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(ip))
+    }
+
+    /// Whether _every_ address a domain resolved to is inside one of the allowed networks, so a
+    /// partially matching domain fails closed
+    pub(crate) fn contains_all(&self, addrs: &[IpAddr]) -> bool {
+        !addrs.is_empty() && addrs.iter().all(|ip| self.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_split_allow_list() {
+        let entries = vec![
+            "https://example.com".to_owned(),
+            "api.test".to_owned(),
+            "10.0.0.0/8".to_owned(),
+            " 192.168.1.10 ".to_owned(),
+            "fd00::/8".to_owned(),
+            "::1".to_owned(),
+            "http://[2001:db8::1]:8080".to_owned(),
+        ];
+        let (domains, networks) = split_allow_list(entries);
+        assert_eq!(
+            domains,
+            vec![
+                "https://example.com".to_owned(),
+                "api.test".to_owned(),
+                "http://[2001:db8::1]:8080".to_owned(),
+            ]
+        );
+        assert_eq!(
+            networks,
+            vec![
+                net("10.0.0.0/8"),
+                net("192.168.1.10/32"),
+                net("fd00::/8"),
+                net("::1/128"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_listed_hosts() {
+        let allow_list = AllowList::new(
+            vec![
+                "https://example.com".to_owned(),
+                "api.test".to_owned(),
+                "http://[2001:db8::1]:8080".to_owned(),
+            ],
+            Vec::new(),
+        );
+        assert!(allow_list.lists("example.com"));
+        assert!(allow_list.lists("api.test"));
+        assert!(allow_list.lists("[2001:db8::1]"));
+        assert!(!allow_list.lists("other.example"));
+        assert!(!allow_list.has_networks());
+        assert!(!AllowList::default().lists("example.com"));
+    }
+
+    #[test]
+    fn test_networks() {
+        let allow_list = AllowList::new(Vec::new(), vec![net("10.0.0.0/8"), net("fd00::/8")]);
+        assert!(allow_list.has_networks());
+        assert!(allow_list.contains(&ip("10.1.2.3")));
+        assert!(!allow_list.contains(&ip("11.1.2.3")));
+        assert!(allow_list.contains(&ip("fd12::1")));
+        assert!(!allow_list.contains(&ip("fe80::1")));
+        // An IPv4 network doesn't contain the IPv6 form of its addresses
+        assert!(!allow_list.contains(&ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn test_domains_resolving_to_many_addresses() {
+        let allow_list = AllowList::new(Vec::new(), vec![net("10.0.0.0/8"), net("fd00::/8")]);
+        assert!(allow_list.contains_all(&[ip("10.0.0.1"), ip("10.0.0.2"), ip("fd00::2")]));
+        // One address outside the networks fails the whole domain
+        assert!(!allow_list.contains_all(&[ip("10.0.0.1"), ip("8.8.8.8")]));
+        assert!(!allow_list.contains_all(&[ip("fd00::2"), ip("2001:db8::1")]));
+        // As does a domain without any addresses
+        assert!(!allow_list.contains_all(&[]));
+    }
+}
@@ -0,0 +1,327 @@
+//! Auditing of the host calls a module makes.
+//!
+//! When a pod asks for it, every host function a module imports is replaced in the linker with
+//! one that counts the call before forwarding it, and the counts are logged once the module
+//! exits. The capabilities and network access a module is granted can then be narrowed down to
+//! what it actually used. The wrappers go through wasmtime's dynamic calling convention, which
+//! makes every host call noticeably slower, so modules are only audited on request.
+//!
+//! Host functions find the module's memory through the instance that called them, and a host
+//! function calling another directly doesn't have one. Once the module is instantiated, calls are
+//! forwarded through a small module that imports the original functions along with the module's
+//! memory and exports that memory again, so the original functions see it as they would without
+//! the audit. The memory is imported with the limits the module declared for it, so it matches
+//! however the module was built. Shared memories, which modules built with threads use, are
+//! rejected when this version of wasmtime compiles a module, so an audited module's memory is
+//! never shared. Throttled modules can only call host functions asynchronously, and wasm can't
+//! be called back into from there, so modules with a CPU limit can't be audited.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, info};
+use wasmtime::{
+    Caller, Extern, Func, FuncType, Instance, Linker, MemoryType, Module, Store, Trap, Val,
+};
+
+use crate::wasi_runtime::StoreData;
+
+/// The module the forwarding module imports the original functions and memory from
+const FORWARDING_IMPORT_MODULE: &str = "audit";
+
+/// The number of times a module called each of its host function imports
+#[derive(Clone)]
+pub(crate) struct CallAudit {
+    /// Counts keyed by the import's module and name
+    calls: Arc<BTreeMap<(String, String), AtomicU64>>,
+    /// The original functions, in the order the forwarding module imports them
+    originals: Vec<Func>,
+    /// The functions of the forwarding module, in the same order as the originals, once the
+    /// module has been instantiated
+    forwarders: Arc<Mutex<Option<Vec<Func>>>>,
+}
+
+impl CallAudit {
+    /// Replaces the host functions the module imports from the linker with ones that count their
+    /// calls. Imports the linker doesn't define are left for instantiation to report
+    pub(crate) fn instrument(
+        linker: &mut Linker<StoreData>,
+        store: &mut Store<StoreData>,
+        module: &Module,
+    ) -> anyhow::Result<Self> {
+        let mut imports = Vec::new();
+        for import in module.imports() {
+            let name = match import.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let original = match linker
+                .get(&mut *store, import.module(), Some(name))
+                .and_then(|e| e.into_func())
+            {
+                Some(func) => func,
+                None => continue,
+            };
+            imports.push((import.module().to_owned(), name.to_owned(), original));
+        }
+        let calls: BTreeMap<_, _> = imports
+            .iter()
+            .map(|(module, name, _)| ((module.clone(), name.clone()), AtomicU64::new(0)))
+            .collect();
+        let audit = CallAudit {
+            calls: Arc::new(calls),
+            originals: imports.iter().map(|(_, _, original)| *original).collect(),
+            forwarders: Arc::new(Mutex::new(None)),
+        };
+
+        linker.allow_shadowing(true);
+        for (index, (module, name, original)) in imports.into_iter().enumerate() {
+            let ty = original.ty(&*store);
+            let calls = audit.calls.clone();
+            let key = (module.clone(), name.clone());
+            let forwarders = audit.forwarders.clone();
+            // Counts the call and returns the function to forward it to. Calls made while the
+            // module is being instantiated go straight to the original
+            let count = move || {
+                if let Some(count) = calls.get(&key) {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+                forwarders
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map_or(original, |forwarders| forwarders[index])
+            };
+            let counted = Func::new(
+                &mut *store,
+                ty,
+                move |mut caller: Caller<'_, StoreData>, params: &[Val], results: &mut [Val]| {
+                    let target = count();
+                    let ret = target.call(&mut caller, params).map_err(Trap::from)?;
+                    results.clone_from_slice(&ret);
+                    Ok(())
+                },
+            );
+            linker.define(&module, &name, counted)?;
+        }
+        linker.allow_shadowing(false);
+        debug!(imports = audit.calls.len(), "Auditing host calls of module");
+        Ok(audit)
+    }
+
+    /// Starts forwarding calls through a module that exports the memory of the instantiated
+    /// module. Modules that don't export a memory keep calling the original functions directly
+    pub(crate) fn attach(
+        &self,
+        store: &mut Store<StoreData>,
+        instance: &Instance,
+    ) -> anyhow::Result<()> {
+        let memory = match instance.get_memory(&mut *store, "memory") {
+            Some(memory) => memory,
+            None => return Ok(()),
+        };
+        let types: Vec<FuncType> = self.originals.iter().map(|f| f.ty(&*store)).collect();
+        let memory_type = memory.ty(&*store);
+        let module = Module::new(store.engine(), forwarding_module(&memory_type, &types))?;
+        let mut imports: Vec<Extern> = vec![memory.into()];
+        imports.extend(self.originals.iter().map(|f| Extern::from(*f)));
+        let forwarding = Instance::new(&mut *store, &module, &imports)?;
+        let forwarders = (0..types.len())
+            .map(|index| {
+                forwarding
+                    .get_func(&mut *store, &format!("f{}", index))
+                    .ok_or_else(|| anyhow::anyhow!("forwarding module has no function {}", index))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        *self.forwarders.lock().unwrap() = Some(forwarders);
+        Ok(())
+    }
+
+    /// Logs how many times the module called each of its imports, with the imports it never
+    /// called listed apart
+    pub(crate) fn report(&self, name: &str) {
+        let calls: BTreeMap<String, u64> = self
+            .calls
+            .iter()
+            .map(|((module, func), count)| {
+                (
+                    format!("{}::{}", module, func),
+                    count.load(Ordering::Relaxed),
+                )
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        let unused: Vec<String> = self
+            .calls
+            .iter()
+            .filter(|(_, count)| count.load(Ordering::Relaxed) == 0)
+            .map(|((module, func), _)| format!("{}::{}", module, func))
+            .collect();
+        info!(%name, ?calls, ?unused, "Host calls made by module");
+    }
+}
+
+/// The text of a module that imports a memory of the given type and exports it as `memory`, and
+/// imports functions of the given types as `f0`, `f1` and so on. Each is exported under the same
+/// name by a function that calls through to it
+fn forwarding_module(memory: &MemoryType, types: &[FuncType]) -> String {
+    let signatures: Vec<String> = types
+        .iter()
+        .map(|ty| {
+            let params = ty.params().map(|param| format!(" (param {})", param));
+            let results = ty.results().map(|result| format!(" (result {})", result));
+            params.chain(results).collect()
+        })
+        .collect();
+    let limits = memory.limits();
+    let max = limits
+        .max()
+        .map(|max| format!(" {}", max))
+        .unwrap_or_default();
+    let mut wat = format!(
+        "(module\n  (import \"{}\" \"memory\" (memory {}{}))\n",
+        FORWARDING_IMPORT_MODULE,
+        limits.min(),
+        max
+    );
+    for (index, signature) in signatures.iter().enumerate() {
+        writeln!(
+            wat,
+            "  (import \"{0}\" \"f{1}\" (func $f{1}{2}))",
+            FORWARDING_IMPORT_MODULE, index, signature
+        )
+        .unwrap();
+    }
+    wat.push_str("  (export \"memory\" (memory 0))\n");
+    for (index, (ty, signature)) in types.iter().zip(&signatures).enumerate() {
+        let args: String = (0..ty.params().len())
+            .map(|param| format!(" local.get {}", param))
+            .collect();
+        writeln!(
+            wat,
+            "  (func (export \"f{0}\"){1}{2} call $f{0})",
+            index, signature, args
+        )
+        .unwrap();
+    }
+    wat.push(')');
+    wat
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasmtime::{Engine, Limits, ValType};
+    use wasmtime_wasi::sync::WasiCtxBuilder;
+
+    #[test]
+    fn test_forwarding_module() {
+        let types = [
+            FuncType::new(vec![ValType::I32, ValType::I64], vec![ValType::I32]),
+            FuncType::new(vec![], vec![]),
+        ];
+        let memory = MemoryType::new(Limits::new(1, Some(2)));
+        let wat = forwarding_module(&memory, &types);
+        assert_eq!(
+            wat,
+            "(module
+  (import \"audit\" \"memory\" (memory 1 2))
+  (import \"audit\" \"f0\" (func $f0 (param i32) (param i64) (result i32)))
+  (import \"audit\" \"f1\" (func $f1))
+  (export \"memory\" (memory 0))
+  (func (export \"f0\") (param i32) (param i64) (result i32) local.get 0 local.get 1 call $f0)
+  (func (export \"f1\") call $f1)
+)"
+        );
+        Module::new(&Engine::default(), wat).unwrap();
+
+        let memory = MemoryType::new(Limits::at_least(0));
+        let wat = forwarding_module(&memory, &[]);
+        assert!(wat.contains("(memory 0))"));
+        Module::new(&Engine::default(), wat).unwrap();
+    }
+
+    /// A store and a linker with a host function that reads a byte of the calling module's
+    /// memory, as WASI functions do
+    fn linker(engine: &Engine) -> (Store<StoreData>, Linker<StoreData>) {
+        let store = Store::new(engine, StoreData::unlimited(WasiCtxBuilder::new().build()));
+        let mut linker = Linker::new(engine);
+        linker
+            .func_wrap(
+                "env",
+                "peek",
+                |mut caller: Caller<'_, StoreData>, ptr: i32| -> Result<i32, Trap> {
+                    let memory = caller
+                        .get_export("memory")
+                        .and_then(|e| e.into_memory())
+                        .ok_or_else(|| Trap::new("no memory"))?;
+                    Ok(memory.data(&caller)[ptr as usize] as i32)
+                },
+            )
+            .unwrap();
+        (store, linker)
+    }
+
+    fn calls(audit: &CallAudit, module: &str, name: &str) -> u64 {
+        audit.calls[&(module.to_owned(), name.to_owned())].load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_attach() {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+                (import "env" "peek" (func $peek (param i32) (result i32)))
+                (memory (export "memory") 1 2)
+                (data (i32.const 8) "\2a")
+                (func (export "run") (result i32) (call $peek (i32.const 8))))"#,
+        )
+        .unwrap();
+        let (mut store, mut linker) = linker(&engine);
+        let audit = CallAudit::instrument(&mut linker, &mut store, &module).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        audit.attach(&mut store, &instance).unwrap();
+        let run = instance
+            .get_typed_func::<(), i32, _>(&mut store, "run")
+            .unwrap();
+        // The original function sees the module's memory through the forwarding module
+        assert_eq!(42, run.call(&mut store, ()).unwrap());
+        assert_eq!(42, run.call(&mut store, ()).unwrap());
+        assert_eq!(2, calls(&audit, "env", "peek"));
+    }
+
+    #[test]
+    fn test_attach_without_memory() {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+                (import "env" "peek" (func $peek (param i32) (result i32)))
+                (func (export "run") (result i32) (call $peek (i32.const 0))))"#,
+        )
+        .unwrap();
+        let (mut store, mut linker) = linker(&engine);
+        let audit = CallAudit::instrument(&mut linker, &mut store, &module).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        audit.attach(&mut store, &instance).unwrap();
+        assert!(audit.forwarders.lock().unwrap().is_none());
+        // Calls are still counted, and go straight to the original
+        let run = instance
+            .get_typed_func::<(), i32, _>(&mut store, "run")
+            .unwrap();
+        assert!(run.call(&mut store, ()).is_err());
+        assert_eq!(1, calls(&audit, "env", "peek"));
+    }
+
+    #[test]
+    fn test_shared_memories_are_not_compiled() {
+        // Modules built with threads can't get as far as being audited, so the forwarding module
+        // never has to import a shared memory
+        let mut config = wasmtime::Config::new();
+        config.wasm_threads(true);
+        let engine = Engine::new(&config).unwrap();
+        assert!(Module::new(&engine, r#"(module (memory (export "memory") 1 1 shared))"#).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Gating of the WASI capabilities a module is given.
+//!
+//! WASI has no way to withhold clocks or randomness from a module that imports them, so a
+//! module without those capabilities still links, but sees clocks frozen at a fixed point and
+//! a random source seeded with a fixed value. That makes runs reproducible, and means the
+//! random numbers are not fit for anything that needs to be secure.
+use std::time::{Duration, UNIX_EPOCH};
+
+use cap_std::time::{Instant, SystemTime};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use wasi_common::clocks::{WasiClocks, WasiMonotonicClock, WasiSystemClock};
+use wasi_common::WasiCtx;
+
+/// The seed of the random source of modules without the `random` capability
+const FIXED_RANDOM_SEED: u64 = 0;
+
+/// The WASI capabilities a module is given. Every capability is given by default
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WasiCapabilities {
+    /// Whether the module can read the real time
+    pub clocks: bool,
+    /// Whether the module gets secure random numbers
+    pub random: bool,
+    /// Whether the module can see the container's environment variables
+    pub env: bool,
+    /// Whether the container's volumes are mounted into the module
+    pub filesystem: bool,
+}
+
+impl Default for WasiCapabilities {
+    fn default() -> Self {
+        WasiCapabilities {
+            clocks: true,
+            random: true,
+            env: true,
+            filesystem: true,
+        }
+    }
+}
+
+impl WasiCapabilities {
+    /// Parses a comma separated list of the capabilities to take away, such as `clocks,random`
+    pub fn disabling(list: &str) -> anyhow::Result<Self> {
+        let mut capabilities = WasiCapabilities::default();
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "clocks" => capabilities.clocks = false,
+                "random" => capabilities.random = false,
+                "env" => capabilities.env = false,
+                "filesystem" => capabilities.filesystem = false,
+                _ => anyhow::bail!(
+                    "unknown capability {:?}, expected one of clocks, random, env or filesystem",
+                    name
+                ),
+            }
+        }
+        Ok(capabilities)
+    }
+
+    /// Replaces the clocks and random source of the context if the module shouldn't have them
+    pub(crate) fn restrict(&self, ctx: &mut WasiCtx) {
+        if !self.clocks {
+            let start = Instant::from_std(std::time::Instant::now());
+            ctx.clocks = WasiClocks {
+                system: Box::new(FrozenSystemClock),
+                monotonic: Box::new(FrozenMonotonicClock(start)),
+                creation_time: start,
+            };
+        }
+        if !self.random {
+            ctx.random = Box::new(StdRng::seed_from_u64(FIXED_RANDOM_SEED));
+        }
+    }
+}
+
+/// A wall clock that always reads the Unix epoch
+struct FrozenSystemClock;
+
+impl WasiSystemClock for FrozenSystemClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self, _precision: Duration) -> SystemTime {
+        SystemTime::from_std(UNIX_EPOCH)
+    }
+}
+
+/// A monotonic clock that always reads the time the module started
+struct FrozenMonotonicClock(Instant);
+
+impl WasiMonotonicClock for FrozenMonotonicClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self, _precision: Duration) -> Instant {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasi_cap_std_sync::WasiCtxBuilder;
+
+    #[test]
+    fn test_disabling() {
+        assert_eq!(
+            WasiCapabilities::default(),
+            WasiCapabilities::disabling("").unwrap()
+        );
+        assert_eq!(
+            WasiCapabilities {
+                clocks: false,
+                random: false,
+                ..Default::default()
+            },
+            WasiCapabilities::disabling(" clocks, random,").unwrap()
+        );
+        assert_eq!(
+            WasiCapabilities {
+                clocks: true,
+                random: true,
+                env: false,
+                filesystem: false,
+            },
+            WasiCapabilities::disabling("env,filesystem").unwrap()
+        );
+        assert!(WasiCapabilities::disabling("clocks,network").is_err());
+        assert!(WasiCapabilities::disabling("Clocks").is_err());
+    }
+
+    #[test]
+    fn test_restrict() {
+        let random_bytes = |ctx: &mut WasiCtx| {
+            let mut bytes = [0; 16];
+            ctx.random.fill_bytes(&mut bytes);
+            bytes
+        };
+        let restricted = || {
+            let mut ctx = WasiCtxBuilder::new().build();
+            WasiCapabilities::disabling("clocks,random")
+                .unwrap()
+                .restrict(&mut ctx);
+            ctx
+        };
+
+        let mut ctx = restricted();
+        assert_eq!(
+            SystemTime::from_std(UNIX_EPOCH),
+            ctx.clocks.system.now(Duration::from_secs(1))
+        );
+        let started = ctx.clocks.monotonic.now(Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(started, ctx.clocks.monotonic.now(Duration::from_secs(1)));
+        // Every run sees the same random numbers
+        assert_eq!(random_bytes(&mut ctx), random_bytes(&mut restricted()));
+
+        // Capabilities that are given are left alone
+        let mut ctx = WasiCtxBuilder::new().build();
+        WasiCapabilities::default().restrict(&mut ctx);
+        assert_ne!(
+            SystemTime::from_std(UNIX_EPOCH),
+            ctx.clocks.system.now(Duration::from_secs(1))
+        );
+        assert_ne!(random_bytes(&mut ctx), random_bytes(&mut restricted()));
+    }
+}
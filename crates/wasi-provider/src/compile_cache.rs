@@ -0,0 +1,101 @@
+//! Caches modules compiled by wasmtime on disk so they don't have to be recompiled every time a
+//! container starts.
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use kubelet::config::ModuleOptLevel;
+use sha2::Digest;
+use tracing::{debug, warn};
+use wasmtime::{Engine, Module};
+
+use crate::engine::Engines;
+use crate::features::WasmFeatures;
+
+/// An on disk cache of compiled modules.
+///
+/// Compiled modules are keyed by the digest of the module, the architecture of the node and the
+/// engine settings that change the generated code. Wasmtime refuses to load artifacts that were
+/// compiled by a different version or for CPU features the node doesn't have, so an artifact
+/// that fails to load is treated as a cache miss and replaced with a freshly compiled one. This
+/// means the cache invalidates itself whenever wasmtime is upgraded.
+#[derive(Clone, Debug)]
+pub(crate) struct CompileCache {
+    dir: PathBuf,
+    /// The optimization level modules are compiled with, which changes the generated code
+    opt_level: ModuleOptLevel,
+}
+
+impl CompileCache {
+    /// Creates a cache that keeps modules compiled at the given optimization level in the given
+    /// directory
+    pub(crate) fn new(dir: PathBuf, opt_level: ModuleOptLevel) -> Self {
+        CompileCache { dir, opt_level }
+    }
+
+    /// Loads the compiled module from the cache, compiling it with `engines` and caching it if it
+    /// isn't there. `fuel` is whether the engine was configured to consume fuel and `features` are the features
+    /// it compiles with, as both change the generated code
+    pub(crate) async fn load(
+        &self,
+        engines: &Arc<Engines>,
+        engine: &Engine,
+        module_data: &[u8],
+        fuel: bool,
+        features: WasmFeatures,
+    ) -> anyhow::Result<Module> {
+        // Modules compiled at the default level keep the names they had before the level could be
+        // changed, so existing caches stay valid
+        let opt_level = match self.opt_level {
+            ModuleOptLevel::Speed => "",
+            ModuleOptLevel::None => "-opt-none",
+            ModuleOptLevel::SpeedAndSize => "-opt-speed-and-size",
+        };
+        let path = self.dir.join(format!(
+            "{:x}-{}-{}{}{}.cwasm",
+            sha2::Sha256::digest(module_data),
+            std::env::consts::ARCH,
+            if fuel { "fuel" } else { "nofuel" },
+            opt_level,
+            features.cache_suffix()
+        ));
+
+        if let Ok(compiled) = tokio::fs::read(&path).await {
+            // SAFETY: the artifact was written by `Module::serialize` below, and wasmtime checks
+            // that it is compatible with the engine before loading it
+            match unsafe { Module::deserialize(engine, &compiled) } {
+                Ok(module) => {
+                    debug!(path = %path.display(), "Loaded compiled module from cache");
+                    return Ok(module);
+                }
+                Err(e) => {
+                    debug!(error = %e, path = %path.display(), "Compiled module is stale, recompiling")
+                }
+            }
+        }
+
+        let module = Arc::clone(engines)
+            .compile_blocking(engine, module_data)
+            .await?;
+        // Failing to cache the module shouldn't stop it from running
+        if let Err(e) = self.store(&path, &module).await {
+            warn!(error = %e, path = %path.display(), "Unable to cache compiled module");
+        }
+        Ok(module)
+    }
+
+    async fn store(&self, path: &Path, module: &Module) -> anyhow::Result<()> {
+        let compiled = module.serialize()?;
+        let dir = self.dir.clone();
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            std::fs::create_dir_all(&dir)?;
+            // Write to a temporary file first so a partially written artifact is never loaded
+            let mut temp = tempfile::NamedTempFile::new_in(&dir)?;
+            temp.write_all(&compiled)?;
+            temp.persist(path)?;
+            Ok(())
+        })
+        .await?
+    }
+}
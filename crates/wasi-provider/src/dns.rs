@@ -0,0 +1,432 @@
+//! Resolution of the hosts of outbound HTTP requests with a pod's DNS settings.
+//!
+//! Hosts are otherwise resolved with the node's resolver. For pods with their own DNS settings,
+//! such as the cluster's nameservers under `ClusterFirst`, the HTTP functions resolve the host of
+//! each request with a small stub resolver using those settings, and connect to the address it
+//! found. The request itself is left as the module made it, so the `Host` header and the name
+//! certificates are checked against are the host the module asked for, as they are for requests
+//! from containers.
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use kubelet::pod::dns::ResolverConfig;
+use tracing::debug;
+
+const DNS_PORT: u16 = 53;
+/// How long to wait for a nameserver to answer before trying the next one
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// The largest DNS message that can be sent over UDP without extensions
+const MAX_MESSAGE_SIZE: usize = 512;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// The response code for a name that doesn't exist
+const RCODE_NAME_ERROR: u16 = 3;
+/// The flag set in responses that were cut short to fit in a UDP message
+const FLAG_TRUNCATED: u16 = 0x0200;
+
+/// What a nameserver sent back for a query
+#[derive(Debug, PartialEq)]
+enum Response {
+    /// The message isn't a response to the query, such as a late answer to an earlier one
+    Unrelated,
+    /// The answer didn't fit in a UDP message, so the query has to be sent again over TCP
+    Truncated,
+    /// The addresses in the answer. A name that doesn't exist has none
+    Addresses(Vec<IpAddr>),
+}
+
+/// A stub resolver that sends queries straight to the configured nameservers
+#[derive(Debug)]
+pub(crate) struct Resolver {
+    config: ResolverConfig,
+}
+
+impl Resolver {
+    pub(crate) fn new(config: ResolverConfig) -> Self {
+        Resolver { config }
+    }
+
+    /// Resolves a name, searching the configured domains the same way the system resolver does.
+    /// A candidate that can't be looked up is skipped like one that doesn't exist, so the error
+    /// is only returned if no candidate has any addresses
+    pub(crate) fn resolve(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let mut last_error = None;
+        for candidate in self.candidates(name) {
+            match self.lookup(&candidate) {
+                Ok(addrs) if !addrs.is_empty() => {
+                    debug!(%name, %candidate, ?addrs, "Resolved name with pod DNS settings");
+                    return Ok(addrs);
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    debug!(%name, %candidate, error = %e, "Unable to look up name");
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.context(format!("unable to resolve {}", name))),
+            None => anyhow::bail!("unable to resolve {}", name),
+        }
+    }
+
+    /// The names to look up for the given name, in order. Names with at least `ndots` dots are
+    /// tried as they are before the search domains, and others after
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_owned()];
+        }
+        let searched = self
+            .config
+            .searches
+            .iter()
+            .map(|domain| format!("{}.{}", name, domain.trim_end_matches('.')));
+        if name.matches('.').count() as u32 >= self.config.ndots {
+            std::iter::once(name.to_owned()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.to_owned())).collect()
+        }
+    }
+
+    /// Looks up the addresses of a fully qualified name, trying each nameserver in turn until
+    /// one answers
+    fn lookup(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let mut last_error = None;
+        for server in &self.config.nameservers {
+            match query(SocketAddr::new(*server, DNS_PORT), name) {
+                Ok(addrs) => return Ok(addrs),
+                Err(e) => {
+                    debug!(%server, %name, error = %e, "Nameserver did not answer");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no nameservers are configured")))
+    }
+}
+
+/// Asks a nameserver for the IPv4 and IPv6 addresses of a name. A name that doesn't exist has
+/// no addresses. Queries are sent over UDP, and again over TCP if the answer doesn't fit
+fn query(server: SocketAddr, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let bind_addr: SocketAddr = if server.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+
+    let mut addrs = Vec::new();
+    for qtype in &[TYPE_A, TYPE_AAAA] {
+        let id = query_id();
+        socket.send(&encode_query(id, name, *qtype)?)?;
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        // Skip any late answers to earlier queries
+        loop {
+            let len = socket.recv(&mut buf)?;
+            match parse_response(&buf[..len], id)? {
+                Response::Unrelated => continue,
+                Response::Truncated => {
+                    debug!(%server, %name, "DNS answer was truncated, asking again over TCP");
+                    addrs.extend(query_tcp(server, name, *qtype)?);
+                }
+                Response::Addresses(found) => addrs.extend(found),
+            }
+            break;
+        }
+    }
+    Ok(addrs)
+}
+
+/// Asks a nameserver for the addresses of a name over TCP, for answers too large for UDP
+fn query_tcp(server: SocketAddr, name: &str, qtype: u16) -> anyhow::Result<Vec<IpAddr>> {
+    let mut stream = TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.set_write_timeout(Some(QUERY_TIMEOUT))?;
+
+    let id = query_id();
+    let query = encode_query(id, name, qtype)?;
+    // Messages over TCP are preceded by their length
+    let mut framed = Vec::with_capacity(2 + query.len());
+    framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&query);
+    stream.write_all(&framed)?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    match parse_response(&response, id)? {
+        Response::Addresses(addrs) => Ok(addrs),
+        Response::Truncated => anyhow::bail!("DNS response over TCP is truncated"),
+        Response::Unrelated => anyhow::bail!("DNS response over TCP is to a different query"),
+    }
+}
+
+/// A random ID for a query, so spoofed answers are unlikely to match
+fn query_id() -> u16 {
+    rand::random()
+}
+
+/// Encodes a recursive query for the given name and record type
+fn encode_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    if name.len() > 253 {
+        anyhow::bail!("name {} is too long", name);
+    }
+    let mut message = Vec::with_capacity(18 + name.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired
+    message.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question and no other records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("name {} is not a valid domain name", name);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Parses the addresses out of the answers of a response to the query with the given ID
+fn parse_response(message: &[u8], id: u16) -> anyhow::Result<Response> {
+    let read_u16 = |pos: usize| -> anyhow::Result<u16> {
+        message
+            .get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| anyhow::anyhow!("DNS response is truncated"))
+    };
+    if read_u16(0)? != id {
+        return Ok(Response::Unrelated);
+    }
+    let flags = read_u16(2)?;
+    if flags & 0x8000 == 0 {
+        return Ok(Response::Unrelated);
+    }
+    match flags & 0x000f {
+        0 => (),
+        RCODE_NAME_ERROR => return Ok(Response::Addresses(Vec::new())),
+        rcode => anyhow::bail!("DNS query failed with response code {}", rcode),
+    }
+    // What answers did fit may not be all of them
+    if flags & FLAG_TRUNCATED != 0 {
+        return Ok(Response::Truncated);
+    }
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+        let rtype = read_u16(pos)?;
+        let rdlength = read_u16(pos + 8)? as usize;
+        let data = message
+            .get(pos + 10..pos + 10 + rdlength)
+            .ok_or_else(|| anyhow::anyhow!("DNS response is truncated"))?;
+        match (rtype, data.len()) {
+            (TYPE_A, 4) => addrs.push(IpAddr::from([data[0], data[1], data[2], data[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::from(octets));
+            }
+            // Aliases are followed by the nameserver, which answers with their addresses too
+            _ => (),
+        }
+        pos += 10 + rdlength;
+    }
+    Ok(Response::Addresses(addrs))
+}
+
+/// Returns the position just past the (possibly compressed) name starting at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> anyhow::Result<usize> {
+    loop {
+        let len = *message
+            .get(pos)
+            .ok_or_else(|| anyhow::anyhow!("DNS response is truncated"))?;
+        match len {
+            0 => return Ok(pos + 1),
+            // A pointer to the rest of the name elsewhere in the message
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l => pos += 1 + l as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A response to a query for `example.com`, with the given flags and answers. Answers refer
+    /// back to the name in the question, as nameservers compress them
+    fn response(id: u16, flags: u16, answers: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut message = encode_query(id, "example.com", TYPE_A).unwrap();
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for (rtype, data) in answers {
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&300u32.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        message
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "example.com", TYPE_AAAA).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x07example\x03com\x00");
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(expected, query);
+
+        assert!(encode_query(1, "a..b", TYPE_A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), TYPE_A).is_err());
+        assert!(encode_query(1, &["a"; 128].join("."), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        // The alias is skipped, as the nameserver answers with its addresses as well
+        let alias = b"\x03www\xc0\x0c";
+        let message = response(
+            7,
+            0x8180,
+            &[
+                (5, alias),
+                (TYPE_A, &[10, 0, 0, 1]),
+                (TYPE_AAAA, &Ipv6Addr::LOCALHOST.octets()),
+            ],
+        );
+        assert_eq!(
+            Response::Addresses(vec![
+                IpAddr::from([10, 0, 0, 1]),
+                IpAddr::from(Ipv6Addr::LOCALHOST)
+            ]),
+            parse_response(&message, 7).unwrap()
+        );
+        // Records of the wrong size aren't addresses
+        let message = response(7, 0x8180, &[(TYPE_A, &[10, 0, 0])]);
+        assert_eq!(
+            Response::Addresses(Vec::new()),
+            parse_response(&message, 7).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_response_status() {
+        let message = response(7, 0x8180, &[]);
+        assert_eq!(Response::Unrelated, parse_response(&message, 8).unwrap());
+        // A query isn't a response
+        let message = response(7, 0x0100, &[]);
+        assert_eq!(Response::Unrelated, parse_response(&message, 7).unwrap());
+        let message = response(7, 0x8183, &[]);
+        assert_eq!(
+            Response::Addresses(Vec::new()),
+            parse_response(&message, 7).unwrap()
+        );
+        let message = response(7, 0x8182, &[]);
+        assert!(parse_response(&message, 7).is_err());
+        let message = response(7, 0x8380, &[(TYPE_A, &[10, 0, 0, 1])]);
+        assert_eq!(Response::Truncated, parse_response(&message, 7).unwrap());
+    }
+
+    #[test]
+    fn test_parse_malformed_response() {
+        let message = response(7, 0x8180, &[(TYPE_A, &[10, 0, 0, 1])]);
+        for len in 0..message.len() {
+            assert!(parse_response(&message[..len], 7).is_err(), "{}", len);
+        }
+        // A record claiming more data than the message has
+        let mut message = message;
+        let rdlength = message.len() - 6;
+        message[rdlength..rdlength + 2].copy_from_slice(&100u16.to_be_bytes());
+        assert!(parse_response(&message, 7).is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let resolver = Resolver::new(ResolverConfig {
+            nameservers: Vec::new(),
+            searches: vec![
+                "default.svc.cluster.local".to_owned(),
+                "cluster.local.".to_owned(),
+            ],
+            ndots: 2,
+        });
+        assert_eq!(
+            vec!["api.default.svc.cluster.local", "api.cluster.local", "api"],
+            resolver.candidates("api")
+        );
+        assert_eq!(
+            vec![
+                "a.b.c",
+                "a.b.c.default.svc.cluster.local",
+                "a.b.c.cluster.local"
+            ],
+            resolver.candidates("a.b.c")
+        );
+        assert_eq!(vec!["api"], resolver.candidates("api."));
+        assert!(resolver.resolve("api").is_err());
+    }
+
+    /// Answers a query as a nameserver would, with the given flags and addresses for A queries
+    fn answer(query: &[u8], flags: u16, addrs: &[[u8; 4]]) -> Vec<u8> {
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+        let answers: Vec<(u16, &[u8])> = if qtype == TYPE_A {
+            addrs.iter().map(|addr| (TYPE_A, &addr[..])).collect()
+        } else {
+            Vec::new()
+        };
+        response(id, flags, &answers)
+    }
+
+    #[test]
+    fn test_query_falls_back_to_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(server).unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; MAX_MESSAGE_SIZE];
+            loop {
+                let (len, from) = udp.recv_from(&mut buf).unwrap();
+                // Only part of the answer fits over UDP
+                let reply = answer(&buf[..len], 0x8380, &[[10, 0, 0, 1]]);
+                udp.send_to(&reply, from).unwrap();
+            }
+        });
+        std::thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).unwrap();
+                let reply = answer(&query, 0x8180, &[[10, 0, 0, 1], [10, 0, 0, 2]]);
+                stream
+                    .write_all(&(reply.len() as u16).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        assert_eq!(
+            vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])],
+            query(server, "example.com").unwrap()
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Creation of the wasmtime engines modules are compiled and run with.
+//!
+//! Without a pool, every module run gets an engine of its own and its instance is allocated on
+//! demand. With the pooling allocator, memory for a fixed number of instances is reserved up front
+//! and shared by all modules, which makes instantiation much cheaper under heavy pod churn. The
+//! pool belongs to an engine, so pooled engines are shared between runs, one for each combination
+//! of settings that runs need. Each of at most `MAX_POOLED_ENGINES` engines reserves a pool of the
+//! configured size. Runs needing any other combination of settings, and runs that find their
+//! engine's pool already in use by as many instances as it holds, get an engine that allocates on
+//! demand.
+//!
+//! Modules are compiled with Cranelift, the only strategy wasmtime supports on every platform.
+//! How much it optimizes and whether it compiles the functions of a module in parallel are set by
+//! the node's configuration. Optimizing less makes containers start sooner, which suits short
+//! lived modules, while long running modules are better served by the fastest code. Parallel
+//! compilation makes each module compile sooner, at the cost of taking CPU from modules that are
+//! already running.
+//!
+//! The WebAssembly features modules are compiled with can differ between pods, and are one of the
+//! settings pooled engines are kept for.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use kubelet::config::{Config, ModuleOptLevel};
+use tracing::{debug, info};
+use wasmtime::{
+    Engine, InstanceAllocationStrategy, InstanceLimits, Module, ModuleLimits, OptLevel,
+    PoolingAllocationStrategy,
+};
+
+use crate::features::{parse_disabled, WasmFeature, WasmFeatures};
+
+/// The most pooled engines there can be, each of them reserving a pool of its own
+const MAX_POOLED_ENGINES: usize = 4;
+
+/// The sizes of the instance pool
+#[derive(Clone, Copy, Debug)]
+struct PoolLimits {
+    instances: u32,
+    memory_pages: u32,
+    table_elements: u32,
+}
+
+/// An engine that allocates from a pool, along with a count of the instances it has handed out
+#[derive(Clone)]
+struct PooledEngine {
+    engine: Engine,
+    in_use: Arc<()>,
+}
+
+/// One of the instances of a pooled engine's pool, held for as long as a module run uses it. Runs
+/// on engines that allocate on demand don't hold one
+pub(crate) struct PoolSlot(Option<Arc<()>>);
+
+/// Hands out engines configured for each module run
+pub(crate) struct Engines {
+    pool: Option<PoolLimits>,
+    opt_level: ModuleOptLevel,
+    parallel_compilation: bool,
+    /// The features modules may not be compiled with
+    disabled_features: Vec<WasmFeature>,
+    /// The pooled engines created so far, keyed by whether they consume fuel, whether they
+    /// support async and the features they compile modules with
+    pooled: Mutex<HashMap<(bool, bool, WasmFeatures), PooledEngine>>,
+}
+
+impl Default for Engines {
+    /// Engines that allocate instances on demand and compile with wasmtime's default settings
+    fn default() -> Self {
+        Engines {
+            pool: None,
+            opt_level: ModuleOptLevel::default(),
+            parallel_compilation: true,
+            disabled_features: Vec::new(),
+            pooled: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Engines {
+    pub(crate) fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let pool = config.module_pool_size.map(|instances| PoolLimits {
+            instances,
+            memory_pages: config.module_pool_memory_pages,
+            table_elements: config.module_pool_table_elements,
+        });
+        Ok(Engines {
+            pool,
+            opt_level: config.module_opt_level,
+            parallel_compilation: config.parallel_compilation,
+            disabled_features: parse_disabled(&config.disabled_wasm_features)?,
+            pooled: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The features modules may not be compiled with
+    pub(crate) fn disabled_features(&self) -> &[WasmFeature] {
+        &self.disabled_features
+    }
+
+    /// Compiles a module with an engine. Without parallel compilation, the functions of the module
+    /// are compiled one at a time
+    pub(crate) fn compile(&self, engine: &Engine, module_data: &[u8]) -> anyhow::Result<Module> {
+        if self.parallel_compilation {
+            return Module::new(engine, module_data);
+        }
+        // wasmtime compiles functions on rayon's thread pool, so give it a pool of one
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()?
+            .install(|| Module::new(engine, module_data))
+    }
+
+    /// Compiles a module like [`Engines::compile`], but on a blocking thread, as compiling a large
+    /// module takes long enough to hold up the other tasks of the async runtime
+    pub(crate) async fn compile_blocking(
+        self: Arc<Self>,
+        engine: &Engine,
+        module_data: &[u8],
+    ) -> anyhow::Result<Module> {
+        let engine = engine.clone();
+        let module_data = module_data.to_vec();
+        tokio::task::spawn_blocking(move || self.compile(&engine, &module_data)).await?
+    }
+
+    /// Gets an engine with the given settings. It is a pooled engine if there is a pool, there
+    /// aren't already as many pooled engines as there can be, and the engine's pool has an
+    /// instance free, which is held for the run with the returned slot
+    pub(crate) fn get(
+        &self,
+        consume_fuel: bool,
+        async_support: bool,
+        features: WasmFeatures,
+    ) -> anyhow::Result<(Engine, PoolSlot)> {
+        let mut config = wasmtime::Config::new();
+        config.interruptable(true);
+        config.consume_fuel(consume_fuel);
+        config.async_support(async_support);
+        features.apply(&mut config);
+        config.cranelift_opt_level(match self.opt_level {
+            ModuleOptLevel::None => OptLevel::None,
+            ModuleOptLevel::Speed => OptLevel::Speed,
+            ModuleOptLevel::SpeedAndSize => OptLevel::SpeedAndSize,
+        });
+
+        let on_demand = |config| Ok((Engine::new(config)?, PoolSlot(None)));
+        let pool = match self.pool {
+            Some(pool) => pool,
+            None => return on_demand(&config),
+        };
+        let mut pooled = self.pooled.lock().unwrap();
+        if let Some(engine) = pooled.get(&(consume_fuel, async_support, features)) {
+            // The engine itself holds one reference to the count
+            if Arc::strong_count(&engine.in_use) > pool.instances as usize {
+                debug!(
+                    consume_fuel,
+                    async_support, "Instance pool is in use, allocating the instance on demand"
+                );
+                return on_demand(&config);
+            }
+            return Ok((
+                engine.engine.clone(),
+                PoolSlot(Some(Arc::clone(&engine.in_use))),
+            ));
+        }
+        if pooled.len() >= MAX_POOLED_ENGINES {
+            debug!(
+                consume_fuel,
+                async_support,
+                "There are as many pooled engines as there can be, allocating instances on demand"
+            );
+            return on_demand(&config);
+        }
+        let instances = pool.instances;
+        info!(
+            instances,
+            memory_pages = pool.memory_pages,
+            table_elements = pool.table_elements,
+            consume_fuel,
+            async_support,
+            "Creating pooled engine"
+        );
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling {
+            strategy: PoolingAllocationStrategy::NextAvailable,
+            module_limits: ModuleLimits {
+                memory_pages: pool.memory_pages,
+                table_elements: pool.table_elements,
+                ..Default::default()
+            },
+            instance_limits: InstanceLimits {
+                count: instances,
+                ..Default::default()
+            },
+        });
+        let engine = PooledEngine {
+            engine: Engine::new(&config)?,
+            in_use: Arc::new(()),
+        };
+        let slot = PoolSlot(Some(Arc::clone(&engine.in_use)));
+        pooled.insert((consume_fuel, async_support, features), engine.clone());
+        Ok((engine.engine, slot))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(instances: u32) -> PoolLimits {
+        PoolLimits {
+            instances,
+            memory_pages: 160,
+            table_elements: 10000,
+        }
+    }
+
+    #[test]
+    fn test_pooled_engines_each_get_the_pool() {
+        let engines = Engines {
+            pool: Some(pool(2)),
+            ..Default::default()
+        };
+        let features = WasmFeatures::default();
+        let (first, first_slot) = engines.get(false, false, features).unwrap();
+        // The same settings get the same engine while its pool has instances free
+        let (second, second_slot) = engines.get(false, false, features).unwrap();
+        assert!(Engine::same(&first, &second));
+        // Once they are all in use, runs get an engine that allocates on demand rather than
+        // failing to instantiate
+        let (third, third_slot) = engines.get(false, false, features).unwrap();
+        assert!(!Engine::same(&first, &third));
+        assert!(third_slot.0.is_none());
+        drop(second_slot);
+        let (fourth, _) = engines.get(false, false, features).unwrap();
+        assert!(Engine::same(&first, &fourth));
+        drop(first_slot);
+
+        // Other settings get pools of their own, rather than a share of one
+        let (fueled, _) = engines.get(true, false, features).unwrap();
+        let (fueled_again, _) = engines.get(true, false, features).unwrap();
+        assert!(Engine::same(&fueled, &fueled_again));
+    }
+
+    #[test]
+    fn test_pooled_engines_are_limited() {
+        let engines = Engines {
+            pool: Some(pool(8)),
+            ..Default::default()
+        };
+        let features = WasmFeatures::default();
+        let (first, _) = engines.get(false, false, features).unwrap();
+        // The same settings get the same engine
+        assert!(Engine::same(
+            &first,
+            &engines.get(false, false, features).unwrap().0
+        ));
+        for (consume_fuel, async_support) in &[(true, false), (false, true), (true, true)] {
+            engines
+                .get(*consume_fuel, *async_support, features)
+                .unwrap();
+        }
+        assert_eq!(engines.pooled.lock().unwrap().len(), 4);
+        // Once there are as many pooled engines as there can be, other settings get engines of
+        // their own that aren't kept
+        let threads = WasmFeatures::from_annotation(Some("threads"), &[]).unwrap();
+        engines.get(false, false, threads).unwrap();
+        assert_eq!(engines.pooled.lock().unwrap().len(), 4);
+    }
+}
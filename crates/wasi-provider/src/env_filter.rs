@@ -0,0 +1,108 @@
+//! Filtering of the environment variables a module is given.
+//!
+//! Pods can list the variables their modules may see, the variables they must not see, or both.
+//! Entries are variable names, or prefixes ending in `*` (e.g. `KUBERNETES_*`).
+use std::collections::HashMap;
+
+use tracing::debug;
+
+/// Which of the container's environment variables are passed to its module
+#[derive(Debug, Default)]
+pub(crate) struct EnvFilter {
+    /// Only these variables are passed, if set
+    allow: Option<Vec<String>>,
+    /// These variables are never passed, even if allowed
+    deny: Vec<String>,
+}
+
+impl EnvFilter {
+    /// Creates a filter from comma separated allow and deny lists
+    pub(crate) fn new(allow: Option<&str>, deny: Option<&str>) -> Self {
+        EnvFilter {
+            allow: allow.map(parse_list),
+            deny: deny.map(parse_list).unwrap_or_default(),
+        }
+    }
+
+    /// Drops the variables the module isn't allowed to see
+    pub(crate) fn apply(&self, env: &mut HashMap<String, String>) {
+        env.retain(|key, _| {
+            let allowed = self
+                .allow
+                .as_ref()
+                .map_or(true, |allow| allow.iter().any(|p| matches(p, key)));
+            let keep = allowed && !self.deny.iter().any(|p| matches(p, key));
+            if !keep {
+                debug!(name = %key, "Not passing environment variable to module");
+            }
+            keep
+        });
+    }
+}
+
+fn parse_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => pattern == key,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The variables of a sample environment that the filter keeps, in order
+    fn filtered(allow: Option<&str>, deny: Option<&str>) -> Vec<String> {
+        let mut env: HashMap<String, String> = [
+            "HOME",
+            "APP_MODE",
+            "APP_TOKEN",
+            "KUBERNETES_SERVICE_HOST",
+            "KUBERNETES_SERVICE_PORT",
+        ]
+        .iter()
+        .map(|key| (key.to_string(), "value".to_owned()))
+        .collect();
+        EnvFilter::new(allow, deny).apply(&mut env);
+        let mut keys: Vec<String> = env.into_iter().map(|(key, _)| key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_no_lists_keep_everything() {
+        assert_eq!(5, filtered(None, None).len());
+        assert_eq!(5, filtered(None, Some(" , ")).len());
+    }
+
+    #[test]
+    fn test_allow_list() {
+        assert_eq!(
+            vec!["APP_MODE", "APP_TOKEN", "HOME"],
+            filtered(Some("HOME, APP_*"), None)
+        );
+        // Names must match exactly unless they end in `*`
+        assert_eq!(Vec::<String>::new(), filtered(Some("APP_,home"), None));
+        // An empty allow list allows nothing
+        assert_eq!(Vec::<String>::new(), filtered(Some(""), None));
+    }
+
+    #[test]
+    fn test_deny_list() {
+        assert_eq!(
+            vec!["APP_MODE", "APP_TOKEN", "HOME"],
+            filtered(None, Some("KUBERNETES_*"))
+        );
+        // Denying takes precedence over allowing
+        assert_eq!(vec!["APP_MODE"], filtered(Some("APP_*"), Some("APP_TOKEN")));
+        assert_eq!(Vec::<String>::new(), filtered(Some("HOME"), Some("*")));
+    }
+}
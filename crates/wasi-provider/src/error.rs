@@ -0,0 +1,214 @@
+//! Errors that stop a container from being started.
+//!
+//! Each error knows the reason reported for it in the container's status, and whether trying
+//! again could get any further, so the states can report and handle each failure precisely.
+//! Errors are turned into `anyhow` ones wherever they leave the provider.
+use std::path::PathBuf;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Why a container couldn't be started
+#[derive(Error, Debug)]
+pub(crate) enum ContainerError {
+    /// The container mounts a volume the pod doesn't have
+    #[error("no volume with the name of {volume} found for container {container}")]
+    VolumeNotFound { volume: String, container: String },
+    /// The container mounts a volume that hasn't been set up on the node yet
+    #[error("volume {volume} has not been mounted yet")]
+    VolumeNotMounted { volume: String },
+    /// A volume mount asks for mount propagation modules can't honor
+    #[error(
+        "volume {volume} requests {mode} mount propagation, but only None and HostToContainer are supported"
+    )]
+    UnsupportedMountPropagation { volume: String, mode: String },
+    /// A file is mounted at a path with no directory to put it in
+    #[error("cannot mount file {path:?} of volume {volume} at {mount_path}")]
+    InvalidMountPath {
+        volume: String,
+        path: PathBuf,
+        mount_path: String,
+    },
+    /// A path mounted from a volume resolves to somewhere outside of it
+    #[error("{path:?} in volume {volume} resolves to {resolved:?}, which is outside the volume")]
+    OutsideVolume {
+        volume: String,
+        path: PathBuf,
+        resolved: PathBuf,
+    },
+    /// A path in a volume couldn't be resolved, read or created
+    #[error("unable to mount {path:?} of volume {volume}: {source}")]
+    VolumeIo {
+        volume: String,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Files mounted into the container couldn't be copied into place
+    #[error("unable to stage mounted files: {0}")]
+    Staging(#[from] std::io::Error),
+    /// The container mounts more directories than the node lets a module hold open
+    #[error("mounts {dirs} directories, more than the limit of {limit}")]
+    TooManyDirs { dirs: usize, limit: usize },
+    /// The container's module hasn't been loaded for the pod
+    #[error("failed to load module data for container {container}")]
+    ModuleMissing { container: String },
+    /// The runtime the module runs in couldn't be set up
+    #[error("failed to construct runtime: {0:#}")]
+    RuntimeInit(anyhow::Error),
+    /// The module couldn't be started
+    #[error("failed to start: {0:#}")]
+    RuntimeStart(anyhow::Error),
+    /// The module took too long to be set up and started
+    #[error("did not start within {} seconds", .0.as_secs())]
+    StartTimedOut(Duration),
+}
+
+impl ContainerError {
+    /// The reason reported in the container's status when it fails with this error
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            ContainerError::VolumeNotFound { .. }
+            | ContainerError::UnsupportedMountPropagation { .. }
+            | ContainerError::InvalidMountPath { .. }
+            | ContainerError::OutsideVolume { .. }
+            | ContainerError::TooManyDirs { .. } => "CreateContainerConfigError",
+            ContainerError::VolumeNotMounted { .. }
+            | ContainerError::VolumeIo { .. }
+            | ContainerError::Staging(_)
+            | ContainerError::RuntimeInit(_) => "CreateContainerError",
+            ContainerError::ModuleMissing { .. } => "ModuleMissing",
+            ContainerError::RuntimeStart(_) => "StartError",
+            ContainerError::StartTimedOut(_) => "StartTimeout",
+        }
+    }
+
+    /// Whether starting the container again could succeed. Errors in how the pod is specified
+    /// fail the same way every time, so the container isn't restarted after them
+    pub(crate) fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            ContainerError::VolumeNotFound { .. }
+                | ContainerError::UnsupportedMountPropagation { .. }
+                | ContainerError::InvalidMountPath { .. }
+                | ContainerError::OutsideVolume { .. }
+                | ContainerError::TooManyDirs { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reason_and_retries() {
+        let io_error = || std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+        // Each error with the reason it is reported with and whether it is retried
+        let errors = vec![
+            (
+                ContainerError::VolumeNotFound {
+                    volume: "data".to_owned(),
+                    container: "module".to_owned(),
+                },
+                "CreateContainerConfigError",
+                false,
+            ),
+            (
+                ContainerError::UnsupportedMountPropagation {
+                    volume: "data".to_owned(),
+                    mode: "Bidirectional".to_owned(),
+                },
+                "CreateContainerConfigError",
+                false,
+            ),
+            (
+                ContainerError::InvalidMountPath {
+                    volume: "data".to_owned(),
+                    path: PathBuf::from("config.toml"),
+                    mount_path: "/".to_owned(),
+                },
+                "CreateContainerConfigError",
+                false,
+            ),
+            (
+                ContainerError::OutsideVolume {
+                    volume: "data".to_owned(),
+                    path: PathBuf::from("link"),
+                    resolved: PathBuf::from("/etc"),
+                },
+                "CreateContainerConfigError",
+                false,
+            ),
+            (
+                ContainerError::TooManyDirs {
+                    dirs: 300,
+                    limit: 256,
+                },
+                "CreateContainerConfigError",
+                false,
+            ),
+            (
+                ContainerError::VolumeNotMounted {
+                    volume: "data".to_owned(),
+                },
+                "CreateContainerError",
+                true,
+            ),
+            (
+                ContainerError::VolumeIo {
+                    volume: "data".to_owned(),
+                    path: PathBuf::from("config.toml"),
+                    source: io_error(),
+                },
+                "CreateContainerError",
+                true,
+            ),
+            (
+                ContainerError::Staging(io_error()),
+                "CreateContainerError",
+                true,
+            ),
+            (
+                ContainerError::RuntimeInit(anyhow::anyhow!("no engine")),
+                "CreateContainerError",
+                true,
+            ),
+            (
+                ContainerError::ModuleMissing {
+                    container: "module".to_owned(),
+                },
+                "ModuleMissing",
+                true,
+            ),
+            (
+                ContainerError::RuntimeStart(anyhow::anyhow!("trap")),
+                "StartError",
+                true,
+            ),
+            (
+                ContainerError::StartTimedOut(Duration::from_secs(30)),
+                "StartTimeout",
+                true,
+            ),
+        ];
+        for (error, reason, retryable) in errors {
+            assert_eq!(reason, error.reason(), "{}", error);
+            assert_eq!(retryable, error.is_retryable(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            "did not start within 30 seconds",
+            ContainerError::StartTimedOut(Duration::from_millis(30_500)).to_string()
+        );
+        // The whole chain of the runtime's error is kept
+        let e = anyhow::anyhow!("unknown import").context("instantiating module");
+        assert_eq!(
+            "failed to start: instantiating module: unknown import",
+            ContainerError::RuntimeStart(e).to_string()
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! The WebAssembly features modules are compiled with.
+//!
+//! Modules are compiled with wasmtime's default features, along with SIMD where the node's CPU
+//! supports it. A pod can turn features on or off for its modules with an annotation listing
+//! feature names, each optionally prefixed with `+` to enable it or `-` to disable it, such as
+//! `threads,-reference-types`. The node can disable features outright, in which case pods that
+//! ask for them are failed before their modules are compiled.
+//!
+//! Pods can also ask for a number of threads for their modules. Any more than one turns on the
+//! threads feature, just as naming it would, and the number is kept as a limit on the threads the
+//! module may run on.
+use std::fmt;
+use std::str::FromStr;
+
+use crate::wasi_runtime::simd_supported;
+
+/// A WebAssembly feature that can be turned on or off for a module
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum WasmFeature {
+    Simd,
+    BulkMemory,
+    ReferenceTypes,
+    MultiValue,
+    Threads,
+}
+
+impl WasmFeature {
+    const ALL: &'static [WasmFeature] = &[
+        WasmFeature::Simd,
+        WasmFeature::BulkMemory,
+        WasmFeature::ReferenceTypes,
+        WasmFeature::MultiValue,
+        WasmFeature::Threads,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            WasmFeature::Simd => "simd",
+            WasmFeature::BulkMemory => "bulk-memory",
+            WasmFeature::ReferenceTypes => "reference-types",
+            WasmFeature::MultiValue => "multi-value",
+            WasmFeature::Threads => "threads",
+        }
+    }
+}
+
+impl fmt::Display for WasmFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for WasmFeature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WasmFeature::ALL
+            .iter()
+            .copied()
+            .find(|feature| feature.name() == s)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unknown wasm feature {:?}, expected one of {}",
+                    s,
+                    WasmFeature::ALL
+                        .iter()
+                        .map(|f| f.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// The features a module is compiled with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct WasmFeatures {
+    simd: bool,
+    bulk_memory: bool,
+    reference_types: bool,
+    multi_value: bool,
+    threads: bool,
+}
+
+impl Default for WasmFeatures {
+    /// Wasmtime's default features, with SIMD if the node supports it
+    fn default() -> Self {
+        WasmFeatures {
+            simd: simd_supported(),
+            bulk_memory: true,
+            reference_types: true,
+            multi_value: true,
+            threads: false,
+        }
+    }
+}
+
+impl WasmFeatures {
+    /// The default features with those the annotation turns on or off. Features the node has
+    /// disabled are left off, and an error naming the feature is returned if the annotation asks
+    /// for one of them
+    pub(crate) fn from_annotation(
+        annotation: Option<&str>,
+        disabled: &[WasmFeature],
+    ) -> anyhow::Result<Self> {
+        let mut features = WasmFeatures::default();
+        for feature in disabled {
+            features.set(*feature, false);
+        }
+        let items = annotation
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty());
+        for item in items {
+            let (name, enabled) = match (item.strip_prefix('+'), item.strip_prefix('-')) {
+                (Some(name), _) => (name, true),
+                (_, Some(name)) => (name, false),
+                _ => (item, true),
+            };
+            let feature: WasmFeature = name.trim().parse()?;
+            if enabled && disabled.contains(&feature) {
+                anyhow::bail!("wasm feature {} is disabled on this node", feature);
+            }
+            if enabled && feature == WasmFeature::Simd && !simd_supported() {
+                anyhow::bail!(
+                    "wasm feature {} is not supported by this node's CPU",
+                    feature
+                );
+            }
+            features.set(feature, enabled);
+        }
+        // Wasmtime needs bulk memory for these, and would otherwise turn it back on silently
+        for feature in &[WasmFeature::ReferenceTypes, WasmFeature::Threads] {
+            if features.enabled(*feature) && !features.bulk_memory {
+                anyhow::bail!(
+                    "wasm feature {} requires {} to be enabled",
+                    feature,
+                    WasmFeature::BulkMemory
+                );
+            }
+        }
+        Ok(features)
+    }
+
+    /// Turns on threads if the module may run on more than one. Modules are still run on a single
+    /// host thread, as this version of wasmtime can't spawn threads for them, but they are given
+    /// the atomic instructions that modules built with threads use. An error is returned if more
+    /// than one thread is asked for when the node has disabled threads
+    pub(crate) fn with_max_threads(
+        mut self,
+        max_threads: u32,
+        disabled: &[WasmFeature],
+    ) -> anyhow::Result<Self> {
+        if max_threads > 1 {
+            if disabled.contains(&WasmFeature::Threads) {
+                anyhow::bail!(
+                    "{} threads were requested, but wasm feature {} is disabled on this node",
+                    max_threads,
+                    WasmFeature::Threads
+                );
+            }
+            if !self.bulk_memory {
+                anyhow::bail!(
+                    "wasm feature {} requires {} to be enabled",
+                    WasmFeature::Threads,
+                    WasmFeature::BulkMemory
+                );
+            }
+            self.threads = true;
+        }
+        Ok(self)
+    }
+
+    fn set(&mut self, feature: WasmFeature, enabled: bool) {
+        match feature {
+            WasmFeature::Simd => self.simd = enabled,
+            WasmFeature::BulkMemory => self.bulk_memory = enabled,
+            WasmFeature::ReferenceTypes => self.reference_types = enabled,
+            WasmFeature::MultiValue => self.multi_value = enabled,
+            WasmFeature::Threads => self.threads = enabled,
+        }
+    }
+
+    fn enabled(&self, feature: WasmFeature) -> bool {
+        match feature {
+            WasmFeature::Simd => self.simd,
+            WasmFeature::BulkMemory => self.bulk_memory,
+            WasmFeature::ReferenceTypes => self.reference_types,
+            WasmFeature::MultiValue => self.multi_value,
+            WasmFeature::Threads => self.threads,
+        }
+    }
+
+    /// Turns the features on or off in an engine's config
+    pub(crate) fn apply(&self, config: &mut wasmtime::Config) {
+        config.wasm_simd(self.simd);
+        config.wasm_bulk_memory(self.bulk_memory);
+        config.wasm_reference_types(self.reference_types);
+        config.wasm_multi_value(self.multi_value);
+        config.wasm_threads(self.threads);
+    }
+
+    /// What is added to the name of modules compiled with these features in the compile cache.
+    /// Modules compiled with the default features keep the name they had before features could be
+    /// chosen
+    pub(crate) fn cache_suffix(&self) -> String {
+        let default = WasmFeatures::default();
+        WasmFeature::ALL
+            .iter()
+            .filter(|feature| self.enabled(**feature) != default.enabled(**feature))
+            .map(|feature| {
+                let sign = if self.enabled(*feature) { '+' } else { '-' };
+                format!("-{}{}", sign, feature)
+            })
+            .collect()
+    }
+}
+
+/// Parses the number of threads a pod asks for its modules to run on at most, which must be at
+/// least one
+pub(crate) fn parse_max_threads(annotation: &str) -> anyhow::Result<u32> {
+    let max_threads: u32 = annotation.trim().parse()?;
+    if max_threads == 0 {
+        anyhow::bail!("a module needs at least one thread");
+    }
+    Ok(max_threads)
+}
+
+/// Parses the names of the features the node has disabled
+pub(crate) fn parse_disabled(names: &[String]) -> anyhow::Result<Vec<WasmFeature>> {
+    names.iter().map(|name| name.trim().parse()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_feature_names() {
+        for feature in WasmFeature::ALL {
+            assert_eq!(
+                feature.to_string().parse::<WasmFeature>().unwrap(),
+                *feature
+            );
+        }
+        let err = "tail-call".parse::<WasmFeature>().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unknown wasm feature \"tail-call\""));
+    }
+
+    #[test]
+    fn test_features_from_annotation() {
+        let default = WasmFeatures::default();
+        assert_eq!(WasmFeatures::from_annotation(None, &[]).unwrap(), default);
+        assert_eq!(
+            WasmFeatures::from_annotation(Some(" , "), &[]).unwrap(),
+            default
+        );
+
+        let features =
+            WasmFeatures::from_annotation(Some("threads, -reference-types,+multi-value"), &[])
+                .unwrap();
+        assert!(features.threads);
+        assert!(!features.reference_types);
+        assert!(features.multi_value);
+        assert!(features.bulk_memory);
+
+        assert!(WasmFeatures::from_annotation(Some("threads,gc"), &[]).is_err());
+    }
+
+    #[test]
+    fn test_disabled_features() {
+        let features = WasmFeatures::from_annotation(None, &[WasmFeature::MultiValue]).unwrap();
+        assert!(!features.multi_value);
+        // Turning a disabled feature off again is fine, asking for it isn't
+        assert!(
+            WasmFeatures::from_annotation(Some("-multi-value"), &[WasmFeature::MultiValue]).is_ok()
+        );
+        let err = WasmFeatures::from_annotation(Some("multi-value"), &[WasmFeature::MultiValue])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "wasm feature multi-value is disabled on this node"
+        );
+    }
+
+    #[test]
+    fn test_features_needing_bulk_memory() {
+        assert!(WasmFeatures::from_annotation(Some("-bulk-memory"), &[]).is_err());
+        assert!(WasmFeatures::from_annotation(Some("-bulk-memory,-reference-types"), &[]).is_ok());
+        assert!(
+            WasmFeatures::from_annotation(Some("-bulk-memory,-reference-types,threads"), &[])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_features_with_max_threads() {
+        let default = WasmFeatures::default();
+        assert!(!default.with_max_threads(1, &[]).unwrap().threads);
+        assert!(default.with_max_threads(4, &[]).unwrap().threads);
+        let err = default
+            .with_max_threads(4, &[WasmFeature::Threads])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "4 threads were requested, but wasm feature threads is disabled on this node"
+        );
+        // A single thread doesn't need the feature, so it is fine when threads are disabled
+        assert!(default.with_max_threads(1, &[WasmFeature::Threads]).is_ok());
+        let without_bulk_memory =
+            WasmFeatures::from_annotation(Some("-bulk-memory,-reference-types"), &[]).unwrap();
+        assert!(without_bulk_memory.with_max_threads(2, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_threads() {
+        assert_eq!(parse_max_threads("1").unwrap(), 1);
+        assert_eq!(parse_max_threads(" 4 ").unwrap(), 4);
+        assert!(parse_max_threads("0").is_err());
+        assert!(parse_max_threads("-2").is_err());
+        assert!(parse_max_threads("many").is_err());
+    }
+
+    #[test]
+    fn test_cache_suffix() {
+        assert_eq!(WasmFeatures::default().cache_suffix(), "");
+        let features = WasmFeatures::from_annotation(Some("threads,-multi-value"), &[]).unwrap();
+        assert_eq!(features.cache_suffix(), "--multi-value-+threads");
+    }
+
+    #[test]
+    fn test_parse_disabled() {
+        assert_eq!(
+            parse_disabled(&[" simd ".to_owned(), "threads".to_owned()]).unwrap(),
+            vec![WasmFeature::Simd, WasmFeature::Threads]
+        );
+        assert!(parse_disabled(&["simd".to_owned(), "bogus".to_owned()]).is_err());
+    }
+}
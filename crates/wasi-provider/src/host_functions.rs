@@ -0,0 +1,310 @@
+//! Host functions modules can import beyond WASI and the functions krustlet provides itself.
+//!
+//! Functions are grouped by the module namespace they are imported from. Whoever builds the
+//! provider registers each namespace's functions with a [`HostFunctionRegistry`], and they are
+//! defined in the linker of every module run, before the module is instantiated. Modules that
+//! don't import a namespace are unaffected by it.
+//!
+//! [`HostLog`] is a small example, which lets modules write to krustlet's own log:
+//!
+//! ```rust,no_run
+//! use std::sync::Arc;
+//! use wasi_provider::{HostFunctionRegistry, HostLog};
+//!
+//! let mut host_functions = HostFunctionRegistry::default();
+//! host_functions.register(Arc::new(HostLog)).unwrap();
+//! ```
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tracing::{debug, error, info, trace, warn};
+use wasmtime::{Caller, Extern, Linker};
+
+use crate::http::HTTP_MODULE;
+use crate::sockets::SOCKETS_MODULE;
+use crate::wasi_runtime::StoreData;
+
+/// The namespaces krustlet defines functions in itself, which can't be registered
+const RESERVED_NAMESPACES: &[&str] = &[
+    "wasi_snapshot_preview1",
+    "wasi_unstable",
+    HTTP_MODULE,
+    SOCKETS_MODULE,
+];
+
+/// A set of host functions that modules import from one namespace
+pub trait HostFunctions: Send + Sync {
+    /// The module namespace the functions are imported from
+    fn namespace(&self) -> &str;
+
+    /// Defines the functions in the linker of a module run, all in [`namespace`](Self::namespace).
+    /// `module_name` is the name of the run, such as the container it is for
+    fn add_to_linker(
+        &self,
+        linker: &mut Linker<StoreData>,
+        module_name: &str,
+    ) -> anyhow::Result<()>;
+}
+
+/// The host functions registered for modules to import, keyed by namespace
+#[derive(Clone, Default)]
+pub struct HostFunctionRegistry {
+    functions: BTreeMap<String, Arc<dyn HostFunctions>>,
+}
+
+impl HostFunctionRegistry {
+    /// Registers a set of host functions. An error is returned if its namespace is one krustlet
+    /// defines functions in, or already has functions registered
+    pub fn register(&mut self, functions: Arc<dyn HostFunctions>) -> anyhow::Result<()> {
+        let namespace = functions.namespace().to_owned();
+        if RESERVED_NAMESPACES.contains(&namespace.as_str()) {
+            anyhow::bail!("host function namespace {} is reserved", namespace);
+        }
+        if self.functions.contains_key(&namespace) {
+            anyhow::bail!(
+                "host functions are already registered in namespace {}",
+                namespace
+            );
+        }
+        self.functions.insert(namespace, functions);
+        Ok(())
+    }
+
+    /// Defines every registered function in the linker of a module run
+    pub(crate) fn add_to_linker(
+        &self,
+        linker: &mut Linker<StoreData>,
+        module_name: &str,
+    ) -> anyhow::Result<()> {
+        for (namespace, functions) in &self.functions {
+            functions.add_to_linker(linker, module_name).map_err(|e| {
+                anyhow::anyhow!(
+                    "unable to link host functions in namespace {}: {}",
+                    namespace,
+                    e
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// The namespace [`HostLog`] defines its function in
+pub const HOST_LOG_MODULE: &str = "krustlet_log";
+
+/// Lets modules write to krustlet's log with `krustlet_log::log(level, ptr, len)`, where the
+/// message is the UTF-8 string of `len` bytes at `ptr` in the module's exported memory, and
+/// `level` is 1 for errors through to 5 for traces. Each message is logged along with the name of
+/// the module run it came from. Messages longer than 4096 bytes are cut short,
+/// and messages that can't be read are dropped
+pub struct HostLog;
+
+/// The most bytes of a message [`HostLog`] logs
+const MAX_LOG_MESSAGE_LEN: u32 = 4096;
+
+impl HostFunctions for HostLog {
+    fn namespace(&self) -> &str {
+        HOST_LOG_MODULE
+    }
+
+    fn add_to_linker(
+        &self,
+        linker: &mut Linker<StoreData>,
+        module_name: &str,
+    ) -> anyhow::Result<()> {
+        let module_name = module_name.to_owned();
+        linker.func_wrap(
+            HOST_LOG_MODULE,
+            "log",
+            move |mut caller: Caller<'_, StoreData>, level: u32, ptr: u32, len: u32| {
+                let message = match read_string(&mut caller, ptr, len.min(MAX_LOG_MESSAGE_LEN)) {
+                    Some(message) => message,
+                    None => return,
+                };
+                match level {
+                    1 => error!(module = %module_name, "{}", message),
+                    2 => warn!(module = %module_name, "{}", message),
+                    3 => info!(module = %module_name, "{}", message),
+                    4 => debug!(module = %module_name, "{}", message),
+                    _ => trace!(module = %module_name, "{}", message),
+                }
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Reads the string of `len` bytes at `ptr` in the module's exported memory. The range is checked
+/// against the size of the memory before anything is allocated for it, so a module can't have the
+/// host allocate more than it has itself. A string cut short in the middle of a character keeps
+/// what comes before it
+fn read_string(caller: &mut Caller<'_, StoreData>, ptr: u32, len: u32) -> Option<String> {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => return None,
+    };
+    let end = u64::from(ptr) + u64::from(len);
+    if end > memory.data_size(&*caller) as u64 {
+        return None;
+    }
+    let mut buf = vec![0; len as usize];
+    memory.read(&mut *caller, ptr as usize, &mut buf).ok()?;
+    match String::from_utf8(buf) {
+        Ok(message) => Some(message),
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut buf = e.into_bytes();
+            buf.truncate(valid);
+            String::from_utf8(buf).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use wasmtime::{Engine, Module, Store};
+    use wasmtime_wasi::sync::WasiCtxBuilder;
+
+    struct Named(&'static str);
+
+    impl HostFunctions for Named {
+        fn namespace(&self) -> &str {
+            self.0
+        }
+
+        fn add_to_linker(&self, linker: &mut Linker<StoreData>, _: &str) -> anyhow::Result<()> {
+            linker.func_wrap(self.0, "f", || {})?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = HostFunctionRegistry::default();
+        registry.register(Arc::new(Named("custom"))).unwrap();
+        let err = registry.register(Arc::new(Named("custom"))).unwrap_err();
+        assert!(err.to_string().contains("already registered"), "{}", err);
+        for namespace in RESERVED_NAMESPACES {
+            let err = registry.register(Arc::new(Named(namespace))).unwrap_err();
+            assert!(err.to_string().contains("reserved"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_add_to_linker() {
+        let engine = Engine::default();
+        let mut linker = Linker::new(&engine);
+        let mut registry = HostFunctionRegistry::default();
+        registry.register(Arc::new(Named("custom"))).unwrap();
+        registry.add_to_linker(&mut linker, "test").unwrap();
+        let mut store = Store::new(&engine, StoreData::unlimited(WasiCtxBuilder::new().build()));
+        assert!(linker.get(&mut store, "custom", Some("f")).is_some());
+        // Defining the same functions twice in one linker fails, naming the namespace
+        let err = registry.add_to_linker(&mut linker, "test").unwrap_err();
+        assert!(err.to_string().contains("namespace custom"), "{}", err);
+    }
+
+    /// Runs a module with one page of memory holding `data` at address 0, that calls the imported
+    /// `read(ptr, len)` with each of `calls`, returning what `read_string` made of them
+    fn read_strings(data: &str, calls: &[(u32, u32)]) -> Vec<Option<String>> {
+        let body: String = calls
+            .iter()
+            .map(|(ptr, len)| format!("(call $read (i32.const {}) (i32.const {}))", ptr, len))
+            .collect();
+        let wat = format!(
+            r#"(module
+                (import "test" "read" (func $read (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{}")
+                (func (export "_start") {}))"#,
+            data, body
+        );
+        let engine = Engine::default();
+        let module = Module::new(&engine, wat).unwrap();
+        let mut linker = Linker::new(&engine);
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let results = Arc::clone(&read);
+        linker
+            .func_wrap(
+                "test",
+                "read",
+                move |mut caller: Caller<'_, StoreData>, ptr: u32, len: u32| {
+                    let string = read_string(&mut caller, ptr, len);
+                    results.lock().unwrap().push(string);
+                },
+            )
+            .unwrap();
+        let mut store = Store::new(&engine, StoreData::unlimited(WasiCtxBuilder::new().build()));
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        instance
+            .get_typed_func::<(), (), _>(&mut store, "_start")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+        let read = read.lock().unwrap().clone();
+        read
+    }
+
+    #[test]
+    fn test_read_string() {
+        let read = read_strings(
+            "hello\\e2\\82\\ac\\ff",
+            &[
+                (0, 5),
+                // Ranges past the end of memory are refused without allocating for them
+                (0, u32::MAX),
+                (u32::MAX, 2),
+                (65535, 2),
+                (65535, 1),
+                // A euro sign cut short keeps what comes before it
+                (0, 7),
+                (0, 8),
+                // Invalid UTF-8 isn't
+                (0, 9),
+            ],
+        );
+        assert_eq!(
+            vec![
+                Some("hello".to_owned()),
+                None,
+                None,
+                None,
+                Some("\0".to_owned()),
+                Some("hello".to_owned()),
+                Some("hello\u{20ac}".to_owned()),
+                None,
+            ],
+            read
+        );
+    }
+
+    #[test]
+    fn test_host_log() {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+                (import "krustlet_log" "log" (func $log (param i32 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "hello")
+                (func (export "_start")
+                    (call $log (i32.const 3) (i32.const 0) (i32.const 5))
+                    (call $log (i32.const 1) (i32.const 0) (i32.const -1))
+                    (call $log (i32.const 9) (i32.const -1) (i32.const -1))))"#,
+        )
+        .unwrap();
+        let mut linker = Linker::new(&engine);
+        HostLog.add_to_linker(&mut linker, "test").unwrap();
+        let mut store = Store::new(&engine, StoreData::unlimited(WasiCtxBuilder::new().build()));
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        // Messages that can't be read are dropped rather than trapping the module
+        instance
+            .get_typed_func::<(), (), _>(&mut store, "_start")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+    }
+}
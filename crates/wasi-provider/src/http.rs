@@ -0,0 +1,1048 @@
+//! The outbound HTTP functions modules import from `wasi_experimental_http`.
+//!
+//! These follow the interface of the experimental HTTP library. A request is sent with `req`,
+//! which hands back a handle that the response's headers and body are read through, and the
+//! handle is released with `close`. Every function returns one of the library's error codes, with
+//! 0 meaning success. Responses are read in full before `req` returns, so a request timeout
+//! covers the whole exchange with the server, including any retries.
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use k8s_openapi::ByteString;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::redirect::Policy;
+#[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+use reqwest::{Certificate, Identity};
+use reqwest::{Client, ClientBuilder, Method, Request};
+use tokio::runtime::Handle;
+use tracing::{debug, warn};
+use url::{Host, Url};
+use wasmtime::{Caller, Linker};
+
+use crate::allow_list::AllowList;
+use crate::dns::Resolver;
+use crate::wasi_runtime::StoreData;
+
+/// The module and function name the HTTP request call is imported under
+pub(crate) const HTTP_MODULE: &str = "wasi_experimental_http";
+pub(crate) const HTTP_REQUEST_FUNC: &str = "req";
+
+/// The most redirects followed for a request
+const MAX_REDIRECTS: usize = 10;
+/// The longest the wait between retries of a request grows to
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The errors returned to the module, with the codes the experimental HTTP library gives them
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HttpError {
+    InvalidHandle = 1,
+    MemoryNotFound = 2,
+    MemoryAccessError = 3,
+    BufferTooSmall = 4,
+    HeaderNotFound = 5,
+    Utf8Error = 6,
+    DestinationNotAllowed = 7,
+    InvalidMethod = 8,
+    InvalidEncoding = 9,
+    InvalidUrl = 10,
+    RequestError = 11,
+    TooManySessions = 13,
+}
+
+/// The code returned to the module for the result of a call
+fn code(result: Result<(), HttpError>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u32,
+    }
+}
+
+/// A response that has been read in full and is waiting for the module to read it
+struct Response {
+    status: u16,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// How much of the body the module has read so far
+    read: usize,
+}
+
+/// The responses the module holds handles to
+#[derive(Default)]
+struct Responses {
+    next_handle: u32,
+    open: HashMap<u32, Response>,
+}
+
+impl Responses {
+    fn insert(&mut self, response: Response) -> u32 {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.open.insert(handle, response);
+        handle
+    }
+}
+
+/// The clients requests are sent with
+struct Clients {
+    /// Sends requests to wherever the node resolves their host to
+    default: Client,
+    /// Clients that send requests for a domain to the address it was last checked at, keyed by
+    /// the domain
+    pinned: Mutex<HashMap<String, (IpAddr, Client)>>,
+}
+
+/// How idempotent requests that fail to connect or get a server error are retried
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The most times a request is retried after its first attempt
+    pub max_retries: u32,
+    /// How long to wait before the first retry. The wait doubles for every retry after, until it
+    /// reaches 30 seconds
+    pub backoff: Duration,
+}
+
+/// The certificate and private key requests identify the module with to servers that ask for
+/// one, as they do for mutual TLS
+#[derive(Clone)]
+pub struct ClientCert {
+    /// The certificate and key in the form the TLS backend krustlet was built with loads
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    identity: Vec<u8>,
+}
+
+impl ClientCert {
+    /// The keys of a secret a client certificate can be read from
+    pub(crate) const SECRET_KEYS: &'static [&'static str] = &["tls.p12", "tls.crt", "tls.key"];
+
+    /// Reads the certificate from the data of a secret. Builds using native TLS load it from a
+    /// PKCS #12 bundle without a password under `tls.p12`, as they can't load PEM keys
+    #[cfg(feature = "native-tls")]
+    pub(crate) fn from_secret_data(data: &BTreeMap<String, ByteString>) -> anyhow::Result<Self> {
+        match data.get("tls.p12") {
+            Some(bundle) => Ok(ClientCert {
+                identity: bundle.0.clone(),
+            }),
+            None => anyhow::bail!("secret has no tls.p12 key"),
+        }
+    }
+
+    /// Reads the certificate from the data of a `kubernetes.io/tls` secret, which has the PEM
+    /// encoded certificate under `tls.crt` and its key under `tls.key`
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    pub(crate) fn from_secret_data(data: &BTreeMap<String, ByteString>) -> anyhow::Result<Self> {
+        let cert = data
+            .get("tls.crt")
+            .ok_or_else(|| anyhow::anyhow!("secret has no tls.crt key"))?;
+        let key = data
+            .get("tls.key")
+            .ok_or_else(|| anyhow::anyhow!("secret has no tls.key key"))?;
+        let mut identity = cert.0.clone();
+        identity.push(b'\n');
+        identity.extend_from_slice(&key.0);
+        Ok(ClientCert { identity })
+    }
+
+    /// Client certificates can't be used without a TLS backend
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    pub(crate) fn from_secret_data(_data: &BTreeMap<String, ByteString>) -> anyhow::Result<Self> {
+        anyhow::bail!("krustlet was built without TLS support")
+    }
+
+    #[cfg(feature = "native-tls")]
+    fn identity(&self) -> anyhow::Result<Identity> {
+        Ok(Identity::from_pkcs12_der(&self.identity, "")?)
+    }
+
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    fn identity(&self) -> anyhow::Result<Identity> {
+        Ok(Identity::from_pem(&self.identity)?)
+    }
+}
+
+/// Certificate authorities the certificates of servers requests are sent to are verified against
+#[derive(Clone)]
+pub struct CaBundle {
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    certs: Vec<Certificate>,
+    /// Whether the bundle replaces the system's roots rather than adding to them
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    exclusive: bool,
+}
+
+impl CaBundle {
+    /// Reads every certificate in a PEM encoded bundle. An exclusive bundle is the only one
+    /// servers are verified against, otherwise the system's roots are trusted as well
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    pub(crate) fn from_pem(pem: &[u8], exclusive: bool) -> anyhow::Result<Self> {
+        const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+        const PEM_END: &str = "-----END CERTIFICATE-----";
+        let pem = std::str::from_utf8(pem)?;
+        let certs = pem
+            .split_inclusive(PEM_END)
+            .filter(|block| block.contains(PEM_BEGIN))
+            .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+            .collect::<reqwest::Result<Vec<_>>>()?;
+        if certs.is_empty() {
+            anyhow::bail!("bundle has no PEM encoded certificates");
+        }
+        Ok(CaBundle { certs, exclusive })
+    }
+
+    /// Certificate authorities can't be trusted without a TLS backend
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    pub(crate) fn from_pem(_pem: &[u8], _exclusive: bool) -> anyhow::Result<Self> {
+        anyhow::bail!("krustlet was built without TLS support")
+    }
+}
+
+/// The settings outbound requests are made with
+pub(crate) struct HttpCtx {
+    /// The domains and networks requests may be sent to
+    allow_list: AllowList,
+    /// The most responses the module may hold open at once
+    max_concurrent_requests: Option<u32>,
+    /// How long a request may take, including reading its response, before it is abandoned
+    request_timeout: Option<Duration>,
+    /// How failed requests are retried, if they are retried at all
+    retry: Option<RetryPolicy>,
+    /// The certificate requests identify the module with, if it has one
+    client_cert: Option<ClientCert>,
+    /// The certificate authorities servers are verified against, if not only the system's
+    ca_bundle: Option<CaBundle>,
+    /// Resolves the hosts of requests with the pod's DNS settings, if not with the node's
+    resolver: Option<Arc<Resolver>>,
+}
+
+impl HttpCtx {
+    pub(crate) fn new(
+        allow_list: AllowList,
+        max_concurrent_requests: Option<u32>,
+        request_timeout: Option<Duration>,
+        retry: Option<RetryPolicy>,
+    ) -> Self {
+        HttpCtx {
+            allow_list,
+            max_concurrent_requests,
+            request_timeout,
+            retry,
+            client_cert: None,
+            ca_bundle: None,
+            resolver: None,
+        }
+    }
+
+    /// Sets the certificate requests identify the module with to servers that ask for one
+    pub(crate) fn with_client_cert(mut self, client_cert: Option<ClientCert>) -> Self {
+        self.client_cert = client_cert;
+        self
+    }
+
+    /// Sets the certificate authorities the certificates of servers are verified against
+    pub(crate) fn with_ca_bundle(mut self, ca_bundle: Option<CaBundle>) -> Self {
+        self.ca_bundle = ca_bundle;
+        self
+    }
+
+    /// Sets the resolver the hosts of requests are resolved with instead of the node's
+    pub(crate) fn with_resolver(mut self, resolver: Option<Arc<Resolver>>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Defines the HTTP functions in the linker. Requests are run on the current tokio runtime,
+    /// which the module's calls block on
+    pub(crate) fn add_to_linker(self, linker: &mut Linker<StoreData>) -> anyhow::Result<()> {
+        let runtime = Handle::current();
+        let clients = Clients {
+            default: self.client_builder()?.build()?,
+            pinned: Mutex::new(HashMap::new()),
+        };
+        let responses = Arc::new(Mutex::new(Responses::default()));
+        let ctx = Arc::new(self);
+
+        let open = responses.clone();
+        linker.func_wrap(
+            HTTP_MODULE,
+            HTTP_REQUEST_FUNC,
+            move |mut caller: Caller<'_, StoreData>,
+                  url_ptr: u32,
+                  url_len: u32,
+                  method_ptr: u32,
+                  method_len: u32,
+                  headers_ptr: u32,
+                  headers_len: u32,
+                  body_ptr: u32,
+                  body_len: u32,
+                  status_ptr: u32,
+                  handle_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let url = read_string(&mut caller, url_ptr, url_len)?;
+                    let method = read_string(&mut caller, method_ptr, method_len)?;
+                    let headers = read_string(&mut caller, headers_ptr, headers_len)?;
+                    let body = read(&mut caller, body_ptr, body_len)?;
+                    let url = Url::parse(&url).map_err(|_| HttpError::InvalidUrl)?;
+                    let client = ctx.client(&clients, &url)?;
+                    let request = ctx.request(&client, &open, &url, &method, &headers, body)?;
+                    let response =
+                        runtime.block_on(send(&client, request, ctx.request_timeout, ctx.retry));
+                    let response = response.map_err(|e| {
+                        warn!(%url, error = %e, "Outbound HTTP request failed");
+                        HttpError::RequestError
+                    })?;
+                    write(&mut caller, status_ptr, &response.status.to_le_bytes())?;
+                    let handle = open.lock().unwrap().insert(response);
+                    write(&mut caller, handle_ptr, &handle.to_le_bytes())
+                })();
+                code(result)
+            },
+        )?;
+
+        let open = responses.clone();
+        linker.func_wrap(HTTP_MODULE, "close", move |handle: u32| -> u32 {
+            code(
+                open.lock()
+                    .unwrap()
+                    .open
+                    .remove(&handle)
+                    .map(|_| ())
+                    .ok_or(HttpError::InvalidHandle),
+            )
+        })?;
+
+        let open = responses.clone();
+        linker.func_wrap(
+            HTTP_MODULE,
+            "header_get",
+            move |mut caller: Caller<'_, StoreData>,
+                  handle: u32,
+                  name_ptr: u32,
+                  name_len: u32,
+                  value_ptr: u32,
+                  value_len: u32,
+                  written_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let name = read_string(&mut caller, name_ptr, name_len)?;
+                    let value = {
+                        let open = open.lock().unwrap();
+                        let response = open.open.get(&handle).ok_or(HttpError::InvalidHandle)?;
+                        let value = response
+                            .headers
+                            .get(name.as_str())
+                            .ok_or(HttpError::HeaderNotFound)?;
+                        value.as_bytes().to_vec()
+                    };
+                    write_buffer(&mut caller, value_ptr, value_len, written_ptr, &value)
+                })();
+                code(result)
+            },
+        )?;
+
+        let open = responses.clone();
+        linker.func_wrap(
+            HTTP_MODULE,
+            "headers_get_all",
+            move |mut caller: Caller<'_, StoreData>,
+                  handle: u32,
+                  buf_ptr: u32,
+                  buf_len: u32,
+                  written_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let headers = {
+                        let open = open.lock().unwrap();
+                        let response = open.open.get(&handle).ok_or(HttpError::InvalidHandle)?;
+                        format_headers(&response.headers)
+                    };
+                    write_buffer(
+                        &mut caller,
+                        buf_ptr,
+                        buf_len,
+                        written_ptr,
+                        headers.as_bytes(),
+                    )
+                })();
+                code(result)
+            },
+        )?;
+
+        let open = responses;
+        linker.func_wrap(
+            HTTP_MODULE,
+            "body_read",
+            move |mut caller: Caller<'_, StoreData>,
+                  handle: u32,
+                  buf_ptr: u32,
+                  buf_len: u32,
+                  read_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let chunk = {
+                        let mut open = open.lock().unwrap();
+                        let response =
+                            open.open.get_mut(&handle).ok_or(HttpError::InvalidHandle)?;
+                        let end = response.body.len().min(response.read + buf_len as usize);
+                        let chunk = response.body[response.read..end].to_vec();
+                        response.read = end;
+                        chunk
+                    };
+                    write_buffer(&mut caller, buf_ptr, buf_len, read_ptr, &chunk)
+                })();
+                code(result)
+            },
+        )?;
+        Ok(())
+    }
+
+    /// A builder for clients with the certificates requests are sent with
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    fn client_builder(&self) -> anyhow::Result<ClientBuilder> {
+        let mut client = Client::builder().redirect(redirect_policy());
+        if let Some(client_cert) = &self.client_cert {
+            client = client.identity(
+                client_cert
+                    .identity()
+                    .map_err(|e| anyhow::anyhow!("unable to load client certificate: {}", e))?,
+            );
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            for cert in &ca_bundle.certs {
+                client = client.add_root_certificate(cert.clone());
+            }
+            client = client.tls_built_in_root_certs(!ca_bundle.exclusive);
+        }
+        Ok(client)
+    }
+
+    /// Without a TLS backend there are no certificates to send requests with, as neither a
+    /// client certificate nor a CA bundle can be read
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    fn client_builder(&self) -> anyhow::Result<ClientBuilder> {
+        Ok(Client::builder().redirect(redirect_policy()))
+    }
+
+    /// The client to send a request to the given URL with, if requests may be sent there at all.
+    /// Domains that have to be resolved to be checked, or that the pod resolves itself, are
+    /// resolved here and the client connects to the address they were checked at
+    fn client(&self, clients: &Clients, url: &Url) -> Result<Client, HttpError> {
+        match self.destination(url)? {
+            None => Ok(clients.default.clone()),
+            Some(addr) => {
+                let domain = url.host_str().ok_or(HttpError::InvalidUrl)?;
+                pinned_client(clients, domain, addr, || self.client_builder()).map_err(|e| {
+                    warn!(%url, error = %e, "Unable to build client for request");
+                    HttpError::RequestError
+                })
+            }
+        }
+    }
+
+    /// Checks that requests may be sent to the host of the URL. Returns the address the request
+    /// must be sent to, or `None` if it can go wherever its host resolves to
+    fn destination(&self, url: &Url) -> Result<Option<IpAddr>, HttpError> {
+        let host = url.host().ok_or(HttpError::InvalidUrl)?;
+        let listed = self.allow_list.lists(&host.to_string());
+        let domain = match host {
+            Host::Domain(domain) => domain,
+            Host::Ipv4(ip) if listed || self.allow_list.contains(&ip.into()) => return Ok(None),
+            Host::Ipv6(ip) if listed || self.allow_list.contains(&ip.into()) => return Ok(None),
+            _ => return Err(not_allowed(url)),
+        };
+        if listed && self.resolver.is_none() {
+            return Ok(None);
+        }
+        if !listed && !self.allow_list.has_networks() {
+            return Err(not_allowed(url));
+        }
+        let addrs = self.resolve(domain, url).map_err(|e| {
+            warn!(%url, error = %e, "Unable to resolve request host");
+            HttpError::RequestError
+        })?;
+        if !listed && !self.allow_list.contains_all(&addrs) {
+            debug!(%domain, ?addrs, "Domain resolved outside of the allowed networks");
+            return Err(not_allowed(url));
+        }
+        Ok(addrs.first().copied())
+    }
+
+    /// Resolves a domain with the pod's DNS settings, or the node's if it has none
+    fn resolve(&self, domain: &str, url: &Url) -> anyhow::Result<Vec<IpAddr>> {
+        match &self.resolver {
+            Some(resolver) => resolver.resolve(domain),
+            None => {
+                let port = url.port_or_known_default().unwrap_or(80);
+                Ok((domain, port)
+                    .to_socket_addrs()?
+                    .map(|addr| addr.ip())
+                    .collect())
+            }
+        }
+    }
+
+    /// Checks a request the module asked for and builds it
+    fn request(
+        &self,
+        client: &Client,
+        responses: &Mutex<Responses>,
+        url: &Url,
+        method: &str,
+        headers: &str,
+        body: Vec<u8>,
+    ) -> Result<Request, HttpError> {
+        if let Some(max) = self.max_concurrent_requests {
+            if responses.lock().unwrap().open.len() >= max as usize {
+                return Err(HttpError::TooManySessions);
+            }
+        }
+        let method = Method::from_bytes(method.as_bytes()).map_err(|_| HttpError::InvalidMethod)?;
+        debug!(%url, %method, "Sending outbound HTTP request");
+        client
+            .request(method, url.clone())
+            .headers(parse_headers(headers)?)
+            .body(body)
+            .build()
+            .map_err(|_| HttpError::InvalidUrl)
+    }
+}
+
+/// The error for a request to a destination outside of the allow list
+fn not_allowed(url: &Url) -> HttpError {
+    warn!(%url, "Blocked request to destination outside of the allow list");
+    HttpError::DestinationNotAllowed
+}
+
+/// Follows redirects that stay on the same scheme, host and port, up to the usual limit. Redirects
+/// anywhere else are handed back to the module, as their destination hasn't been checked against
+/// the allow list
+fn redirect_policy() -> Policy {
+    Policy::custom(|attempt| {
+        let same_origin = attempt.previous().last().map_or(false, |previous| {
+            previous.origin() == attempt.url().origin()
+        });
+        if !same_origin {
+            attempt.stop()
+        } else if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// A client that connects to the given address for requests to the domain. Clients are kept for
+/// as long as the domain resolves to the same address, so connections to it can be reused
+fn pinned_client(
+    clients: &Clients,
+    domain: &str,
+    addr: IpAddr,
+    builder: impl FnOnce() -> anyhow::Result<ClientBuilder>,
+) -> anyhow::Result<Client> {
+    let mut pinned = clients.pinned.lock().unwrap();
+    if let Some((pinned_addr, client)) = pinned.get(domain) {
+        if *pinned_addr == addr {
+            return Ok(client.clone());
+        }
+    }
+    // The port of the address is ignored, requests go to the port of their URL. A proxy would
+    // resolve the domain itself, so pinned requests never go through one
+    let client = builder()?
+        .resolve(domain, SocketAddr::new(addr, 0))
+        .no_proxy()
+        .build()?;
+    pinned.insert(domain.to_owned(), (addr, client.clone()));
+    Ok(client)
+}
+
+/// Sends a request and reads its response in full, retrying it as the policy allows and giving
+/// up if that takes longer than the timeout
+async fn send(
+    client: &Client,
+    request: Request,
+    timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+) -> anyhow::Result<Response> {
+    let exchange = async {
+        match retry {
+            Some(retry) if is_idempotent(request.method()) => {
+                exchange_with_retries(client, request, retry).await
+            }
+            _ => exchange(client, request).await,
+        }
+    };
+    match timeout {
+        Some(timeout) => Ok(tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| anyhow::anyhow!("request timed out after {:?}", timeout))??),
+        None => Ok(exchange.await?),
+    }
+}
+
+/// Sends a request, retrying it while it fails to connect or gets a server error, up to the
+/// limit of the policy. The outcome of the last attempt is returned
+async fn exchange_with_retries(
+    client: &Client,
+    mut request: Request,
+    retry: RetryPolicy,
+) -> reqwest::Result<Response> {
+    let mut backoff = retry.backoff;
+    let mut retries = 0;
+    loop {
+        // Requests from modules always have their whole body in memory, so they can be cloned
+        let next = request.try_clone();
+        let result = exchange(client, request).await;
+        let retryable = match &result {
+            Ok(response) => response.status >= 500,
+            Err(e) => e.is_connect(),
+        };
+        request = match next {
+            Some(next) if retryable && retries < retry.max_retries => next,
+            _ => return result,
+        };
+        retries += 1;
+        debug!(
+            url = %request.url(),
+            retries,
+            ?backoff,
+            "Retrying failed outbound HTTP request"
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = next_backoff(backoff);
+    }
+}
+
+/// The wait before the retry after one that waited for `backoff`. It doubles up to
+/// [`MAX_RETRY_BACKOFF`], unless the first wait was already longer than that
+fn next_backoff(backoff: Duration) -> Duration {
+    backoff
+        .saturating_mul(2)
+        .min(MAX_RETRY_BACKOFF.max(backoff))
+}
+
+/// Sends a request once and reads its response in full
+async fn exchange(client: &Client, request: Request) -> reqwest::Result<Response> {
+    let response = client.execute(request).await?;
+    let status = response.status().as_u16();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?.to_vec();
+    Ok(Response {
+        status,
+        headers,
+        body,
+        read: 0,
+    })
+}
+
+/// Whether sending a request more than once has the same effect as sending it once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
+
+/// Parses headers given as `name:value` lines
+fn parse_headers(headers: &str) -> Result<HeaderMap, HttpError> {
+    let mut map = HeaderMap::new();
+    for line in headers.lines().filter(|l| !l.trim().is_empty()) {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().trim();
+        let value = parts.next().ok_or(HttpError::InvalidEncoding)?.trim();
+        map.append(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| HttpError::InvalidEncoding)?,
+            HeaderValue::from_str(value).map_err(|_| HttpError::InvalidEncoding)?,
+        );
+    }
+    Ok(map)
+}
+
+/// Formats headers as `name:value` lines
+fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect()
+}
+
+fn memory(caller: &mut Caller<'_, StoreData>) -> Result<wasmtime::Memory, HttpError> {
+    caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or(HttpError::MemoryNotFound)
+}
+
+fn read(caller: &mut Caller<'_, StoreData>, ptr: u32, len: u32) -> Result<Vec<u8>, HttpError> {
+    let memory = memory(caller)?;
+    let mut buf = vec![0; len as usize];
+    memory
+        .read(&*caller, ptr as usize, &mut buf)
+        .map_err(|_| HttpError::MemoryAccessError)?;
+    Ok(buf)
+}
+
+fn read_string(
+    caller: &mut Caller<'_, StoreData>,
+    ptr: u32,
+    len: u32,
+) -> Result<String, HttpError> {
+    String::from_utf8(read(caller, ptr, len)?).map_err(|_| HttpError::Utf8Error)
+}
+
+fn write(caller: &mut Caller<'_, StoreData>, ptr: u32, bytes: &[u8]) -> Result<(), HttpError> {
+    let memory = memory(caller)?;
+    memory
+        .write(&mut *caller, ptr as usize, bytes)
+        .map_err(|_| HttpError::MemoryAccessError)
+}
+
+/// Writes bytes to a buffer the module gave, along with how many bytes were written
+fn write_buffer(
+    caller: &mut Caller<'_, StoreData>,
+    buf_ptr: u32,
+    buf_len: u32,
+    written_ptr: u32,
+    bytes: &[u8],
+) -> Result<(), HttpError> {
+    if bytes.len() > buf_len as usize {
+        return Err(HttpError::BufferTooSmall);
+    }
+    write(caller, buf_ptr, bytes)?;
+    write(caller, written_ptr, &(bytes.len() as u32).to_le_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::allow_list::split_allow_list;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn requests_to_a_stalling_server_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accept the connection but never answer it
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            drop(socket);
+        });
+
+        let client = Client::new();
+        let request = client.get(&format!("http://{}/", addr)).build().unwrap();
+        let started = std::time::Instant::now();
+        let err = send(&client, request, Some(Duration::from_millis(200)), None)
+            .await
+            .err()
+            .expect("request to a stalling server should fail");
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    /// Serves each connection with the next status in turn, counting the requests it gets
+    async fn serve_statuses(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                socket.read(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn idempotent_requests_are_retried_on_server_errors() {
+        let retry = Some(RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+        });
+        let client = Client::new();
+
+        let (url, requests) = serve_statuses(vec![503, 502, 200]).await;
+        let request = client.get(&url).build().unwrap();
+        let response = send(&client, request, None, retry).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let (url, requests) = serve_statuses(vec![503, 200]).await;
+        let request = client.post(&url).build().unwrap();
+        let response = send(&client, request, None, retry).await.unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(
+            Duration::from_millis(200),
+            next_backoff(Duration::from_millis(100))
+        );
+        assert_eq!(MAX_RETRY_BACKOFF, next_backoff(Duration::from_secs(20)));
+        assert_eq!(MAX_RETRY_BACKOFF, next_backoff(MAX_RETRY_BACKOFF));
+        let long = Duration::from_secs(60);
+        assert_eq!(long, next_backoff(long));
+        assert_eq!(Duration::MAX, next_backoff(Duration::MAX));
+    }
+
+    #[tokio::test]
+    async fn retries_stop_at_the_limit() {
+        let retry = Some(RetryPolicy {
+            max_retries: 1,
+            backoff: Duration::from_millis(10),
+        });
+        let client = Client::new();
+        let (url, requests) = serve_statuses(vec![500, 500, 200]).await;
+        let request = client.get(&url).build().unwrap();
+        let response = send(&client, request, None, retry).await.unwrap();
+        assert_eq!(response.status, 500);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn pinned_clients_connect_to_the_resolved_address() {
+        let (url, requests) = serve_statuses(vec![200]).await;
+        let port = Url::parse(&url).unwrap().port().unwrap();
+        let clients = Clients {
+            default: Client::new(),
+            pinned: Mutex::new(HashMap::new()),
+        };
+        let client = pinned_client(&clients, "pinned.test", [127, 0, 0, 1].into(), || {
+            Ok(Client::builder())
+        })
+        .unwrap();
+        let request = client
+            .get(&format!("http://pinned.test:{}/", port))
+            .build()
+            .unwrap();
+        let response = send(&client, request, None, None).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // The client is reused while the domain resolves to the same address
+        let again = pinned_client(&clients, "pinned.test", [127, 0, 0, 1].into(), || {
+            anyhow::bail!("client should have been reused")
+        });
+        assert!(again.is_ok());
+    }
+
+    #[test]
+    fn test_parse_and_format_headers() {
+        let headers = parse_headers("Content-Type: text/plain\nx-trace:abc\n\n").unwrap();
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(headers["x-trace"], "abc");
+        assert_eq!(
+            format_headers(&headers),
+            "content-type:text/plain\nx-trace:abc\n"
+        );
+        assert_eq!(
+            parse_headers("no separator"),
+            Err(HttpError::InvalidEncoding)
+        );
+    }
+
+    fn secret_data(entries: &[(&str, &[u8])]) -> BTreeMap<String, ByteString> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), ByteString(value.to_vec())))
+            .collect()
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn test_client_cert_from_secret_data() {
+        let cert = ClientCert::from_secret_data(&secret_data(&[("tls.p12", b"bundle")])).unwrap();
+        assert_eq!(b"bundle".to_vec(), cert.identity);
+        // Native TLS can't load PEM keys, so a kubernetes.io/tls secret isn't enough
+        let err = ClientCert::from_secret_data(&secret_data(&[
+            ("tls.crt", b"cert"),
+            ("tls.key", b"key"),
+        ]))
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("tls.p12"), "{}", err);
+        // What isn't a PKCS #12 bundle fails once it is loaded
+        assert!(cert.identity().is_err());
+    }
+
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    #[test]
+    fn test_client_cert_from_secret_data() {
+        let cert = ClientCert::from_secret_data(&secret_data(&[
+            ("tls.crt", b"cert"),
+            ("tls.key", b"key"),
+        ]))
+        .unwrap();
+        assert_eq!(b"cert\nkey".to_vec(), cert.identity);
+        for missing in &["tls.crt", "tls.key"] {
+            let data = secret_data(&[("tls.crt", b"cert"), ("tls.key", b"key")])
+                .into_iter()
+                .filter(|(key, _)| key != missing)
+                .collect();
+            let err = ClientCert::from_secret_data(&data).err().unwrap();
+            assert!(err.to_string().contains(missing), "{}", err);
+        }
+        assert!(cert.identity().is_err());
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    #[test]
+    fn test_client_cert_from_secret_data() {
+        let data = secret_data(&[
+            ("tls.p12", b"bundle"),
+            ("tls.crt", b"cert"),
+            ("tls.key", b"key"),
+        ]);
+        assert!(ClientCert::from_secret_data(&data).is_err());
+    }
+
+    #[test]
+    fn test_ca_bundle_without_certificates() {
+        assert!(CaBundle::from_pem(b"", false).is_err());
+        assert!(CaBundle::from_pem(b"not a certificate", true).is_err());
+    }
+
+    const CA_CERT: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBfDCCASGgAwIBAgIUFXTuK6SOV5cSZcVAmypULX+6SkIwCgYIKoZIzj0EAwIw\n\
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTUxNDAzMzlaGA8yMTI2MDkyMTE0\n\
+MDMzOVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH\n\
+A0IABKp3Qvb0aOpvO3b3MeP9xrNmDBvIbkVdPlVEaDBlRQl9XV2vvIwM8Rq/l4Dn\n\
++3Pw6b4stlW3sQixUQV/HHEuFnWjUzBRMB0GA1UdDgQWBBTHZ+sp9GJ8EoSelgNM\n\
+DhdQSGBeYjAfBgNVHSMEGDAWgBTHZ+sp9GJ8EoSelgNMDhdQSGBeYjAPBgNVHRMB\n\
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0kAMEYCIQDn1nyaUuMY7Bd6UWLJS5QM8Kch\n\
+jGm3uWL282qOckwpeAIhANNiUIXa47Z0+c1T9a+a4JHH3/waaQlJSW0ezwaMIwFW\n\
+-----END CERTIFICATE-----";
+
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    #[test]
+    fn test_ca_bundle_from_pem() {
+        let bundle = CaBundle::from_pem(CA_CERT.as_bytes(), true).unwrap();
+        assert_eq!(1, bundle.certs.len());
+        assert!(bundle.exclusive);
+
+        // Text around and between the certificates of a bundle is skipped
+        let pem = format!("# Test CA\n{}\n\n# Test CA again\n{}\n", CA_CERT, CA_CERT);
+        let bundle = CaBundle::from_pem(pem.as_bytes(), false).unwrap();
+        assert_eq!(2, bundle.certs.len());
+        assert!(!bundle.exclusive);
+
+        // A single broken certificate fails the whole bundle
+        let pem = format!(
+            "{}\n-----BEGIN CERTIFICATE-----\nbroken\n-----END CERTIFICATE-----\n",
+            CA_CERT
+        );
+        assert!(CaBundle::from_pem(pem.as_bytes(), false).is_err());
+        assert!(CaBundle::from_pem(&[0xff, 0xfe], false).is_err());
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+    #[test]
+    fn test_client_builder_with_ca_bundle() {
+        let ctx = HttpCtx::new(AllowList::new(Vec::new(), Vec::new()), None, None, None)
+            .with_ca_bundle(Some(CaBundle::from_pem(CA_CERT.as_bytes(), true).unwrap()));
+        assert!(ctx.client_builder().unwrap().build().is_ok());
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    #[test]
+    fn test_ca_bundle_from_pem() {
+        assert!(CaBundle::from_pem(CA_CERT.as_bytes(), true).is_err());
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let ctx = HttpCtx::new(
+            AllowList::new(
+                vec!["https://example.com".to_owned(), "api.test".to_owned()],
+                Vec::new(),
+            ),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(ctx.destination(&url("https://example.com/path")), Ok(None));
+        assert_eq!(ctx.destination(&url("http://api.test:8080/")), Ok(None));
+        assert_eq!(
+            ctx.destination(&url("https://other.example/")),
+            Err(HttpError::DestinationNotAllowed)
+        );
+        assert_eq!(
+            HttpCtx::new(AllowList::default(), None, None, None)
+                .destination(&url("https://example.com/")),
+            Err(HttpError::DestinationNotAllowed)
+        );
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let (domains, networks) = split_allow_list(vec![
+            "10.0.0.0/8".to_owned(),
+            "fd00::/8".to_owned(),
+            "127.0.0.0/8".to_owned(),
+            "::1".to_owned(),
+        ]);
+        let ctx = HttpCtx::new(AllowList::new(domains, networks), None, None, None);
+        assert_eq!(ctx.destination(&url("http://10.1.2.3/")), Ok(None));
+        assert_eq!(ctx.destination(&url("http://[fd00::1]:8080/")), Ok(None));
+        assert_eq!(
+            ctx.destination(&url("http://11.1.2.3/")),
+            Err(HttpError::DestinationNotAllowed)
+        );
+        assert_eq!(
+            ctx.destination(&url("http://[2001:db8::1]/")),
+            Err(HttpError::DestinationNotAllowed)
+        );
+        // A domain that isn't listed is sent to an address it was checked at
+        let pinned = ctx.destination(&url("http://localhost:8080/")).unwrap();
+        assert!(pinned.map_or(false, |ip| ip.is_loopback()), "{:?}", pinned);
+
+        let ctx = HttpCtx::new(
+            AllowList::new(Vec::new(), vec!["10.0.0.0/8".parse().unwrap()]),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            ctx.destination(&url("http://localhost/")),
+            Err(HttpError::DestinationNotAllowed)
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_to_other_origins_are_not_followed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            socket.read(&mut buf).await.unwrap();
+            let response = "HTTP/1.1 302 Found\r\nlocation: http://disallowed.test/\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let ctx = HttpCtx::new(AllowList::default(), None, None, None);
+        let client = ctx.client_builder().unwrap().build().unwrap();
+        let request = client.get(&format!("http://{}/", addr)).build().unwrap();
+        let response = send(&client, request, None, None).await.unwrap();
+        assert_eq!(response.status, 302);
+        assert_eq!(response.headers["location"], "http://disallowed.test/");
+    }
+}
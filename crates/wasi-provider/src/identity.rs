@@ -0,0 +1,316 @@
+//! Running modules as the user their container asks to run as.
+//!
+//! Modules run in krustlet's own process, so they can't be given a user the way a container
+//! process is. On Linux the thread running a module instead takes on the user and group for file
+//! system access, which is what decides who owns the files the module creates and which files it
+//! may open. That needs krustlet to hold `CAP_SETUID` and `CAP_SETGID`; without them, and on other
+//! platforms, the module keeps krustlet's identity.
+//!
+//! The identity is only taken on for the module's own file system calls, through [`IdentityDir`],
+//! so krustlet's work on the same thread, such as writing and rotating the container's logs, is
+//! still done as krustlet.
+use std::any::Any;
+use std::path::PathBuf;
+
+use tracing::warn;
+use wasi_common::dir::{ReaddirCursor, ReaddirEntity};
+use wasi_common::file::{FdFlags, Filestat, OFlags};
+use wasi_common::{Error, SystemTimeSpec, WasiDir, WasiFile};
+
+/// A directory mounted for a module, whose files the module accesses as the user and group its
+/// container runs as. Each call switches the identity of the calling thread for just as long as
+/// the call takes; calls into the directory don't yield, so the identity never outlives them
+pub(crate) struct IdentityDir {
+    dir: Box<dyn WasiDir>,
+    user: Option<u32>,
+    group: Option<u32>,
+}
+
+impl IdentityDir {
+    /// Wraps the directory so that it is accessed as the given user and group. The directory is
+    /// returned as it is if neither is given
+    pub(crate) fn wrap(
+        dir: Box<dyn WasiDir>,
+        user: Option<u32>,
+        group: Option<u32>,
+    ) -> Box<dyn WasiDir> {
+        if user.is_none() && group.is_none() {
+            return dir;
+        }
+        Box::new(IdentityDir { dir, user, group })
+    }
+
+    fn assume(&self) -> FsIdentity {
+        FsIdentity::assume(self.user, self.group)
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiDir for IdentityDir {
+    fn as_any(&self) -> &dyn Any {
+        // Renames and links between directories look for the directory underneath
+        self.dir.as_any()
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let _identity = self.assume();
+        self.dir
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .await
+    }
+
+    async fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = {
+            let _identity = self.assume();
+            self.dir.open_dir(symlink_follow, path).await?
+        };
+        Ok(IdentityDir::wrap(dir, self.user, self.group))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.create_dir(path).await
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let _identity = self.assume();
+        self.dir.readdir(cursor).await
+    }
+
+    async fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.symlink(old_path, new_path).await
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.remove_dir(path).await
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.unlink_file(path).await
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let _identity = self.assume();
+        self.dir.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.dir.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        let _identity = self.assume();
+        self.dir.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.rename(path, dest_dir, dest_path).await
+    }
+
+    async fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir.hard_link(path, target_dir, target_path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let _identity = self.assume();
+        self.dir
+            .set_times(path, atime, mtime, follow_symlinks)
+            .await
+    }
+}
+
+/// The file system identity of the current thread, switched for as long as it is held
+pub(crate) struct FsIdentity {
+    #[cfg(target_os = "linux")]
+    previous_user: Option<u32>,
+    #[cfg(target_os = "linux")]
+    previous_group: Option<u32>,
+}
+
+#[cfg(target_os = "linux")]
+impl FsIdentity {
+    /// Makes the current thread access files as the given user and group, until the returned
+    /// value is dropped
+    pub(crate) fn assume(user: Option<u32>, group: Option<u32>) -> Self {
+        // The group is switched first, as changing the user can take away the right to change it
+        let previous_group = group.and_then(|group| {
+            let previous = unsafe { libc::setfsgid(group) } as u32;
+            // Passing an invalid id leaves the identity as it is and returns the current one
+            if unsafe { libc::setfsgid(libc::gid_t::MAX) } as u32 == group {
+                Some(previous)
+            } else {
+                warn!(group, "Unable to access files as the container's group");
+                None
+            }
+        });
+        let previous_user = user.and_then(|user| {
+            let previous = unsafe { libc::setfsuid(user) } as u32;
+            if unsafe { libc::setfsuid(libc::uid_t::MAX) } as u32 == user {
+                Some(previous)
+            } else {
+                warn!(user, "Unable to access files as the container's user");
+                None
+            }
+        });
+        FsIdentity {
+            previous_user,
+            previous_group,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for FsIdentity {
+    fn drop(&mut self) {
+        // The thread goes back to the pool of blocking threads, so it gets its identity back
+        if let Some(user) = self.previous_user {
+            unsafe { libc::setfsuid(user) };
+        }
+        if let Some(group) = self.previous_group {
+            unsafe { libc::setfsgid(group) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FsIdentity {
+    /// File system identities are Linux only, so the module keeps krustlet's identity
+    pub(crate) fn assume(user: Option<u32>, group: Option<u32>) -> Self {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        if user.is_some() || group.is_some() {
+            WARNED.call_once(|| {
+                warn!(
+                    ?user,
+                    ?group,
+                    "Running modules as another user is not supported on this platform"
+                )
+            });
+        }
+        FsIdentity {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasi_cap_std_sync::dir::Dir;
+
+    fn open(path: &std::path::Path) -> Box<dyn WasiDir> {
+        let dir = unsafe { cap_std::fs::Dir::open_ambient_dir(path) }.unwrap();
+        Box::new(Dir::from_cap_std(dir))
+    }
+
+    #[test]
+    fn test_wrap_keeps_underlying_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = IdentityDir::wrap(open(dir.path()), None, None);
+        assert!(plain.as_any().downcast_ref::<Dir>().is_some());
+        // Renames between directories need to find the directory underneath the wrapper
+        let wrapped = IdentityDir::wrap(open(dir.path()), Some(1000), None);
+        assert!(wrapped.as_any().downcast_ref::<Dir>().is_some());
+        assert!(wrapped.as_any().downcast_ref::<IdentityDir>().is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_rename_through_wrapper() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), "data").unwrap();
+        let user = unsafe { libc::getuid() };
+        let wrapped = IdentityDir::wrap(open(dir.path()), Some(user), None);
+        futures::executor::block_on(wrapped.rename("a", wrapped.as_ref(), "b")).unwrap();
+        assert!(dir.path().join("b").exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn current_fs_ids() -> (u32, u32) {
+        // Passing an invalid id leaves the identity as it is and returns the current one
+        unsafe {
+            (
+                libc::setfsuid(libc::uid_t::MAX) as u32,
+                libc::setfsgid(libc::gid_t::MAX) as u32,
+            )
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_assume_restores_identity() {
+        let before = current_fs_ids();
+        {
+            let _identity = FsIdentity::assume(Some(before.0), Some(before.1));
+            assert_eq!(before, current_fs_ids());
+        }
+        assert_eq!(before, current_fs_ids());
+        {
+            let _identity = FsIdentity::assume(None, None);
+        }
+        assert_eq!(before, current_fs_ids());
+    }
+
+    /// Switching to another user needs `CAP_SETUID` and `CAP_SETGID`, so this only checks the
+    /// switch when the tests run as root
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_identity_only_applies_to_module_calls() {
+        use std::os::unix::fs::MetadataExt;
+
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+        const NOBODY: u32 = 65534;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::set_permissions(
+            dir.path(),
+            std::os::unix::fs::PermissionsExt::from_mode(0o777),
+        )
+        .unwrap();
+        let before = current_fs_ids();
+        let wrapped = IdentityDir::wrap(open(dir.path()), Some(NOBODY), Some(NOBODY));
+        futures::executor::block_on(wrapped.create_dir("module")).unwrap();
+        assert_eq!(before, current_fs_ids());
+        // Whatever krustlet does itself on the same thread is still done as krustlet
+        std::fs::create_dir(dir.path().join("krustlet")).unwrap();
+
+        let module = std::fs::metadata(dir.path().join("module")).unwrap();
+        assert_eq!((NOBODY, NOBODY), (module.uid(), module.gid()));
+        let krustlet = std::fs::metadata(dir.path().join("krustlet")).unwrap();
+        assert_eq!(before, (krustlet.uid(), krustlet.gid()));
+    }
+}
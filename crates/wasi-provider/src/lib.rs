@@ -32,21 +32,59 @@
 //!     kubelet.start().await.unwrap();
 //! };
 //! ```
+//!
+//! # Limitations
+//!
+//! Only core WASI modules can be run. The version of wasmtime this provider is built on predates
+//! the component model, so a container whose module is a WebAssembly component fails to start
+//! with an error saying so, rather than one from trying to compile it.
+//!
+//! Modules can make outbound HTTP requests through the experimental `wasi_experimental_http`
+//! functions, but can't serve incoming HTTP. The `wasi:http/proxy` world, and the
+//! `wasi:http/incoming-handler` interface it exports, are defined in terms of the component
+//! model too.
 
 #![deny(missing_docs)]
 
+mod allow_list;
+mod audit;
+mod capabilities;
+mod compile_cache;
+mod dns;
+mod engine;
+mod env_filter;
+mod error;
+mod features;
+mod host_functions;
+mod http;
+mod identity;
+mod lifecycle;
+mod output;
+mod sockets;
+mod termination;
+mod usage;
+mod validation;
+mod wasi_nn;
 mod wasi_runtime;
 
-use std::collections::HashMap;
+pub use host_functions::{HostFunctionRegistry, HostFunctions, HostLog, HOST_LOG_MODULE};
+pub use wasi_runtime::StoreData;
+
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use compile_cache::CompileCache;
+use engine::Engines;
+use kubelet::exec::Output as ExecOutput;
 use kubelet::node::Builder;
 use kubelet::plugin_watcher::PluginRegistry;
+use kubelet::pod::dns::ClusterDns;
 use kubelet::pod::state::prelude::SharedState;
-use kubelet::pod::{Handle, Pod, PodKey};
+use kubelet::pod::{AdmissionHooks, Handle, Pod, PodKey};
 use kubelet::provider::{
     DevicePluginSupport, PluginSupport, Provider, ProviderError, VolumeSupport,
 };
@@ -54,9 +92,13 @@ use kubelet::resources::DeviceManager;
 use kubelet::state::common::registered::Registered;
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{GenericProvider, GenericProviderState};
+use kubelet::stats::PodStats;
+use kubelet::store::oci::SignatureVerifier;
 use kubelet::store::Store;
 use kubelet::volume::VolumeRef;
-use tokio::sync::RwLock;
+use output::LogRotation;
+use tokio::sync::{RwLock, Semaphore};
+use wasi_nn::NnBackend;
 use wasi_runtime::Runtime;
 
 mod states;
@@ -66,12 +108,18 @@ use states::pod::PodState;
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
 const VOLUME_DIR: &str = "volumes";
+const COMPILE_CACHE_DIR: &str = "compiled-modules";
+/// The prefix of the node labels advertising which WASI APIs modules can use
+const WASI_LABEL_PREFIX: &str = "wasi.krustlet.dev";
+/// The prefix of the node labels advertising which WebAssembly proposals modules can use
+const WASM_LABEL_PREFIX: &str = "wasm.krustlet.dev";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
 #[derive(Clone)]
 pub struct WasiProvider {
     shared: ProviderState,
+    shutdown_grace_period: std::time::Duration,
 }
 
 type PodHandleMap = Arc<RwLock<HashMap<PodKey, Arc<Handle<Runtime, wasi_runtime::HandleFactory>>>>>;
@@ -82,7 +130,33 @@ pub struct ProviderState {
     handles: PodHandleMap,
     store: Arc<dyn Store + Sync + Send>,
     log_path: PathBuf,
+    log_rotation: LogRotation,
+    /// How much of each container's most recent output is kept in memory to serve logs from
+    log_buffer_size: usize,
+    json_logs: bool,
+    compile_cache: CompileCache,
+    compile_permits: Arc<Semaphore>,
+    engines: Arc<Engines>,
+    max_module_size: u64,
+    max_preopened_dirs: usize,
+    /// The allow list of modules whose pod and namespace don't give one
+    default_allowed_domains: Vec<String>,
+    /// Whether pods fall back to their namespace's network annotations
+    namespace_network_defaults: bool,
+    /// The directories pods may have their logs written under instead of `log_path`
+    allowed_log_dirs: Vec<PathBuf>,
+    /// The host functions registered for modules to import
+    host_functions: Arc<HostFunctionRegistry>,
+    /// The wasi-nn backends installed on the node
+    wasi_nn_backends: Vec<NnBackend>,
+    /// How long a container's module may take to be set up and started
+    container_start_timeout: std::time::Duration,
+    /// The checks pods must pass before they are run
+    admission_hooks: AdmissionHooks,
+    signature_verifier: Arc<SignatureVerifier>,
     client: kube::Client,
+    node_ip: IpAddr,
+    cluster_dns: ClusterDns,
     volume_path: PathBuf,
     plugin_registry: Arc<PluginRegistry>,
     device_plugin_manager: Arc<DeviceManager>,
@@ -97,14 +171,17 @@ impl GenericProviderState for ProviderState {
         self.store.clone()
     }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
-        let key = PodKey::from(pod);
-        let mut handle_writer = self.handles.write().await;
-        if let Some(handle) = handle_writer.get_mut(&key) {
-            handle.stop().await
-        } else {
-            Ok(())
+        // Stopping waits for up to the grace period, so don't hold on to the
+        // handle map while it does
+        let handle = self.handles.read().await.get(&PodKey::from(pod)).cloned();
+        match handle {
+            Some(handle) => handle.stop_gracefully(pod.termination_grace_period()).await,
+            None => Ok(()),
         }
     }
+    fn admission_hooks(&self) -> AdmissionHooks {
+        self.admission_hooks.clone()
+    }
 }
 
 impl VolumeSupport for ProviderState {
@@ -139,24 +216,73 @@ impl WasiProvider {
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
         let client = kube::Client::try_from(kubeconfig)?;
+        let signature_verifier = Arc::new(SignatureVerifier::from_config(config)?);
         Ok(Self {
             shared: ProviderState {
                 handles: Default::default(),
                 store,
                 log_path,
+                log_rotation: LogRotation {
+                    max_size: config.container_log_max_size,
+                    max_files: config.container_log_max_files,
+                },
+                log_buffer_size: config.container_log_buffer_size as usize,
+                json_logs: config.json_container_logs,
+                compile_cache: CompileCache::new(
+                    config.data_dir.join(COMPILE_CACHE_DIR),
+                    config.module_opt_level,
+                ),
+                compile_permits: Arc::new(Semaphore::new(config.max_concurrent_compilations)),
+                engines: Arc::new(Engines::from_config(config)?),
+                max_module_size: config.max_module_size,
+                max_preopened_dirs: config.max_preopened_dirs,
+                default_allowed_domains: config.default_allowed_domains.clone(),
+                namespace_network_defaults: config.namespace_network_defaults,
+                allowed_log_dirs: config.allowed_log_dirs.clone(),
+                host_functions: Arc::new(HostFunctionRegistry::default()),
+                wasi_nn_backends: wasi_nn::parse_backends(&config.wasi_nn_backends)?,
+                container_start_timeout: config.container_start_timeout,
+                admission_hooks: AdmissionHooks::default(),
+                signature_verifier,
                 volume_path,
                 client,
+                node_ip: config.node_ip,
+                cluster_dns: ClusterDns {
+                    servers: config.cluster_dns.clone(),
+                    domain: config.cluster_domain.clone(),
+                },
                 plugin_registry,
                 device_plugin_manager,
             },
+            shutdown_grace_period: config.shutdown_grace_period,
         })
     }
+
+    /// Makes the registered host functions available for modules to import
+    pub fn with_host_functions(mut self, host_functions: HostFunctionRegistry) -> Self {
+        self.shared.host_functions = Arc::new(host_functions);
+        self
+    }
+
+    /// Runs the admission hooks on every pod before it is run, failing the pods they reject
+    pub fn with_admission_hooks(mut self, admission_hooks: AdmissionHooks) -> Self {
+        self.shared.admission_hooks = admission_hooks;
+        self
+    }
 }
 
 struct ModuleRunContext {
     modules: HashMap<String, Vec<u8>>,
     volumes: HashMap<String, VolumeRef>,
     env_vars: HashMap<String, HashMap<String, String>>,
+    /// The directories files mounted with a `subPath` have been staged in, removed with the pod
+    sub_path_dirs: HashSet<PathBuf>,
+    /// The readiness of each app container, used to work out whether the pod is ready
+    ready: HashMap<String, bool>,
+    /// Set once the app containers have exited, so sidecars that exit aren't restarted
+    stopping_sidecars: bool,
+    /// Set once the pod has run past its active deadline, so its containers aren't restarted
+    deadline_exceeded: bool,
 }
 
 #[async_trait::async_trait]
@@ -176,11 +302,29 @@ impl Provider for WasiProvider {
         builder.set_architecture("wasm-wasi");
         builder.add_taint("NoSchedule", "kubernetes.io/arch", Self::ARCH);
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+
+        // Advertise what modules can rely on, so pods that need more can use a node selector
+        // to stay off this node
+        builder.add_label("krustlet.dev/runtime", "wasmtime");
+        builder.add_label(&format!("{}/preview1", WASI_LABEL_PREFIX), "true");
+        builder.add_label(&format!("{}/preview2", WASI_LABEL_PREFIX), "false");
+        builder.add_label(&format!("{}/experimental-http", WASI_LABEL_PREFIX), "true");
+        builder.add_label(
+            &format!("{}/experimental-sockets", WASI_LABEL_PREFIX),
+            "true",
+        );
+        builder.add_label(
+            &format!("{}/simd", WASM_LABEL_PREFIX),
+            &wasi_runtime::simd_supported().to_string(),
+        );
+        for backend in &self.shared.wasi_nn_backends {
+            builder.add_label(&format!("{}/nn-{}", WASI_LABEL_PREFIX, backend), "true");
+        }
         Ok(())
     }
 
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
-        Ok(PodState::new(pod))
+        Ok(PodState::new(pod, self.shared.node_ip))
     }
 
     async fn logs(
@@ -199,9 +343,34 @@ impl Provider for WasiProvider {
         handle.output(&container_name, sender).await
     }
 
-    // Evict all pods upon shutdown
+    async fn exec(
+        &self,
+        namespace: String,
+        pod_name: String,
+        container_name: String,
+        command: Vec<String>,
+    ) -> anyhow::Result<ExecOutput> {
+        // Commands can take a while, so don't hold on to the handle map while
+        // they run
+        let handle = self
+            .shared
+            .handles
+            .read()
+            .await
+            .get(&PodKey::new(&namespace, &pod_name))
+            .cloned()
+            .ok_or(ProviderError::PodNotFound { pod_name })?;
+        handle.exec(&container_name, command).await
+    }
+
+    async fn pod_stats(&self) -> anyhow::Result<Vec<PodStats>> {
+        let handles: Vec<_> = self.shared.handles.read().await.values().cloned().collect();
+        Ok(futures::future::join_all(handles.iter().map(|handle| handle.stats())).await)
+    }
+
+    // Evict all pods and deregister the node upon shutdown
     async fn shutdown(&self, node_name: &str) -> anyhow::Result<()> {
-        node::drain(&self.shared.client, &node_name).await?;
+        node::drain(&self.shared.client, &node_name, self.shutdown_grace_period).await?;
         Ok(())
     }
 }
@@ -223,6 +392,19 @@ impl GenericProvider for WasiProvider {
                 return Err(anyhow::anyhow!("Cannot run kube-proxy"));
             }
         }
+        // Modules share the node's network, so ports can't be remapped onto the host
+        for port in container.ports() {
+            if let Some(host_port) = port.host_port {
+                if host_port != port.container_port {
+                    return Err(anyhow::anyhow!(
+                        "Container {} maps container port {} to host port {}, but modules listen on the node's ports directly",
+                        container.name(),
+                        port.container_port,
+                        host_port
+                    ));
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -0,0 +1,58 @@
+//! Lifecycle hooks run against a container's module.
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Handler;
+use kubelet::container::probe::{run_network_hook, ProbeOutcome};
+use kubelet::container::Container;
+
+use crate::wasi_runtime::WasiRuntime;
+
+/// A lifecycle hook configured on a container, along with what is needed to run it
+#[derive(Clone, Debug)]
+pub(crate) struct LifecycleHook {
+    handler: Handler,
+    container: Container,
+    host: String,
+}
+
+impl LifecycleHook {
+    /// Returns the container's postStart hook, if it has one. Network hooks are sent to the given
+    /// host unless they name their own
+    pub(crate) fn post_start(container: &Container, host: &str) -> Option<Self> {
+        let handler = container.lifecycle()?.post_start.clone()?;
+        Some(LifecycleHook {
+            handler,
+            container: container.clone(),
+            host: host.to_owned(),
+        })
+    }
+
+    /// Returns the container's preStop hook, if it has one. Network hooks are sent to the given
+    /// host unless they name their own
+    pub(crate) fn pre_stop(container: &Container, host: &str) -> Option<Self> {
+        let handler = container.lifecycle()?.pre_stop.clone()?;
+        Some(LifecycleHook {
+            handler,
+            container: container.clone(),
+            host: host.to_owned(),
+        })
+    }
+
+    /// Runs the hook, giving up once the timeout has elapsed. Exec hooks run a separate instance
+    /// of the container's module with the hook command as its arguments
+    pub(crate) async fn run(&self, runtime: &WasiRuntime, timeout: Duration) -> ProbeOutcome {
+        if let Some(outcome) =
+            run_network_hook(&self.handler, &self.container, &self.host, timeout).await
+        {
+            return outcome;
+        }
+        let command = match self.handler.exec.as_ref() {
+            Some(exec) => exec.command.clone(),
+            None => return ProbeOutcome::Failure("hook has no action to run".to_owned()),
+        };
+        match runtime.exec(command, timeout).await {
+            Ok(outcome) => outcome,
+            Err(e) => ProbeOutcome::Failure(format!("unable to run exec hook: {:?}", e)),
+        }
+    }
+}
@@ -0,0 +1,702 @@
+//! The writers that module output goes through. Alongside writing to the log file, they keep an
+//! index of when output was written so logs can be served from a point in time, keep the most
+//! recent output in memory so logs can be served without waiting on the file, rotate the log
+//! once it grows past its size limit and tag each line with the stream it was written to.
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use chrono::{DateTime, Duration, Utc};
+use kubelet::log::Stream;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+/// Writes closer together than this share an index entry
+const INDEX_GRANULARITY_SECS: i64 = 1;
+/// The most entries the index keeps before dropping the oldest
+const MAX_INDEX_ENTRIES: usize = 100_000;
+/// The longest a line can get before it is written out without waiting for the rest of it
+const MAX_PARTIAL_LINE: usize = 16 * 1024;
+
+#[derive(Default)]
+struct IndexInner {
+    /// The time each entry started at and the log offset its first write went to
+    entries: VecDeque<(DateTime<Utc>, u64)>,
+    /// The current end of the log
+    end: u64,
+}
+
+/// An index of the offsets module output was written to over time
+#[derive(Clone, Default)]
+pub(crate) struct OutputIndex(Arc<Mutex<IndexInner>>);
+
+impl OutputIndex {
+    fn record(&self, offset: u64, end: u64) {
+        let mut inner = self.0.lock().unwrap();
+        let now = Utc::now();
+        let granularity = Duration::seconds(INDEX_GRANULARITY_SECS);
+        if inner
+            .entries
+            .back()
+            .map_or(true, |(t, _)| now - *t >= granularity)
+        {
+            if inner.entries.len() == MAX_INDEX_ENTRIES {
+                inner.entries.pop_front();
+            }
+            inner.entries.push_back((now, offset));
+        }
+        inner.end = end;
+    }
+
+    /// Forgets everything written so far, for when the log has been rotated
+    fn clear(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.entries.clear();
+        inner.end = 0;
+    }
+
+    /// Returns the offset to start reading from to get all output written at or after the given
+    /// time. As entries cover a span of time, this may include a little earlier output
+    pub(crate) fn offset_since(&self, since: DateTime<Utc>) -> u64 {
+        let inner = self.0.lock().unwrap();
+        let granularity = Duration::seconds(INDEX_GRANULARITY_SECS);
+        // Find the last entry whose writes were all done before the cutoff.
+        // Everything after it may have been written since then
+        match inner
+            .entries
+            .iter()
+            .rposition(|(t, _)| *t + granularity <= since)
+        {
+            Some(i) => inner
+                .entries
+                .get(i + 1)
+                .map(|(_, offset)| *offset)
+                .unwrap_or(inner.end),
+            None => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct BufferInner {
+    /// The most recent output, which went to the log starting at `start`
+    data: VecDeque<u8>,
+    start: u64,
+    /// The most output kept
+    capacity: usize,
+}
+
+impl BufferInner {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+}
+
+/// The most recent module output, kept in memory at the offsets it was written to in the log.
+/// It also tracks where the log currently ends, even if it keeps no output at all
+#[derive(Clone, Default)]
+pub(crate) struct OutputBuffer(Arc<Mutex<BufferInner>>);
+
+impl OutputBuffer {
+    /// Creates a buffer that keeps up to `capacity` bytes of output
+    pub(crate) fn new(capacity: usize) -> Self {
+        OutputBuffer(Arc::new(Mutex::new(BufferInner {
+            capacity,
+            ..Default::default()
+        })))
+    }
+
+    fn record(&self, offset: u64, data: &[u8]) {
+        let mut inner = self.0.lock().unwrap();
+        // Output that would be dropped straight away isn't kept, and neither is output that
+        // doesn't follow on from what was kept, as after the log is rotated
+        let skip = data.len().saturating_sub(inner.capacity);
+        if skip > 0 || offset != inner.end() {
+            inner.data.clear();
+            inner.start = offset + skip as u64;
+        }
+        inner.data.extend(&data[skip..]);
+        let excess = inner.data.len().saturating_sub(inner.capacity);
+        inner.data.drain(..excess);
+        inner.start += excess as u64;
+    }
+
+    /// Forgets everything written so far, for when the log has been rotated
+    fn clear(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.data.clear();
+        inner.start = 0;
+    }
+
+    /// The current end of the log
+    fn end(&self) -> u64 {
+        self.0.lock().unwrap().end()
+    }
+
+    /// Copies output from the given offset in the log into `buf`, returning how much was copied.
+    /// Returns `None` if the output there is older than what the buffer keeps
+    fn read_at(&self, offset: u64, buf: &mut ReadBuf<'_>) -> Option<usize> {
+        let inner = self.0.lock().unwrap();
+        if offset < inner.start {
+            return None;
+        }
+        let mut skip = (offset - inner.start) as usize;
+        let mut copied = 0;
+        let (front, back) = inner.data.as_slices();
+        for part in &[front, back] {
+            if skip >= part.len() {
+                skip -= part.len();
+                continue;
+            }
+            let n = (part.len() - skip).min(buf.remaining());
+            buf.put_slice(&part[skip..skip + n]);
+            copied += n;
+            skip = 0;
+        }
+        Some(copied)
+    }
+}
+
+/// Reads module output from an [`OutputBuffer`], falling back to the log file for output older
+/// than the buffer keeps. Where the two overlap the buffer is read, and the end of the log is
+/// wherever the buffer says it is, so a reader sees output as soon as it is written
+pub(crate) struct OutputReader {
+    file: tokio::fs::File,
+    buffer: OutputBuffer,
+    /// Where the next read starts in the log
+    position: u64,
+    /// Where the file is positioned, if it is known
+    file_position: Option<u64>,
+    /// Whether the file is part way through a seek or a read, which must finish before anything
+    /// else is done with it
+    seeking: bool,
+    reading: bool,
+}
+
+impl OutputReader {
+    pub(crate) fn new(file: tokio::fs::File, buffer: OutputBuffer) -> Self {
+        OutputReader {
+            file,
+            buffer,
+            position: 0,
+            file_position: Some(0),
+            seeking: false,
+            reading: false,
+        }
+    }
+}
+
+impl AsyncRead for OutputReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.reading {
+            if let Some(n) = this.buffer.read_at(this.position, buf) {
+                this.position += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+        }
+        if this.file_position != Some(this.position) {
+            if !this.seeking {
+                Pin::new(&mut this.file).start_seek(SeekFrom::Start(this.position))?;
+                this.seeking = true;
+            }
+            let position = futures::ready!(Pin::new(&mut this.file).poll_complete(cx))?;
+            this.seeking = false;
+            this.file_position = Some(position);
+        }
+        let filled = buf.filled().len();
+        this.reading = true;
+        let result = futures::ready!(Pin::new(&mut this.file).poll_read(cx, buf));
+        this.reading = false;
+        match result {
+            Ok(()) => {
+                this.position += (buf.filled().len() - filled) as u64;
+                this.file_position = Some(this.position);
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => {
+                this.file_position = None;
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+impl AsyncSeek for OutputReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        if this.reading {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "unable to seek while a read is in progress",
+            ));
+        }
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (this.position, delta),
+            SeekFrom::End(delta) => (this.buffer.end(), delta),
+        };
+        let target = if delta >= 0 {
+            base.checked_add(delta as u64)
+        } else {
+            base.checked_sub(delta.unsigned_abs())
+        };
+        this.position = target.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+/// Limits on how large a module's log can grow
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LogRotation {
+    /// The size in bytes the log can grow to before it is rotated
+    pub(crate) max_size: u64,
+    /// The number of log files to keep, including the current one
+    pub(crate) max_files: u32,
+}
+
+impl LogRotation {
+    /// The path of the `n`th most recent rotated copy of the log at `path`
+    fn rotated_path(path: &Path, n: u32) -> PathBuf {
+        let mut rotated = OsString::from(path.as_os_str());
+        rotated.push(format!(".{}", n));
+        PathBuf::from(rotated)
+    }
+
+    /// Removes all rotated copies of the log at `path`
+    pub(crate) fn remove_rotated(&self, path: &Path) {
+        for n in 1..self.max_files {
+            let _ = std::fs::remove_file(Self::rotated_path(path, n));
+        }
+    }
+
+    /// Shifts each rotated copy of the log at `path` back by one, dropping the oldest, and copies
+    /// the current log to `path.1`. The current log is copied rather than renamed so that
+    /// everything holding it open (including log readers) keeps the same file
+    fn rotate(&self, path: &Path) -> std::io::Result<()> {
+        let _ = std::fs::remove_file(Self::rotated_path(path, self.max_files - 1));
+        for n in (1..self.max_files - 1).rev() {
+            let from = Self::rotated_path(path, n);
+            if from.exists() {
+                std::fs::rename(from, Self::rotated_path(path, n + 1))?;
+            }
+        }
+        std::fs::copy(path, Self::rotated_path(path, 1))?;
+        Ok(())
+    }
+}
+
+/// Writes module output to the log file, recording when it was written in an [`OutputIndex`] and
+/// keeping the most recent of it in an [`OutputBuffer`]
+pub(crate) struct OutputWriter {
+    file: std::fs::File,
+    offset: u64,
+    index: OutputIndex,
+    buffer: OutputBuffer,
+    /// The path of the log file and the limits to rotate it at, if it is rotated
+    rotation: Option<(PathBuf, LogRotation)>,
+}
+
+impl OutputWriter {
+    pub(crate) fn new(file: std::fs::File, index: OutputIndex, buffer: OutputBuffer) -> Self {
+        OutputWriter {
+            file,
+            offset: 0,
+            index,
+            buffer,
+            rotation: None,
+        }
+    }
+
+    /// Rotates the log file at the given path once a write would take it past the size limit
+    pub(crate) fn with_rotation(mut self, path: PathBuf, rotation: Option<LogRotation>) -> Self {
+        self.rotation = rotation.map(|r| (path, r));
+        self
+    }
+
+    /// Rotates the log if writing `len` more bytes would take it over the size limit. A log that
+    /// is still empty is never rotated, so a single large write can't rotate away everything
+    fn rotate_if_needed(&mut self, len: usize) -> std::io::Result<()> {
+        let (path, rotation) = match &self.rotation {
+            Some((path, rotation)) if self.offset > 0 => (path, rotation),
+            _ => return Ok(()),
+        };
+        if self.offset + len as u64 <= rotation.max_size {
+            return Ok(());
+        }
+        self.file.flush()?;
+        rotation.rotate(path)?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.offset = 0;
+        self.index.clear();
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.rotate_if_needed(buf.len())?;
+        let written = self.file.write(buf)?;
+        let start = self.offset;
+        self.offset += written as u64;
+        self.index.record(start, self.offset);
+        self.buffer.record(start, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// How lines of module output are written to the log
+#[derive(Clone, Debug)]
+pub(crate) enum LogFormat {
+    /// Each line is written as it is, tagged with the stream it came from
+    Raw,
+    /// Each line is written as a JSON object recording when and where it came from
+    Json {
+        namespace: String,
+        pod: String,
+        container: String,
+    },
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Raw
+    }
+}
+
+impl LogFormat {
+    /// The output a line of the log was written for, without the tag or JSON object it was
+    /// written in. Lines that aren't in the format are returned as they are
+    pub(crate) fn message<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        match self {
+            LogFormat::Raw => Cow::Borrowed(Stream::untag(line).1),
+            LogFormat::Json { .. } => {
+                match serde_json::from_str::<serde_json::Value>(line)
+                    .ok()
+                    .as_ref()
+                    .and_then(|record| record.get("message"))
+                    .and_then(|message| message.as_str())
+                {
+                    Some(message) => Cow::Owned(message.to_owned()),
+                    None => Cow::Borrowed(line),
+                }
+            }
+        }
+    }
+}
+
+/// Writes one output stream of a module to a writer it shares with the module's other stream.
+/// Output is written a line at a time, with each line formatted to record the stream it came
+/// from, so lines from the two streams are interleaved in the order they were finished but
+/// never split.
+pub(crate) struct StreamWriter<W: Write> {
+    stream: Stream,
+    inner: Arc<Mutex<W>>,
+    format: LogFormat,
+    /// The start of a line that hasn't been finished yet
+    partial: Vec<u8>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub(crate) fn new(stream: Stream, inner: Arc<Mutex<W>>, format: LogFormat) -> Self {
+        StreamWriter {
+            stream,
+            inner,
+            format,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Writes the buffered line to the shared writer in the log format
+    fn write_line(&mut self) -> std::io::Result<()> {
+        let mut line = match &self.format {
+            LogFormat::Raw => {
+                let mut line = Vec::with_capacity(self.stream.tag().len() + self.partial.len() + 1);
+                line.extend_from_slice(self.stream.tag().as_bytes());
+                line.append(&mut self.partial);
+                line
+            }
+            LogFormat::Json {
+                namespace,
+                pod,
+                container,
+            } => {
+                let record = serde_json::json!({
+                    "timestamp": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                    "namespace": namespace,
+                    "pod": pod,
+                    "container": container,
+                    "stream": self.stream.tag().trim_end(),
+                    "message": String::from_utf8_lossy(&self.partial),
+                });
+                self.partial.clear();
+                serde_json::to_vec(&record)?
+            }
+        };
+        line.push(b'\n');
+        self.inner.lock().unwrap().write_all(&line)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|b| *b == b'\n') {
+            self.partial.extend_from_slice(&rest[..end]);
+            self.write_line()?;
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+        if self.partial.len() >= MAX_PARTIAL_LINE {
+            self.write_line()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // Unfinished lines stay buffered so they aren't split
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for StreamWriter<W> {
+    fn drop(&mut self) {
+        // Nothing more will be written, so whatever is left is the last line
+        if !self.partial.is_empty() {
+            let _ = self.write_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    async fn read_all(reader: &mut OutputReader) -> String {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    async fn reader(path: &Path, buffer: &OutputBuffer) -> OutputReader {
+        let file = tokio::fs::File::open(path).await.unwrap();
+        OutputReader::new(file, buffer.clone())
+    }
+
+    #[test]
+    fn test_log_format_message() {
+        assert_eq!("hello", LogFormat::Raw.message("stdout hello"));
+        assert_eq!(
+            "stdout hello",
+            LogFormat::Raw.message("stderr stdout hello")
+        );
+        assert_eq!("untagged", LogFormat::Raw.message("untagged"));
+
+        let json = LogFormat::Json {
+            namespace: "default".to_owned(),
+            pod: "app".to_owned(),
+            container: "module".to_owned(),
+        };
+        let record = serde_json::json!({
+            "stream": "stderr",
+            "message": "{\"nested\": true}",
+        })
+        .to_string();
+        assert_eq!("{\"nested\": true}", json.message(&record));
+        assert_eq!("not json", json.message("not json"));
+        assert_eq!("{\"stream\":1}", json.message("{\"stream\":1}"));
+    }
+
+    #[test]
+    fn test_stream_writer_raw() {
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let mut stdout = StreamWriter::new(Stream::Stdout, inner.clone(), LogFormat::Raw);
+        let mut stderr = StreamWriter::new(Stream::Stderr, inner.clone(), LogFormat::Raw);
+        stdout.write_all(b"one\ntw").unwrap();
+        stdout.flush().unwrap();
+        stderr.write_all(b"oops\n").unwrap();
+        stdout.write_all(b"o\nthree").unwrap();
+        stderr.write_all(b"\n\n").unwrap();
+        assert_eq!(
+            "stdout one\nstderr oops\nstdout two\nstderr \nstderr \n",
+            String::from_utf8(inner.lock().unwrap().clone()).unwrap()
+        );
+
+        // The unfinished line is written once the writer is dropped
+        drop(stdout);
+        drop(stderr);
+        assert!(String::from_utf8(inner.lock().unwrap().clone())
+            .unwrap()
+            .ends_with("stderr \nstdout three\n"));
+    }
+
+    #[test]
+    fn test_stream_writer_splits_long_lines() {
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let mut stdout = StreamWriter::new(Stream::Stdout, inner.clone(), LogFormat::Raw);
+        stdout.write_all(&vec![b'a'; MAX_PARTIAL_LINE + 1]).unwrap();
+        let log = String::from_utf8(inner.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            format!("stdout {}\n", "a".repeat(MAX_PARTIAL_LINE + 1)),
+            log
+        );
+
+        stdout.write_all(b"b\n").unwrap();
+        let log = String::from_utf8(inner.lock().unwrap().clone()).unwrap();
+        assert!(log.ends_with("\nstdout b\n"));
+    }
+
+    #[test]
+    fn test_stream_writer_json() {
+        let format = LogFormat::Json {
+            namespace: "default".to_owned(),
+            pod: "app".to_owned(),
+            container: "module".to_owned(),
+        };
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let mut stdout = StreamWriter::new(Stream::Stdout, inner.clone(), format.clone());
+        let mut stderr = StreamWriter::new(Stream::Stderr, inner.clone(), format);
+        stdout.write_all(b"hel").unwrap();
+        stderr.write_all(b"oops\n").unwrap();
+        stdout.write_all(b"lo\n").unwrap();
+
+        let log = String::from_utf8(inner.lock().unwrap().clone()).unwrap();
+        let records = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, records.len());
+        assert_eq!("stderr", records[0]["stream"]);
+        assert_eq!("oops", records[0]["message"]);
+        assert_eq!("stdout", records[1]["stream"]);
+        assert_eq!("hello", records[1]["message"]);
+        for record in &records {
+            assert_eq!("default", record["namespace"]);
+            assert_eq!("app", record["pod"]);
+            assert_eq!("module", record["container"]);
+            assert!(DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_cross_from_file_to_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        // The file hasn't caught up with the buffer yet, so the end of the output is only there
+        std::fs::write(&path, "hello ").unwrap();
+        let buffer = OutputBuffer::new(5);
+        buffer.record(0, b"hello world");
+        assert_eq!(
+            read_all(&mut reader(&path, &buffer).await).await,
+            "hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_wraps_around() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, "abcdefgh").unwrap();
+        let buffer = OutputBuffer::new(4);
+        for (offset, data) in &[(0, "abc"), (3, "de"), (5, "fgh")] {
+            buffer.record(*offset, data.as_bytes());
+        }
+        let mut reader = reader(&path, &buffer).await;
+        reader.seek(SeekFrom::Start(5)).await.unwrap();
+        assert_eq!(read_all(&mut reader).await, "fgh");
+        reader.seek(SeekFrom::Start(1)).await.unwrap();
+        assert_eq!(read_all(&mut reader).await, "bcdefgh");
+    }
+
+    #[tokio::test]
+    async fn test_buffer_without_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        std::fs::write(&path, "hello").unwrap();
+        let buffer = OutputBuffer::new(0);
+        buffer.record(0, b"hello");
+        // Nothing is kept, but the end of the log is still known
+        assert_eq!(buffer.end(), 5);
+        let mut reader = reader(&path, &buffer).await;
+        assert_eq!(read_all(&mut reader).await, "hello");
+        assert_eq!(reader.seek(SeekFrom::End(0)).await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_seek_from_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let buffer = OutputBuffer::new(64);
+        let mut writer = OutputWriter::new(
+            std::fs::File::create(&path).unwrap(),
+            OutputIndex::default(),
+            buffer.clone(),
+        );
+        writer.write_all(b"hello world").unwrap();
+        let mut reader = reader(&path, &buffer).await;
+        assert_eq!(reader.seek(SeekFrom::End(-5)).await.unwrap(), 6);
+        assert_eq!(read_all(&mut reader).await, "world");
+        // The end moves as more is written
+        writer.write_all(b"!").unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(-1)).await.unwrap(), 11);
+        assert_eq!(read_all(&mut reader).await, "!");
+        let err = reader.seek(SeekFrom::End(-13)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_clears_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let buffer = OutputBuffer::new(64);
+        let mut writer = OutputWriter::new(
+            std::fs::File::create(&path).unwrap(),
+            OutputIndex::default(),
+            buffer.clone(),
+        )
+        .with_rotation(
+            path.clone(),
+            Some(LogRotation {
+                max_size: 10,
+                max_files: 2,
+            }),
+        );
+        writer.write_all(b"0123456789").unwrap();
+        let mut reader = reader(&path, &buffer).await;
+        assert_eq!(read_all(&mut reader).await, "0123456789");
+        writer.write_all(b"abc").unwrap();
+        assert_eq!(buffer.end(), 3);
+        assert_eq!(
+            std::fs::read_to_string(LogRotation::rotated_path(&path, 1)).unwrap(),
+            "0123456789"
+        );
+        // A reader left past the end of the rotated log doesn't get output from before rotation
+        assert_eq!(read_all(&mut reader).await, "");
+        reader.seek(SeekFrom::Start(0)).await.unwrap();
+        assert_eq!(read_all(&mut reader).await, "abc");
+    }
+}
@@ -0,0 +1,527 @@
+//! The outbound TCP and UDP functions modules import from `wasi_experimental_sockets`.
+//!
+//! The runtime predates the component model, so the `wasi:sockets` interface of WASI preview 2
+//! can't be offered to modules. These functions give core modules the same outbound access in
+//! the style of the experimental HTTP library: a connection is opened with `tcp_connect` or
+//! `udp_connect`, which hands back a handle that data is sent and received through, and the
+//! handle is released with `close`. Every function returns an error code, with 0 meaning
+//! success. Connections may only be opened to the `host:port` pairs the pod allows, with hosts
+//! resolved with the pod's DNS settings when it has its own. Calls block the module until the
+//! host has finished them, or until the module is interrupted, as it is when it is stopped.
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+use wasmtime::{Caller, Linker};
+
+use crate::dns::Resolver;
+use crate::wasi_runtime::StoreData;
+
+/// The module the socket functions are imported from
+pub(crate) const SOCKETS_MODULE: &str = "wasi_experimental_sockets";
+/// How long to wait for a TCP connection to each address of a host to be accepted
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often calls blocked sending or receiving check whether the module has been interrupted
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The errors returned to the module
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SocketError {
+    InvalidHandle = 1,
+    MemoryNotFound = 2,
+    MemoryAccessError = 3,
+    Utf8Error = 4,
+    DestinationNotAllowed = 5,
+    InvalidAddress = 6,
+    ConnectionError = 7,
+    IoError = 8,
+    Interrupted = 9,
+}
+
+/// The code returned to the module for the result of a call
+fn code(result: Result<(), SocketError>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e as u32,
+    }
+}
+
+/// A host and port a module may open connections to
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Endpoint {
+    host: String,
+    port: u16,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    /// Parses a `host:port` pair. IPv6 addresses are written in brackets, as in `[::1]:5432`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => match rest.split_once("]:") {
+                Some(pair) => pair,
+                None => anyhow::bail!("{:?} has no port, expected [host]:port", s),
+            },
+            None => match s.rsplit_once(':') {
+                Some((host, _)) if host.contains(':') => anyhow::bail!(
+                    "{:?} has an IPv6 address that isn't in brackets, expected [host]:port",
+                    s
+                ),
+                Some(pair) => pair,
+                None => anyhow::bail!("{:?} has no port, expected host:port", s),
+            },
+        };
+        if host.is_empty() {
+            anyhow::bail!("{:?} has no host, expected host:port", s);
+        }
+        let port = port
+            .parse()
+            .map_err(|e| anyhow::anyhow!("{:?} has an invalid port: {}", s, e))?;
+        Ok(Endpoint {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+/// An open connection the module holds a handle to
+enum Socket {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    /// Makes sends and receives give up after a while, so the calls blocked on them can check
+    /// whether the module has been interrupted
+    fn set_poll_timeouts(&self) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(stream) => {
+                stream.set_read_timeout(Some(INTERRUPT_POLL_INTERVAL))?;
+                stream.set_write_timeout(Some(INTERRUPT_POLL_INTERVAL))
+            }
+            Socket::Udp(socket) => {
+                socket.set_read_timeout(Some(INTERRUPT_POLL_INTERVAL))?;
+                socket.set_write_timeout(Some(INTERRUPT_POLL_INTERVAL))
+            }
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Socket::Tcp(stream) => Socket::Tcp(stream.try_clone()?),
+            Socket::Udp(socket) => Socket::Udp(socket.try_clone()?),
+        })
+    }
+
+    fn send(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.write(buf),
+            Socket::Udp(socket) => socket.send(buf),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Tcp(stream) => stream.read(buf),
+            Socket::Udp(socket) => socket.recv(buf),
+        }
+    }
+}
+
+/// The connections the module holds handles to
+#[derive(Default)]
+struct Sockets {
+    next_handle: u32,
+    open: HashMap<u32, Socket>,
+}
+
+impl Sockets {
+    fn insert(&mut self, socket: Socket) -> Result<u32, SocketError> {
+        socket
+            .set_poll_timeouts()
+            .map_err(|_| SocketError::IoError)?;
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.open.insert(handle, socket);
+        Ok(handle)
+    }
+
+    /// A second handle on an open connection, so the module can block on it without holding the
+    /// lock on every other connection
+    fn get(&self, handle: u32) -> Result<Socket, SocketError> {
+        self.open
+            .get(&handle)
+            .ok_or(SocketError::InvalidHandle)?
+            .try_clone()
+            .map_err(|_| SocketError::IoError)
+    }
+}
+
+/// The settings outbound connections are made with
+pub(crate) struct SocketsCtx {
+    /// The endpoints connections may be opened to. Modules that aren't given any can't open
+    /// connections at all
+    allowed_endpoints: Vec<Endpoint>,
+    /// Resolves the hosts of endpoints with the pod's DNS settings, if not with the node's
+    resolver: Option<Arc<Resolver>>,
+    /// Set once the module has been interrupted, so blocked calls return
+    interrupted: Arc<AtomicBool>,
+}
+
+impl SocketsCtx {
+    pub(crate) fn new(allowed_endpoints: Vec<Endpoint>) -> Self {
+        SocketsCtx {
+            allowed_endpoints,
+            resolver: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets the resolver the hosts of endpoints are resolved with instead of the node's
+    pub(crate) fn with_resolver(mut self, resolver: Option<Arc<Resolver>>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Sets the flag that is raised when the module is interrupted
+    pub(crate) fn with_interrupted(mut self, interrupted: Arc<AtomicBool>) -> Self {
+        self.interrupted = interrupted;
+        self
+    }
+
+    /// Defines the socket functions in the linker
+    pub(crate) fn add_to_linker(self, linker: &mut Linker<StoreData>) -> anyhow::Result<()> {
+        let sockets = Arc::new(Mutex::new(Sockets::default()));
+        let ctx = Arc::new(self);
+
+        let (open, tcp_ctx) = (sockets.clone(), ctx.clone());
+        linker.func_wrap(
+            SOCKETS_MODULE,
+            "tcp_connect",
+            move |mut caller: Caller<'_, StoreData>,
+                  addr_ptr: u32,
+                  addr_len: u32,
+                  handle_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let addr = read_string(&mut caller, addr_ptr, addr_len)?;
+                    let addrs = tcp_ctx.resolve(&addr)?;
+                    let stream = tcp_ctx.tcp_connect(&addrs).map_err(|e| {
+                        warn!(%addr, error = %e, "Outbound TCP connection failed");
+                        SocketError::ConnectionError
+                    })?;
+                    let handle = open.lock().unwrap().insert(Socket::Tcp(stream))?;
+                    write(&mut caller, handle_ptr, &handle.to_le_bytes())
+                })();
+                code(result)
+            },
+        )?;
+
+        let (open, udp_ctx) = (sockets.clone(), ctx.clone());
+        linker.func_wrap(
+            SOCKETS_MODULE,
+            "udp_connect",
+            move |mut caller: Caller<'_, StoreData>,
+                  addr_ptr: u32,
+                  addr_len: u32,
+                  handle_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let addr = read_string(&mut caller, addr_ptr, addr_len)?;
+                    let addrs = udp_ctx.resolve(&addr)?;
+                    let socket = udp_connect(&addrs).map_err(|e| {
+                        warn!(%addr, error = %e, "Outbound UDP connection failed");
+                        SocketError::ConnectionError
+                    })?;
+                    let handle = open.lock().unwrap().insert(Socket::Udp(socket))?;
+                    write(&mut caller, handle_ptr, &handle.to_le_bytes())
+                })();
+                code(result)
+            },
+        )?;
+
+        let (open, send_ctx) = (sockets.clone(), ctx.clone());
+        linker.func_wrap(
+            SOCKETS_MODULE,
+            "send",
+            move |mut caller: Caller<'_, StoreData>,
+                  handle: u32,
+                  buf_ptr: u32,
+                  buf_len: u32,
+                  written_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let mut socket = open.lock().unwrap().get(handle)?;
+                    let buf = read(&mut caller, buf_ptr, buf_len)?;
+                    let written = send_ctx.poll(|| socket.send(&buf)).map_err(|e| {
+                        debug!(handle, error = ?e, "Sending on socket failed");
+                        e
+                    })?;
+                    write(&mut caller, written_ptr, &(written as u32).to_le_bytes())
+                })();
+                code(result)
+            },
+        )?;
+
+        let (open, recv_ctx) = (sockets.clone(), ctx);
+        linker.func_wrap(
+            SOCKETS_MODULE,
+            "recv",
+            move |mut caller: Caller<'_, StoreData>,
+                  handle: u32,
+                  buf_ptr: u32,
+                  buf_len: u32,
+                  read_ptr: u32|
+                  -> u32 {
+                let result = (|| {
+                    let mut socket = open.lock().unwrap().get(handle)?;
+                    let mut buf = vec![0; buf_len as usize];
+                    let read = recv_ctx.poll(|| socket.recv(&mut buf)).map_err(|e| {
+                        debug!(handle, error = ?e, "Receiving on socket failed");
+                        e
+                    })?;
+                    write(&mut caller, buf_ptr, &buf[..read])?;
+                    write(&mut caller, read_ptr, &(read as u32).to_le_bytes())
+                })();
+                code(result)
+            },
+        )?;
+
+        let open = sockets;
+        linker.func_wrap(SOCKETS_MODULE, "close", move |handle: u32| -> u32 {
+            code(
+                open.lock()
+                    .unwrap()
+                    .open
+                    .remove(&handle)
+                    .map(|_| ())
+                    .ok_or(SocketError::InvalidHandle),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Checks that the module may connect to a `host:port` address, and resolves it
+    fn resolve(&self, addr: &str) -> Result<Vec<SocketAddr>, SocketError> {
+        let endpoint: Endpoint = addr.parse().map_err(|_| SocketError::InvalidAddress)?;
+        if !self.allowed_endpoints.contains(&endpoint) {
+            warn!(%addr, "Blocked connection to destination outside of the allowed endpoints");
+            return Err(SocketError::DestinationNotAllowed);
+        }
+        debug!(%addr, "Opening outbound connection");
+        let addrs: Vec<SocketAddr> = match (endpoint.host.parse::<IpAddr>(), &self.resolver) {
+            (Ok(ip), _) => vec![SocketAddr::new(ip, endpoint.port)],
+            (Err(_), Some(resolver)) => resolver
+                .resolve(&endpoint.host)
+                .map_err(|e| {
+                    warn!(%addr, error = %e, "Unable to resolve host with pod DNS settings");
+                    SocketError::InvalidAddress
+                })?
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, endpoint.port))
+                .collect(),
+            (Err(_), None) => (endpoint.host.as_str(), endpoint.port)
+                .to_socket_addrs()
+                .map_err(|_| SocketError::InvalidAddress)?
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(SocketError::InvalidAddress);
+        }
+        Ok(addrs)
+    }
+
+    /// Connects to the first of the addresses that accepts the connection, giving up on each
+    /// after a while
+    fn tcp_connect(&self, addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addrs {
+            if self.interrupted.load(Ordering::SeqCst) {
+                break;
+            }
+            match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            std::io::Error::new(ErrorKind::Interrupted, "module was interrupted")
+        }))
+    }
+
+    /// Runs a send or receive until it gets somewhere, checking whether the module has been
+    /// interrupted each time it times out
+    fn poll(&self, mut op: impl FnMut() -> std::io::Result<usize>) -> Result<usize, SocketError> {
+        loop {
+            match op() {
+                Ok(len) => return Ok(len),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if self.interrupted.load(Ordering::SeqCst) {
+                        return Err(SocketError::Interrupted);
+                    }
+                }
+                Err(_) => return Err(SocketError::IoError),
+            }
+        }
+    }
+}
+
+/// Connects a UDP socket to the first of the addresses it can be bound for
+fn udp_connect(addrs: &[SocketAddr]) -> std::io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in addrs {
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        match UdpSocket::bind(local).and_then(|socket| socket.connect(addr).map(|_| socket)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no addresses to connect to",
+        )
+    }))
+}
+
+fn memory(caller: &mut Caller<'_, StoreData>) -> Result<wasmtime::Memory, SocketError> {
+    caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .ok_or(SocketError::MemoryNotFound)
+}
+
+fn read(caller: &mut Caller<'_, StoreData>, ptr: u32, len: u32) -> Result<Vec<u8>, SocketError> {
+    let memory = memory(caller)?;
+    let mut buf = vec![0; len as usize];
+    memory
+        .read(&*caller, ptr as usize, &mut buf)
+        .map_err(|_| SocketError::MemoryAccessError)?;
+    Ok(buf)
+}
+
+fn read_string(
+    caller: &mut Caller<'_, StoreData>,
+    ptr: u32,
+    len: u32,
+) -> Result<String, SocketError> {
+    String::from_utf8(read(caller, ptr, len)?).map_err(|_| SocketError::Utf8Error)
+}
+
+fn write(caller: &mut Caller<'_, StoreData>, ptr: u32, bytes: &[u8]) -> Result<(), SocketError> {
+    let memory = memory(caller)?;
+    memory
+        .write(&mut *caller, ptr as usize, bytes)
+        .map_err(|_| SocketError::MemoryAccessError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn endpoint(host: &str, port: u16) -> Endpoint {
+        Endpoint {
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            "example.com:443".parse::<Endpoint>().unwrap(),
+            endpoint("example.com", 443)
+        );
+        assert_eq!(
+            " DB.Local:5432 ".parse::<Endpoint>().unwrap(),
+            endpoint("db.local", 5432)
+        );
+        assert_eq!(
+            "10.0.0.1:53".parse::<Endpoint>().unwrap(),
+            endpoint("10.0.0.1", 53)
+        );
+        assert_eq!(
+            "[::1]:5432".parse::<Endpoint>().unwrap(),
+            endpoint("::1", 5432)
+        );
+        assert_eq!(
+            "[fe80::1]:80".parse::<Endpoint>().unwrap(),
+            endpoint("fe80::1", 80)
+        );
+        // IPv6 addresses must be in brackets, or the port can't be told apart from the address
+        assert!("::1:5432".parse::<Endpoint>().is_err());
+        assert!("fe80::1".parse::<Endpoint>().is_err());
+        assert!("[::1]".parse::<Endpoint>().is_err());
+        assert!("[::1]5432".parse::<Endpoint>().is_err());
+        // Missing or invalid parts
+        assert!("example.com".parse::<Endpoint>().is_err());
+        assert!("example.com:".parse::<Endpoint>().is_err());
+        assert!(":443".parse::<Endpoint>().is_err());
+        assert!("[]:443".parse::<Endpoint>().is_err());
+        assert!("example.com:https".parse::<Endpoint>().is_err());
+        assert!("example.com:65536".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn connections_are_only_opened_to_allowed_endpoints() {
+        let ctx = SocketsCtx::new(vec![endpoint("127.0.0.1", 5432), endpoint("::1", 5432)]);
+        assert_eq!(
+            ctx.resolve("127.0.0.1:5432"),
+            Ok(vec!["127.0.0.1:5432".parse().unwrap()])
+        );
+        assert_eq!(
+            ctx.resolve("[::1]:5432"),
+            Ok(vec!["[::1]:5432".parse().unwrap()])
+        );
+        assert_eq!(
+            ctx.resolve("127.0.0.1:5433"),
+            Err(SocketError::DestinationNotAllowed)
+        );
+        assert_eq!(
+            ctx.resolve("example.com:5432"),
+            Err(SocketError::DestinationNotAllowed)
+        );
+        assert_eq!(ctx.resolve("127.0.0.1"), Err(SocketError::InvalidAddress));
+        assert_eq!(
+            SocketsCtx::new(Vec::new()).resolve("127.0.0.1:5432"),
+            Err(SocketError::DestinationNotAllowed)
+        );
+    }
+
+    #[test]
+    fn blocked_receives_return_once_interrupted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ctx = SocketsCtx::new(Vec::new());
+        let mut sockets = Sockets::default();
+        let stream = ctx.tcp_connect(&[listener.local_addr().unwrap()]).unwrap();
+        let handle = sockets.insert(Socket::Tcp(stream)).unwrap();
+        // The server accepts the connection but never sends anything
+        let (_server, _) = listener.accept().unwrap();
+
+        let interrupted = ctx.interrupted.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            interrupted.store(true, Ordering::SeqCst);
+        });
+        let mut socket = sockets.get(handle).unwrap();
+        let mut buf = [0; 16];
+        let started = Instant::now();
+        assert_eq!(
+            ctx.poll(|| socket.recv(&mut buf)),
+            Err(SocketError::Interrupted)
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,17 +1,31 @@
 use crate::ModuleRunContext;
 use crate::ProviderState;
 use krator::{ObjectState, SharedState};
+use kubelet::backoff::ExponentialBackoffStrategy;
 use kubelet::container::{Container, ContainerKey, Status};
 use kubelet::pod::Pod;
+use tokio::sync::oneshot;
 
+mod probe;
 pub(crate) mod running;
 pub(crate) mod terminated;
+pub(crate) mod validating;
 pub(crate) mod waiting;
 
+/// The fraction of each restart backoff that may be randomly cut off, so that containers crashing
+/// together don't all restart at the same time
+const BACKOFF_JITTER: f64 = 0.1;
+
 pub(crate) struct ContainerState {
     pod: Pod,
     container_key: ContainerKey,
     run_context: SharedState<ModuleRunContext>,
+    /// The number of times the container has been restarted
+    restart_count: u32,
+    /// The backoff between restarts of the container
+    backoff: ExponentialBackoffStrategy,
+    /// Signalled the first time the container has started, if anything is waiting on it
+    started: Option<oneshot::Sender<()>>,
 }
 
 impl ContainerState {
@@ -20,10 +34,28 @@ impl ContainerState {
         container_key: ContainerKey,
         run_context: SharedState<ModuleRunContext>,
     ) -> Self {
+        // Restarts before the kubelet was last started still count
+        let restart_count = pod.container_restart_count(&container_key);
         ContainerState {
             pod,
             container_key,
             run_context,
+            restart_count,
+            backoff: ExponentialBackoffStrategy::default().with_jitter(BACKOFF_JITTER),
+            started: None,
+        }
+    }
+
+    /// Signals the sender the first time the container has started, which is once its startup
+    /// probe passes if it has one
+    pub fn with_started_signal(mut self, started: oneshot::Sender<()>) -> Self {
+        self.started = Some(started);
+        self
+    }
+
+    fn signal_started(&mut self) {
+        if let Some(started) = self.started.take() {
+            let _ = started.send(());
         }
     }
 }
@@ -35,3 +67,57 @@ impl ObjectState for ContainerState {
     type SharedState = ProviderState;
     async fn async_drop(self, _shared_state: &mut Self::SharedState) {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn run_context() -> ModuleRunContext {
+        ModuleRunContext {
+            modules: Default::default(),
+            volumes: Default::default(),
+            env_vars: Default::default(),
+            sub_path_dirs: Default::default(),
+            ready: Default::default(),
+            stopping_sidecars: false,
+            deadline_exceeded: false,
+        }
+    }
+
+    #[test]
+    fn test_started_signal_is_sent_once() {
+        let (tx, mut rx) = oneshot::channel();
+        let mut state = ContainerState::new(
+            Pod::default(),
+            ContainerKey::Init("proxy".to_owned()),
+            Arc::new(RwLock::new(run_context())),
+        )
+        .with_started_signal(tx);
+        assert!(rx.try_recv().is_err());
+        state.signal_started();
+        assert!(rx.try_recv().is_ok());
+        // Restarts of a sidecar don't signal again
+        assert!(state.started.is_none());
+        state.signal_started();
+    }
+
+    #[test]
+    fn test_restart_count_is_seeded_from_status() {
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {"containers": [{"name": "app"}]},
+            "status": {
+                "containerStatuses": [{"name": "app", "ready": true, "restartCount": 3, "image": "", "imageID": ""}]
+            }
+        }))
+        .unwrap();
+        let state = ContainerState::new(
+            pod,
+            ContainerKey::App("app".to_owned()),
+            Arc::new(RwLock::new(run_context())),
+        );
+        assert_eq!(3, state.restart_count);
+    }
+}
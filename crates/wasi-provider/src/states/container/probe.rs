@@ -0,0 +1,71 @@
+use k8s_openapi::api::core::v1::Probe;
+use kubelet::container::probe::{run_network_probe, ProbeState, ProbeTracker};
+use kubelet::container::Container;
+use kubelet::pod::Pod;
+use tokio::time::{Instant, Interval};
+
+use crate::wasi_runtime::WasiRuntime;
+
+/// The host network probes are sent to if the pod hasn't been assigned an IP
+pub(crate) const DEFAULT_PROBE_HOST: &str = "127.0.0.1";
+
+/// A probe configured on a container along with the timer that drives it
+pub(crate) struct ContainerProbe {
+    probe: Probe,
+    tracker: ProbeTracker,
+    interval: Interval,
+}
+
+impl ContainerProbe {
+    pub(crate) fn new(probe: &Probe) -> Self {
+        let tracker = ProbeTracker::new(probe);
+        let interval =
+            tokio::time::interval_at(Instant::now() + tracker.initial_delay(), tracker.period());
+        ContainerProbe {
+            probe: probe.clone(),
+            tracker,
+            interval,
+        }
+    }
+
+    /// Waits until the probe is next due. If there is no probe, this never completes, which
+    /// makes it easy to use optional probes in a `select!`
+    pub(crate) async fn tick(probe: &mut Option<Self>) {
+        match probe {
+            Some(p) => {
+                p.interval.tick().await;
+            }
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Runs the probe once and records the outcome. Exec probes run a separate instance of the
+    /// container's module with the probe command as its arguments
+    pub(crate) async fn run(
+        &mut self,
+        container: &Container,
+        pod: &Pod,
+        runtime: &WasiRuntime,
+    ) -> ProbeState {
+        let host = pod.pod_ip().unwrap_or(DEFAULT_PROBE_HOST);
+        let outcome = match run_network_probe(&self.probe, container, host).await {
+            Some(outcome) => outcome,
+            None => {
+                let command = self
+                    .probe
+                    .exec
+                    .as_ref()
+                    .map(|e| e.command.clone())
+                    .unwrap_or_default();
+                match runtime.exec(command, self.tracker.timeout()).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => kubelet::container::probe::ProbeOutcome::Failure(format!(
+                        "unable to run exec probe: {:?}",
+                        e
+                    )),
+                }
+            }
+        };
+        self.tracker.record(outcome)
+    }
+}
@@ -1,54 +1,484 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::probe::{ContainerProbe, DEFAULT_PROBE_HOST};
 use super::terminated::Terminated;
 use super::ContainerState;
-use crate::ProviderState;
+use crate::lifecycle::LifecycleHook;
+use crate::termination::termination_message;
+use crate::wasi_runtime::WasiRuntime;
+use crate::{ModuleRunContext, ProviderState};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use kubelet::backoff::BackoffStrategy;
+use kubelet::container::patch_container_status;
+use kubelet::container::probe::{ProbeOutcome, ProbeState};
 use kubelet::container::state::prelude::*;
+use kubelet::pod::{make_ready_conditions, patch_status, PodKey, StatusBuilder};
+use kubelet::state::common::GenericProviderState;
+use kubelet::volume::VolumeRef;
 use tokio::sync::mpsc::Receiver;
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
+
+/// How often the usage of size limited volumes is checked while the container runs
+const VOLUME_USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a container has to run before its restart backoff is reset
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(600);
+/// The reason reported for a container stopped because its postStart hook failed
+const FAILED_POST_START_HOOK: &str = "FailedPostStartHook";
+/// How long a postStart hook may run before it is counted as failed
+const POST_START_HOOK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How the container's postStart hook ended
+#[derive(Debug)]
+enum PostStart {
+    Completed,
+    Failed(String),
+    /// The module stopped before the hook completed. This holds the status it stopped with, or
+    /// nothing if the runtime hung up
+    Exited(Option<Status>),
+}
+
+/// Waits for the postStart hook to complete, while watching the runtime's status updates so a
+/// module that stops in the meantime isn't left waiting on its hook
+async fn run_post_start(
+    hook: impl std::future::Future<Output = ProbeOutcome>,
+    rx: &mut Receiver<Status>,
+) -> PostStart {
+    tokio::pin!(hook);
+    loop {
+        tokio::select! {
+            outcome = &mut hook => {
+                return match outcome {
+                    ProbeOutcome::Success => PostStart::Completed,
+                    ProbeOutcome::Failure(reason) => PostStart::Failed(reason),
+                }
+            }
+            status = rx.recv() => match status {
+                Some(status @ Status::Terminated { .. }) => return PostStart::Exited(Some(status)),
+                Some(status) => debug!(?status, "Got status update from WASI Runtime"),
+                None => return PostStart::Exited(None),
+            },
+        }
+    }
+}
+
+/// Checks every size limited volume mounted by the container, returning an error for the first
+/// one found to be over its limit
+async fn check_volume_limits(
+    container: &Container,
+    run_context: &SharedState<ModuleRunContext>,
+) -> anyhow::Result<()> {
+    let run_context = run_context.read().await;
+    for vm in container.volume_mounts() {
+        if let Some(VolumeRef::EmptyDir(vol)) = run_context.volumes.get(&vm.name) {
+            vol.check_size_limit().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Signals the container's runtime to stop, marking it as no longer ready
+async fn stop_container(shared_state: &SharedState<ProviderState>, state: &ContainerState) {
+    if state.container_key.is_app() {
+        update_status(shared_state, state, Status::running_with_readiness(false)).await;
+    }
+    let handles = shared_state.read().await.handles.clone();
+    let handle = handles.read().await.get(&PodKey::from(&state.pod)).cloned();
+    if let Some(handle) = handle {
+        if let Err(e) = handle.stop_container(&state.container_key).await {
+            warn!(error = %e, "Unable to stop container");
+        }
+    }
+}
+
+/// The liveness and readiness probes for the container, which begin once it has started.
+/// Readiness only applies to app containers
+fn health_probes(
+    container: &Container,
+    state: &ContainerState,
+) -> (Option<ContainerProbe>, Option<ContainerProbe>) {
+    let liveness = container.liveness_probe().map(ContainerProbe::new);
+    let readiness = if state.container_key.is_app() {
+        container.readiness_probe().map(ContainerProbe::new)
+    } else {
+        None
+    };
+    (liveness, readiness)
+}
+
+/// Whether the container is ready after its readiness probe reached the given state. It only
+/// becomes unready once the probe has failed `failureThreshold` times in a row, and ready again
+/// once it has passed `successThreshold` times; in between it stays as it was
+fn is_ready(ready: bool, probe_state: &ProbeState) -> bool {
+    match probe_state {
+        ProbeState::Passing => true,
+        ProbeState::Failed { .. } => false,
+        ProbeState::Failing { .. } => ready,
+    }
+}
+
+/// Patches the running status of the container into the pod, along with the resulting readiness
+/// of the pod as a whole
+async fn update_status(
+    shared_state: &SharedState<ProviderState>,
+    state: &ContainerState,
+    status: Status,
+) {
+    let ready = matches!(status, Status::Running { ready: true, .. });
+    let (was_ready, pod_ready) = {
+        let mut run_context = state.run_context.write().await;
+        let pod_ready = |run_context: &ModuleRunContext| {
+            state
+                .pod
+                .containers()
+                .iter()
+                .all(|c| run_context.ready.get(c.name()).copied().unwrap_or(false))
+        };
+        let was_ready = pod_ready(&run_context);
+        run_context.ready.insert(state.container_key.name(), ready);
+        (was_ready, pod_ready(&run_context))
+    };
+    let client = shared_state.read().await.client();
+    let api: Api<KubePod> = Api::namespaced(client, state.pod.namespace());
+    if let Err(e) = patch_container_status(&api, &state.pod, &state.container_key, &status).await {
+        warn!(error = %e, "Unable to patch container readiness");
+    }
+    // The conditions record when the pod's readiness last changed, so they are left alone while it
+    // stays the same. Pods start out not ready
+    if pod_ready != was_ready {
+        let status = StatusBuilder::new()
+            .conditions(make_ready_conditions(pod_ready))
+            .build();
+        patch_status(&api, state.pod.name(), status).await;
+    }
+}
 
 /// The container is starting.
 #[derive(Debug, TransitionTo)]
 #[transition_to(Terminated)]
 pub struct Running {
     rx: Receiver<Status>,
+    runtime: WasiRuntime,
+    /// Where the module writes its termination message on the host, if it can write one
+    termination_log: Option<PathBuf>,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<Status>) -> Self {
-        Running { rx }
+    pub fn new(
+        rx: Receiver<Status>,
+        runtime: WasiRuntime,
+        termination_log: Option<PathBuf>,
+    ) -> Self {
+        Running {
+            rx,
+            runtime,
+            termination_log,
+        }
+    }
+
+    /// The state the container moves to once its module has stopped with the given status, or
+    /// without one if the runtime hung up
+    async fn exited(
+        &self,
+        shared_state: &SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: &Container,
+        started_at: Instant,
+        status: Option<Status>,
+    ) -> Terminated {
+        let (failed, message, reason, exit_code) = match status {
+            Some(Status::Terminated {
+                failed,
+                message,
+                reason,
+                exit_code,
+                ..
+            }) => (failed, message, reason, exit_code),
+            _ => {
+                warn!("WASI Runtime channel hung up");
+                return Terminated::new("WASI Runtime channel hung up".to_string(), true);
+            }
+        };
+        if started_at.elapsed() >= BACKOFF_RESET_AFTER {
+            state.backoff.reset();
+        }
+        if state.container_key.is_app() {
+            update_status(shared_state, state, Status::running_with_readiness(false)).await;
+        }
+        if state.run_context.read().await.deadline_exceeded {
+            return Terminated::deadline_exceeded().with_exit_code(exit_code);
+        }
+        // What the module says about why it exited takes the place of how it exited
+        let message = termination_message(
+            container,
+            self.termination_log.as_deref(),
+            failed,
+            &self.runtime,
+        )
+        .await
+        .unwrap_or(message);
+        Terminated::new(message, failed)
+            .with_reason(reason)
+            .with_exit_code(exit_code)
     }
 }
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Running {
-    #[instrument(level = "info", skip(self, _shared_state, _state, _container))]
+    #[instrument(
+        level = "info",
+        skip(self, shared_state, state, container),
+        fields(pod_namespace = state.pod.namespace(), pod_name = state.pod.name(), container_name)
+    )]
     async fn next(
         mut self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
-        _container: Manifest<Container>,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
     ) -> Transition<ContainerState> {
+        let container = container.latest();
+
+        tracing::Span::current().record("container_name", &container.name());
+
+        let started_at = Instant::now();
+
+        // The container hasn't finished starting until its postStart hook completes, so nothing
+        // is probed until then
+        if let Some(hook) =
+            LifecycleHook::post_start(&container, state.pod.pod_ip().unwrap_or(DEFAULT_PROBE_HOST))
+        {
+            info!("Running postStart hook");
+            let run = hook.run(&self.runtime, POST_START_HOOK_TIMEOUT);
+            match run_post_start(run, &mut self.rx).await {
+                PostStart::Completed => debug!("postStart hook completed"),
+                PostStart::Failed(reason) => {
+                    warn!(%reason, "postStart hook failed, stopping container");
+                    stop_container(&shared_state, state).await;
+                    return Transition::next(
+                        self,
+                        Terminated::new(format!("postStart hook failed: {}", reason), true)
+                            .with_reason(Some(FAILED_POST_START_HOOK.to_owned())),
+                    );
+                }
+                PostStart::Exited(status) => {
+                    info!("Container exited before its postStart hook completed");
+                    let terminated = self
+                        .exited(&shared_state, state, &container, started_at, status)
+                        .await;
+                    return Transition::next(self, terminated);
+                }
+            }
+        }
+
+        let mut usage_check = tokio::time::interval(VOLUME_USAGE_CHECK_INTERVAL);
+        // Liveness and readiness probes are held back until the startup probe
+        // (if any) passes. Containers without a readiness probe are ready as
+        // soon as they have started
+        let mut startup = container.startup_probe().map(ContainerProbe::new);
+        let (mut liveness, mut readiness) = if startup.is_none() {
+            health_probes(&container, state)
+        } else {
+            (None, None)
+        };
+        let mut ready = startup.is_none() && readiness.is_none();
+        if startup.is_none() {
+            state.signal_started();
+        }
+        if state.container_key.is_app() {
+            let status = if startup.is_some() {
+                Status::starting()
+            } else {
+                Status::running_with_readiness(ready)
+            };
+            update_status(&shared_state, state, status).await;
+        }
+
         debug!("Awaiting container status updates");
-        while let Some(status) = self.rx.recv().await {
-            debug!(?status, "Got status update from WASI Runtime");
-            if let Status::Terminated {
-                failed, message, ..
-            } = status
-            {
-                return Transition::next(self, Terminated::new(message, failed));
+        loop {
+            tokio::select! {
+                status = self.rx.recv() => match status {
+                    Some(status @ Status::Terminated { .. }) => {
+                        let terminated = self
+                            .exited(&shared_state, state, &container, started_at, Some(status))
+                            .await;
+                        return Transition::next(self, terminated);
+                    }
+                    Some(status) => debug!(?status, "Got status update from WASI Runtime"),
+                    None => {
+                        let terminated = self
+                            .exited(&shared_state, state, &container, started_at, None)
+                            .await;
+                        return Transition::next(self, terminated);
+                    }
+                },
+                _ = usage_check.tick() => {
+                    if let Err(e) = check_volume_limits(&container, &state.run_context).await {
+                        warn!(error = %e, "Container exceeded a volume size limit, stopping it");
+                        stop_container(&shared_state, state).await;
+                        return Transition::next(self, Terminated::new(e.to_string(), true));
+                    }
+                }
+                _ = ContainerProbe::tick(&mut startup) => {
+                    let probe_state = match startup.as_mut() {
+                        Some(probe) => probe.run(&container, &state.pod, &self.runtime).await,
+                        None => continue,
+                    };
+                    match probe_state {
+                        ProbeState::Passing => {
+                            info!("Startup probe passed, starting health probes");
+                            startup = None;
+                            state.signal_started();
+                            let probes = health_probes(&container, state);
+                            liveness = probes.0;
+                            readiness = probes.1;
+                            ready = readiness.is_none();
+                            if state.container_key.is_app() {
+                                update_status(&shared_state, state, Status::running_with_readiness(ready)).await;
+                            }
+                        }
+                        ProbeState::Failed { failures, reason } => {
+                            warn!(failures, %reason, "Startup probe failed, stopping container");
+                            stop_container(&shared_state, state).await;
+                            return Transition::next(
+                                self,
+                                Terminated::new(
+                                    format!(
+                                        "Startup probe failed {} times in a row: {}",
+                                        failures, reason
+                                    ),
+                                    true,
+                                ),
+                            );
+                        }
+                        ProbeState::Failing { failures, reason: Some(reason) } => {
+                            debug!(failures, %reason, "Startup probe has not passed yet");
+                        }
+                        _ => {}
+                    }
+                }
+                _ = ContainerProbe::tick(&mut liveness) => {
+                    let probe_state = match liveness.as_mut() {
+                        Some(probe) => probe.run(&container, &state.pod, &self.runtime).await,
+                        None => continue,
+                    };
+                    match probe_state {
+                        ProbeState::Failed { failures, reason } => {
+                            warn!(failures, %reason, "Liveness probe failed, stopping container");
+                            stop_container(&shared_state, state).await;
+                            return Transition::next(
+                                self,
+                                Terminated::new(
+                                    format!(
+                                        "Liveness probe failed {} times in a row: {}",
+                                        failures, reason
+                                    ),
+                                    true,
+                                ),
+                            );
+                        }
+                        ProbeState::Failing { failures, reason: Some(reason) } => {
+                            warn!(failures, %reason, "Liveness probe failed");
+                        }
+                        _ => {}
+                    }
+                }
+                _ = ContainerProbe::tick(&mut readiness) => {
+                    let probe_state = match readiness.as_mut() {
+                        Some(probe) => probe.run(&container, &state.pod, &self.runtime).await,
+                        None => continue,
+                    };
+                    // A failing readiness probe only takes the container out
+                    // of service, it doesn't stop it
+                    let now_ready = is_ready(ready, &probe_state);
+                    if let ProbeState::Failed { failures, reason } = &probe_state {
+                        warn!(failures, %reason, "Readiness probe failed");
+                    }
+                    if now_ready != ready {
+                        info!(ready = now_ready, "Container readiness changed");
+                        ready = now_ready;
+                        update_status(&shared_state, state, Status::running_with_readiness(ready)).await;
+                    }
+                }
             }
         }
-        warn!("WASI Runtime channel hung up");
-        Transition::next(
-            self,
-            Terminated::new("WASI Runtime channel hung up".to_string(), true),
-        )
     }
 
     async fn status(
         &self,
-        _state: &mut ContainerState,
-        _container: &Container,
+        state: &mut ContainerState,
+        container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::running())
+        // Containers with a postStart hook or startup probe are still starting
+        // until they complete, and those with a readiness probe start out not
+        // ready until that probe passes
+        let has_post_start = container
+            .lifecycle()
+            .map(|l| l.post_start.is_some())
+            .unwrap_or(false);
+        if has_post_start || container.startup_probe().is_some() {
+            return Ok(Status::starting());
+        }
+        Ok(Status::running_with_readiness(
+            !state.container_key.is_app() || container.readiness_probe().is_none(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_post_start_outcome() {
+        let (_tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let outcome = run_post_start(async { ProbeOutcome::Success }, &mut rx).await;
+        assert!(matches!(outcome, PostStart::Completed));
+        let outcome = run_post_start(
+            async { ProbeOutcome::Failure("exit code 1".to_owned()) },
+            &mut rx,
+        )
+        .await;
+        assert!(matches!(outcome, PostStart::Failed(reason) if reason == "exit code 1"));
+    }
+
+    #[tokio::test]
+    async fn test_post_start_stops_waiting_once_module_exits() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        tx.send(Status::running()).await.unwrap();
+        tx.send(Status::terminated("exited", false)).await.unwrap();
+        // The hook would otherwise never complete
+        let outcome = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_post_start(futures::future::pending(), &mut rx),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            outcome,
+            PostStart::Exited(Some(Status::Terminated { failed: false, .. }))
+        ));
+
+        drop(tx);
+        let outcome = run_post_start(futures::future::pending(), &mut rx).await;
+        assert!(matches!(outcome, PostStart::Exited(None)));
+    }
+
+    #[test]
+    fn test_readiness_follows_probe_thresholds() {
+        let failing = ProbeState::Failing {
+            failures: 1,
+            reason: Some("connection refused".to_owned()),
+        };
+        let failed = ProbeState::Failed {
+            failures: 3,
+            reason: "connection refused".to_owned(),
+        };
+        // A failure below the threshold doesn't take a ready container out of service
+        assert!(is_ready(true, &failing));
+        assert!(!is_ready(true, &failed));
+        // Nor does a success below the threshold bring an unready one back
+        assert!(!is_ready(false, &failing));
+        assert!(is_ready(false, &ProbeState::Passing));
     }
 }
@@ -1,37 +1,156 @@
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::error::ErrorResponse;
+use kube::Api;
+use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::prelude::*;
-use tracing::{error, instrument};
+use kubelet::container::{patch_container_restart_count, ContainerKey};
+use kubelet::pod::Pod;
+use kubelet::state::common::GenericProviderState;
+use tracing::{error, info, instrument, warn};
 
-use crate::ProviderState;
+use crate::error::ContainerError;
+use crate::states::pod::{DEADLINE_EXCEEDED, DEADLINE_EXCEEDED_MESSAGE};
+use crate::{ModuleRunContext, ProviderState};
 
+use super::waiting::Waiting;
 use super::ContainerState;
 
-/// The container is starting.
+/// Checks whether the pod is being deleted, in which case its containers shouldn't be restarted
+async fn is_pod_deleted(shared_state: &SharedState<ProviderState>, pod: &Pod) -> bool {
+    let client = shared_state.read().await.client();
+    let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+    match api.get(pod.name()).await {
+        Ok(p) => p.metadata.deletion_timestamp.is_some(),
+        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => true,
+        Err(e) => {
+            warn!(error = %e, "Unable to check whether pod is being deleted");
+            false
+        }
+    }
+}
+
+/// Records the container's restart in its status. Failing to is logged, as the restart should
+/// still go ahead
+async fn record_restart(shared_state: &SharedState<ProviderState>, state: &ContainerState) {
+    let client = shared_state.read().await.client();
+    let api: Api<KubePod> = Api::namespaced(client, state.pod.namespace());
+    if let Err(e) =
+        patch_container_restart_count(&api, &state.pod, &state.container_key, state.restart_count)
+            .await
+    {
+        warn!(error = %e, "Unable to record container restart count");
+    }
+}
+
+/// The container has exited.
 #[derive(Debug, TransitionTo)]
-#[transition_to()]
+#[transition_to(Waiting)]
 pub struct Terminated {
     message: String,
     failed: bool,
+    reason: Option<String>,
+    exit_code: Option<i32>,
+    /// Whether the container may be restarted under the pod's restart policy
+    retryable: bool,
 }
 
 impl Terminated {
     pub fn new(message: String, failed: bool) -> Self {
-        Terminated { message, failed }
+        Terminated {
+            message,
+            failed,
+            reason: None,
+            exit_code: None,
+            retryable: true,
+        }
+    }
+
+    /// The container was stopped, or not started at all, because the pod ran past its active
+    /// deadline
+    pub(crate) fn deadline_exceeded() -> Self {
+        Terminated::new(DEADLINE_EXCEEDED_MESSAGE.to_owned(), true)
+            .with_reason(Some(DEADLINE_EXCEEDED.to_owned()))
+    }
+
+    /// The container failed to start with the error. Its reason is reported in the container
+    /// status, and the container is only restarted if starting it again could succeed
+    pub(crate) fn from_error(message: String, error: &ContainerError) -> Self {
+        Terminated {
+            message,
+            failed: true,
+            reason: Some(error.reason().to_owned()),
+            exit_code: None,
+            retryable: error.is_retryable(),
+        }
+    }
+
+    /// Whether the container should be restarted. Sidecars are restarted however they exit,
+    /// until the app containers are done with them. Nothing is restarted once the pod has run
+    /// past its active deadline, or after an error that would only happen again
+    fn should_restart(
+        &self,
+        pod: &Pod,
+        key: &ContainerKey,
+        run_context: &ModuleRunContext,
+    ) -> bool {
+        if !self.retryable || run_context.deadline_exceeded {
+            false
+        } else if pod.is_sidecar(key) {
+            !run_context.stopping_sidecars
+        } else {
+            pod.restart_policy().should_restart(key, self.failed)
+        }
+    }
+
+    /// Sets a more specific reason for the termination to report in the container status
+    pub fn with_reason(mut self, reason: Option<String>) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Sets the code the module exited with to report in the container status
+    pub fn with_exit_code(mut self, exit_code: Option<i32>) -> Self {
+        self.exit_code = exit_code;
+        self
     }
 }
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Terminated {
-    #[instrument(level = "info", skip(self, _shared_state, _state, container), fields(pod_name = _state.pod.name(), container_name))]
+    #[instrument(level = "info", skip(self, shared_state, state, container), fields(pod_namespace = state.pod.namespace(), pod_name = state.pod.name(), container_name))]
     async fn next(
         self: Box<Self>,
-        _shared_state: SharedState<ProviderState>,
-        _state: &mut ContainerState,
+        shared_state: SharedState<ProviderState>,
+        state: &mut ContainerState,
         container: Manifest<Container>,
     ) -> Transition<ContainerState> {
         let container = container.latest();
 
         tracing::Span::current().record("container_name", &container.name());
 
+        if self.failed {
+            kubelet::metrics::CONTAINER_FAILURES.inc();
+        }
+
+        let restart_policy = state.pod.restart_policy();
+        let restart = self.should_restart(
+            &state.pod,
+            &state.container_key,
+            &*state.run_context.read().await,
+        );
+        if restart && !is_pod_deleted(&shared_state, &state.pod).await {
+            state.restart_count += 1;
+            record_restart(&shared_state, state).await;
+            let backoff = state.backoff.next_duration();
+            info!(
+                ?restart_policy,
+                failed = self.failed,
+                restart_count = state.restart_count,
+                "Restarting container"
+            );
+            return Transition::next(self, Waiting::restart(backoff));
+        }
+
         if self.failed {
             error!(
                 error = %self.message,
@@ -48,6 +167,88 @@ impl State<ContainerState> for Terminated {
         _state: &mut ContainerState,
         _container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::terminated(&self.message, self.failed))
+        Ok(Status::Terminated {
+            timestamp: chrono::Utc::now(),
+            message: self.message.clone(),
+            failed: self.failed,
+            reason: self.reason.clone(),
+            exit_code: self.exit_code,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_context() -> ModuleRunContext {
+        ModuleRunContext {
+            modules: Default::default(),
+            volumes: Default::default(),
+            env_vars: Default::default(),
+            sub_path_dirs: Default::default(),
+            ready: Default::default(),
+            stopping_sidecars: false,
+            deadline_exceeded: false,
+        }
+    }
+
+    fn pod(restart_policy: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "test"},
+            "spec": {
+                "restartPolicy": restart_policy,
+                "initContainers": [
+                    {"name": "setup"},
+                    {"name": "proxy", "restartPolicy": "Always"}
+                ],
+                "containers": [{"name": "app"}]
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sidecars_restart_until_stopped() {
+        let sidecar = ContainerKey::Init("proxy".to_owned());
+        let mut run_context = run_context();
+        for policy in &["Always", "OnFailure", "Never"] {
+            let pod = pod(policy);
+            for failed in &[false, true] {
+                let terminated = Terminated::new(String::new(), *failed);
+                assert!(terminated.should_restart(&pod, &sidecar, &run_context));
+            }
+        }
+        run_context.stopping_sidecars = true;
+        let terminated = Terminated::new(String::new(), true);
+        assert!(!terminated.should_restart(&pod("Always"), &sidecar, &run_context));
+    }
+
+    #[test]
+    fn test_should_restart() {
+        let mut run_context = run_context();
+        let pod = pod("Always");
+        let init = ContainerKey::Init("setup".to_owned());
+        let app = ContainerKey::App("app".to_owned());
+        let succeeded = Terminated::new(String::new(), false);
+        let failed = Terminated::new(String::new(), true);
+        // Stopping sidecars doesn't affect other containers
+        run_context.stopping_sidecars = true;
+        assert!(succeeded.should_restart(&pod, &app, &run_context));
+        assert!(!succeeded.should_restart(&pod, &init, &run_context));
+        assert!(failed.should_restart(&pod, &init, &run_context));
+
+        let error = ContainerError::VolumeNotFound {
+            volume: "data".to_owned(),
+            container: "app".to_owned(),
+        };
+        let not_retryable = Terminated::from_error(String::new(), &error);
+        assert!(!not_retryable.should_restart(&pod, &app, &run_context));
+
+        run_context.deadline_exceeded = true;
+        assert!(!failed.should_restart(&pod, &app, &run_context));
+        let sidecar = ContainerKey::Init("proxy".to_owned());
+        run_context.stopping_sidecars = false;
+        assert!(!failed.should_restart(&pod, &sidecar, &run_context));
     }
 }
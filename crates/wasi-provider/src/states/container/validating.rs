@@ -0,0 +1,150 @@
+use kubelet::container::state::prelude::*;
+use kubelet::state::common::GenericProviderState;
+use tracing::{debug, info, instrument};
+
+use crate::features::{parse_max_threads, WasmFeatures};
+use crate::validation::validate_module;
+use crate::wasi_nn::select_backend;
+use crate::wasi_runtime::DEFAULT_ENTRYPOINT;
+use crate::ProviderState;
+
+use super::terminated::Terminated;
+use super::waiting::{
+    module_annotations, Waiting, ENTRYPOINT_ANNOTATION_KEY, MAX_THREADS_ANNOTATION_KEY,
+    WASI_NN_BACKEND_ANNOTATION_KEY, WASM_FEATURES_ANNOTATION_KEY,
+};
+use super::ContainerState;
+
+pub const VALIDATE_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/validate-module";
+
+/// The container's module is checked before it is first started, if the pod asks for it.
+#[derive(Default, Debug, TransitionTo)]
+#[transition_to(Waiting, Terminated)]
+pub struct Validating;
+
+#[async_trait::async_trait]
+impl State<ContainerState> for Validating {
+    #[instrument(
+        level = "info",
+        skip(self, shared, state, container),
+        fields(
+            pod_namespace = state.pod.namespace(),
+            pod_name = state.pod.name(),
+            container_name
+        )
+    )]
+    async fn next(
+        self: Box<Self>,
+        shared: SharedState<ProviderState>,
+        state: &mut ContainerState,
+        container: Manifest<Container>,
+    ) -> Transition<ContainerState> {
+        let container = container.latest();
+
+        tracing::Span::current().record("container_name", &container.name());
+
+        let validate = state.pod.annotations().get(VALIDATE_ANNOTATION_KEY);
+        if validate.map(|v| v.trim()) != Some("true") {
+            return Transition::next(self, Waiting::default());
+        }
+        let store = shared.read().await.store();
+        let annotations = module_annotations(store.as_ref(), &container, &state.pod).await;
+
+        // A missing module is reported when the container starts
+        let module_data = match state.run_context.read().await.modules.get(container.name()) {
+            Some(data) => data.clone(),
+            None => return Transition::next(self, Waiting::default()),
+        };
+        // An empty entrypoint is reported when the container starts
+        let entrypoint = annotations
+            .get(ENTRYPOINT_ANNOTATION_KEY)
+            .map(|e| e.trim().to_owned())
+            .filter(|e| !e.is_empty())
+            .unwrap_or_else(|| DEFAULT_ENTRYPOINT.to_owned());
+        let (engines, host_functions, wasi_nn_backends) = {
+            let provider_state = shared.read().await;
+            (
+                provider_state.engines.clone(),
+                provider_state.host_functions.clone(),
+                provider_state.wasi_nn_backends.clone(),
+            )
+        };
+        // Features the node can't give the module are reported when the container starts
+        let features = match WasmFeatures::from_annotation(
+            annotations
+                .get(WASM_FEATURES_ANNOTATION_KEY)
+                .map(String::as_str),
+            engines.disabled_features(),
+        )
+        .and_then(
+            |features| match annotations.get(MAX_THREADS_ANNOTATION_KEY) {
+                Some(annotation) => parse_max_threads(annotation).and_then(|max_threads| {
+                    features.with_max_threads(max_threads, engines.disabled_features())
+                }),
+                None => Ok(features),
+            },
+        ) {
+            Ok(features) => features,
+            Err(_) => return Transition::next(self, Waiting::default()),
+        };
+        // As are wasi-nn backends the node doesn't have
+        let nn_backend = match annotations
+            .get(WASI_NN_BACKEND_ANNOTATION_KEY)
+            .map(|annotation| select_backend(annotation, &wasi_nn_backends))
+            .transpose()
+        {
+            Ok(backend) => backend,
+            Err(_) => return Transition::next(self, Waiting::default()),
+        };
+
+        debug!(%entrypoint, "Validating module");
+        let result = tokio::task::spawn_blocking(move || {
+            validate_module(
+                &module_data,
+                &entrypoint,
+                features,
+                &host_functions,
+                nn_backend,
+            )
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {
+                info!("Module passed validation");
+                Transition::next(self, Waiting::default())
+            }
+            Ok(Err(e)) => Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} module failed validation: {:#}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            ),
+            Err(e) => Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} module could not be validated: {}",
+                        state.pod.name(),
+                        container.name(),
+                        e
+                    ),
+                    true,
+                ),
+            ),
+        }
+    }
+
+    async fn status(
+        &self,
+        _state: &mut ContainerState,
+        _container: &Container,
+    ) -> anyhow::Result<Status> {
+        Ok(Status::waiting("Module is being validated."))
+    }
+}
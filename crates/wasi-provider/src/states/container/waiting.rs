@@ -1,18 +1,44 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{Namespace, VolumeMount};
+use k8s_openapi::ByteString;
+use kube::error::ErrorResponse;
+use kube::Api;
+use sha2::Digest;
 
 use tokio::sync::mpsc;
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
 
 use kubelet::container::state::prelude::*;
-use kubelet::pod::{Handle as PodHandle, PodKey};
+use kubelet::container::{expand_var_refs, PullPolicy};
+use kubelet::pod::dns::ResolverConfig;
+use kubelet::pod::{Handle as PodHandle, Pod, PodKey};
+use kubelet::secret::RegistryAuthResolver;
 use kubelet::state::common::GenericProviderState;
-use kubelet::volume::VolumeRef;
+use kubelet::store::oci::SignatureVerifier;
+use kubelet::store::Store;
+use kubelet::volume::{VolumeRef, SERVICE_ACCOUNT_MOUNT_PATH, SERVICE_ACCOUNT_VOLUME_NAME};
 
-use crate::wasi_runtime::{WasiHttpConfig, WasiRuntime};
+use crate::allow_list::split_allow_list;
+use crate::capabilities::WasiCapabilities;
+use crate::env_filter::EnvFilter;
+use crate::error::ContainerError;
+use crate::features::{parse_max_threads, WasmFeatures};
+use crate::http::{CaBundle, ClientCert, RetryPolicy};
+use crate::lifecycle::LifecycleHook;
+use crate::output::LogFormat;
+use crate::sockets::Endpoint;
+use crate::termination;
+use crate::wasi_nn::select_backend;
+use crate::wasi_runtime::{WasiExecConfig, WasiHttpConfig, WasiRuntime};
 use crate::ProviderState;
 
+use super::probe::DEFAULT_PROBE_HOST;
 use super::running::Running;
 use super::terminated::Terminated;
 use super::ContainerState;
@@ -20,49 +46,718 @@ use super::ContainerState;
 pub const MAX_CONNCURRENT_REQUESTS_ANNOTATION_KEY: &str =
     "alpha.wasi.krustlet.dev/max-concurrent-requests";
 pub const ALLOWED_DOMAINS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/allowed-domains";
+pub const ALLOWED_SOCKETS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/allowed-sockets";
+pub const HTTP_REQUEST_TIMEOUT_ANNOTATION_KEY: &str =
+    "alpha.wasi.krustlet.dev/http-request-timeout-ms";
+pub const HTTP_MAX_RETRIES_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/http-max-retries";
+pub const HTTP_RETRY_BACKOFF_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/http-retry-backoff-ms";
+pub const HTTP_CLIENT_CERT_SECRET_ANNOTATION_KEY: &str =
+    "alpha.wasi.krustlet.dev/http-client-cert-secret";
+pub const HTTP_CA_BUNDLE_CONFIGMAP_ANNOTATION_KEY: &str =
+    "alpha.wasi.krustlet.dev/http-ca-bundle-configmap";
+pub const HTTP_CA_BUNDLE_ONLY_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/http-ca-bundle-only";
+pub const MAX_FUEL_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/max-fuel";
+pub const ENTRYPOINT_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/entrypoint";
+pub const DISABLED_CAPABILITIES_ANNOTATION_KEY: &str =
+    "alpha.wasi.krustlet.dev/disabled-capabilities";
+pub const ENV_ALLOW_LIST_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/env-allow-list";
+pub const ENV_DENY_LIST_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/env-deny-list";
+pub const WASM_FEATURES_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/wasm-features";
+pub const MAX_THREADS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/max-threads";
+pub const WASI_NN_BACKEND_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/wasi-nn-backend";
+pub const AUDIT_HOST_CALLS_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/audit-host-calls";
+pub const STDIN_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/stdin";
+pub const STDIN_CONFIGMAP_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/stdin-configmap";
+pub const WORKING_DIR_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/working-dir";
+pub const LOG_DIR_ANNOTATION_KEY: &str = "alpha.wasi.krustlet.dev/log-dir";
+
+/// The annotations that set a module's outbound network access. A namespace can give these to
+/// set the default for the pods in it
+const NETWORK_ANNOTATION_KEYS: &[&str] = &[
+    ALLOWED_DOMAINS_ANNOTATION_KEY,
+    ALLOWED_SOCKETS_ANNOTATION_KEY,
+    MAX_CONNCURRENT_REQUESTS_ANNOTATION_KEY,
+    HTTP_REQUEST_TIMEOUT_ANNOTATION_KEY,
+    HTTP_MAX_RETRIES_ANNOTATION_KEY,
+    HTTP_RETRY_BACKOFF_ANNOTATION_KEY,
+];
+
+/// The annotations that describe how a module is built to be run. A module's image can give these
+/// in its manifest, so the pod doesn't have to repeat them for every module. Annotations that
+/// grant a module access to anything are only ever taken from the pod
+const IMAGE_ANNOTATION_KEYS: &[&str] = &[
+    ENTRYPOINT_ANNOTATION_KEY,
+    WASM_FEATURES_ANNOTATION_KEY,
+    MAX_THREADS_ANNOTATION_KEY,
+];
+
+/// The directory under the volume path that files mounted with a `subPath` are staged in
+const SUB_PATH_DIR: &str = ".subpaths";
+
+/// Where the data files shipped in the module's image are mounted in the guest. They make up the
+/// guest's root filesystem, as the files of a container image do, and are always read only as
+/// they are shared by every container run from the image
+const IMAGE_DATA_PATH: &str = "/";
+
+/// How many times a failed outbound request is retried, if the pod only sets the backoff
+const DEFAULT_HTTP_MAX_RETRIES: u32 = 3;
+/// How long to wait before retrying a failed outbound request, if the pod doesn't say
+const DEFAULT_HTTP_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The name of the directory the files of a container of the pod are staged in. Pod names and
+/// namespaces can both contain `-`, so joining them could give different pods the same directory.
+/// The pod's UID has a fixed form and belongs to no other pod, including earlier pods of the same
+/// name
+fn staging_dir_name(pod: &Pod, container: &Container) -> String {
+    format!("{}-{}", pod.pod_uid(), container.name())
+}
 
-fn volume_path_map(
+/// Checks that the mount propagation requested for a volume mount is one modules can honor.
+///
+/// Volumes are preopened directories that the module reaches through the host's filesystem, so
+/// mounts made on the host under a volume are seen by the module, and both `None` and
+/// `HostToContainer` are honored. Modules can't make mounts of their own, so `Bidirectional`
+/// propagation, and any mode Kubernetes doesn't define, is rejected rather than ignored
+fn check_mount_propagation(vm: &VolumeMount) -> Result<(), ContainerError> {
+    match vm.mount_propagation.as_deref() {
+        None | Some("None") | Some("HostToContainer") => Ok(()),
+        Some(mode) => Err(ContainerError::UnsupportedMountPropagation {
+            volume: vm.name.clone(),
+            mode: mode.to_owned(),
+        }),
+    }
+}
+
+/// Checks that the container doesn't mount more directories than the limit, as each one is held
+/// open while the module runs
+fn check_preopened_dirs(
+    dirs: &HashMap<PathBuf, (Option<PathBuf>, bool)>,
+    limit: usize,
+) -> Result<(), ContainerError> {
+    if dirs.len() > limit {
+        return Err(ContainerError::TooManyDirs {
+            dirs: dirs.len(),
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Maps the host path of each volume mounted into the container to its path in the guest and
+/// whether it is read-only.
+///
+/// WASI can only preopen directories, so mounted files, such as a `File` host path or a key
+/// mounted with a `subPath`, are copied into a directory under `staging_dir` that stands in for
+/// the guest directory they are mounted in. Staged files are a one-time copy, so they are always
+/// mounted read-only
+async fn volume_path_map(
     container: &Container,
     volumes: &HashMap<String, VolumeRef>,
-) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
+    staging_dir: &Path,
+) -> Result<HashMap<PathBuf, (Option<PathBuf>, bool)>, ContainerError> {
+    // Start from scratch in case the container is being restarted
+    match tokio::fs::remove_dir_all(staging_dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+
+    let mut paths = HashMap::new();
+    // The staging directory for each guest directory that has files mounted into it
+    let mut staged: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for vm in container.volume_mounts() {
+        check_mount_propagation(vm)?;
+        // Volumes are mounted once for the whole pod, so every container
+        // that mounts a volume is given the same host directory.
+        // Check the volume exists first
+        let vol = volumes
+            .get(&vm.name)
+            .ok_or_else(|| ContainerError::VolumeNotFound {
+                volume: vm.name.clone(),
+                container: container.name().to_owned(),
+            })?;
+        let volume_root = vol.get_path().map(|p| p.to_owned()).ok_or_else(|| {
+            ContainerError::VolumeNotMounted {
+                volume: vm.name.clone(),
+            }
+        })?;
+        // We can safely assume that this should be valid UTF-8 because it would have
+        // been validated by the k8s API
+        let guest_path = PathBuf::from(&vm.mount_path);
+        // Secrets and projected volumes are always mounted read-only, as they are in Kubernetes.
+        // Whether other volumes can be written follows the mount, whatever the container's
+        // `readOnlyRootFilesystem`: modules have no root filesystem of their own, and the
+        // directories that stand in for one, of staged files and image data, are always read-only
+        let read_only = vm.read_only.unwrap_or(false)
+            || matches!(vol, VolumeRef::Secret(_) | VolumeRef::Projected(_));
+
+        let sub_path = vm.sub_path.as_deref().filter(|p| !p.is_empty());
+        // The API server rejects absolute sub paths and ones containing `..`, but a symlink in
+        // the volume could still lead out of it
+        let host_path = match sub_path {
+            Some(sub_path) => {
+                resolve_in_volume(&volume_root, &volume_root.join(sub_path), &vm.name).await?
+            }
+            None => volume_root,
+        };
+        match tokio::fs::metadata(&host_path).await {
+            Ok(metadata) if metadata.is_file() => (),
+            Ok(_) => {
+                paths.insert(host_path, (Some(guest_path), read_only));
+                continue;
+            }
+            // Like Kubernetes, create the sub path as a directory if it doesn't exist yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && sub_path.is_some() => {
+                if let Err(source) = tokio::fs::create_dir_all(&host_path).await {
+                    return Err(ContainerError::VolumeIo {
+                        volume: vm.name.clone(),
+                        path: host_path,
+                        source,
+                    });
+                }
+                paths.insert(host_path, (Some(guest_path), read_only));
+                continue;
+            }
+            Err(source) => {
+                return Err(ContainerError::VolumeIo {
+                    volume: vm.name.clone(),
+                    path: host_path,
+                    source,
+                })
+            }
+        }
+
+        let (guest_dir, file_name) = match (guest_path.parent(), guest_path.file_name()) {
+            (Some(dir), Some(name)) => (dir.to_owned(), name.to_owned()),
+            _ => {
+                return Err(ContainerError::InvalidMountPath {
+                    volume: vm.name.clone(),
+                    path: host_path,
+                    mount_path: vm.mount_path.clone(),
+                })
+            }
+        };
+        let dir = match staged.get(&guest_dir) {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = staging_dir.join(staged.len().to_string());
+                tokio::fs::create_dir_all(&dir).await?;
+                staged.insert(guest_dir.clone(), dir.clone());
+                paths.insert(dir.clone(), (Some(guest_dir), true));
+                dir
+            }
+        };
+        tokio::fs::copy(&host_path, dir.join(file_name)).await?;
+    }
+    // The kubelet only adds this volume when no container mounts the service account itself
+    if let Some(host_path) = volumes
+        .get(SERVICE_ACCOUNT_VOLUME_NAME)
+        .and_then(|vol| vol.get_path())
+    {
+        paths.insert(
+            host_path.to_owned(),
+            (Some(PathBuf::from(SERVICE_ACCOUNT_MOUNT_PATH)), true),
+        );
+    }
+    Ok(paths)
+}
+
+/// Resolves a path in the volume rooted at `root`, following any symlinks, and checks that it is
+/// still inside the volume. Whatever writes to a volume controls the symlinks in it, so one that
+/// points out of the volume would otherwise hand the module files from anywhere on the node.
+/// Symlinks within directories the module is given are already confined by the preopen, so only
+/// the paths mounted themselves need checking. Parts of the path that don't exist yet are
+/// resolved from their nearest existing parent, so creating them can't escape the volume either
+async fn resolve_in_volume(
+    root: &Path,
+    path: &Path,
+    volume: &str,
+) -> Result<PathBuf, ContainerError> {
+    let volume_io = |path: &Path, source| ContainerError::VolumeIo {
+        volume: volume.to_owned(),
+        path: path.to_owned(),
+        source,
+    };
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .map_err(|e| volume_io(root, e))?;
+    let resolved = resolve_existing(path)
+        .await
+        .map_err(|e| volume_io(path, e))?;
+    if !resolved.starts_with(&root) {
+        return Err(ContainerError::OutsideVolume {
+            volume: volume.to_owned(),
+            path: path.to_owned(),
+            resolved,
+        });
+    }
+    Ok(resolved)
+}
+
+/// Finds the host directory for the working directory the pod asked for, along with whether it
+/// is read only. It must be an existing directory in one of the container's mounts, which is the
+/// most specific mount it is under
+async fn working_dir(
+    requested: &str,
+    volumes: &HashMap<PathBuf, (Option<PathBuf>, bool)>,
+) -> anyhow::Result<(PathBuf, bool)> {
+    let requested = Path::new(requested.trim());
+    if !requested.is_absolute() {
+        anyhow::bail!("working directory {:?} is not an absolute path", requested);
+    }
+    let (host_root, guest_root, read_only) = volumes
+        .iter()
+        .filter_map(|(host, (guest, read_only))| Some((host, guest.as_ref()?, *read_only)))
+        .filter(|(_, guest, _)| requested.starts_with(guest))
+        .max_by_key(|(_, guest, _)| guest.components().count())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "working directory {:?} is not in any of the container's volume mounts",
+                requested
+            )
+        })?;
+    let relative = requested.strip_prefix(guest_root)?;
+    if relative
+        .components()
+        .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        anyhow::bail!("working directory {:?} must not contain '..'", requested);
+    }
+    let host_dir = resolve_in_volume(
+        host_root,
+        &host_root.join(relative),
+        &guest_root.display().to_string(),
+    )
+    .await?;
+    match tokio::fs::metadata(&host_dir).await {
+        Ok(metadata) if metadata.is_dir() => Ok((host_dir, read_only)),
+        _ => anyhow::bail!("working directory {:?} is not a directory", requested),
+    }
+}
+
+/// Whether the container's module is pulled again before it is run. Like the kubelet, a container
+/// restarted with a pull policy of Always pulls its module again, so an update pushed to its tag
+/// is picked up without recreating the pod. It is only pulled before the first attempt to load it,
+/// not again for every retry
+fn pulls_module_again(
+    container: &Container,
+    restart_count: u32,
+    module_load_attempts: u32,
+) -> bool {
+    restart_count > 0
+        && module_load_attempts == 0
+        && matches!(container.effective_pull_policy(), Ok(PullPolicy::Always))
+}
+
+/// The policy outbound requests are retried with. Requests are retried if the pod sets the retry
+/// limit, the backoff, or both, with defaults for whichever it leaves out. On error, returns the
+/// key of the annotation that couldn't be parsed
+fn http_retry_policy(
+    annotations: &BTreeMap<String, String>,
+) -> Result<Option<RetryPolicy>, (&'static str, std::num::ParseIntError)> {
+    let max_retries = annotations.get(HTTP_MAX_RETRIES_ANNOTATION_KEY);
+    let backoff = annotations.get(HTTP_RETRY_BACKOFF_ANNOTATION_KEY);
+    if max_retries.is_none() && backoff.is_none() {
+        return Ok(None);
+    }
+    let max_retries = match max_retries {
+        Some(annotation) => annotation
+            .trim()
+            .parse()
+            .map_err(|e| (HTTP_MAX_RETRIES_ANNOTATION_KEY, e))?,
+        None => DEFAULT_HTTP_MAX_RETRIES,
+    };
+    let backoff = match backoff {
+        Some(annotation) => Duration::from_millis(
+            annotation
+                .trim()
+                .parse()
+                .map_err(|e| (HTTP_RETRY_BACKOFF_ANNOTATION_KEY, e))?,
+        ),
+        None => DEFAULT_HTTP_RETRY_BACKOFF,
+    };
+    Ok(Some(RetryPolicy {
+        max_retries,
+        backoff,
+    }))
+}
+
+/// The command line the module is run with. Modules have no image entrypoint for `command` to
+/// override, so the container's `command` and `args` are concatenated, as Kubernetes does, and
+/// passed to the module starting at `argv[0]` with their `$(VAR)` references expanded from the
+/// module's environment. Nothing is prepended, so a module that skips `argv[0]` as its program
+/// name should be given one as the first item of `command`
+fn command_line(container: &Container, env: &HashMap<String, String>) -> Vec<String> {
     container
-        .volume_mounts()
+        .command()
         .iter()
-        .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-            // Check the volume exists first
-            let vol = volumes.get(&vm.name).ok_or_else(|| {
-                anyhow::anyhow!(
-                    "no volume with the name of {} found for container {}",
-                    vm.name,
-                    container.name()
-                )
-            })?;
-            let host_path = vol
-                .get_path()
-                .map(|p| p.to_owned())
-                .ok_or_else(|| anyhow::anyhow!("Volume {} has not been mounted yet", vm.name))?;
-            let mut guest_path = PathBuf::from(&vm.mount_path);
-            if let Some(sub_path) = &vm.sub_path {
-                guest_path.push(sub_path);
-            }
-            // We can safely assume that this should be valid UTF-8 because it would have
-            // been validated by the k8s API
-            Ok((host_path, Some(guest_path)))
+        .chain(container.args())
+        .map(|arg| expand_var_refs(arg, env))
+        .collect()
+}
+
+/// The digest of a module, recorded in spans so they can be correlated with the module that ran
+fn module_digest(module_data: &[u8]) -> String {
+    format!("sha256:{:x}", sha2::Sha256::digest(module_data))
+}
+
+/// Verifies the signature of the container's module if the node or the pod requires modules to be
+/// signed, returning the digest of the signed manifest. Returns `None` if no signature is required
+async fn verify_module_signature(
+    verifier: &SignatureVerifier,
+    client: &kube::Client,
+    pod: &Pod,
+    container: &Container,
+    module_data: &[u8],
+) -> anyhow::Result<Option<String>> {
+    if !verifier.is_required(pod) {
+        return Ok(None);
+    }
+    let image = container
+        .image()?
+        .ok_or_else(|| anyhow::anyhow!("only modules pulled from a registry can be verified"))?;
+    let auth = RegistryAuthResolver::new(client.clone(), pod)
+        .resolve_registry_auth(&image)
+        .await?;
+    verifier.verify(&image, &auth, module_data).await.map(Some)
+}
+
+/// Reads what the container's stdin is fed from, if the pod gives anything. That is either the
+/// value of the stdin annotation, or a key of a config map one of the pod's volumes holds, named
+/// as `<config map>/<key>`
+async fn stdin_data(
+    pod: &Pod,
+    volumes: &HashMap<String, VolumeRef>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let annotations = pod.annotations();
+    let source = match (
+        annotations.get(STDIN_ANNOTATION_KEY),
+        annotations.get(STDIN_CONFIGMAP_ANNOTATION_KEY),
+    ) {
+        (None, None) => return Ok(None),
+        (Some(data), None) => return Ok(Some(data.as_bytes().to_vec())),
+        (None, Some(source)) => source.trim(),
+        (Some(_), Some(_)) => anyhow::bail!(
+            "only one of {} and {} may be given",
+            STDIN_ANNOTATION_KEY,
+            STDIN_CONFIGMAP_ANNOTATION_KEY
+        ),
+    };
+    mounted_config_map_key(volumes, source, "stdin")
+        .await
+        .map(Some)
+}
+
+/// Reads a key of a config map one of the pod's volumes holds, named as `<config map>/<key>`. The
+/// node may only read the config maps its pods refer to, so one that isn't a volume of the pod
+/// can't be read. `purpose` is what the data is for, which errors are reported against
+async fn mounted_config_map_key(
+    volumes: &HashMap<String, VolumeRef>,
+    source: &str,
+    purpose: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let (name, key) = match source.split_once('/') {
+        Some((name, key)) if !name.is_empty() && !key.is_empty() => (name, key),
+        _ => anyhow::bail!(
+            "{} config map {:?} should be given as <config map>/<key>",
+            purpose,
+            source
+        ),
+    };
+    let volume = volumes
+        .values()
+        .find_map(|volume| match volume {
+            VolumeRef::ConfigMap(config_map) if config_map.config_map_name() == name => {
+                Some(config_map)
+            }
+            _ => None,
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "{} config map {} is not the config map of any of the pod's volumes",
+                purpose,
+                name
+            )
+        })?;
+    let path = volume
+        .key_path(key)
+        .ok_or_else(|| anyhow::anyhow!("{} config map {} has no key {}", purpose, name, key))?;
+    match tokio::fs::read(&path).await {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("{} config map {} has no key {}", purpose, name, key)
+        }
+        Err(e) => anyhow::bail!("unable to read {} config map {}: {}", purpose, name, e),
+    }
+}
+
+/// Reads the extra certificate authorities outbound requests are verified against from the key
+/// of a config map the pod names, if it names one. Like the client certificate, the config map
+/// has to be one of the pod's volumes
+async fn http_ca_bundle(
+    pod: &Pod,
+    volumes: &HashMap<String, VolumeRef>,
+) -> anyhow::Result<Option<CaBundle>> {
+    let annotations = pod.annotations();
+    let source = match annotations.get(HTTP_CA_BUNDLE_CONFIGMAP_ANNOTATION_KEY) {
+        Some(source) => source.trim(),
+        None => return Ok(None),
+    };
+    let exclusive = match annotations.get(HTTP_CA_BUNDLE_ONLY_ANNOTATION_KEY) {
+        Some(only) => only.trim().parse().map_err(|e| {
+            anyhow::anyhow!(
+                "Error parsing annotation from key {:?}: {}",
+                HTTP_CA_BUNDLE_ONLY_ANNOTATION_KEY,
+                e
+            )
+        })?,
+        None => false,
+    };
+    let pem = mounted_config_map_key(volumes, source, "CA bundle").await?;
+    CaBundle::from_pem(&pem, exclusive)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("CA bundle {} is invalid: {}", source, e))
+}
+
+/// Reads the client certificate outbound requests are made with from the secret the pod names, if
+/// it names one. The secret is read from the pod's volumes, as the node may only read the secrets
+/// its pods refer to, so it has to be the secret of one of them. It doesn't have to be mounted
+/// into any container
+async fn http_client_cert(
+    pod: &Pod,
+    volumes: &HashMap<String, VolumeRef>,
+) -> anyhow::Result<Option<ClientCert>> {
+    let name = match pod
+        .annotations()
+        .get(HTTP_CLIENT_CERT_SECRET_ANNOTATION_KEY)
+    {
+        Some(name) => name.trim(),
+        None => return Ok(None),
+    };
+    let volume = volumes
+        .values()
+        .find_map(|volume| match volume {
+            VolumeRef::Secret(secret) if secret.secret_name() == name => Some(secret),
+            _ => None,
         })
-        .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "client certificate secret {} is not the secret of any of the pod's volumes",
+                name
+            )
+        })?;
+    let mut data = BTreeMap::new();
+    for key in ClientCert::SECRET_KEYS {
+        let path = match volume.key_path(key) {
+            Some(path) => path,
+            None => continue,
+        };
+        match tokio::fs::read(&path).await {
+            Ok(value) => {
+                data.insert(key.to_string(), ByteString(value));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => anyhow::bail!("unable to read client certificate secret {}: {}", name, e),
+        }
+    }
+    ClientCert::from_secret_data(&data)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("client certificate secret {} is invalid: {}", name, e))
+}
+
+/// Resolves the symlinks in a path that may not exist yet. The deepest of its ancestors that does
+/// exist is canonicalized, and the components after it are added back as they are
+async fn resolve_existing(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    loop {
+        match tokio::fs::canonicalize(existing).await {
+            Ok(resolved) => {
+                return Ok(rest
+                    .into_iter()
+                    .rev()
+                    .fold(resolved, |resolved, c| resolved.join(c)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        rest.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Resolves the directory the pod asked for its logs to be written to, creating it if need be.
+/// The directory must be under one of the allowed roots once symlinks are resolved, so a pod
+/// can't have its logs written over anything else on the node. This is checked before anything is
+/// created as well as after, so a symlink under a root can't have directories created elsewhere
+async fn pod_log_dir(requested: &str, allowed_roots: &[PathBuf]) -> anyhow::Result<PathBuf> {
+    let requested = Path::new(requested.trim());
+    if !requested.is_absolute() {
+        anyhow::bail!("log directory {:?} is not an absolute path", requested);
+    }
+    if requested
+        .components()
+        .any(|c| matches!(c, std::path::Component::ParentDir))
+    {
+        anyhow::bail!("log directory {:?} must not contain '..'", requested);
+    }
+    let root = allowed_roots
+        .iter()
+        .find(|root| requested.starts_with(root))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "log directory {:?} is not under any of the allowed log directories {:?}",
+                requested,
+                allowed_roots
+            )
+        })?;
+    let resolved_root = resolve_existing(root).await?;
+    let resolved = resolve_existing(requested).await?;
+    if !resolved.starts_with(&resolved_root) {
+        anyhow::bail!(
+            "log directory {:?} resolves to {:?}, which is outside {:?}",
+            requested,
+            resolved,
+            root
+        );
+    }
+    tokio::fs::create_dir_all(requested)
+        .await
+        .map_err(|e| anyhow::anyhow!("unable to create log directory {:?}: {}", requested, e))?;
+    let resolved = tokio::fs::canonicalize(requested).await?;
+    if !resolved.starts_with(tokio::fs::canonicalize(root).await?) {
+        anyhow::bail!(
+            "log directory {:?} resolves to {:?}, which is outside {:?}",
+            requested,
+            resolved,
+            root
+        );
+    }
+    Ok(resolved)
+}
+
+/// Gathers the annotations that apply to the container's module. These are the pod's, along with
+/// those its image describes itself with that the pod doesn't set
+pub(super) async fn module_annotations(
+    store: &(dyn Store + Send + Sync),
+    container: &Container,
+    pod: &Pod,
+) -> BTreeMap<String, String> {
+    let mut annotations = pod.annotations().clone();
+    if let Ok(Some(image)) = container.image() {
+        for (key, value) in store.get_annotations(&image).await {
+            if IMAGE_ANNOTATION_KEYS.contains(&key.as_str()) {
+                annotations.entry(key).or_insert(value);
+            }
+        }
+    }
+    annotations
+}
+
+/// Gathers the network annotations that apply to the pod. Each one is taken from the pod if it
+/// gives it, and otherwise, if `namespace_defaults` is set, from the pod's namespace. The node
+/// isn't always allowed to read namespaces, in which case the pod only gets its own annotations
+async fn network_annotations(
+    client: &kube::Client,
+    pod: &Pod,
+    namespace_defaults: bool,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut annotations: BTreeMap<String, String> = pod
+        .annotations()
+        .iter()
+        .filter(|(key, _)| NETWORK_ANNOTATION_KEYS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    if !namespace_defaults || annotations.len() == NETWORK_ANNOTATION_KEYS.len() {
+        return Ok(annotations);
+    }
+    let namespace = match Api::<Namespace>::all(client.clone())
+        .get(pod.namespace())
+        .await
+    {
+        Ok(namespace) => namespace,
+        Err(kube::Error::Api(ErrorResponse { code: 403, .. })) => {
+            warn!(
+                namespace = %pod.namespace(),
+                "Node isn't allowed to read namespaces, so pod gets no namespace network defaults"
+            );
+            return Ok(annotations);
+        }
+        Err(e) => anyhow::bail!("unable to fetch namespace {}: {}", pod.namespace(), e),
+    };
+    for (key, value) in namespace.metadata.annotations {
+        if NETWORK_ANNOTATION_KEYS.contains(&key.as_str()) {
+            annotations.entry(key).or_insert(value);
+        }
+    }
+    Ok(annotations)
 }
 
-/// The container is starting.
+/// How many times to wait for the pod's modules to be loaded before failing the container
+const MAX_MODULE_LOAD_ATTEMPTS: u32 = 5;
+/// How long to wait for the pod's modules to be loaded the first time. The wait doubles with
+/// each attempt
+const MODULE_LOAD_BACKOFF: Duration = Duration::from_millis(200);
+
+/// The container is starting, after backing off first if it is being restarted.
 #[derive(Default, Debug, TransitionTo)]
-#[transition_to(Running, Terminated)]
-pub struct Waiting;
+#[transition_to(Running, Terminated, Waiting)]
+pub struct Waiting {
+    /// When to restart the container, if it is backing off after exiting
+    retry_at: Option<DateTime<Utc>>,
+    /// When to look for the pod's modules again, if they weren't loaded yet
+    modules_due_at: Option<DateTime<Utc>>,
+    /// How many times the container has already waited for the pod's modules to be loaded
+    module_load_attempts: u32,
+}
+
+impl Waiting {
+    /// Creates a state that restarts the container once the given backoff has elapsed
+    pub fn restart(backoff: std::time::Duration) -> Self {
+        let backoff =
+            chrono::Duration::from_std(backoff).unwrap_or_else(|_| chrono::Duration::zero());
+        Waiting {
+            retry_at: Some(Utc::now() + backoff),
+            modules_due_at: None,
+            module_load_attempts: 0,
+        }
+    }
+
+    /// Creates a state that tries again to start the container once the pod's modules have had
+    /// some more time to load, or `None` if the container has waited long enough
+    fn wait_for_modules(&self) -> Option<Self> {
+        if self.module_load_attempts >= MAX_MODULE_LOAD_ATTEMPTS {
+            return None;
+        }
+        let backoff = MODULE_LOAD_BACKOFF * 2u32.pow(self.module_load_attempts);
+        Some(Waiting {
+            retry_at: None,
+            modules_due_at: Some(
+                Utc::now()
+                    + chrono::Duration::from_std(backoff)
+                        .unwrap_or_else(|_| chrono::Duration::zero()),
+            ),
+            module_load_attempts: self.module_load_attempts + 1,
+        })
+    }
+}
 
 #[async_trait::async_trait]
 impl State<ContainerState> for Waiting {
     #[instrument(
         level = "info",
         skip(self, shared, state, container),
-        fields(pod_name = state.pod.name(), container_name)
+        fields(
+            pod_namespace = state.pod.namespace(),
+            pod_name = state.pod.name(),
+            container_name,
+            module_digest
+        )
     )]
     async fn next(
         self: Box<Self>,
@@ -74,61 +769,330 @@ impl State<ContainerState> for Waiting {
 
         tracing::Span::current().record("container_name", &container.name());
 
+        if let Some(retry_at) = self.retry_at {
+            info!(
+                %retry_at,
+                restart_count = state.restart_count,
+                "Backing off before starting container"
+            );
+            if let Ok(backoff) = (retry_at - Utc::now()).to_std() {
+                tokio::time::sleep(backoff).await;
+            }
+        }
+        if let Some(modules_due_at) = self.modules_due_at {
+            if let Ok(wait) = (modules_due_at - Utc::now()).to_std() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        // The pod may have run past its active deadline while the container waited to be
+        // (re)started, after its containers were stopped for it
+        if state.run_context.read().await.deadline_exceeded {
+            return Transition::next(self, Terminated::deadline_exceeded());
+        }
+
         info!("Starting container for pod");
 
-        let (client, log_path) = {
+        let (
+            client,
+            log_path,
+            log_rotation,
+            log_buffer_size,
+            json_logs,
+            compile_cache,
+            compile_permits,
+            engines,
+            max_module_size,
+            max_preopened_dirs,
+            default_allowed_domains,
+            namespace_network_defaults,
+            allowed_log_dirs,
+            host_functions,
+            wasi_nn_backends,
+            container_start_timeout,
+            signature_verifier,
+            cluster_dns,
+            staging_dir,
+        ) = {
             let provider_state = shared.read().await;
-            (provider_state.client(), provider_state.log_path.clone())
+            (
+                provider_state.client(),
+                provider_state.log_path.clone(),
+                provider_state.log_rotation,
+                provider_state.log_buffer_size,
+                provider_state.json_logs,
+                provider_state.compile_cache.clone(),
+                provider_state.compile_permits.clone(),
+                provider_state.engines.clone(),
+                provider_state.max_module_size,
+                provider_state.max_preopened_dirs,
+                provider_state.default_allowed_domains.clone(),
+                provider_state.namespace_network_defaults,
+                provider_state.allowed_log_dirs.clone(),
+                provider_state.host_functions.clone(),
+                provider_state.wasi_nn_backends.clone(),
+                provider_state.container_start_timeout,
+                provider_state.signature_verifier.clone(),
+                provider_state.cluster_dns.clone(),
+                provider_state
+                    .volume_path
+                    .join(SUB_PATH_DIR)
+                    .join(staging_dir_name(&state.pod, &container)),
+            )
         };
 
-        let (module_data, container_volumes, container_envs) = {
-            let mut run_context = state.run_context.write().await;
-            let module_data = match run_context.modules.remove(container.name()) {
-                Some(data) => data,
-                None => {
+        let log_path = match state.pod.annotations().get(LOG_DIR_ANNOTATION_KEY) {
+            Some(requested) => match pod_log_dir(requested, &allowed_log_dirs).await {
+                Ok(dir) => dir,
+                Err(e) => {
                     return Transition::next(
                         self,
                         Terminated::new(
                             format!(
-                                "Pod {} container {} failed load module data from run context.",
-                                state.pod.name(),
-                                container.name(),
+                                "Error parsing annotation from key {:?}: {:#}",
+                                LOG_DIR_ANNOTATION_KEY, e
                             ),
                             true,
                         ),
                     );
                 }
-            };
-            let container_volumes = match volume_path_map(&container, &run_context.volumes) {
-                Ok(volumes) => volumes,
+            },
+            None => log_path,
+        };
+
+        if pulls_module_again(&container, state.restart_count, self.module_load_attempts) {
+            let store = shared.read().await.store();
+            let auth_resolver = RegistryAuthResolver::new(client.clone(), &state.pod);
+            match store
+                .fetch_container_module(&container, &auth_resolver)
+                .await
+            {
+                Ok(module) => {
+                    state
+                        .run_context
+                        .write()
+                        .await
+                        .modules
+                        .insert(container.name().to_owned(), module);
+                }
                 Err(e) => {
                     return Transition::next(
                         self,
                         Terminated::new(
                             format!(
-                                "Pod {} container {} failed to map volume paths: {:?}",
+                                "Pod {} container {} failed to pull its module again: {}",
                                 state.pod.name(),
                                 container.name(),
                                 e
                             ),
                             true,
+                        )
+                        .with_reason(Some("ErrImagePull".to_owned())),
+                    );
+                }
+            }
+        }
+
+        let (module_data, container_volumes, container_envs, termination_log, image_data_digest) = {
+            let mut run_context = state.run_context.write().await;
+            // Module data is left in the run context so the container can be restarted
+            let module_data = match run_context.modules.get(container.name()).cloned() {
+                Some(data) => data,
+                None => {
+                    // Nothing has been loaded for the pod yet, so its modules are most likely
+                    // still being put in the run context rather than missing
+                    if run_context.modules.is_empty() {
+                        if let Some(next) = self.wait_for_modules() {
+                            info!(
+                                attempt = next.module_load_attempts,
+                                "Module data not loaded yet, waiting before starting container"
+                            );
+                            return Transition::next(self, next);
+                        }
+                    }
+                    let e = ContainerError::ModuleMissing {
+                        container: container.name().to_owned(),
+                    };
+                    return Transition::next(
+                        self,
+                        Terminated::from_error(format!("Pod {} {}", state.pod.name(), e), &e),
+                    );
+                }
+            };
+            run_context.sub_path_dirs.insert(staging_dir.clone());
+            let mut container_volumes =
+                match volume_path_map(&container, &run_context.volumes, &staging_dir).await {
+                    Ok(volumes) => volumes,
+                    Err(e) => {
+                        return Transition::next(
+                            self,
+                            Terminated::from_error(
+                                format!(
+                                    "Pod {} container {} failed to map volume paths: {}",
+                                    state.pod.name(),
+                                    container.name(),
+                                    e
+                                ),
+                                &e,
+                            ),
+                        )
+                    }
+                };
+            let mut image_data_digest = None;
+            if let Ok(Some(image)) = container.image() {
+                let store = shared.read().await.store();
+                if let Some((digest, data_dir)) = store.get_data_path(&image).await {
+                    container_volumes
+                        .entry(data_dir)
+                        .or_insert((Some(PathBuf::from(IMAGE_DATA_PATH)), true));
+                    image_data_digest = Some(digest);
+                }
+            }
+            let termination_log = match termination::termination_log(
+                &container,
+                &mut container_volumes,
+                &staging_dir,
+            )
+            .await
+            {
+                Ok(path) => path,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::from_error(
+                            format!(
+                                "Pod {} container {} failed to create its termination log: {}",
+                                state.pod.name(),
+                                container.name(),
+                                e
+                            ),
+                            &e,
                         ),
                     )
                 }
             };
+            if let Err(e) = check_preopened_dirs(&container_volumes, max_preopened_dirs) {
+                return Transition::next(
+                    self,
+                    Terminated::from_error(
+                        format!(
+                            "Pod {} container {} {}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        &e,
+                    ),
+                );
+            }
             (
                 module_data,
                 container_volumes,
                 run_context
                     .env_vars
-                    .remove(container.name())
+                    .get(container.name())
+                    .cloned()
                     .unwrap_or_default(),
+                termination_log,
+                image_data_digest,
             )
         };
 
-        let mut env = kubelet::provider::env_vars(&container, &state.pod, &client).await;
+        tracing::Span::current().record("module_digest", &module_digest(&module_data).as_str());
+
+        // Modules from the cache or a local file haven't been checked while they were fetched
+        if module_data.len() as u64 > max_module_size {
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} module is {} bytes, which is larger than the maximum module size of {} bytes",
+                        state.pod.name(),
+                        container.name(),
+                        module_data.len(),
+                        max_module_size
+                    ),
+                    true,
+                ),
+            );
+        }
+
+        match verify_module_signature(
+            &signature_verifier,
+            &client,
+            &state.pod,
+            &container,
+            &module_data,
+        )
+        .await
+        {
+            Ok(Some(signed_digest)) => {
+                // The image's data files are mounted for the module, so they have to come from
+                // the signed manifest too, not from some other version of the image
+                if let Some(data_digest) = image_data_digest.filter(|d| *d != signed_digest) {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Pod {} container {} failed module signature verification: data files are from manifest {}, not the signed manifest {}",
+                                state.pod.name(),
+                                container.name(),
+                                data_digest,
+                                signed_digest
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+            Ok(None) => (),
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed module signature verification: {:#}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                );
+            }
+        }
+
+        let mut env = match kubelet::provider::env_vars(&container, &state.pod, &client).await {
+            Ok(env) => env,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} failed to resolve environment variables: {:#}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                );
+            }
+        };
         env.extend(container_envs);
-        let args = container.args().clone();
+        // Keep variables the pod doesn't want modules to see from reaching them
+        let store = shared.read().await.store();
+        let annotations = module_annotations(store.as_ref(), &container, &state.pod).await;
+        EnvFilter::new(
+            annotations
+                .get(ENV_ALLOW_LIST_ANNOTATION_KEY)
+                .map(String::as_str),
+            annotations
+                .get(ENV_DENY_LIST_ANNOTATION_KEY)
+                .map(String::as_str),
+        )
+        .apply(&mut env);
+        // Variables the module can't see aren't expanded into its arguments either
+        let args = command_line(&container, &env);
 
         // TODO: ~magic~ number
         let (tx, rx) = mpsc::channel(8);
@@ -140,14 +1104,40 @@ impl State<ContainerState> for Waiting {
             container.name()
         );
 
+        let log_format = if json_logs {
+            LogFormat::Json {
+                namespace: state.pod.namespace().to_owned(),
+                pod: state.pod.name().to_owned(),
+                container: container.name().to_owned(),
+            }
+        } else {
+            LogFormat::Raw
+        };
+
+        // Policy the pod doesn't set itself falls back to its namespace's if the node is set up to
+        // read it. If the namespace can't be read for any reason other than the node not being
+        // allowed to, the container isn't started rather than given more access
+        let network_annotations =
+            match network_annotations(&client, &state.pod, namespace_network_defaults).await {
+                Ok(annotations) => annotations,
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(format!("Error reading network policy: {:#}", e), true),
+                    );
+                }
+            };
+
         let mut wasi_http_config = WasiHttpConfig::default();
-        let annotations = state.pod.annotations();
 
-        // Parse allowed domains from annotation key
-        if let Some(annotation) = annotations.get(ALLOWED_DOMAINS_ANNOTATION_KEY) {
+        // Parse allowed domains from annotation key. Entries may be domains
+        // or CIDR ranges
+        if let Some(annotation) = network_annotations.get(ALLOWED_DOMAINS_ANNOTATION_KEY) {
             match serde_json::from_str(&annotation) {
-                Ok(allowed_domains) => {
+                Ok(entries) => {
+                    let (allowed_domains, allowed_networks) = split_allow_list(entries);
                     wasi_http_config.allowed_domains = Some(allowed_domains);
+                    wasi_http_config.allowed_networks = allowed_networks;
                 }
                 Err(parse_err) => {
                     return Transition::next(
@@ -162,10 +1152,58 @@ impl State<ContainerState> for Waiting {
                     );
                 }
             }
+        } else if !default_allowed_domains.is_empty() {
+            // Neither the pod nor its namespace say, so the node's default applies
+            let (allowed_domains, allowed_networks) = split_allow_list(default_allowed_domains);
+            wasi_http_config.allowed_domains = Some(allowed_domains);
+            wasi_http_config.allowed_networks = allowed_networks;
+        }
+
+        // Parse the host:port pairs raw connections may be opened to from annotation key
+        let allowed_endpoints = match network_annotations.get(ALLOWED_SOCKETS_ANNOTATION_KEY) {
+            None => Vec::new(),
+            Some(annotation) => {
+                let endpoints = serde_json::from_str::<Vec<String>>(annotation)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|entries| {
+                        entries
+                            .iter()
+                            .map(|e| e.parse::<Endpoint>())
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    });
+                match endpoints {
+                    Ok(endpoints) => endpoints,
+                    Err(parse_err) => {
+                        return Transition::next(
+                            self,
+                            Terminated::new(
+                                format!(
+                                    "Error parsing annotation from key {:?}: {}",
+                                    ALLOWED_SOCKETS_ANNOTATION_KEY, parse_err,
+                                ),
+                                true,
+                            ),
+                        );
+                    }
+                }
+            }
+        };
+
+        match ResolverConfig::for_pod(&state.pod, &cluster_dns, ResolverConfig::from_host) {
+            Ok(resolver) => wasi_http_config.resolver = resolver,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!("Pod {} has invalid DNS settings: {}", state.pod.name(), e),
+                        true,
+                    ),
+                );
+            }
         }
 
         // Parse allowed domains from annotation key
-        if let Some(annotation) = annotations.get(MAX_CONNCURRENT_REQUESTS_ANNOTATION_KEY) {
+        if let Some(annotation) = network_annotations.get(MAX_CONNCURRENT_REQUESTS_ANNOTATION_KEY) {
             match annotation.parse() {
                 Ok(max_concurrent_requests) => {
                     wasi_http_config.max_concurrent_requests = Some(max_concurrent_requests);
@@ -185,26 +1223,296 @@ impl State<ContainerState> for Waiting {
             }
         }
 
-        // TODO: decide how/what it means to propagate annotations (from run_context) into WASM modules.
-        let runtime = match WasiRuntime::new(
-            name,
-            module_data,
-            env,
-            args,
-            container_volumes,
-            log_path,
-            tx,
-            wasi_http_config,
-        )
-        .await
-        {
-            Ok(runtime) => runtime,
+        // Parse the outbound request timeout, in milliseconds, from annotation key
+        if let Some(annotation) = network_annotations.get(HTTP_REQUEST_TIMEOUT_ANNOTATION_KEY) {
+            match annotation.parse::<u64>() {
+                Ok(millis) if millis > 0 => {
+                    wasi_http_config.request_timeout = Some(Duration::from_millis(millis));
+                }
+                result => {
+                    let parse_err = match result {
+                        Err(e) => e.to_string(),
+                        Ok(_) => "timeout must be greater than zero".to_string(),
+                    };
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                HTTP_REQUEST_TIMEOUT_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Parse the outbound request retry policy from annotation keys. Requests are only
+        // retried if the pod asks for it
+        match http_retry_policy(&network_annotations) {
+            Ok(retry) => wasi_http_config.retry = retry,
+            Err((key, parse_err)) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!("Error parsing annotation from key {:?}: {}", key, parse_err),
+                        true,
+                    ),
+                );
+            }
+        }
+
+        // Fetch the certificate requests identify the module with, for servers that require
+        // mutual TLS
+        let client_cert = {
+            let run_context = state.run_context.read().await;
+            http_client_cert(&state.pod, &run_context.volumes).await
+        };
+        match client_cert {
+            Ok(client_cert) => wasi_http_config.client_cert = client_cert,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} {:#}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                );
+            }
+        }
+
+        // Fetch the certificate authorities servers are verified against, for servers with
+        // certificates from a private CA
+        let ca_bundle = {
+            let run_context = state.run_context.read().await;
+            http_ca_bundle(&state.pod, &run_context.volumes).await
+        };
+        match ca_bundle {
+            Ok(ca_bundle) => wasi_http_config.ca_bundle = ca_bundle,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} {:#}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                );
+            }
+        }
+
+        let mut wasi_exec_config = WasiExecConfig::default();
+
+        // Parse max fuel from annotation key
+        if let Some(annotation) = annotations.get(MAX_FUEL_ANNOTATION_KEY) {
+            match annotation.parse() {
+                Ok(fuel) => {
+                    wasi_exec_config.fuel = Some(fuel);
+                }
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                MAX_FUEL_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Call a function other than `_start` if the annotation names one
+        if let Some(annotation) = annotations.get(ENTRYPOINT_ANNOTATION_KEY) {
+            let entrypoint = annotation.trim();
+            if entrypoint.is_empty() {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Error parsing annotation from key {:?}: entrypoint must not be empty",
+                            ENTRYPOINT_ANNOTATION_KEY,
+                        ),
+                        true,
+                    ),
+                );
+            }
+            wasi_exec_config.entrypoint = Some(entrypoint.to_owned());
+        }
+
+        // Take away the WASI capabilities the annotation lists
+        if let Some(annotation) = annotations.get(DISABLED_CAPABILITIES_ANNOTATION_KEY) {
+            match WasiCapabilities::disabling(annotation) {
+                Ok(capabilities) => {
+                    wasi_exec_config.capabilities = capabilities;
+                }
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                DISABLED_CAPABILITIES_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Compile the module with the features the annotation turns on or off, failing now rather
+        // than when the module is compiled if the node can't give it one it asks for
+        match WasmFeatures::from_annotation(
+            annotations
+                .get(WASM_FEATURES_ANNOTATION_KEY)
+                .map(String::as_str),
+            engines.disabled_features(),
+        ) {
+            Ok(features) => {
+                wasi_exec_config.features = features;
+            }
+            Err(e) => {
+                // Features the node has disabled are refused here along with unknown ones
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Unable to apply annotation from key {:?}: {}",
+                            WASM_FEATURES_ANNOTATION_KEY, e,
+                        ),
+                        true,
+                    ),
+                );
+            }
+        }
+
+        // Modules that want more than one thread need threads, which the node may have disabled
+        if let Some(annotation) = annotations.get(MAX_THREADS_ANNOTATION_KEY) {
+            let max_threads = match parse_max_threads(annotation) {
+                Ok(max_threads) => max_threads,
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                MAX_THREADS_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            };
+            match wasi_exec_config
+                .features
+                .with_max_threads(max_threads, engines.disabled_features())
+            {
+                Ok(features) => {
+                    wasi_exec_config.features = features;
+                    wasi_exec_config.max_threads = Some(max_threads);
+                }
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Unable to apply annotation from key {:?}: {}",
+                                MAX_THREADS_ANNOTATION_KEY, e,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Give the module wasi-nn if the pod picks one of the node's backends for it
+        if let Some(annotation) = annotations.get(WASI_NN_BACKEND_ANNOTATION_KEY) {
+            match select_backend(annotation, &wasi_nn_backends) {
+                Ok(backend) => {
+                    wasi_exec_config.nn_backend = Some(backend);
+                }
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                WASI_NN_BACKEND_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Count the module's host calls if the pod wants to know which it uses
+        if let Some(annotation) = annotations.get(AUDIT_HOST_CALLS_ANNOTATION_KEY) {
+            match annotation.trim().parse() {
+                Ok(audit_calls) => {
+                    wasi_exec_config.audit_calls = audit_calls;
+                }
+                Err(parse_err) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                AUDIT_HOST_CALLS_ANNOTATION_KEY, parse_err,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        // Bound the module's memory by the container's memory limit
+        match container.memory_limit() {
+            Ok(memory_limit) => {
+                wasi_exec_config.memory_limit = memory_limit;
+            }
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid memory limit: {:?}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        true,
+                    ),
+                )
+            }
+        }
+
+        // Approximate the container's CPU limit by pausing the module between slices of work
+        match container.cpu_limit() {
+            Ok(cpu_limit) => {
+                wasi_exec_config.cpu_limit = cpu_limit;
+            }
             Err(e) => {
                 return Transition::next(
                     self,
                     Terminated::new(
                         format!(
-                            "Pod {} container {} failed to construct runtime: {:?}",
+                            "Pod {} container {} has an invalid CPU limit: {:?}",
                             state.pod.name(),
                             container.name(),
                             e
@@ -213,16 +1521,81 @@ impl State<ContainerState> for Waiting {
                     ),
                 )
             }
+        }
+
+        // Throttled modules can't be audited, so refuse to run them rather than drop the audit
+        if wasi_exec_config.audit_calls && wasi_exec_config.throttle().is_some() {
+            return Transition::next(
+                self,
+                Terminated::new(
+                    format!(
+                        "Pod {} container {} asks for its host calls to be audited, which can't be done for modules with a CPU limit below 1000m",
+                        state.pod.name(),
+                        container.name(),
+                    ),
+                    true,
+                ),
+            );
+        }
+
+        // Access files as the user and group the container runs as
+        let (run_as_user, run_as_group) = state.pod.run_as(&container);
+        match (
+            run_as_user.map(u32::try_from).transpose(),
+            run_as_group.map(u32::try_from).transpose(),
+        ) {
+            (Ok(user), Ok(group)) => {
+                wasi_exec_config.run_as_user = user;
+                wasi_exec_config.run_as_group = group;
+            }
+            _ => {
+                return Transition::next(
+                    self,
+                    Terminated::new(
+                        format!(
+                            "Pod {} container {} has an invalid user or group to run as",
+                            state.pod.name(),
+                            container.name(),
+                        ),
+                        true,
+                    ),
+                )
+            }
+        }
+
+        // Open relative paths in the directory the annotation names
+        if let Some(annotation) = annotations.get(WORKING_DIR_ANNOTATION_KEY) {
+            match working_dir(annotation, &container_volumes).await {
+                Ok(dir) => {
+                    wasi_exec_config.working_dir = Some(dir);
+                }
+                Err(e) => {
+                    return Transition::next(
+                        self,
+                        Terminated::new(
+                            format!(
+                                "Error parsing annotation from key {:?}: {}",
+                                WORKING_DIR_ANNOTATION_KEY, e,
+                            ),
+                            true,
+                        ),
+                    );
+                }
+            }
+        }
+
+        let stdin = {
+            let run_context = state.run_context.read().await;
+            stdin_data(&state.pod, &run_context.volumes).await
         };
-        debug!("Starting container on thread");
-        let container_handle = match runtime.start().await {
-            Ok(handle) => handle,
+        let stdin = match stdin {
+            Ok(stdin) => stdin,
             Err(e) => {
                 return Transition::next(
                     self,
                     Terminated::new(
                         format!(
-                            "Pod {} container {} failed to start: {:?}",
+                            "Pod {} container {} has no stdin to read: {}",
                             state.pod.name(),
                             container.name(),
                             e
@@ -232,26 +1605,888 @@ impl State<ContainerState> for Waiting {
                 )
             }
         };
+
+        // TODO: decide how/what it means to propagate annotations (from run_context) into WASM modules.
+        // Setting up and starting the runtime can hang, such as on a slow filesystem, so the
+        // container is failed if they don't finish in time rather than left waiting forever
+        let runtime = tokio::time::timeout(
+            container_start_timeout,
+            WasiRuntime::new(
+                name,
+                module_data,
+                env,
+                args,
+                container_volumes,
+                log_path,
+                tx,
+                wasi_http_config,
+                wasi_exec_config,
+            ),
+        )
+        .await
+        .map_err(|_| ContainerError::StartTimedOut(container_start_timeout))
+        .and_then(|runtime| runtime.map_err(ContainerError::RuntimeInit));
+        let runtime = match runtime {
+            Ok(runtime) => runtime
+                .with_pre_stop(LifecycleHook::pre_stop(
+                    &container,
+                    state.pod.pod_ip().unwrap_or(DEFAULT_PROBE_HOST),
+                ))
+                .with_allowed_endpoints(allowed_endpoints)
+                .with_stdin(stdin)
+                .with_log_rotation(Some(log_rotation))
+                .with_log_buffer_size(log_buffer_size)
+                .with_log_format(log_format)
+                .with_compile_cache(Some(compile_cache))
+                .with_compile_permits(Some(compile_permits))
+                .with_engines(Some(engines))
+                .with_host_functions(Some(host_functions))
+                .with_start_timeout(Some(container_start_timeout)),
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::from_error(
+                        format!(
+                            "Pod {} container {} {}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        &e,
+                    ),
+                );
+            }
+        };
+        // Setting up the runtime can take a while, so check the deadline again before starting it
+        if state.run_context.read().await.deadline_exceeded {
+            return Transition::next(self, Terminated::deadline_exceeded());
+        }
+        debug!("Starting container on thread");
+        // The runtime fails its own start if it times out, which it only starts timing once the
+        // module may be compiled, so the wait for other modules to finish compiling isn't counted
+        let container_handle =
+            runtime
+                .start()
+                .await
+                .map_err(|e| match e.downcast::<ContainerError>() {
+                    Ok(e) => e,
+                    Err(e) => ContainerError::RuntimeStart(e),
+                });
+        let container_handle = match container_handle {
+            Ok(handle) => handle,
+            Err(e) => {
+                return Transition::next(
+                    self,
+                    Terminated::from_error(
+                        format!(
+                            "Pod {} container {} {}",
+                            state.pod.name(),
+                            container.name(),
+                            e
+                        ),
+                        &e,
+                    ),
+                );
+            }
+        };
         debug!("WASI Runtime started for container");
+        kubelet::metrics::CONTAINERS_STARTED.inc();
         let pod_key = PodKey::from(&state.pod);
-        {
+        let pod_handle = {
             let provider_state = shared.write().await;
             let mut handles_writer = provider_state.handles.write().await;
-            let pod_handle = handles_writer
-                .entry(pod_key)
-                .or_insert_with(|| Arc::new(PodHandle::new(HashMap::new(), state.pod.clone())));
-            pod_handle
-                .insert_container_handle(state.container_key.clone(), container_handle)
-                .await;
+            Arc::clone(
+                handles_writer
+                    .entry(pod_key)
+                    .or_insert_with(|| Arc::new(PodHandle::new(HashMap::new(), state.pod.clone()))),
+            )
+        };
+        pod_handle
+            .insert_container_handle(state.container_key.clone(), container_handle)
+            .await;
+        // If the deadline passed while the container started, its handle wasn't there to be
+        // stopped, so stop it now. It is reported as stopped for the deadline once it exits
+        if state.run_context.read().await.deadline_exceeded {
+            if let Err(e) = pod_handle.stop_container(&state.container_key).await {
+                warn!(error = %e, "Unable to stop container started past the pod's deadline");
+            }
         }
-        Transition::next(self, Running::new(rx))
+        Transition::next(self, Running::new(rx, runtime, termination_log))
     }
 
     async fn status(
         &self,
-        _state: &mut ContainerState,
+        state: &mut ContainerState,
         _container: &Container,
     ) -> anyhow::Result<Status> {
-        Ok(Status::waiting("Module is starting."))
+        match self.retry_at {
+            Some(retry_at) => Ok(Status::waiting_with_reason(
+                "CrashLoopBackOff",
+                &format!(
+                    "Back-off restarting container, next retry at {} (restart count {}).",
+                    retry_at.to_rfc3339(),
+                    state.restart_count
+                ),
+            )),
+            // The container hasn't failed, its module just isn't there yet
+            None if self.modules_due_at.is_some() => Ok(Status::waiting_with_reason(
+                "ContainerCreating",
+                "Waiting for the container's module to be loaded.",
+            )),
+            None => Ok(Status::waiting("Module is starting.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_http_client_cert_must_be_a_pod_volume() {
+        let pod = annotated_pod;
+        let volumes = HashMap::new();
+        assert!(http_client_cert(&pod(serde_json::json!({})), &volumes)
+            .await
+            .unwrap()
+            .is_none());
+
+        // The node can't read secrets none of its pods refer to
+        let annotations =
+            serde_json::json!({ HTTP_CLIENT_CERT_SECRET_ANNOTATION_KEY: "client-cert" });
+        let err = http_client_cert(&pod(annotations), &volumes)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("client-cert"), "{}", err);
+    }
+
+    /// A client for an API server that answers its one request with the given object
+    async fn serve_once(object: serde_json::Value) -> kube::Client {
+        serve_once_with_status("200 OK", object).await
+    }
+
+    /// A client for an API server that answers its one request with the given status and object
+    async fn serve_once_with_status(
+        status: &'static str,
+        object: serde_json::Value,
+    ) -> kube::Client {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = conn.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = object.to_string();
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            conn.write_all(response.as_bytes()).await.unwrap();
+        });
+        let url = format!("http://{}", addr).parse().unwrap();
+        kube::Client::try_from(kube::Config::new(url)).unwrap()
+    }
+
+    fn annotated_pod(annotations: serde_json::Value) -> Pod {
+        Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {"name": "app", "namespace": "default", "annotations": annotations},
+                "spec": {"containers": []}
+            }))
+            .unwrap(),
+        )
+    }
+
+    /// A store whose images are all annotated with the same annotations
+    struct AnnotatedImages(HashMap<String, String>);
+
+    #[async_trait::async_trait]
+    impl Store for AnnotatedImages {
+        async fn get(
+            &self,
+            _image_ref: &oci_distribution::Reference,
+            _pull_policy: PullPolicy,
+            _auth: &oci_distribution::secrets::RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_annotations(
+            &self,
+            _image_ref: &oci_distribution::Reference,
+        ) -> HashMap<String, String> {
+            self.0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_module_annotations() {
+        let store = AnnotatedImages(
+            vec![
+                (ENTRYPOINT_ANNOTATION_KEY, "image_start"),
+                (WASM_FEATURES_ANNOTATION_KEY, "threads"),
+                (MAX_THREADS_ANNOTATION_KEY, "4"),
+                (WASI_NN_BACKEND_ANNOTATION_KEY, "openvino"),
+                (ALLOWED_DOMAINS_ANNOTATION_KEY, "*"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+        );
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {
+                    "name": "app",
+                    "namespace": "default",
+                    "annotations": {
+                        ENTRYPOINT_ANNOTATION_KEY: "pod_start",
+                        "example.com/other": "kept"
+                    }
+                },
+                "spec": {"containers": [{"name": "app", "image": "example.com/app:1.0"}]}
+            }))
+            .unwrap(),
+        );
+        let container = pod.containers().remove(0);
+        let annotations = module_annotations(&store, &container, &pod).await;
+        let get = |key: &str| annotations.get(key).map(String::as_str);
+        // The pod's own annotations win over the image's
+        assert_eq!(get(ENTRYPOINT_ANNOTATION_KEY), Some("pod_start"));
+        assert_eq!(get("example.com/other"), Some("kept"));
+        assert_eq!(get(WASM_FEATURES_ANNOTATION_KEY), Some("threads"));
+        assert_eq!(get(MAX_THREADS_ANNOTATION_KEY), Some("4"));
+        // Images can't grant their modules access to anything
+        assert_eq!(get(WASI_NN_BACKEND_ANNOTATION_KEY), None);
+        assert_eq!(get(ALLOWED_DOMAINS_ANNOTATION_KEY), None);
+
+        // Modules fetched from a URL have no image to describe them
+        let pod = Pod::from(
+            serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                "metadata": {"name": "app", "namespace": "default"},
+                "spec": {"containers": [{"name": "app", "image": "https://example.com/app.wasm"}]}
+            }))
+            .unwrap(),
+        );
+        let container = pod.containers().remove(0);
+        assert!(module_annotations(&store, &container, &pod)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stdin_data() {
+        let volumes = HashMap::new();
+        let stdin = |annotations| {
+            let volumes = &volumes;
+            async move { stdin_data(&annotated_pod(annotations), volumes).await }
+        };
+        assert_eq!(None, stdin(serde_json::json!({})).await.unwrap());
+        assert_eq!(
+            Some(b"hello".to_vec()),
+            stdin(serde_json::json!({ STDIN_ANNOTATION_KEY: "hello" }))
+                .await
+                .unwrap()
+        );
+        assert!(stdin(serde_json::json!({
+            STDIN_ANNOTATION_KEY: "hello",
+            STDIN_CONFIGMAP_ANNOTATION_KEY: "input/data",
+        }))
+        .await
+        .is_err());
+        // The node can't read config maps none of its pods refer to
+        let e = stdin(serde_json::json!({ STDIN_CONFIGMAP_ANNOTATION_KEY: "input/data" }))
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string()
+                .contains("is not the config map of any of the pod's volumes"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stdin_data_from_config_map_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = serve_once(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "input", "namespace": "default"},
+            "data": {"data": "from the config map"},
+            "binaryData": {"binary": "AAEC"}
+        }))
+        .await;
+        let volume: k8s_openapi::api::core::v1::Volume =
+            serde_json::from_value(serde_json::json!({
+                "name": "stdin",
+                "configMap": {"name": "input"}
+            }))
+            .unwrap();
+        let mut volume = VolumeRef::ConfigMap(
+            kubelet::volume::ConfigMapVolume::new(&volume, "default", client).unwrap(),
+        );
+        volume.mount(dir.path()).await.unwrap();
+        let volumes = vec![("stdin".to_owned(), volume)].into_iter().collect();
+
+        let stdin = |source: &str| {
+            let pod = annotated_pod(serde_json::json!({
+                STDIN_CONFIGMAP_ANNOTATION_KEY: source
+            }));
+            let volumes = &volumes;
+            async move { stdin_data(&pod, volumes).await }
+        };
+        assert_eq!(
+            Some(b"from the config map".to_vec()),
+            stdin("input/data").await.unwrap()
+        );
+        assert_eq!(Some(vec![0, 1, 2]), stdin("input/binary").await.unwrap());
+        let e = stdin("input/other").await.err().unwrap();
+        assert!(e.to_string().contains("has no key other"), "{}", e);
+        assert!(stdin("input").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_ca_bundle_must_be_a_pod_volume() {
+        let pod = annotated_pod;
+        let volumes = HashMap::new();
+        assert!(http_ca_bundle(&pod(serde_json::json!({})), &volumes)
+            .await
+            .unwrap()
+            .is_none());
+
+        let err = |annotations| async {
+            http_ca_bundle(&pod(annotations), &volumes)
+                .await
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        let e =
+            err(serde_json::json!({ HTTP_CA_BUNDLE_CONFIGMAP_ANNOTATION_KEY: "ca-bundle" })).await;
+        assert!(e.contains("<config map>/<key>"), "{}", e);
+        let e = err(serde_json::json!({
+            HTTP_CA_BUNDLE_CONFIGMAP_ANNOTATION_KEY: "ca-bundle/ca.crt",
+            HTTP_CA_BUNDLE_ONLY_ANNOTATION_KEY: "yes",
+        }))
+        .await;
+        assert!(e.contains(HTTP_CA_BUNDLE_ONLY_ANNOTATION_KEY), "{}", e);
+        let e =
+            err(serde_json::json!({ HTTP_CA_BUNDLE_CONFIGMAP_ANNOTATION_KEY: "ca-bundle/ca.crt" }))
+                .await;
+        assert!(
+            e.contains("is not the config map of any of the pod's volumes"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_staging_dir_name() {
+        let pod = |namespace: &str, name: &str, uid: &str| {
+            Pod::from(
+                serde_json::from_value::<k8s_openapi::api::core::v1::Pod>(serde_json::json!({
+                    "metadata": {"name": name, "namespace": namespace, "uid": uid},
+                    "spec": {"containers": []}
+                }))
+                .unwrap(),
+            )
+        };
+        let container = |name: &str| {
+            Container::new(&serde_json::from_value(serde_json::json!({ "name": name })).unwrap())
+        };
+        // These would once have shared `a-b-c-d`
+        let first = staging_dir_name(
+            &pod("a-b", "c", "6f1c2a4e-0d6b-4f0e-9a52-1d4c7b8e9f01"),
+            &container("d"),
+        );
+        let second = staging_dir_name(
+            &pod("a", "b-c", "0b9e4d3c-7a2f-4c1d-8e6b-5f4a3d2c1b0a"),
+            &container("d"),
+        );
+        assert_ne!(first, second);
+        assert_eq!("6f1c2a4e-0d6b-4f0e-9a52-1d4c7b8e9f01-d", first);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resolve_in_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("volume");
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        std::os::unix::fs::symlink("data", root.join("relative")).unwrap();
+        let resolved_root = std::fs::canonicalize(&root).unwrap();
+
+        let resolve = |path: &str| {
+            let (root, path) = (root.clone(), root.join(path));
+            async move { resolve_in_volume(&root, &path, "vol").await }
+        };
+        assert_eq!(resolved_root.join("data"), resolve("data").await.unwrap());
+        assert_eq!(
+            resolved_root.join("data"),
+            resolve("relative").await.unwrap()
+        );
+        // Parts that don't exist yet are resolved from the parent that does
+        assert_eq!(
+            resolved_root.join("data/new/file"),
+            resolve("relative/new/file").await.unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resolve_in_volume_stays_in_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("volume");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("relative")).unwrap();
+        std::os::unix::fs::symlink("loop-b", root.join("loop-a")).unwrap();
+        std::os::unix::fs::symlink("loop-a", root.join("loop-b")).unwrap();
+
+        let resolve = |path: &str| {
+            let (root, path) = (root.clone(), root.join(path));
+            async move { resolve_in_volume(&root, &path, "vol").await }
+        };
+        for path in &["absolute", "absolute/new", "relative", "..", "../outside"] {
+            assert!(
+                matches!(
+                    resolve(path).await,
+                    Err(ContainerError::OutsideVolume { .. })
+                ),
+                "{}",
+                path
+            );
+        }
+        // Leaving the volume through a part that doesn't exist can't be resolved at all
+        assert!(matches!(
+            resolve("missing/../../outside").await,
+            Err(ContainerError::VolumeIo { .. })
+        ));
+        // Nor can a symlink loop
+        assert!(matches!(
+            resolve("loop-a").await,
+            Err(ContainerError::VolumeIo { .. })
+        ));
+        assert!(matches!(
+            resolve("loop-a/new").await,
+            Err(ContainerError::VolumeIo { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_volume_path_map_read_only_follows_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let host_dir = dir.path().join("host");
+        std::fs::create_dir(&host_dir).unwrap();
+        let volume: k8s_openapi::api::core::v1::Volume =
+            serde_json::from_value(serde_json::json!({
+                "name": "data",
+                "hostPath": {"path": host_dir}
+            }))
+            .unwrap();
+        let volumes = vec![(
+            "data".to_owned(),
+            VolumeRef::HostPath(kubelet::volume::HostPathVolume::new(&volume).unwrap()),
+        )]
+        .into_iter()
+        .collect();
+        let container = |read_only: Option<bool>| {
+            Container::new(
+                &serde_json::from_value(serde_json::json!({
+                    "name": "module",
+                    "securityContext": {"readOnlyRootFilesystem": true},
+                    "volumeMounts": [{"name": "data", "mountPath": "/data", "readOnly": read_only}]
+                }))
+                .unwrap(),
+            )
+        };
+        let staging_dir = dir.path().join("staging");
+
+        // A read only root filesystem doesn't make a volume mounted for writing read only
+        let paths = volume_path_map(&container(None), &volumes, &staging_dir)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths.get(&host_dir),
+            Some(&(Some(PathBuf::from("/data")), false))
+        );
+        let paths = volume_path_map(&container(Some(true)), &volumes, &staging_dir)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths.get(&host_dir),
+            Some(&(Some(PathBuf::from("/data")), true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let config = dir.path().join("config");
+        std::fs::create_dir_all(data.join("sub")).unwrap();
+        std::fs::create_dir(&config).unwrap();
+        std::fs::write(data.join("file.txt"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path(), data.join("escape")).unwrap();
+        let volumes: HashMap<PathBuf, (Option<PathBuf>, bool)> = vec![
+            (data.clone(), (Some(PathBuf::from("/data")), false)),
+            (config.clone(), (Some(PathBuf::from("/data/config")), true)),
+            // Staged files that aren't mounted at a path of their own
+            (dir.path().to_owned(), (None, true)),
+        ]
+        .into_iter()
+        .collect();
+
+        let (host_dir, read_only) = working_dir(" /data/sub ", &volumes).await.unwrap();
+        assert_eq!(data.join("sub").canonicalize().unwrap(), host_dir);
+        assert!(!read_only);
+        // The most specific mount is used
+        let (host_dir, read_only) = working_dir("/data/config", &volumes).await.unwrap();
+        assert_eq!(config.canonicalize().unwrap(), host_dir);
+        assert!(read_only);
+
+        for (requested, error) in &[
+            ("data/sub", "not an absolute path"),
+            ("/", "not in any of the container's volume mounts"),
+            ("/data/../etc", "must not contain '..'"),
+            ("/data/file.txt", "is not a directory"),
+            ("/data/missing", "missing"),
+        ] {
+            let e = working_dir(requested, &volumes).await.unwrap_err();
+            assert!(e.to_string().contains(error), "{}: {}", requested, e);
+        }
+        #[cfg(unix)]
+        assert!(working_dir("/data/escape", &volumes).await.is_err());
+    }
+
+    #[test]
+    fn test_wait_for_modules() {
+        let mut waiting = Waiting::restart(Duration::from_secs(10));
+        for attempt in 0..MAX_MODULE_LOAD_ATTEMPTS {
+            let before = Utc::now();
+            waiting = waiting.wait_for_modules().unwrap();
+            assert_eq!(attempt + 1, waiting.module_load_attempts);
+            // Only the wait for the modules is kept, not the restart backoff
+            assert!(waiting.retry_at.is_none());
+            let backoff =
+                chrono::Duration::from_std(MODULE_LOAD_BACKOFF * 2u32.pow(attempt)).unwrap();
+            let due_at = waiting.modules_due_at.unwrap();
+            assert!(due_at >= before + backoff && due_at <= Utc::now() + backoff);
+        }
+        // The container has waited long enough once it runs out of attempts
+        assert!(waiting.wait_for_modules().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_network_annotations() {
+        let namespace = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Namespace",
+            "metadata": {
+                "name": "default",
+                "annotations": {
+                    ALLOWED_DOMAINS_ANNOTATION_KEY: "[\"https://namespace.example.com\"]",
+                    HTTP_MAX_RETRIES_ANNOTATION_KEY: "2",
+                    "example.com/unrelated": "ignored",
+                },
+            },
+        });
+        let pod = annotated_pod(serde_json::json!({
+            ALLOWED_DOMAINS_ANNOTATION_KEY: "[\"https://pod.example.com\"]",
+            "example.com/unrelated": "ignored",
+        }));
+        // A client that fails any request made with it
+        let unreachable =
+            kube::Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap()))
+                .unwrap();
+
+        let annotations = network_annotations(&unreachable, &pod, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![ALLOWED_DOMAINS_ANNOTATION_KEY],
+            annotations.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+
+        // The pod's own annotations win over the namespace's
+        let client = serve_once(namespace).await;
+        let annotations = network_annotations(&client, &pod, true).await.unwrap();
+        assert_eq!(2, annotations.len());
+        assert_eq!(
+            "[\"https://pod.example.com\"]",
+            annotations[ALLOWED_DOMAINS_ANNOTATION_KEY]
+        );
+        assert_eq!("2", annotations[HTTP_MAX_RETRIES_ANNOTATION_KEY]);
+
+        // A pod that gives every annotation doesn't need its namespace read
+        let all: serde_json::Map<String, serde_json::Value> = NETWORK_ANNOTATION_KEYS
+            .iter()
+            .map(|key| (key.to_string(), "1".into()))
+            .collect();
+        let pod_with_all = annotated_pod(all.into());
+        let annotations = network_annotations(&unreachable, &pod_with_all, true)
+            .await
+            .unwrap();
+        assert_eq!(NETWORK_ANNOTATION_KEYS.len(), annotations.len());
+
+        let forbidden = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "message": "namespaces \"default\" is forbidden",
+            "reason": "Forbidden",
+            "code": 403,
+        });
+        let client = serve_once_with_status("403 Forbidden", forbidden).await;
+        let annotations = network_annotations(&client, &pod, true).await.unwrap();
+        assert_eq!(1, annotations.len());
+
+        let e = network_annotations(&unreachable, &pod, true)
+            .await
+            .unwrap_err();
+        assert!(
+            e.to_string().contains("unable to fetch namespace default"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_pulls_module_again() {
+        let container = |image: &str, pull_policy: Option<&str>| {
+            Container::new(
+                &serde_json::from_value(serde_json::json!({
+                    "name": "module",
+                    "image": image,
+                    "imagePullPolicy": pull_policy,
+                }))
+                .unwrap(),
+            )
+        };
+        let always = container("webassembly.azurecr.io/hello:v1", Some("Always"));
+        assert!(!pulls_module_again(&always, 0, 0));
+        assert!(pulls_module_again(&always, 1, 0));
+        // Retrying a failed load doesn't pull it yet again
+        assert!(!pulls_module_again(&always, 1, 1));
+
+        // Images without a tag, or tagged latest, are always pulled by default
+        for image in &[
+            "webassembly.azurecr.io/hello",
+            "webassembly.azurecr.io/hello:latest",
+        ] {
+            assert!(
+                pulls_module_again(&container(image, None), 2, 0),
+                "{}",
+                image
+            );
+        }
+        let image = "webassembly.azurecr.io/hello:v1";
+        for pull_policy in &[None, Some("IfNotPresent"), Some("Never")] {
+            assert!(
+                !pulls_module_again(&container(image, *pull_policy), 2, 0),
+                "{:?}",
+                pull_policy
+            );
+        }
+    }
+
+    #[test]
+    fn test_http_retry_policy() {
+        let policy = |annotations: serde_json::Value| {
+            http_retry_policy(&serde_json::from_value(annotations).unwrap())
+        };
+        assert_eq!(Ok(None), policy(serde_json::json!({})));
+        assert_eq!(
+            Ok(Some(RetryPolicy {
+                max_retries: 5,
+                backoff: DEFAULT_HTTP_RETRY_BACKOFF,
+            })),
+            policy(serde_json::json!({ HTTP_MAX_RETRIES_ANNOTATION_KEY: "5" }))
+        );
+        // The backoff asks for retries on its own
+        assert_eq!(
+            Ok(Some(RetryPolicy {
+                max_retries: DEFAULT_HTTP_MAX_RETRIES,
+                backoff: Duration::from_millis(250),
+            })),
+            policy(serde_json::json!({ HTTP_RETRY_BACKOFF_ANNOTATION_KEY: " 250 " }))
+        );
+        assert_eq!(
+            Ok(Some(RetryPolicy {
+                max_retries: 0,
+                backoff: Duration::from_millis(1),
+            })),
+            policy(serde_json::json!({
+                HTTP_MAX_RETRIES_ANNOTATION_KEY: "0",
+                HTTP_RETRY_BACKOFF_ANNOTATION_KEY: "1",
+            }))
+        );
+
+        let err = policy(serde_json::json!({ HTTP_MAX_RETRIES_ANNOTATION_KEY: "-1" }));
+        assert_eq!(HTTP_MAX_RETRIES_ANNOTATION_KEY, err.unwrap_err().0);
+        let err = policy(serde_json::json!({
+            HTTP_MAX_RETRIES_ANNOTATION_KEY: "2",
+            HTTP_RETRY_BACKOFF_ANNOTATION_KEY: "1s",
+        }));
+        assert_eq!(HTTP_RETRY_BACKOFF_ANNOTATION_KEY, err.unwrap_err().0);
+    }
+
+    #[test]
+    fn test_command_line() {
+        let container = |spec: serde_json::Value| {
+            let mut spec = spec;
+            spec["name"] = "module".into();
+            Container::new(&serde_json::from_value(spec).unwrap())
+        };
+        let env = vec![("GREETING".to_owned(), "hello world".to_owned())]
+            .into_iter()
+            .collect();
+
+        assert!(command_line(&container(serde_json::json!({})), &env).is_empty());
+        // Nothing stands in for argv[0], so args alone start there
+        assert_eq!(
+            vec!["--verbose"],
+            command_line(&container(serde_json::json!({"args": ["--verbose"]})), &env)
+        );
+        assert_eq!(
+            vec![
+                "app.wasm",
+                "say",
+                "hello world",
+                "$(GREETING)",
+                "$(MISSING)"
+            ],
+            command_line(
+                &container(serde_json::json!({
+                    "command": ["app.wasm", "say"],
+                    "args": ["$(GREETING)", "$$(GREETING)", "$(MISSING)"],
+                })),
+                &env
+            )
+        );
+    }
+
+    #[test]
+    fn test_check_mount_propagation() {
+        let mount = |mode: Option<&str>| -> VolumeMount {
+            serde_json::from_value(serde_json::json!({
+                "name": "data",
+                "mountPath": "/data",
+                "mountPropagation": mode,
+            }))
+            .unwrap()
+        };
+        for mode in &[None, Some("None"), Some("HostToContainer")] {
+            assert!(check_mount_propagation(&mount(*mode)).is_ok(), "{:?}", mode);
+        }
+        for mode in &["Bidirectional", "Sideways"] {
+            match check_mount_propagation(&mount(Some(mode))) {
+                Err(ContainerError::UnsupportedMountPropagation { volume, mode: m }) => {
+                    assert_eq!("data", volume);
+                    assert_eq!(*mode, m);
+                }
+                other => panic!("{} should be rejected, got {:?}", mode, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_preopened_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut volumes = HashMap::new();
+        let mut mounts = Vec::new();
+        for i in 0..3 {
+            let name = format!("data-{}", i);
+            let host_dir = dir.path().join(&name);
+            std::fs::create_dir(&host_dir).unwrap();
+            let volume: k8s_openapi::api::core::v1::Volume =
+                serde_json::from_value(serde_json::json!({
+                    "name": name,
+                    "hostPath": {"path": host_dir}
+                }))
+                .unwrap();
+            volumes.insert(
+                name.clone(),
+                VolumeRef::HostPath(kubelet::volume::HostPathVolume::new(&volume).unwrap()),
+            );
+            mounts.push(serde_json::json!({"name": name, "mountPath": format!("/{}", name)}));
+        }
+        let container = Container::new(
+            &serde_json::from_value(serde_json::json!({
+                "name": "module",
+                "volumeMounts": mounts,
+            }))
+            .unwrap(),
+        );
+        let dirs = volume_path_map(&container, &volumes, &dir.path().join("staging"))
+            .await
+            .unwrap();
+
+        assert!(check_preopened_dirs(&dirs, 3).is_ok());
+        match check_preopened_dirs(&dirs, 2) {
+            Err(e @ ContainerError::TooManyDirs { .. }) => {
+                assert_eq!(
+                    "mounts 3 directories, more than the limit of 2",
+                    e.to_string()
+                );
+                assert!(!e.is_retryable());
+            }
+            other => panic!("expected too many directories, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pod_log_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("logs");
+        let roots = vec![root.clone()];
+        // The root is created along with the directory if need be
+        let created = pod_log_dir(root.join("a/b").to_str().unwrap(), &roots)
+            .await
+            .unwrap();
+        assert!(created.is_dir());
+        assert!(created.ends_with("logs/a/b"));
+
+        assert!(pod_log_dir("relative/path", &roots).await.is_err());
+        assert!(pod_log_dir(root.join("a/../..").to_str().unwrap(), &roots)
+            .await
+            .is_err());
+        assert!(
+            pod_log_dir(dir.path().join("other").to_str().unwrap(), &roots)
+                .await
+                .is_err()
+        );
+        assert!(!dir.path().join("other").exists());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn test_pod_log_dir_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("logs");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        let roots = vec![root.clone()];
+        let requested = root.join("escape/new/dirs");
+        assert!(pod_log_dir(requested.to_str().unwrap(), &roots)
+            .await
+            .is_err());
+        // Nothing was created outside the root on the way
+        assert!(!outside.join("new").exists());
     }
 }
@@ -1,17 +1,32 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use krator::{ObjectState, SharedState};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use krator::{Manifest, ObjectState, SharedState};
+use kube::Api;
 use kubelet::backoff::BackoffStrategy;
 use kubelet::backoff::ExponentialBackoffStrategy;
+use kubelet::container::state::run_to_completion;
+use kubelet::container::{
+    patch_container_status, Container, ContainerKey, Status as ContainerStatus,
+};
+use kubelet::pod::Phase;
 use kubelet::pod::Pod;
 use kubelet::pod::PodKey;
 use kubelet::pod::Status;
-use kubelet::state::common::{BackoffSequence, GenericPodState, ThresholdTrigger};
+use kubelet::pod::StatusBuilder;
+use kubelet::secret::RegistryAuthResolver;
+use kubelet::state::common::{
+    BackoffSequence, GenericPodState, GenericProviderState, ThresholdTrigger,
+};
 use tokio::sync::RwLock;
-use tracing::error;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 
+use crate::states::container::validating::Validating;
+use crate::states::container::ContainerState;
 use crate::ModuleRunContext;
 use crate::ProviderState;
 
@@ -20,9 +35,195 @@ pub(crate) mod initializing;
 pub(crate) mod running;
 pub(crate) mod starting;
 
+/// The tasks running the pod's sidecars, in the order they were started. Each finishes once its
+/// sidecar is stopped
+pub(crate) type Sidecars = Vec<JoinHandle<anyhow::Result<()>>>;
+
+/// The reason given to pods, and their containers, stopped for running past their active deadline
+pub(crate) const DEADLINE_EXCEEDED: &str = "DeadlineExceeded";
+/// The message given to pods, and their containers, stopped for running past their active deadline
+pub(crate) const DEADLINE_EXCEEDED_MESSAGE: &str =
+    "Pod was active on the node longer than the specified deadline";
+
+/// Stops the pod's sidecars once nothing needs them anymore, in the reverse of the order they
+/// were started, and waits for them to exit
+pub(crate) async fn stop_sidecars(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Pod,
+    sidecars: Sidecars,
+) {
+    if sidecars.is_empty() {
+        return;
+    }
+    pod_state.run_context.write().await.stopping_sidecars = true;
+    let handles = provider_state.read().await.handles.clone();
+    for (container, task) in pod.sidecar_containers().iter().zip(sidecars).rev() {
+        info!(
+            container_name = container.name(),
+            "Stopping sidecar container"
+        );
+        let key = ContainerKey::Init(container.name().to_owned());
+        let handle = handles.read().await.get(&PodKey::from(pod)).cloned();
+        if let Some(handle) = handle {
+            if let Err(e) = handle.stop_container(&key).await {
+                warn!(error = %e, container_name = container.name(), "Unable to stop sidecar container");
+            }
+        }
+        match task.await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => {
+                warn!(error = %e, container_name = container.name(), "Sidecar container exited with error")
+            }
+            Err(e) => {
+                warn!(error = %e, container_name = container.name(), "Sidecar container task failed")
+            }
+        }
+    }
+}
+
+/// Stops the pod's app containers once it has run past its active deadline. They exit as failed
+/// and aren't restarted, which completes the pod
+pub(crate) async fn stop_for_deadline(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Pod,
+) {
+    pod_state.run_context.write().await.deadline_exceeded = true;
+    let handles = provider_state.read().await.handles.clone();
+    let handle = handles.read().await.get(&PodKey::from(pod)).cloned();
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    for container in pod.containers() {
+        let key = ContainerKey::App(container.name().to_owned());
+        if let Err(e) = handle.stop_container(&key).await {
+            warn!(error = %e, container_name = container.name(), "Unable to stop container");
+        }
+    }
+}
+
+/// Starts the ephemeral containers that have been added to the pod since it was last checked.
+/// Their modules are pulled as they are added, and they go through the same states as the pod's
+/// other containers, but they run only once and the pod doesn't wait for them to exit
+pub(crate) async fn start_ephemeral_containers(
+    provider_state: &SharedState<ProviderState>,
+    pod_state: &PodState,
+    pod: &Manifest<Pod>,
+    started: &mut HashSet<String>,
+) {
+    let latest_pod = pod.latest();
+    for container in latest_pod.ephemeral_containers() {
+        if !started.insert(container.name().to_owned()) {
+            continue;
+        }
+        info!(
+            container_name = container.name(),
+            "Starting ephemeral container"
+        );
+        let key = ContainerKey::Ephemeral(container.name().to_owned());
+        let container_state = ContainerState::new(
+            latest_pod.clone(),
+            key.clone(),
+            Arc::clone(&pod_state.run_context),
+        );
+        let run_context = Arc::clone(&pod_state.run_context);
+        let task_provider = Arc::clone(provider_state);
+        let task_pod = pod.clone();
+        tokio::task::spawn(async move {
+            let (client, store) = {
+                let provider_state = task_provider.read().await;
+                (provider_state.client(), provider_state.store())
+            };
+            let pod = task_pod.latest();
+            let auth_resolver = RegistryAuthResolver::new(client.clone(), &pod);
+            match store
+                .fetch_container_module(&container, &auth_resolver)
+                .await
+            {
+                Ok(module) => {
+                    run_context
+                        .write()
+                        .await
+                        .modules
+                        .insert(container.name().to_owned(), module);
+                }
+                Err(e) => {
+                    error!(error = %e, container_name = container.name(), "Unable to pull ephemeral container module");
+                    report_pull_failure(&client, &pod, &key, &container, e).await;
+                    return;
+                }
+            }
+            let result = run_to_completion(
+                &client,
+                Validating::default(),
+                task_provider,
+                container_state,
+                task_pod,
+                key,
+            )
+            .await;
+            if let Err(e) = result {
+                warn!(error = %e, container_name = container.name(), "Ephemeral container exited with error");
+            }
+        });
+    }
+}
+
+/// Reports an ephemeral container whose module couldn't be pulled as terminated, as it won't be
+/// tried again
+async fn report_pull_failure(
+    client: &kube::Client,
+    pod: &Pod,
+    key: &ContainerKey,
+    container: &Container,
+    error: anyhow::Error,
+) {
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    let status = ContainerStatus::Terminated {
+        timestamp: chrono::Utc::now(),
+        message: format!("Unable to pull module: {}", error),
+        failed: true,
+        reason: Some("ErrImagePull".to_owned()),
+        exit_code: None,
+    };
+    if let Err(e) = patch_container_status(&api, pod, key, &status).await {
+        warn!(error = %e, container_name = container.name(), "Unable to report ephemeral container status");
+    }
+}
+
+/// Stops the pod's ephemeral containers that are still running, once the pod is done
+pub(crate) async fn stop_ephemeral_containers(
+    provider_state: &SharedState<ProviderState>,
+    pod: &Pod,
+    started: &HashSet<String>,
+) {
+    if started.is_empty() {
+        return;
+    }
+    let handles = provider_state.read().await.handles.clone();
+    let handle = handles.read().await.get(&PodKey::from(pod)).cloned();
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return,
+    };
+    for name in started {
+        // Containers that already exited, or never started, have nothing left to stop
+        if let Err(e) = handle
+            .stop_container(&ContainerKey::Ephemeral(name.clone()))
+            .await
+        {
+            debug!(error = %e, container_name = %name, "Ephemeral container not stopped");
+        }
+    }
+}
+
 /// State that is shared between pod state handlers.
 pub struct PodState {
     key: PodKey,
+    /// The IP of the node, which pods share as modules run in the node's network
+    node_ip: IpAddr,
     run_context: SharedState<ModuleRunContext>,
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
@@ -45,6 +246,13 @@ impl ObjectState for PodState {
                     }
                 });
                 futures::future::join_all(unmounts).await;
+                for dir in context.sub_path_dirs.drain() {
+                    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+                        if e.kind() != std::io::ErrorKind::NotFound {
+                            error!(error = %e, path = %dir.display(), "Unable to remove sub path staging directory");
+                        }
+                    }
+                }
             }
             let mut handles = provider_state.handles.write().await;
             handles.remove(&self.key);
@@ -53,21 +261,40 @@ impl ObjectState for PodState {
 }
 
 impl PodState {
-    pub fn new(pod: &Pod) -> Self {
+    pub fn new(pod: &Pod, node_ip: IpAddr) -> Self {
         let run_context = ModuleRunContext {
             modules: Default::default(),
             volumes: Default::default(),
             env_vars: Default::default(),
+            sub_path_dirs: Default::default(),
+            ready: Default::default(),
+            stopping_sidecars: false,
+            deadline_exceeded: false,
         };
         let key = PodKey::from(pod);
         PodState {
             key,
+            node_ip,
             run_context: Arc::new(RwLock::new(run_context)),
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
         }
     }
+
+    /// Creates a pod status that also reports the IPs the pod can be reached at. Modules share
+    /// the node's network, so the pod IP is the node IP and the ports containers declare are
+    /// served on it
+    pub(crate) fn make_status(&self, phase: Phase, reason: &str) -> Status {
+        let ip = self.node_ip.to_string();
+        StatusBuilder::new()
+            .phase(phase)
+            .reason(reason)
+            .message(reason)
+            .host_ip(&ip)
+            .pod_ip(&ip)
+            .build()
+    }
 }
 
 #[async_trait]
@@ -1,22 +1,83 @@
+use super::{DEADLINE_EXCEEDED, DEADLINE_EXCEEDED_MESSAGE};
 use crate::{PodState, ProviderState};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{make_condition, patch_status, StatusBuilder, CONTAINERS_READY, READY};
+use kubelet::state::common::GenericProviderState;
+use tracing::error;
 
-/// Pod was deleted.
+/// The reason given for a pod that failed
+const FAILED_REASON: &str = "Error";
+
+/// All of the Pod's containers have exited and none of them will be restarted.
 #[derive(Default, Debug)]
-pub struct Completed;
+pub struct Completed {
+    failure: Option<String>,
+    /// The reason given for the failure, when there is one more specific than `Error`
+    reason: Option<&'static str>,
+}
+
+impl Completed {
+    /// The Pod completed with at least one container having failed
+    pub fn failed(message: String) -> Self {
+        Completed {
+            failure: Some(message),
+            reason: None,
+        }
+    }
+
+    /// The Pod was stopped for running past its active deadline
+    pub fn deadline_exceeded() -> Self {
+        Completed {
+            failure: Some(DEADLINE_EXCEEDED_MESSAGE.to_owned()),
+            reason: Some(DEADLINE_EXCEEDED),
+        }
+    }
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for Completed {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
-        _pod: Manifest<Pod>,
+        provider_state: SharedState<ProviderState>,
+        pod_state: &mut PodState,
+        pod: Manifest<Pod>,
     ) -> Transition<PodState> {
+        // The state machine is completed without an error even when the pod failed, as an error
+        // would have its status replaced by a generic failure, losing the reason and message
+        let pod = pod.latest();
+        if let Some(message) = &self.failure {
+            error!(error = %message, "Pod failed");
+        }
+        let client = provider_state.read().await.client();
+        let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+        match self.status(pod_state, &pod).await {
+            Ok(status) => patch_status(&api, pod.name(), status).await,
+            Err(e) => error!(error = %e, "Unable to build the status of the completed pod"),
+        }
         Transition::Complete(Ok(()))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Succeeded, "Completed"))
+    async fn status(&self, _pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<PodStatus> {
+        // The reason is a short CamelCase word, with the details of a failure in the message
+        let (phase, reason, message) = match &self.failure {
+            Some(message) => (
+                Phase::Failed,
+                self.reason.unwrap_or(FAILED_REASON),
+                message.as_str(),
+            ),
+            None => (Phase::Succeeded, "Completed", "Completed"),
+        };
+        // A completed pod won't serve again, which controllers such as Jobs look for
+        Ok(StatusBuilder::new()
+            .phase(phase)
+            .reason(reason)
+            .message(message)
+            .conditions(vec![
+                make_condition(pod, CONTAINERS_READY, false, Some("PodCompleted")),
+                make_condition(pod, READY, false, Some("PodCompleted")),
+            ])
+            .build())
     }
 }
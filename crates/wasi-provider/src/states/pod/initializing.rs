@@ -1,19 +1,24 @@
 use std::sync::Arc;
 
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::Api;
+use tokio::sync::oneshot;
 use tracing::{error, info, instrument};
 
 use kubelet::backoff::BackoffStrategy;
 use kubelet::container::state::run_to_completion;
 use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
+use kubelet::pod::{make_condition, patch_status, StatusBuilder, INITIALIZED};
 use kubelet::state::common::error::Error;
 use kubelet::state::common::GenericProviderState;
 
-use crate::states::container::waiting::Waiting;
+use crate::states::container::validating::Validating;
 use crate::states::container::ContainerState;
 use crate::{PodState, ProviderState};
 
 use super::starting::Starting;
+use super::{stop_sidecars, Sidecars};
 
 #[derive(Default, Debug, TransitionTo)]
 #[transition_to(Starting, Error<crate::WasiProvider>)]
@@ -42,7 +47,22 @@ impl State<PodState> for Initializing {
             provider_state.client()
         };
 
-        for init_container in pod.init_containers() {
+        let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+        let init_containers = pod.init_containers();
+        let total = init_containers.len();
+        let mut sidecars = Sidecars::new();
+        // Init containers run one at a time, in order. Failed ones are
+        // restarted by their own state machine according to the pod's
+        // restartPolicy, so a failure here means no more retries will happen.
+        // Sidecars are left running once they have started, and the next init
+        // container starts after them
+        for (index, init_container) in init_containers.into_iter().enumerate() {
+            patch_status(
+                &api,
+                pod.name(),
+                make_status(Phase::Pending, &format!("Init:{}/{}", index, total)),
+            )
+            .await;
             info!(
                 container_name = init_container.name(),
                 "Starting init container for pod"
@@ -51,7 +71,7 @@ impl State<PodState> for Initializing {
             // Each new init container resets the CrashLoopBackoff timer.
             pod_state.crash_loop_backoff_strategy.reset();
 
-            let initial_state = Waiting;
+            let initial_state = Validating::default();
 
             let container_key = ContainerKey::Init(init_container.name().to_string());
             let container_state = ContainerState::new(
@@ -60,6 +80,43 @@ impl State<PodState> for Initializing {
                 Arc::clone(&pod_state.run_context),
             );
 
+            if pod.is_sidecar(&container_key) {
+                let (started_tx, started_rx) = oneshot::channel();
+                let container_state = container_state.with_started_signal(started_tx);
+                let task_client = client.clone();
+                let task_provider = Arc::clone(&provider_state);
+                let task_pod = pod_rx.clone();
+                sidecars.push(tokio::task::spawn(async move {
+                    run_to_completion(
+                        &task_client,
+                        initial_state,
+                        task_provider,
+                        container_state,
+                        task_pod,
+                        container_key,
+                    )
+                    .await
+                }));
+                // The signal is dropped without being sent if the sidecar gives up before it
+                // has started
+                if started_rx.await.is_err() {
+                    error!(
+                        container_name = init_container.name(),
+                        "Sidecar container exited before it started"
+                    );
+                    stop_sidecars(&provider_state, pod_state, &pod, sidecars).await;
+                    return Transition::Complete(Err(anyhow::anyhow!(
+                        "Sidecar container {} failed to start",
+                        init_container.name()
+                    )));
+                }
+                info!(
+                    container_name = init_container.name(),
+                    "Sidecar container started for pod"
+                );
+                continue;
+            }
+
             match run_to_completion(
                 &client,
                 initial_state,
@@ -74,6 +131,7 @@ impl State<PodState> for Initializing {
                 Ok(_) => (),
                 Err(e) => {
                     error!(error = %e, "Init container failed");
+                    stop_sidecars(&provider_state, pod_state, &pod, sidecars).await;
                     return Transition::Complete(Err(anyhow::anyhow!(format!(
                         "Init container {} failed",
                         init_container.name()
@@ -82,11 +140,22 @@ impl State<PodState> for Initializing {
             }
         }
         info!("Finished init containers for pod");
+        if total > 0 {
+            let status = StatusBuilder::new()
+                .conditions(vec![make_condition(
+                    &pod_rx.latest(),
+                    INITIALIZED,
+                    true,
+                    None,
+                )])
+                .build();
+            patch_status(&api, pod.name(), status).await;
+        }
         pod_state.crash_loop_backoff_strategy.reset();
-        Transition::next(self, Starting)
+        Transition::next(self, Starting::new(sidecars))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pmeod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Initializing"))
+    async fn status(&self, pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.make_status(Phase::Pending, "Initializing"))
     }
 }
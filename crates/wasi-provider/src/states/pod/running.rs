@@ -1,11 +1,17 @@
+use std::collections::HashSet;
+
+use futures::StreamExt;
 use tokio::sync::mpsc::Receiver;
+use tracing::{error, warn};
 
 use kubelet::pod::state::prelude::*;
 use kubelet::state::common::error::Error;
-use kubelet::state::common::GenericProviderState;
 
 use super::completed::Completed;
-use crate::fail_fatal;
+use super::{
+    start_ephemeral_containers, stop_ephemeral_containers, stop_for_deadline, stop_sidecars,
+    Sidecars,
+};
 use crate::{PodState, ProviderState};
 
 /// The Kubelet is running the Pod.
@@ -13,11 +19,18 @@ use crate::{PodState, ProviderState};
 #[transition_to(Completed, Error<crate::WasiProvider>)]
 pub struct Running {
     rx: Receiver<anyhow::Result<()>>,
+    sidecars: Sidecars,
+    /// The names of the ephemeral containers that have been started
+    ephemeral: HashSet<String>,
 }
 
 impl Running {
-    pub fn new(rx: Receiver<anyhow::Result<()>>) -> Self {
-        Running { rx }
+    pub fn new(rx: Receiver<anyhow::Result<()>>, sidecars: Sidecars) -> Self {
+        Running {
+            rx,
+            sidecars,
+            ephemeral: HashSet::new(),
+        }
     }
 }
 
@@ -26,30 +39,77 @@ impl State<PodState> for Running {
     async fn next(
         mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
-        _pod_state: &mut PodState,
-        pod: Manifest<Pod>,
+        pod_state: &mut PodState,
+        mut manifest: Manifest<Pod>,
     ) -> Transition<PodState> {
-        let pod = pod.latest();
+        let mut pod = manifest.latest();
 
         let mut completed = 0;
+        let mut failure = None;
         let total_containers = pod.containers().len();
 
-        while let Some(result) = self.rx.recv().await {
-            match result {
-                Ok(()) => {
-                    completed += 1;
-                    if completed == total_containers {
-                        return Transition::next(self, Completed);
+        // Like the kubelet, the pod only completes once every container has exited. Containers
+        // that fail aren't restarted under its restart policy, so the pod fails with the first
+        // of their errors. Sidecars are stopped once the app containers are done, and how they
+        // exit doesn't change the outcome of the pod. Ephemeral containers added while the pod
+        // runs are started alongside it, and don't count towards it completing either
+        // A pod with an active deadline has its app containers stopped once it passes, failing the
+        // pod with them. The deadline can be changed while the pod runs, so the timer is reset
+        // whenever it is
+        let started = chrono::Utc::now();
+        let mut deadline = pod.active_deadline(started);
+        let deadline_timer = tokio::time::sleep(time_until(deadline));
+        tokio::pin!(deadline_timer);
+        let mut deadline_exceeded = false;
+        start_ephemeral_containers(&provider_state, pod_state, &manifest, &mut self.ephemeral)
+            .await;
+        loop {
+            let result = tokio::select! {
+                result = self.rx.recv() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                Some(latest) = manifest.next() => {
+                    pod = latest;
+                    let latest_deadline = pod.active_deadline(started);
+                    if latest_deadline != deadline {
+                        deadline = latest_deadline;
+                        deadline_timer
+                            .as_mut()
+                            .reset(tokio::time::Instant::now() + time_until(deadline));
                     }
+                    start_ephemeral_containers(
+                        &provider_state,
+                        pod_state,
+                        &manifest,
+                        &mut self.ephemeral,
+                    )
+                    .await;
+                    continue;
                 }
-                Err(e) => {
-                    // Stop remaining containers;
-                    {
-                        let provider = provider_state.write().await;
-                        provider.stop(&pod).await.ok();
-                    }
-                    fail_fatal!(e);
+                _ = &mut deadline_timer, if deadline.is_some() && !deadline_exceeded => {
+                    warn!(?deadline, "Pod exceeded its active deadline, stopping containers");
+                    deadline_exceeded = true;
+                    stop_for_deadline(&provider_state, pod_state, &pod).await;
+                    continue;
                 }
+            };
+            completed += 1;
+            if let Err(e) = result {
+                error!(error = %e, "Pod container failed");
+                failure.get_or_insert(e);
+            }
+            if completed == total_containers {
+                let sidecars = std::mem::take(&mut self.sidecars);
+                stop_sidecars(&provider_state, pod_state, &pod, sidecars).await;
+                stop_ephemeral_containers(&provider_state, &pod, &self.ephemeral).await;
+                return match failure {
+                    Some(_) if deadline_exceeded => {
+                        Transition::next(self, Completed::deadline_exceeded())
+                    }
+                    Some(e) => Transition::next(self, Completed::failed(e.to_string())),
+                    None => Transition::next(self, Completed::default()),
+                };
             }
         }
         Transition::next(
@@ -61,7 +121,14 @@ impl State<PodState> for Running {
         )
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Running, "Running"))
+    async fn status(&self, pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.make_status(Phase::Running, "Running"))
     }
 }
+
+/// How long until the pod's active deadline, if it has one. A deadline that has passed is due now
+fn time_until(deadline: Option<chrono::DateTime<chrono::Utc>>) -> std::time::Duration {
+    deadline
+        .and_then(|deadline| (deadline - chrono::Utc::now()).to_std().ok())
+        .unwrap_or_default()
+}
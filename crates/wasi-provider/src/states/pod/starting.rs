@@ -7,16 +7,25 @@ use kubelet::container::ContainerKey;
 use kubelet::pod::state::prelude::*;
 use kubelet::state::common::GenericProviderState;
 
-use crate::states::container::waiting::Waiting;
+use crate::states::container::validating::Validating;
 use crate::states::container::ContainerState;
 use crate::{PodState, ProviderState};
 
 use super::running::Running;
+use super::Sidecars;
 
-#[derive(Default, Debug, TransitionTo)]
+#[derive(Debug, TransitionTo)]
 #[transition_to(Running)]
 /// The Kubelet is starting the Pod containers
-pub(crate) struct Starting;
+pub(crate) struct Starting {
+    sidecars: Sidecars,
+}
+
+impl Starting {
+    pub(crate) fn new(sidecars: Sidecars) -> Self {
+        Starting { sidecars }
+    }
+}
 
 #[async_trait::async_trait]
 impl State<PodState> for Starting {
@@ -26,7 +35,7 @@ impl State<PodState> for Starting {
         fields(pod_name)
     )]
     async fn next(
-        self: Box<Self>,
+        mut self: Box<Self>,
         provider_state: SharedState<ProviderState>,
         pod_state: &mut PodState,
         pod: Manifest<Pod>,
@@ -40,7 +49,7 @@ impl State<PodState> for Starting {
         let containers = pod.containers();
         let (tx, rx) = tokio::sync::mpsc::channel(containers.len());
         for container in containers {
-            let initial_state = Waiting;
+            let initial_state = Validating::default();
             let container_key = ContainerKey::App(container.name().to_string());
             let container_state = ContainerState::new(
                 pod.clone(),
@@ -69,10 +78,11 @@ impl State<PodState> for Starting {
             });
         }
         info!("All containers started for pod");
-        Transition::next(self, Running::new(rx))
+        let sidecars = std::mem::take(&mut self.sidecars);
+        Transition::next(self, Running::new(rx, sidecars))
     }
 
-    async fn status(&self, _pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
-        Ok(make_status(Phase::Pending, "Starting"))
+    async fn status(&self, pod_state: &mut PodState, _pod: &Pod) -> anyhow::Result<PodStatus> {
+        Ok(pod_state.make_status(Phase::Pending, "Starting"))
     }
 }
@@ -0,0 +1,231 @@
+//! Termination messages modules leave behind when they exit.
+//!
+//! As with containers, a module writes why it exited to the file at its container's
+//! `terminationMessagePath`, and what it wrote becomes the message of its terminated status.
+//! WASI can only preopen directories, so the file is made in a directory of the container's own
+//! that is mounted over the directory the path is in. The module can put anything at the path,
+//! so the host only reads the message from a regular file and never follows a link there.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use kubelet::container::Container;
+use tokio::io::AsyncReadExt;
+
+use crate::error::ContainerError;
+use crate::wasi_runtime::WasiRuntime;
+
+/// Where modules write their termination message if their container doesn't say
+const DEFAULT_TERMINATION_MESSAGE_PATH: &str = "/dev/termination-log";
+/// The policy that falls back to the end of the module's output if it failed without writing a
+/// termination message
+const FALLBACK_TO_LOGS_ON_ERROR: &str = "FallbackToLogsOnError";
+/// The most of a termination message that is read, which is as much as the kubelet reads
+const MAX_MESSAGE_BYTES: u64 = 4096;
+/// How much of the end of the output a failed module's message falls back to
+const FALLBACK_MAX_LINES: usize = 80;
+const FALLBACK_MAX_BYTES: u64 = 2048;
+/// The directory under the container's staging directory the termination message is written in
+const TERMINATION_DIR: &str = "termination";
+
+/// Makes the file the container's module writes its termination message to, mounting the
+/// directory it is in, and returns its path on the host. `staging_dir` is the container's own
+/// directory that is cleared every time it starts. No file is made if a volume is mounted where
+/// the path is, so files that belong to the volume are never replaced
+pub(crate) async fn termination_log(
+    container: &Container,
+    volumes: &mut HashMap<PathBuf, (Option<PathBuf>, bool)>,
+    staging_dir: &Path,
+) -> Result<Option<PathBuf>, ContainerError> {
+    let guest_path = Path::new(
+        container
+            .termination_message_path()
+            .map(String::as_str)
+            .filter(|p| !p.is_empty())
+            .unwrap_or(DEFAULT_TERMINATION_MESSAGE_PATH),
+    );
+    let (guest_dir, file_name) = match (guest_path.parent(), guest_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(None),
+    };
+    let mounted = volumes
+        .iter()
+        .any(|(host, (guest, _))| guest.as_deref().unwrap_or(host) == guest_dir);
+    if mounted {
+        return Ok(None);
+    }
+    let dir = staging_dir.join(TERMINATION_DIR);
+    tokio::fs::create_dir_all(&dir).await?;
+    let host_path = dir.join(file_name);
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&host_path)
+        .await?;
+    volumes.insert(dir, (Some(guest_dir.to_owned()), false));
+    Ok(Some(host_path))
+}
+
+/// Reads the message the container's module left when it exited. If it left none, a module that
+/// failed may have the end of its output as its message instead, if its container asks for that
+pub(crate) async fn termination_message(
+    container: &Container,
+    termination_log: Option<&Path>,
+    failed: bool,
+    runtime: &WasiRuntime,
+) -> Option<String> {
+    if let Some(path) = termination_log {
+        if let Some(message) = read_message(path).await {
+            return Some(message);
+        }
+    }
+    let fallback = container.termination_message_policy().map(String::as_str)
+        == Some(FALLBACK_TO_LOGS_ON_ERROR);
+    if failed && fallback {
+        return runtime
+            .output_tail(FALLBACK_MAX_LINES, FALLBACK_MAX_BYTES)
+            .await
+            .ok()
+            .filter(|tail| !tail.trim().is_empty());
+    }
+    None
+}
+
+/// Reads a termination message from the file at `path`. Anything other than a regular file,
+/// including a link to one, is ignored
+async fn read_message(path: &Path) -> Option<String> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.read(true);
+    // Opening a link fails rather than following it, and opening a FIFO doesn't wait for a
+    // writer
+    #[cfg(target_os = "linux")]
+    options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    #[cfg(not(target_os = "linux"))]
+    if tokio::fs::symlink_metadata(path)
+        .await
+        .ok()?
+        .file_type()
+        .is_symlink()
+    {
+        return None;
+    }
+    let file = options.open(path).await.ok()?;
+    if !file.metadata().await.ok()?.is_file() {
+        return None;
+    }
+    let mut message = Vec::new();
+    file.take(MAX_MESSAGE_BYTES)
+        .read_to_end(&mut message)
+        .await
+        .ok()?;
+    let message = String::from_utf8_lossy(&message).into_owned();
+    if message.trim().is_empty() {
+        None
+    } else {
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn container(spec: serde_json::Value) -> Container {
+        Container::new(&serde_json::from_value(spec).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_termination_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut volumes = HashMap::new();
+        let host_path = termination_log(
+            &container(serde_json::json!({"name": "module"})),
+            &mut volumes,
+            dir.path(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(dir.path().join("termination/termination-log"), host_path);
+        assert!(host_path.is_file());
+        assert_eq!(
+            Some(&(Some(PathBuf::from("/dev")), false)),
+            volumes.get(&dir.path().join("termination"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_termination_log_leaves_volumes_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let container = container(serde_json::json!({
+            "name": "module",
+            "terminationMessagePath": "/data/message"
+        }));
+        let mut volumes = HashMap::new();
+        volumes.insert(
+            PathBuf::from("/volumes/data"),
+            (Some(PathBuf::from("/data")), false),
+        );
+        assert_eq!(
+            None,
+            termination_log(&container, &mut volumes, dir.path())
+                .await
+                .unwrap()
+        );
+        assert_eq!(1, volumes.len());
+
+        // A path without a file name has nowhere to write a message
+        let container = self::container(serde_json::json!({
+            "name": "module",
+            "terminationMessagePath": "/"
+        }));
+        assert_eq!(
+            None,
+            termination_log(&container, &mut volumes, dir.path())
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("message");
+        assert_eq!(None, read_message(&path).await);
+        std::fs::write(&path, " \n").unwrap();
+        assert_eq!(None, read_message(&path).await);
+        std::fs::write(&path, "out of memory\n").unwrap();
+        assert_eq!(
+            Some("out of memory\n".to_owned()),
+            read_message(&path).await
+        );
+
+        // Only as much as the kubelet reads is read
+        std::fs::write(&path, "a".repeat(MAX_MESSAGE_BYTES as usize + 10)).unwrap();
+        assert_eq!(
+            MAX_MESSAGE_BYTES as usize,
+            read_message(&path).await.unwrap().len()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_read_message_ignores_links_and_fifos() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "host secret").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&secret, &link).unwrap();
+        assert_eq!(None, read_message(&link).await);
+
+        let fifo = dir.path().join("fifo");
+        let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) });
+        // Reading a FIFO would wait for a writer that never comes
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), read_message(&fifo))
+            .await
+            .unwrap();
+        assert_eq!(None, message);
+
+        assert_eq!(None, read_message(dir.path()).await);
+    }
+}
@@ -0,0 +1,251 @@
+//! Tracking the resources a running module uses, so they can be reported to metrics consumers.
+//!
+//! Memory is the size of the module's linear memories, which only grow, so it is what the module
+//! has asked for at its peak rather than what it actively uses. CPU is the CPU time of the thread
+//! the module runs on, which on Linux is measured by the kernel. On other platforms the time the
+//! module has been running is used instead, which overstates the usage of modules that spend
+//! their time waiting.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use kubelet::stats::Usage;
+use wasmtime::{ResourceLimiter, StoreLimits};
+
+use crate::wasi_runtime::WASM_PAGE_SIZE;
+
+/// The resources used by a module run
+pub(crate) struct ResourceUsage {
+    start_time: DateTime<Utc>,
+    /// The total size of the module's linear memories, in bytes
+    memory_bytes: AtomicU64,
+    cpu: Mutex<CpuTime>,
+}
+
+/// The CPU time of a module run
+enum CpuTime {
+    /// The module hasn't started running yet
+    NotStarted,
+    /// The module is running on the thread the clock measures
+    Running(ThreadClock),
+    /// The module has finished running, after using the given CPU time
+    Finished(Duration),
+}
+
+impl ResourceUsage {
+    pub(crate) fn new() -> Self {
+        ResourceUsage {
+            start_time: Utc::now(),
+            memory_bytes: AtomicU64::new(0),
+            cpu: Mutex::new(CpuTime::NotStarted),
+        }
+    }
+
+    /// Starts counting the CPU time of the current thread towards the module, until the returned
+    /// value is dropped. The module must run on the current thread for as long as it is held
+    pub(crate) fn track_current_thread(self: &Arc<Self>) -> CpuTracking {
+        *self.cpu.lock().unwrap() = CpuTime::Running(ThreadClock::current());
+        CpuTracking {
+            usage: self.clone(),
+        }
+    }
+
+    /// The resources the module has used so far
+    pub(crate) fn usage(&self) -> Usage {
+        let cpu_time = match &*self.cpu.lock().unwrap() {
+            CpuTime::NotStarted => Duration::default(),
+            CpuTime::Running(clock) => clock.elapsed(),
+            CpuTime::Finished(cpu_time) => *cpu_time,
+        };
+        Usage {
+            start_time: self.start_time,
+            cpu_time,
+            memory_bytes: self.memory_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts the CPU time of the thread a module runs on towards it while held
+pub(crate) struct CpuTracking {
+    usage: Arc<ResourceUsage>,
+}
+
+impl Drop for CpuTracking {
+    fn drop(&mut self) {
+        // The thread goes back to the pool of blocking threads, so the time it spends on other
+        // work mustn't count towards the module
+        let mut cpu = self.usage.cpu.lock().unwrap();
+        if let CpuTime::Running(clock) = &*cpu {
+            *cpu = CpuTime::Finished(clock.elapsed());
+        }
+    }
+}
+
+/// Measures the CPU time of the thread it was created on
+struct ThreadClock {
+    started: Instant,
+    /// The thread's CPU clock and its reading when the clock was created, if it can be read
+    #[cfg(target_os = "linux")]
+    cpu: Option<(libc::clockid_t, Duration)>,
+}
+
+impl ThreadClock {
+    #[cfg(target_os = "linux")]
+    fn current() -> Self {
+        let mut clock: libc::clockid_t = 0;
+        let cpu = if unsafe { pthread_getcpuclockid(libc::pthread_self(), &mut clock) } == 0 {
+            read_clock(clock).map(|started| (clock, started))
+        } else {
+            None
+        };
+        ThreadClock {
+            started: Instant::now(),
+            cpu,
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn current() -> Self {
+        ThreadClock {
+            started: Instant::now(),
+        }
+    }
+
+    /// The CPU time used since the clock was created. This is only valid while the thread the
+    /// clock was created on is still running
+    #[cfg(target_os = "linux")]
+    fn elapsed(&self) -> Duration {
+        match self.cpu {
+            Some((clock, started)) => read_clock(clock)
+                .and_then(|now| now.checked_sub(started))
+                .unwrap_or_default(),
+            None => self.started.elapsed(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+// Not bound by the libc crate, but part of glibc and musl
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn pthread_getcpuclockid(thread: libc::pthread_t, clock: *mut libc::clockid_t) -> libc::c_int;
+}
+
+#[cfg(target_os = "linux")]
+fn read_clock(clock: libc::clockid_t) -> Option<Duration> {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut time) } != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+/// The limits of a store, which record how much memory the module has grown to as they are
+/// checked
+pub(crate) struct TrackedLimits {
+    limits: StoreLimits,
+    /// Where the memory size is recorded. `None` means the usage of the store isn't tracked
+    usage: Option<Arc<ResourceUsage>>,
+}
+
+impl TrackedLimits {
+    pub(crate) fn new(limits: StoreLimits, usage: Option<Arc<ResourceUsage>>) -> Self {
+        TrackedLimits { limits, usage }
+    }
+}
+
+impl ResourceLimiter for TrackedLimits {
+    fn memory_growing(&mut self, current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        let allowed = self.limits.memory_growing(current, desired, maximum);
+        if let Some(usage) = self.usage.as_ref().filter(|_| allowed) {
+            let grown = desired.saturating_sub(current) as u64 * WASM_PAGE_SIZE;
+            usage.memory_bytes.fetch_add(grown, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    fn table_growing(&mut self, current: u32, desired: u32, maximum: Option<u32>) -> bool {
+        self.limits.table_growing(current, desired, maximum)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances()
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables()
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wasmtime::{Engine, Instance, Module, Store, StoreLimitsBuilder};
+
+    /// Keeps the current thread busy for a while, so it uses some CPU time
+    fn spin(duration: Duration) {
+        let started = Instant::now();
+        while started.elapsed() < duration {}
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let usage = Arc::new(ResourceUsage::new());
+        assert_eq!(usage.usage().cpu_time, Duration::default());
+
+        let tracking = usage.track_current_thread();
+        spin(Duration::from_millis(20));
+        let running = usage.usage().cpu_time;
+        assert!(running > Duration::default());
+        spin(Duration::from_millis(20));
+        assert!(usage.usage().cpu_time > running);
+
+        // Once the module is done with the thread, its time no longer counts
+        drop(tracking);
+        let finished = usage.usage().cpu_time;
+        spin(Duration::from_millis(20));
+        assert_eq!(usage.usage().cpu_time, finished);
+    }
+
+    #[test]
+    fn test_memory_growth() {
+        let engine = Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "grow") (param i32) (result i32) (memory.grow (local.get 0))))"#,
+        )
+        .unwrap();
+        let usage = Arc::new(ResourceUsage::new());
+        let limits = StoreLimitsBuilder::new().memory_pages(4).build();
+        let mut store = Store::new(&engine, TrackedLimits::new(limits, Some(usage.clone())));
+        store.limiter(|limits| limits);
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let grow = instance
+            .get_typed_func::<i32, i32, _>(&mut store, "grow")
+            .unwrap();
+        let memory_bytes = || usage.usage().memory_bytes;
+        assert_eq!(memory_bytes(), WASM_PAGE_SIZE);
+
+        assert_eq!(grow.call(&mut store, 2).unwrap(), 1);
+        assert_eq!(memory_bytes(), 3 * WASM_PAGE_SIZE);
+        // Growth the limits refuse isn't counted
+        assert_eq!(grow.call(&mut store, 2).unwrap(), -1);
+        assert_eq!(memory_bytes(), 3 * WASM_PAGE_SIZE);
+        assert_eq!(grow.call(&mut store, 0).unwrap(), 3);
+        assert_eq!(memory_bytes(), 3 * WASM_PAGE_SIZE);
+    }
+}
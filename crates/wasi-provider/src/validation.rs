@@ -0,0 +1,197 @@
+//! Checks of a module that can be made before it is run, so a misbuilt module is reported with a
+//! clear error rather than failing while its run is being set up.
+use std::collections::HashSet;
+
+use wasi_cap_std_sync::WasiCtxBuilder;
+use wasmparser::{ExternalKind, ImportSectionEntryType, Parser, Payload};
+use wasmtime::{Linker, Module, Store};
+
+use crate::allow_list::AllowList;
+use crate::engine::Engines;
+use crate::features::WasmFeatures;
+use crate::host_functions::HostFunctionRegistry;
+use crate::http::{HttpCtx, HTTP_MODULE};
+use crate::sockets::{SocketsCtx, SOCKETS_MODULE};
+use crate::wasi_nn::{self, NnBackend};
+use crate::wasi_runtime::StoreData;
+
+/// Checks that the module is valid WebAssembly, that every import it has is a function the
+/// runtime provides, and that it exports the function it is run with. Modules that use the HTTP
+/// or socket functions must also export their memory, which the functions read requests from
+pub(crate) fn validate_module(
+    module_data: &[u8],
+    entrypoint: &str,
+    features: WasmFeatures,
+    host_functions: &HostFunctionRegistry,
+    nn_backend: Option<NnBackend>,
+) -> anyhow::Result<()> {
+    let (engine, _) = Engines::default().get(false, false, features)?;
+    Module::validate(&engine, module_data)?;
+
+    let mut linker = Linker::new(&engine);
+    wasmtime_wasi::add_to_linker(&mut linker, |data: &mut StoreData| &mut data.wasi)?;
+    HttpCtx::new(AllowList::default(), None, None, None).add_to_linker(&mut linker)?;
+    SocketsCtx::new(Vec::new()).add_to_linker(&mut linker)?;
+    host_functions.add_to_linker(&mut linker, "validation")?;
+    let mut store = Store::new(&engine, StoreData::unlimited(WasiCtxBuilder::new().build()));
+    if let Some(backend) = nn_backend {
+        wasi_nn::add_to_linker(&mut linker, store.data_mut(), backend)?;
+    }
+    let provided: HashSet<(String, String)> = linker
+        .iter(&mut store)
+        .map(|(module, name, _)| (module.to_owned(), name.to_owned()))
+        .collect();
+
+    let mut missing = Vec::new();
+    let mut uses_http = false;
+    let mut uses_sockets = false;
+    let mut exports_memory = false;
+    let mut exported_functions = HashSet::new();
+    for payload in Parser::new(0).parse_all(module_data) {
+        match payload? {
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    let import = import?;
+                    let name = import.field.unwrap_or_default();
+                    uses_http |= import.module == HTTP_MODULE;
+                    uses_sockets |= import.module == SOCKETS_MODULE;
+                    let is_function = matches!(import.ty, ImportSectionEntryType::Function(_));
+                    if !is_function
+                        || !provided.contains(&(import.module.to_owned(), name.to_owned()))
+                    {
+                        missing.push(format!("{}::{}", import.module, name));
+                    }
+                }
+            }
+            Payload::ExportSection(exports) => {
+                for export in exports {
+                    let export = export?;
+                    match export.kind {
+                        ExternalKind::Function => {
+                            exported_functions.insert(export.field.to_owned());
+                        }
+                        ExternalKind::Memory => {
+                            exports_memory |= export.field == "memory";
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "module imports {} that the runtime doesn't provide",
+            missing.join(", ")
+        );
+    }
+    if !exported_functions.contains(entrypoint) {
+        anyhow::bail!(
+            "module does not export an entrypoint function named {}",
+            entrypoint
+        );
+    }
+    if uses_http && !exports_memory {
+        anyhow::bail!("module uses the HTTP functions but does not export its memory");
+    }
+    if uses_sockets && !exports_memory {
+        anyhow::bail!("module uses the socket functions but does not export its memory");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The HTTP functions are linked against the runtime the module is validated on, so these
+    // tests need one as well
+    fn validate(module: &str) -> anyhow::Result<()> {
+        validate_module(
+            &wat::parse_str(module).unwrap(),
+            "_start",
+            WasmFeatures::default(),
+            &HostFunctionRegistry::default(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_validate_module() {
+        validate(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func (param i32 i32 i32 i32) (result i32)))
+                (func (export "_start")))"#,
+        )
+        .unwrap();
+
+        assert!(validate_module(
+            b"not wasm",
+            "_start",
+            WasmFeatures::default(),
+            &HostFunctionRegistry::default(),
+            None,
+        )
+        .is_err());
+
+        let e = validate(r#"(module (func (export "run")))"#).unwrap_err();
+        assert!(
+            e.to_string().contains("entrypoint function named _start"),
+            "{}",
+            e
+        );
+        let e = validate(r#"(module (global (export "_start") i32 (i32.const 0)))"#).unwrap_err();
+        assert!(
+            e.to_string().contains("entrypoint function named _start"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_module_imports() {
+        let e = validate(
+            r#"(module
+                (import "env" "missing" (func))
+                (import "wasi_snapshot_preview1" "fd_write" (memory 1))
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (func (export "_start")))"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            "module imports env::missing, wasi_snapshot_preview1::fd_write that the runtime doesn't provide",
+            e.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_module_memory_export() {
+        for (module, function) in &[(HTTP_MODULE, "close"), (SOCKETS_MODULE, "tcp_connect")] {
+            let without_memory = format!(
+                r#"(module
+                    (import "{}" "{}" (func))
+                    (memory 1)
+                    (func (export "_start")))"#,
+                module, function
+            );
+            let e = validate(&without_memory).unwrap_err();
+            assert!(
+                e.to_string().contains("does not export its memory"),
+                "{}",
+                e
+            );
+
+            let with_memory = format!(
+                r#"(module
+                    (import "{}" "{}" (func))
+                    (memory (export "memory") 1)
+                    (func (export "_start")))"#,
+                module, function
+            );
+            validate(&with_memory).unwrap();
+        }
+    }
+}
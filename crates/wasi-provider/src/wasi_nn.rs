@@ -0,0 +1,153 @@
+//! Support for modules that run machine learning inference with
+//! [wasi-nn](https://github.com/WebAssembly/wasi-nn).
+//!
+//! wasi-nn hands inference off to a native backend, which has to be installed on the node, so it
+//! is only available when krustlet is built with the `wasi-nn` feature and the node lists the
+//! backends it has installed. A pod picks the backend its modules use with an annotation, and
+//! only modules of pods that do so are given the wasi-nn functions.
+use std::fmt;
+use std::str::FromStr;
+
+use wasmtime::Linker;
+
+use crate::wasi_runtime::StoreData;
+
+/// A native backend wasi-nn can run inference with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum NnBackend {
+    OpenVino,
+}
+
+impl NnBackend {
+    // The wasi-nn implementation for this version of wasmtime only has an OpenVINO backend
+    const ALL: &'static [NnBackend] = &[NnBackend::OpenVino];
+
+    fn name(self) -> &'static str {
+        match self {
+            NnBackend::OpenVino => "openvino",
+        }
+    }
+}
+
+impl fmt::Display for NnBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for NnBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NnBackend::ALL
+            .iter()
+            .copied()
+            .find(|backend| backend.name() == s)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "unsupported wasi-nn backend {:?}, expected one of {}",
+                    s,
+                    NnBackend::ALL
+                        .iter()
+                        .map(|b| b.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+/// Parses the backends installed on the node. Listing any is an error if krustlet was built
+/// without wasi-nn, as pods could otherwise be scheduled expecting it
+pub(crate) fn parse_backends(names: &[String]) -> anyhow::Result<Vec<NnBackend>> {
+    let backends = names
+        .iter()
+        .map(|name| name.trim().parse())
+        .collect::<anyhow::Result<Vec<NnBackend>>>()?;
+    if !backends.is_empty() && !cfg!(feature = "wasi-nn") {
+        anyhow::bail!("wasi-nn backends are configured but krustlet was built without wasi-nn");
+    }
+    Ok(backends)
+}
+
+/// The backend a pod's annotation selects, which must be one the node has installed
+pub(crate) fn select_backend(
+    annotation: &str,
+    installed: &[NnBackend],
+) -> anyhow::Result<NnBackend> {
+    let backend: NnBackend = annotation.trim().parse()?;
+    if !installed.contains(&backend) {
+        anyhow::bail!("wasi-nn backend {} is not installed on this node", backend);
+    }
+    Ok(backend)
+}
+
+/// Gives the module the wasi-nn functions, backed by a fresh context in its store
+#[cfg(feature = "wasi-nn")]
+pub(crate) fn add_to_linker(
+    linker: &mut Linker<StoreData>,
+    data: &mut StoreData,
+    backend: NnBackend,
+) -> anyhow::Result<()> {
+    // Backends are picked by the module when it loads a graph. OpenVINO is the only one there is
+    let NnBackend::OpenVino = backend;
+    data.wasi_nn = Some(wasmtime_wasi_nn::WasiNnCtx::new()?);
+    wasmtime_wasi_nn::add_to_linker(linker, |data: &mut StoreData| {
+        data.wasi_nn
+            .as_mut()
+            .expect("the wasi-nn context is set when its functions are linked")
+    })
+}
+
+/// Gives the module the wasi-nn functions, which krustlet was built without
+#[cfg(not(feature = "wasi-nn"))]
+pub(crate) fn add_to_linker(
+    _linker: &mut Linker<StoreData>,
+    _data: &mut StoreData,
+    backend: NnBackend,
+) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "unable to use wasi-nn backend {}: krustlet was built without wasi-nn",
+        backend
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_backends() {
+        assert!(parse_backends(&[]).unwrap().is_empty());
+        let e = parse_backends(&names(&["openvino", "tensorflow"])).unwrap_err();
+        assert!(e.to_string().contains("\"tensorflow\""), "{}", e);
+        assert!(e.to_string().contains("expected one of openvino"), "{}", e);
+
+        let parsed = parse_backends(&names(&[" openvino "]));
+        if cfg!(feature = "wasi-nn") {
+            assert_eq!(vec![NnBackend::OpenVino], parsed.unwrap());
+        } else {
+            let e = parsed.unwrap_err();
+            assert!(e.to_string().contains("built without wasi-nn"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_select_backend() {
+        assert_eq!(
+            NnBackend::OpenVino,
+            select_backend(" openvino", &[NnBackend::OpenVino]).unwrap()
+        );
+        let e = select_backend("openvino", &[]).unwrap_err();
+        assert_eq!(
+            "wasi-nn backend openvino is not installed on this node",
+            e.to_string()
+        );
+        assert!(select_backend("OpenVINO", &[NnBackend::OpenVino]).is_err());
+        assert!(select_backend("", &[NnBackend::OpenVino]).is_err());
+    }
+}
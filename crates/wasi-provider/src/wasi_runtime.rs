@@ -1,23 +1,159 @@
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, instrument, trace, warn};
 
+use futures::future::BoxFuture;
+use ipnet::IpNet;
 use tempfile::NamedTempFile;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{watch, Semaphore};
 use tokio::task::JoinHandle;
 use wasi_cap_std_sync::WasiCtxBuilder;
-use wasmtime::{InterruptHandle, Linker};
+use wasi_common::dir::DirCaps;
+use wasi_common::file::FileCaps;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasi_common::{WasiCtx, WasiFile};
+use wasmtime::{InterruptHandle, Linker, Memory, Store, StoreLimits, StoreLimitsBuilder};
 
+use kubelet::container::probe::ProbeOutcome;
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
-use kubelet::handle::StopHandler;
+use kubelet::exec::Output as ExecOutput;
+use kubelet::handle::{ExecHandler, StopHandler, UsageHandler};
+use kubelet::log::{LineFormat, Stream};
+use kubelet::pod::dns::ResolverConfig;
+use kubelet::stats::Usage;
 
-use wasi_experimental_http_wasmtime::HttpCtx as WasiHttpCtx;
+use crate::allow_list::AllowList;
+use crate::audit::CallAudit;
+use crate::capabilities::WasiCapabilities;
+use crate::compile_cache::CompileCache;
+use crate::dns::Resolver;
+use crate::engine::Engines;
+use crate::error::ContainerError;
+use crate::features::WasmFeatures;
+use crate::host_functions::HostFunctionRegistry;
+use crate::http::{CaBundle, ClientCert, HttpCtx, RetryPolicy};
+use crate::identity::IdentityDir;
+use crate::lifecycle::LifecycleHook;
+use crate::output::{
+    LogFormat, LogRotation, OutputBuffer, OutputIndex, OutputReader, OutputWriter, StreamWriter,
+};
+use crate::sockets::{Endpoint, SocketsCtx};
+use crate::usage::{ResourceUsage, TrackedLimits};
+use crate::wasi_nn::{self, NnBackend};
+
+/// How long a command exec'd into a container may run before it is stopped
+const EXEC_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How much fuel a module limited to less than a whole CPU burns through before it yields, so
+/// its CPU use can be checked. Roughly one unit of fuel is consumed per instruction, so this is a
+/// few milliseconds of work
+const THROTTLE_FUEL_SLICE: u64 = 10_000_000;
+
+/// The fuel a throttled module starts with and how many more slices it is refilled with, so
+/// that it gets exactly its fuel limit if it has one. The first slice holds whatever doesn't
+/// divide into whole slices
+fn throttle_slices(fuel: Option<u64>) -> (u64, u32) {
+    match fuel {
+        None => (THROTTLE_FUEL_SLICE, u32::MAX),
+        Some(fuel) => {
+            let slices = fuel / THROTTLE_FUEL_SLICE + (fuel % THROTTLE_FUEL_SLICE != 0) as u64;
+            let refills = slices.saturating_sub(1).min(u32::MAX as u64);
+            (fuel - refills * THROTTLE_FUEL_SLICE, refills as u32)
+        }
+    }
+}
+
+/// The size of a page of WebAssembly linear memory, the unit memories grow by
+pub(crate) const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// The function WASI commands export to be run
+pub(crate) const DEFAULT_ENTRYPOINT: &str = "_start";
+
+/// The exit code reported for modules that trap, which is what the `wasmtime` CLI exits with as
+/// well: 128 plus the number of `SIGABRT`
+const TRAP_EXIT_CODE: i32 = 134;
+/// The exit code reported for modules that are stopped forcibly or run out of memory, as a
+/// process killed with `SIGKILL` would have
+const KILLED_EXIT_CODE: i32 = 137;
+/// The most frames of a trap's backtrace included in the container status
+const MAX_BACKTRACE_FRAMES: usize = 16;
+
+/// The magic number every WebAssembly binary, core module or component, starts with
+const WASM_MAGIC: &[u8] = b"\0asm";
+/// The layer field that follows the version in the preamble of a component. Core modules have
+/// a layer of 0 as their version is 4 bytes long
+const COMPONENT_LAYER: &[u8] = &[0x01, 0x00];
+
+/// Whether the binary is a component rather than a core module, as told by its preamble
+fn is_component(module_data: &[u8]) -> bool {
+    module_data.starts_with(WASM_MAGIC) && module_data.get(6..8) == Some(COMPONENT_LAYER)
+}
+
+/// Whether modules that use SIMD instructions can run on this node. Cranelift needs SSE4.1 to
+/// compile SIMD on x86_64
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn simd_supported() -> bool {
+    is_x86_feature_detected!("sse4.1")
+}
+
+/// Whether modules that use SIMD instructions can run on this node. Cranelift always supports
+/// SIMD on aarch64
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn simd_supported() -> bool {
+    true
+}
+
+/// Whether modules that use SIMD instructions can run on this node. Cranelift doesn't support
+/// SIMD on other architectures
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn simd_supported() -> bool {
+    false
+}
+
+/// How a running module has been asked to stop, which decides how its termination is reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StopRequest {
+    /// The module hasn't been asked to stop
+    None,
+    /// The module is being given its grace period to exit
+    Graceful,
+    /// The module didn't exit within its grace period and is being forcibly stopped
+    GracePeriodExpired,
+}
+
+/// Interrupts a running module. Host calls that can block for a long time, such as receiving on
+/// a socket, watch for the module being interrupted so they don't hold it up
+pub(crate) struct Interrupt {
+    handle: InterruptHandle,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    pub(crate) fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.handle.interrupt();
+    }
+}
 
 pub struct Runtime {
     handle: JoinHandle<anyhow::Result<()>>,
-    interrupt_handle: InterruptHandle,
+    /// The result of the module run, if it was already awaited while stopping gracefully
+    exit: Option<Result<anyhow::Result<()>, tokio::task::JoinError>>,
+    interrupt_handle: Interrupt,
+    stop_request: Arc<Mutex<StopRequest>>,
+    /// The runtime the module was started from, which hooks and exec'd commands run against
+    runtime: WasiRuntime,
+    /// The hook to run before the module is gracefully stopped
+    pre_stop: Option<LifecycleHook>,
+    /// The resources the module has used
+    usage: Arc<ResourceUsage>,
 }
 
 #[async_trait::async_trait]
@@ -27,14 +163,68 @@ impl StopHandler for Runtime {
         Ok(())
     }
 
+    // WASI has no way of signalling a module, so the preStop hook is the only
+    // notice it gets. After that it has whatever is left of the grace period to
+    // exit on its own before it is interrupted
+    async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + grace_period;
+        *self.stop_request.lock().unwrap() = StopRequest::Graceful;
+        if let Some(hook) = &self.pre_stop {
+            info!(?grace_period, "Running preStop hook");
+            match hook.run(&self.runtime, grace_period).await {
+                ProbeOutcome::Success => debug!("preStop hook completed"),
+                ProbeOutcome::Failure(reason) => warn!(%reason, "preStop hook failed"),
+            }
+        }
+        if self.exit.is_none() {
+            match tokio::time::timeout_at(deadline, &mut self.handle).await {
+                Ok(exit) => {
+                    debug!("Module exited within its grace period");
+                    self.exit = Some(exit);
+                    return Ok(());
+                }
+                Err(_) => {
+                    info!(
+                        ?grace_period,
+                        "Grace period expired, forcibly stopping module"
+                    );
+                    *self.stop_request.lock().unwrap() = StopRequest::GracePeriodExpired;
+                }
+            }
+        }
+        self.stop().await
+    }
+
     async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
+        match self.exit.take() {
+            Some(exit) => exit??,
+            None => (&mut self.handle).await??,
+        }
         Ok(())
     }
 }
 
+impl ExecHandler for Runtime {
+    fn exec(&self, command: Vec<String>) -> BoxFuture<'static, anyhow::Result<ExecOutput>> {
+        let runtime = self.runtime.clone();
+        Box::pin(async move {
+            runtime
+                .run_command(command, EXEC_TIMEOUT)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("command did not finish within {:?}", EXEC_TIMEOUT))
+        })
+    }
+}
+
+impl UsageHandler for Runtime {
+    fn usage(&self) -> Usage {
+        self.usage.usage()
+    }
+}
+
 /// WasiRuntime provides a WASI compatible runtime. A runtime should be used for
 /// each "instance" of a process and can be passed to a thread pool for running
+#[derive(Clone)]
 pub struct WasiRuntime {
     /// name of the process
     name: String,
@@ -46,13 +236,141 @@ pub struct WasiRuntime {
     status_sender: Sender<Status>,
     /// Configuration for the WASI http
     http_config: WasiHttpConfig,
+    /// The endpoints the module may open TCP and UDP connections to
+    allowed_endpoints: Vec<Endpoint>,
+    /// What the module reads from stdin. `None` means stdin is empty
+    stdin: Option<Arc<Vec<u8>>>,
+    /// Configuration for executing the module
+    exec_config: WasiExecConfig,
+    /// The hook to run before the module is gracefully stopped
+    pre_stop: Option<LifecycleHook>,
+    /// When to rotate the output tempfile, if it should be rotated at all
+    log_rotation: Option<LogRotation>,
+    /// How much of the most recent output is kept in memory to serve logs from
+    log_buffer_size: usize,
+    /// How lines of output are written to the tempfile
+    log_format: LogFormat,
+    /// Where compiled modules are cached, if they should be cached at all
+    compile_cache: Option<CompileCache>,
+    /// Permits bounding how many modules are compiled at once, if compilation is bounded at all
+    compile_permits: Option<Arc<Semaphore>>,
+    /// Where the engine the module runs on comes from, if not a fresh engine for every run
+    engines: Option<Arc<Engines>>,
+    /// The host functions the module may import beyond the ones krustlet provides
+    host_functions: Option<Arc<HostFunctionRegistry>>,
+    /// How long setting up the module may take, if it may only take so long
+    start_timeout: Option<Duration>,
+}
+
+impl std::fmt::Debug for WasiRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WasiRuntime")
+            .field("name", &self.name)
+            .finish()
+    }
 }
 
 // Configuration for WASI http.
 #[derive(Clone, Default)]
 pub struct WasiHttpConfig {
     pub allowed_domains: Option<Vec<String>>,
+    /// Network ranges requests may be sent to in addition to `allowed_domains`
+    pub allowed_networks: Vec<IpNet>,
     pub max_concurrent_requests: Option<u32>,
+    /// The DNS settings request hosts are resolved with, if they aren't resolved by the node
+    pub resolver: Option<ResolverConfig>,
+    /// How long a request may take before it is abandoned. `None` means requests can take as long
+    /// as the server does
+    pub request_timeout: Option<Duration>,
+    /// How failed requests are retried. `None` means every request is only tried once
+    pub retry: Option<RetryPolicy>,
+    /// The certificate requests present to servers that ask for one, as for mutual TLS
+    pub client_cert: Option<ClientCert>,
+    /// The certificate authorities servers are verified against in addition to, or instead of,
+    /// the system's
+    pub ca_bundle: Option<CaBundle>,
+}
+
+// Configuration for module execution.
+#[derive(Clone, Default)]
+pub struct WasiExecConfig {
+    /// The amount of fuel the module may consume before it is trapped. `None`
+    /// means the module can run for as long as it likes
+    pub fuel: Option<u64>,
+    /// The maximum size in bytes the module's linear memory may grow to
+    pub memory_limit: Option<u64>,
+    /// The share of a CPU the module may use, in millicores. Modules run on a single thread, so
+    /// a limit of 1000 or more doesn't restrict them
+    pub cpu_limit: Option<u64>,
+    /// The exported function called to run the module. `None` means the WASI default of `_start`
+    pub entrypoint: Option<String>,
+    /// The WASI capabilities the module is given
+    pub capabilities: WasiCapabilities,
+    /// The user the module accesses files as. `None` means krustlet's own user
+    pub run_as_user: Option<u32>,
+    /// The group the module accesses files as. `None` means krustlet's own group
+    pub run_as_group: Option<u32>,
+    /// The WebAssembly features the module is compiled with
+    pub(crate) features: WasmFeatures,
+    /// The host directory relative paths are opened in, and whether it is read only. `None`
+    /// means relative paths can't be opened
+    pub working_dir: Option<(PathBuf, bool)>,
+    /// The wasi-nn backend the module runs inference with. `None` means it isn't given wasi-nn
+    pub(crate) nn_backend: Option<NnBackend>,
+    /// Whether the module's host calls are counted and logged when it exits
+    pub audit_calls: bool,
+    /// The most threads the module may run on. `None` leaves it to wasmtime's default limits
+    pub max_threads: Option<u32>,
+}
+
+impl WasiExecConfig {
+    /// The limits of the store the module runs in. Each thread of a module is an instance of it,
+    /// so its thread limit caps the instances in the store, leaving room for the module that
+    /// forwards audited host calls
+    fn store_limits(&self) -> StoreLimits {
+        let mut limits = StoreLimitsBuilder::new();
+        if let Some(memory_limit) = self.memory_limit {
+            debug!(memory_limit, "Configuring memory limit for module");
+            // Memories grow a page at a time, so round the limit down to whole pages
+            let pages = (memory_limit / WASM_PAGE_SIZE).min(u32::MAX as u64);
+            limits = limits.memory_pages(pages as u32);
+        }
+        if let Some(max_threads) = self.max_threads {
+            debug!(max_threads, "Configuring thread limit for module");
+            limits = limits.instances(max_threads as usize + self.audit_calls as usize);
+        }
+        limits.build()
+    }
+
+    /// The CPU limit the module is throttled to, in millicores. `None` means the module runs
+    /// without being throttled, as it does when the limit is a whole CPU or more
+    pub(crate) fn throttle(&self) -> Option<u64> {
+        match self.cpu_limit {
+            Some(millicores) if millicores > 0 && millicores < 1000 => Some(millicores),
+            _ => None,
+        }
+    }
+}
+
+/// The data stored alongside a module in its wasmtime store
+pub struct StoreData {
+    pub(crate) wasi: WasiCtx,
+    limits: TrackedLimits,
+    /// The context of the wasi-nn functions, if the module was given them
+    #[cfg(feature = "wasi-nn")]
+    pub(crate) wasi_nn: Option<wasmtime_wasi_nn::WasiNnCtx>,
+}
+
+impl StoreData {
+    /// Data for a store whose memory isn't limited
+    pub(crate) fn unlimited(wasi: WasiCtx) -> Self {
+        StoreData {
+            wasi,
+            limits: TrackedLimits::new(StoreLimitsBuilder::new().build(), None),
+            #[cfg(feature = "wasi-nn")]
+            wasi_nn: None,
+        }
+    }
 }
 
 struct Data {
@@ -64,19 +382,53 @@ struct Data {
     args: Vec<String>,
     /// a hash map of local file system paths to optional path names in the runtime
     /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    /// the same path will be allowed in the runtime
-    dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// the same path will be allowed in the runtime. The flag marks directories that should be
+    /// mounted read only
+    dirs: HashMap<PathBuf, (Option<PathBuf>, bool)>,
 }
 
 /// Holds our tempfile handle.
 pub struct HandleFactory {
     temp: Arc<NamedTempFile>,
+    /// When output was written to the tempfile
+    index: OutputIndex,
+    /// The most recent output, which is read ahead of the tempfile
+    buffer: OutputBuffer,
+    /// Closed once the module has stopped writing output
+    closed: watch::Receiver<()>,
+    /// How the tempfile is rotated, so the rotated copies can be cleaned up along with it
+    rotation: Option<LogRotation>,
+    /// How lines in the tempfile record their stream, either tagged or formatted as JSON
+    line_format: LineFormat,
+}
+
+impl Drop for HandleFactory {
+    fn drop(&mut self) {
+        if let Some(rotation) = self.rotation {
+            rotation.remove_rotated(self.temp.path());
+        }
+    }
 }
 
-impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
-    /// Creates `tokio::fs::File` on demand for log reading.
-    fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
+impl kubelet::log::HandleFactory<OutputReader> for HandleFactory {
+    /// Creates an `OutputReader` over the tempfile on demand for log reading.
+    fn new_handle(&self) -> OutputReader {
+        OutputReader::new(
+            tokio::fs::File::from_std(self.temp.reopen().unwrap()),
+            self.buffer.clone(),
+        )
+    }
+
+    fn offset_since(&self, since: chrono::DateTime<chrono::Utc>) -> Option<u64> {
+        Some(self.index.offset_since(since))
+    }
+
+    fn closed(&self) -> Option<watch::Receiver<()>> {
+        Some(self.closed.clone())
+    }
+
+    fn line_format(&self) -> LineFormat {
+        self.line_format
     }
 }
 
@@ -87,22 +439,34 @@ impl WasiRuntime {
     ///
     /// * `module_path` - the path to the WebAssembly binary
     /// * `env` - a collection of key/value pairs containing the environment variables
-    /// * `args` - the arguments passed as the command-line arguments list
+    /// * `args` - the command-line arguments list, passed to the module as is starting at
+    ///     `argv[0]`
     /// * `dirs` - a map of local file system paths to optional path names in the runtime
     ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    ///     the same path will be allowed in the runtime
+    ///     the same path will be allowed in the runtime. Directories flagged as read only are
+    ///     opened without any capabilities that would allow the module to modify them
     /// * `log_dir` - location for storing logs
+    /// * `exec_config` - limits applied to the module while it runs
     #[allow(clippy::too_many_arguments)]
     pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
         name: String,
         module_data: Vec<u8>,
         env: HashMap<String, String>,
         args: Vec<String>,
-        dirs: HashMap<PathBuf, Option<PathBuf>>,
+        dirs: HashMap<PathBuf, (Option<PathBuf>, bool)>,
         log_dir: L,
         status_sender: Sender<Status>,
         http_config: WasiHttpConfig,
+        exec_config: WasiExecConfig,
     ) -> anyhow::Result<Self> {
+        // The version of wasmtime this runtime is built on predates the component model, so
+        // components can only be rejected with a clearer error than compiling them would give
+        if is_component(&module_data) {
+            anyhow::bail!(
+                "module is a WebAssembly component, but only core WASI modules are supported"
+            );
+        }
+
         let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
             Ok(NamedTempFile::new_in(log_dir)?)
         })
@@ -125,42 +489,320 @@ impl WasiRuntime {
             output: Arc::new(temp),
             status_sender,
             http_config,
+            allowed_endpoints: Vec::new(),
+            stdin: None,
+            exec_config,
+            pre_stop: None,
+            log_rotation: None,
+            log_buffer_size: 0,
+            log_format: LogFormat::Raw,
+            compile_cache: None,
+            compile_permits: None,
+            engines: None,
+            host_functions: None,
+            start_timeout: None,
         })
     }
 
+    /// Sets the hook to run before the module is gracefully stopped
+    pub(crate) fn with_pre_stop(mut self, pre_stop: Option<LifecycleHook>) -> Self {
+        self.pre_stop = pre_stop;
+        self
+    }
+
+    /// Sets the endpoints the module may open TCP and UDP connections to
+    pub(crate) fn with_allowed_endpoints(mut self, allowed_endpoints: Vec<Endpoint>) -> Self {
+        self.allowed_endpoints = allowed_endpoints;
+        self
+    }
+
+    /// Sets what the module reads from stdin
+    pub(crate) fn with_stdin(mut self, stdin: Option<Vec<u8>>) -> Self {
+        self.stdin = stdin.map(Arc::new);
+        self
+    }
+
+    /// Sets the limits the module's output is rotated at
+    pub(crate) fn with_log_rotation(mut self, log_rotation: Option<LogRotation>) -> Self {
+        self.log_rotation = log_rotation;
+        self
+    }
+
+    /// Sets how much of the module's most recent output is kept in memory to serve logs from
+    pub(crate) fn with_log_buffer_size(mut self, log_buffer_size: usize) -> Self {
+        self.log_buffer_size = log_buffer_size;
+        self
+    }
+
+    /// Sets how lines of the module's output are written to its log
+    pub(crate) fn with_log_format(mut self, log_format: LogFormat) -> Self {
+        self.log_format = log_format;
+        self
+    }
+
+    /// Sets the cache compiled modules are loaded from and saved to
+    pub(crate) fn with_compile_cache(mut self, compile_cache: Option<CompileCache>) -> Self {
+        self.compile_cache = compile_cache;
+        self
+    }
+
+    /// Sets the permits the module must acquire before it is compiled, which bound how many
+    /// modules are compiled at once
+    pub(crate) fn with_compile_permits(mut self, compile_permits: Option<Arc<Semaphore>>) -> Self {
+        self.compile_permits = compile_permits;
+        self
+    }
+
+    /// Sets how long setting up the module may take before starting it fails. Both the setup
+    /// before the module is compiled and the compilation itself get this long, so time spent
+    /// waiting for other modules to finish compiling doesn't count towards it
+    pub(crate) fn with_start_timeout(mut self, start_timeout: Option<Duration>) -> Self {
+        self.start_timeout = start_timeout;
+        self
+    }
+
+    /// The deadline a step of setting up the module started now has to finish by
+    fn start_deadline(&self) -> Option<tokio::time::Instant> {
+        self.start_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout)
+    }
+
+    /// Waits for a step of setting up the module, failing it if the deadline passes first
+    async fn before_deadline<T>(
+        &self,
+        deadline: Option<tokio::time::Instant>,
+        step: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        match (deadline, self.start_timeout) {
+            (Some(deadline), Some(timeout)) => tokio::time::timeout_at(deadline, step)
+                .await
+                .map_err(|_| ContainerError::StartTimedOut(timeout))?,
+            _ => step.await,
+        }
+    }
+
+    /// Sets where the engine the module runs on comes from, which can share a pool of instances
+    /// between modules
+    pub(crate) fn with_engines(mut self, engines: Option<Arc<Engines>>) -> Self {
+        self.engines = engines;
+        self
+    }
+
+    /// Sets the host functions the module may import beyond the ones krustlet provides
+    pub(crate) fn with_host_functions(
+        mut self,
+        host_functions: Option<Arc<HostFunctionRegistry>>,
+    ) -> Self {
+        self.host_functions = host_functions;
+        self
+    }
+
+    /// Reads the last `max_lines` lines of the module's output, from at most its last `max_bytes`.
+    /// Lines are read as the module wrote them, without the stream tag or JSON object they were
+    /// logged in, and a line cut off by the byte limit is left out
+    pub(crate) async fn output_tail(
+        &self,
+        max_lines: usize,
+        max_bytes: u64,
+    ) -> std::io::Result<String> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut file = tokio::fs::File::open(self.output.path()).await?;
+        let len = file.metadata().await?.len();
+        // The byte before the tail is read too, as it says whether the tail starts a line
+        let start = len.saturating_sub(max_bytes.saturating_add(1));
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await?;
+        let tail = if len <= max_bytes {
+            &tail[..]
+        } else {
+            match tail.iter().position(|b| *b == b'\n') {
+                Some(end) => &tail[end + 1..],
+                None => &[],
+            }
+        };
+        let tail = String::from_utf8_lossy(tail);
+        let lines: Vec<_> = tail
+            .lines()
+            .map(|line| self.log_format.message(line))
+            .collect();
+        Ok(lines[lines.len().saturating_sub(max_lines)..].join("\n"))
+    }
+
+    /// Runs a separate instance of the module to completion with the given arguments, sharing
+    /// the environment, mounts and configuration of this runtime. This is how exec probes are run
+    /// as there is no process to exec into. The instance is stopped if it doesn't finish within
+    /// the timeout
+    pub async fn exec(&self, args: Vec<String>, timeout: Duration) -> anyhow::Result<ProbeOutcome> {
+        let outcome = match self.run_command(args, timeout).await? {
+            Some(output) if output.exit_code == 0 => ProbeOutcome::Success,
+            Some(output) => ProbeOutcome::Failure(format!(
+                "exec probe exited with code {}: {}",
+                output.exit_code,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            None => ProbeOutcome::Failure(format!("exec probe timed out after {:?}", timeout)),
+        };
+        Ok(outcome)
+    }
+
+    /// Runs a separate instance of the module to completion with the given arguments, capturing
+    /// its output and exit code. The instance shares the environment, mounts and configuration of
+    /// this runtime. Returns `None` if the instance didn't finish within the timeout, in which case
+    /// it is stopped
+    pub async fn run_command(
+        &self,
+        args: Vec<String>,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<ExecOutput>> {
+        // Nothing watches the statuses of the instance, but the channel has
+        // to stay open while it runs
+        let (status_sender, _status_receiver) = mpsc::channel(8);
+        let runtime = WasiRuntime {
+            name: format!("{}:exec", self.name),
+            data: Arc::new(Data {
+                module_data: self.data.module_data.clone(),
+                env: self.data.env.clone(),
+                args,
+                dirs: self.data.dirs.clone(),
+            }),
+            status_sender,
+            pre_stop: None,
+            // Commands exec'd into the container don't share its stdin
+            stdin: None,
+            ..self.clone()
+        };
+        let stdout = WritePipe::new_in_memory();
+        let stderr = WritePipe::new_in_memory();
+        let (closed, _) = watch::channel(());
+        // Commands aren't counted towards the container's usage
+        let (interrupt_handle, mut handle) = runtime
+            .spawn_wasmtime(
+                Box::new(stdout.clone()),
+                Box::new(stderr.clone()),
+                Arc::new(Mutex::new(StopRequest::None)),
+                closed,
+                Arc::new(ResourceUsage::new()),
+                runtime.start_deadline(),
+            )
+            .await?;
+
+        let result = match tokio::time::timeout(timeout, &mut handle).await {
+            Ok(result) => result?,
+            Err(_) => {
+                interrupt_handle.interrupt();
+                if let Err(e) = handle.await {
+                    warn!(error = %e, "Timed out command did not stop cleanly");
+                }
+                return Ok(None);
+            }
+        };
+        // Modules that trap without exiting don't have an exit code of their
+        // own, so they are reported as a generic failure
+        let exit_code = match &result {
+            Ok(_) => 0,
+            Err(e) => exit_status(e).unwrap_or(1),
+        };
+        // The instance has finished, so its output pipes are no longer shared
+        let into_output = |pipe: WritePipe<std::io::Cursor<Vec<u8>>>| {
+            pipe.try_into_inner()
+                .map(|cursor| cursor.into_inner())
+                .map_err(|_| anyhow::anyhow!("command output is still in use"))
+        };
+        Ok(Some(ExecOutput {
+            stdout: into_output(stdout)?,
+            stderr: into_output(stderr)?,
+            exit_code,
+        }))
+    }
+
     pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
+        let setup_deadline = self.start_deadline();
         let temp = self.output.clone();
         // Because a reopen is blocking, run in a blocking task to get new
         // handles to the tempfile
-        let output_write = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
-            Ok(temp.reopen()?)
-        })
-        .await??;
+        let output_write = self
+            .before_deadline(setup_deadline, async move {
+                tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+                    Ok(temp.reopen()?)
+                })
+                .await?
+            })
+            .await?;
 
+        let index = OutputIndex::default();
+        let buffer = OutputBuffer::new(self.log_buffer_size);
+        let (closed_tx, closed_rx) = watch::channel(());
+        let stop_request = Arc::new(Mutex::new(StopRequest::None));
+        // Standard output and error share a writer so they are interleaved in
+        // the log in the order they were written, with each line tagged with
+        // the stream it came from
+        let output_writer = Arc::new(Mutex::new(
+            OutputWriter::new(output_write, index.clone(), buffer.clone())
+                .with_rotation(self.output.path().to_owned(), self.log_rotation),
+        ));
+        let stdout = WritePipe::new(StreamWriter::new(
+            Stream::Stdout,
+            output_writer.clone(),
+            self.log_format.clone(),
+        ));
+        let stderr = WritePipe::new(StreamWriter::new(
+            Stream::Stderr,
+            output_writer,
+            self.log_format.clone(),
+        ));
+        let usage = Arc::new(ResourceUsage::new());
         let (interrupt_handle, handle) = self
-            .spawn_wasmtime(tokio::fs::File::from_std(output_write))
+            .spawn_wasmtime(
+                Box::new(stdout),
+                Box::new(stderr),
+                stop_request.clone(),
+                closed_tx,
+                usage.clone(),
+                setup_deadline,
+            )
             .await?;
 
         let log_handle_factory = HandleFactory {
             temp: self.output.clone(),
+            index,
+            buffer,
+            closed: closed_rx,
+            rotation: self.log_rotation,
+            line_format: match self.log_format {
+                LogFormat::Raw => LineFormat::Tagged,
+                LogFormat::Json { .. } => LineFormat::Json,
+            },
         };
 
         Ok(ContainerHandle::new(
             Runtime {
                 handle,
+                exit: None,
                 interrupt_handle,
+                stop_request,
+                runtime: self.clone(),
+                pre_stop: self.pre_stop.clone(),
+                usage,
             },
             log_handle_factory,
         ))
     }
 
     // Spawns a running wasmtime instance with the given context and status
-    // channel.
-    #[instrument(level = "info", skip(self, output_write), fields(name = %self.name))]
+    // channel. The setup before the module is compiled has to finish by the
+    // given deadline, and compiling gets as long again once it may begin.
+    #[instrument(level = "info", skip(self, stdout, stderr, closed, usage, setup_deadline), fields(name = %self.name))]
     async fn spawn_wasmtime(
         &self,
-        output_write: tokio::fs::File,
-    ) -> anyhow::Result<(InterruptHandle, JoinHandle<anyhow::Result<()>>)> {
+        stdout: Box<dyn WasiFile>,
+        stderr: Box<dyn WasiFile>,
+        stop_request: Arc<Mutex<StopRequest>>,
+        closed: watch::Sender<()>,
+        usage: Arc<ResourceUsage>,
+        setup_deadline: Option<tokio::time::Instant>,
+    ) -> anyhow::Result<(Interrupt, JoinHandle<anyhow::Result<()>>)> {
         // Clone the module data Arc so it can be moved
         let data = self.data.clone();
         let status_sender = self.status_sender.clone();
@@ -172,45 +814,186 @@ impl WasiRuntime {
             .iter()
             .map(|(k, v)| (k.to_string(), v.to_string()))
             .collect();
-        let stdout = wasi_cap_std_sync::file::File::from_cap_std(unsafe {
-            cap_std::fs::File::from_std(output_write.try_clone().await?.into_std().await)
-        });
-        let stderr = wasi_cap_std_sync::file::File::from_cap_std(unsafe {
-            cap_std::fs::File::from_std(output_write.try_clone().await?.into_std().await)
-        });
-
+        let capabilities = self.exec_config.capabilities;
         // Create the WASI context builder and pass arguments, environment,
         // and standard output and error.
-        let mut builder = WasiCtxBuilder::new()
-            .args(&data.args)?
-            .envs(&env)?
-            .stdout(Box::new(stdout))
-            .stderr(Box::new(stderr));
-
-        // Add preopen dirs.
-        for (key, value) in data.dirs.iter() {
-            let guest_dir = value.as_ref().unwrap_or(key);
+        let mut builder = WasiCtxBuilder::new().args(&data.args)?;
+        if capabilities.env {
+            builder = builder.envs(&env)?;
+        }
+        let mut builder = builder.stdout(stdout).stderr(stderr);
+        if let Some(stdin) = &self.stdin {
+            builder = builder.stdin(Box::new(ReadPipe::from(stdin.as_ref().clone())));
+        }
+
+        let mut ctx = builder.build();
+        capabilities.restrict(&mut ctx);
+
+        // Add preopen dirs. These are inserted directly rather than through
+        // the builder so that read only mounts can be given restricted
+        // capabilities. Descriptors 0-2 are taken by stdio. Modules without
+        // the filesystem capability get no directories at all. The working
+        // directory comes first, as `.`, so relative paths the module opens
+        // resolve in it. It stays reachable at its own path through its mount
+        let working_dir = self
+            .exec_config
+            .working_dir
+            .as_ref()
+            .map(|(host_dir, read_only)| (host_dir, PathBuf::from("."), *read_only));
+        let mounts = data.dirs.iter().map(|(key, (value, read_only))| {
+            (
+                key,
+                value.clone().unwrap_or_else(|| key.clone()),
+                *read_only,
+            )
+        });
+        let dirs: Vec<_> = working_dir
+            .into_iter()
+            .chain(mounts)
+            .filter(|_| capabilities.filesystem)
+            .map(|(key, guest_dir, read_only)| (key.clone(), guest_dir, read_only))
+            .collect();
+        for (key, guest_dir, read_only) in &dirs {
             debug!(
                 hostpath = %key.display(),
                 guestpath = %guest_dir.display(),
+                read_only,
                 "mounting hostpath in modules"
             );
-            let preopen_dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
+        }
+        // Opening a directory blocks, and can hang on a slow or unresponsive filesystem
+        let dirs = self
+            .before_deadline(setup_deadline, async move {
+                tokio::task::spawn_blocking(move || {
+                    dirs.into_iter()
+                        .map(|(key, guest_dir, read_only)| {
+                            let dir = unsafe { cap_std::fs::Dir::open_ambient_dir(key) }?;
+                            Ok((dir, guest_dir, read_only))
+                        })
+                        .collect::<anyhow::Result<Vec<_>>>()
+                })
+                .await?
+            })
+            .await?;
+        for (fd, (preopen_dir, guest_dir, read_only)) in (3..).zip(dirs) {
+            let (dir_caps, file_caps) = if read_only {
+                (read_only_dir_caps(), read_only_file_caps())
+            } else {
+                (DirCaps::all(), FileCaps::all())
+            };
 
-            builder = builder.preopened_dir(preopen_dir, guest_dir)?;
+            // The module's file system calls are made as the user its container runs as
+            ctx.insert_dir(
+                fd,
+                IdentityDir::wrap(
+                    Box::new(wasi_cap_std_sync::dir::Dir::from_cap_std(preopen_dir)),
+                    self.exec_config.run_as_user,
+                    self.exec_config.run_as_group,
+                ),
+                dir_caps,
+                file_caps,
+                guest_dir,
+            );
         }
 
-        let ctx = builder.build();
+        let WasiExecConfig {
+            fuel,
+            memory_limit,
+            entrypoint,
+            features,
+            audit_calls,
+            ..
+        } = self.exec_config.clone();
+        let throttle = self.exec_config.throttle();
+        // Throttled modules call host functions asynchronously, which the audit can't forward
+        if audit_calls && throttle.is_some() {
+            let message = "host calls can't be audited for modules with a CPU limit";
+            error!("{}", message);
+            status_sender
+                .send(Status::Terminated {
+                    failed: true,
+                    message: message.into(),
+                    timestamp: chrono::Utc::now(),
+                    reason: None,
+                    exit_code: None,
+                })
+                .await?;
+            return Err(anyhow::anyhow!(message));
+        }
+        let consume_fuel = fuel.is_some() || throttle.is_some();
 
-        let mut config = wasmtime::Config::new();
-        config.interruptable(true);
-        let engine = wasmtime::Engine::new(&config)?;
-        let mut store = wasmtime::Store::new(&engine, ctx);
-        let interrupt = store.interrupt_handle()?;
+        // Throttled modules yield to the host after each slice of fuel, which needs async support
+        let engines = self.engines.clone().unwrap_or_default();
+        let (engine, pool_slot) = engines.get(consume_fuel, throttle.is_some(), features)?;
+        let limits = self.exec_config.store_limits();
+        let mut store = wasmtime::Store::new(
+            &engine,
+            StoreData {
+                wasi: ctx,
+                limits: TrackedLimits::new(limits, Some(usage.clone())),
+                #[cfg(feature = "wasi-nn")]
+                wasi_nn: None,
+            },
+        );
+        store.limiter(|data| &mut data.limits);
+        let interrupt = Interrupt {
+            handle: store.interrupt_handle()?,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        };
+        match (fuel, throttle) {
+            (fuel, Some(millicores)) => {
+                debug!(
+                    cpu_limit = millicores,
+                    ?fuel,
+                    "Throttling module to its CPU limit"
+                );
+                // Fuel is handed out a slice at a time, up to the fuel limit if there is one
+                let (first_slice, refills) = throttle_slices(fuel);
+                store.add_fuel(first_slice)?;
+                store.out_of_fuel_async_yield(refills, THROTTLE_FUEL_SLICE);
+            }
+            (Some(fuel), None) => {
+                trace!(fuel, "Configuring fuel limit for module");
+                store.add_fuel(fuel)?;
+            }
+            (None, None) => (),
+        }
 
         let mut linker = Linker::new(&engine);
 
-        let module = match wasmtime::Module::new(&engine, &data.module_data) {
+        // Wait for other modules to finish compiling rather than compiling them all at once
+        let compile_permit = match &self.compile_permits {
+            Some(permits) => Some(permits.acquire().await?),
+            None => None,
+        };
+        // Only now that the module may be compiled does the clock start on compiling it
+        let compile_deadline = self.start_deadline();
+        let compile_timer = kubelet::metrics::MODULE_COMPILE_DURATION.start_timer();
+        let module = self
+            .before_deadline(compile_deadline, async {
+                match &self.compile_cache {
+                    Some(cache) => {
+                        cache
+                            .load(&engines, &engine, &data.module_data, consume_fuel, features)
+                            .await
+                    }
+                    None => {
+                        Arc::clone(&engines)
+                            .compile_blocking(&engine, &data.module_data)
+                            .await
+                    }
+                }
+            })
+            .await;
+        compile_timer.observe_duration();
+        drop(compile_permit);
+        // A module that took too long to compile is failed on its own, not as one that couldn't
+        // be compiled
+        let module = match module {
+            Err(e) if e.is::<ContainerError>() => return Err(e),
+            module => module,
+        };
+        let module = match module {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
             Ok(m) => m,
@@ -222,6 +1005,8 @@ impl WasiRuntime {
                         failed: true,
                         message: message.into(),
                         timestamp: chrono::Utc::now(),
+                        reason: None,
+                        exit_code: None,
                     })
                     .await?;
 
@@ -229,17 +1014,59 @@ impl WasiRuntime {
             }
         };
 
-        wasmtime_wasi::add_to_linker(&mut linker, |cx| cx)?;
+        wasmtime_wasi::add_to_linker(&mut linker, |data: &mut StoreData| &mut data.wasi)?;
 
         // Link WASI HTTP
         let WasiHttpConfig {
             allowed_domains,
+            allowed_networks,
             max_concurrent_requests,
+            resolver,
+            request_timeout,
+            retry,
+            client_cert,
+            ca_bundle,
         } = self.http_config.clone();
-        let wasi_http = WasiHttpCtx::new(allowed_domains, max_concurrent_requests)?;
-        wasi_http.add_to_linker(&mut linker)?;
+        let resolver = resolver.map(|config| Arc::new(Resolver::new(config)));
+        // Modules without an allow list can't send requests anywhere
+        let allow_list = AllowList::new(allowed_domains.unwrap_or_default(), allowed_networks);
+        HttpCtx::new(allow_list, max_concurrent_requests, request_timeout, retry)
+            .with_client_cert(client_cert)
+            .with_ca_bundle(ca_bundle)
+            .with_resolver(resolver.clone())
+            .add_to_linker(&mut linker)?;
+
+        // Link the socket functions. Modules without allowed endpoints can't connect anywhere
+        SocketsCtx::new(self.allowed_endpoints.clone())
+            .with_resolver(resolver)
+            .with_interrupted(interrupt.interrupted.clone())
+            .add_to_linker(&mut linker)?;
 
-        let instance = match linker.instantiate(&mut store, &module) {
+        if let Some(host_functions) = &self.host_functions {
+            host_functions.add_to_linker(&mut linker, &self.name)?;
+        }
+
+        if let Some(backend) = self.exec_config.nn_backend {
+            debug!(%backend, "Linking wasi-nn");
+            wasi_nn::add_to_linker(&mut linker, store.data_mut(), backend)?;
+        }
+
+        let audit = if audit_calls {
+            Some(CallAudit::instrument(&mut linker, &mut store, &module)?)
+        } else {
+            None
+        };
+
+        let instance = if throttle.is_some() {
+            linker.instantiate_async(&mut store, &module).await
+        } else {
+            linker.instantiate(&mut store, &module)
+        };
+        let instance = match (instance, &audit) {
+            (Ok(instance), Some(audit)) => audit.attach(&mut store, &instance).map(|_| instance),
+            (instance, _) => instance,
+        };
+        let instance = match instance {
             // We can't map errors here or it moves the send channel, so we
             // do it in a match
             Ok(i) => i,
@@ -251,6 +1078,8 @@ impl WasiRuntime {
                         failed: true,
                         message: message.into(),
                         timestamp: chrono::Utc::now(),
+                        reason: None,
+                        exit_code: None,
                     })
                     .await?;
                 // Converting from anyhow
@@ -258,33 +1087,41 @@ impl WasiRuntime {
             }
         };
 
-        info!("starting run of module");
-        status_sender
-            .send(Status::Running {
-                timestamp: chrono::Utc::now(),
-            })
-            .await?;
+        // Grab the module's memory so its size can be checked if it traps
+        let memory = instance.get_memory(&mut store, "memory");
 
-        // NOTE(thomastaylor312): In the future, if we want to pass args directly, we'll
-        // need to do a bit more to pass them in here.
-        let export = instance
-            .get_export(&mut store, "_start")
-            .ok_or_else(|| anyhow::anyhow!("_start import doesn't exist in wasm module"))?;
+        info!(cpu_limit = ?throttle, "starting run of module");
+        status_sender.send(Status::running()).await?;
 
         // NOTE(thomastaylor312): In the future (pun intended) we might be able to use something
         // like `func.call(...).await`. We should check every once and a while when upgraing
         // wasmtime
-        let func = match export {
-            wasmtime::Extern::Func(f) => f,
-            _ => {
-                let message =
-                    "_start import was not a function. This is likely a problem with the module";
-                error!(error = message);
+        let entrypoint = entrypoint.as_deref().unwrap_or(DEFAULT_ENTRYPOINT);
+        let func = match instance.get_export(&mut store, entrypoint) {
+            Some(wasmtime::Extern::Func(f)) if f.ty(&store).params().len() == 0 => f,
+            export => {
+                let message = match export {
+                    None => format!(
+                        "module does not export an entrypoint function named {}",
+                        entrypoint
+                    ),
+                    Some(wasmtime::Extern::Func(_)) => format!(
+                        "entrypoint function {} takes parameters, but entrypoints are called without any",
+                        entrypoint
+                    ),
+                    Some(_) => format!(
+                        "module export {} is not a function, so it can't be used as the entrypoint",
+                        entrypoint
+                    ),
+                };
+                error!(error = %message);
                 status_sender
                     .send(Status::Terminated {
                         failed: true,
-                        message: message.into(),
+                        message: message.clone(),
                         timestamp: chrono::Utc::now(),
+                        reason: None,
+                        exit_code: None,
                     })
                     .await?;
 
@@ -294,38 +1131,133 @@ impl WasiRuntime {
 
         let name = self.name.clone();
         let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let span = tracing::info_span!("wasmtime_module_run", %name);
+            let span = tracing::info_span!("wasmtime_module_run", %name, cpu_limit = ?throttle);
             let _enter = span.enter();
+            // Dropped when the run finishes, which lets log followers know
+            // there is no more output coming
+            let _closed = closed;
+            // The instance's place in the engine's pool is given up once the run is over
+            let _pool_slot = pool_slot;
+            // Dropped before `_closed`, so the output writers flush any unfinished line before
+            // log followers are told the output is complete
+            let mut store = store;
+            let _cpu = usage.track_current_thread();
 
-            match func.call(&mut store, &[]) {
+            let result = match throttle {
+                Some(millicores) => run_throttled(func.call_async(&mut store, &[]), millicores),
+                None => func.call(&mut store, &[]),
+            };
+            // Modules that call `proc_exit` end their run with a trap
+            // carrying the status they exited with
+            let exit_code = match &result {
+                Ok(_) => Some(0),
+                Err(e) => exit_status(e),
+            };
+            if let Some(audit) = &audit {
+                audit.report(&name);
+            }
+            match result {
                 // We can't map errors here or it moves the send channel, so we
                 // do it in a match
                 Ok(_) => {}
+                Err(_) if exit_code == Some(0) => {}
+                // A stop request interrupts the guest, which surfaces as a
+                // trap. That is a forced termination rather than a failure of
+                // the module itself
+                Err(e) if is_interrupt(&e) => {
+                    let message = match *stop_request.lock().unwrap() {
+                        StopRequest::GracePeriodExpired => {
+                            "Module run was forcibly stopped after its termination grace period expired"
+                        }
+                        _ => "Module run was forcibly stopped",
+                    };
+                    info!("{}", message);
+                    send(
+                        &status_sender,
+                        &name,
+                        Status::Terminated {
+                            failed: false,
+                            message: message.into(),
+                            timestamp: chrono::Utc::now(),
+                            reason: None,
+                            exit_code: Some(KILLED_EXIT_CODE),
+                        },
+                    );
+                    return Ok(());
+                }
+                Err(e) if exit_code.is_some() => {
+                    let message = format!(
+                        "module exited with status {}",
+                        exit_code.unwrap_or_default()
+                    );
+                    info!("{}", message);
+                    send(
+                        &status_sender,
+                        &name,
+                        Status::Terminated {
+                            failed: true,
+                            message: message.clone(),
+                            timestamp: chrono::Utc::now(),
+                            reason: Some("Error".to_string()),
+                            exit_code,
+                        },
+                    );
+                    return Err(e.context(message));
+                }
                 Err(e) => {
-                    let message = "unable to run module";
+                    // A module that runs out of fuel or memory traps like any
+                    // other error, so check its resource usage to tell them
+                    // apart
+                    let (message, reason, exit_code) = match (fuel, memory_limit) {
+                        (_, Some(limit)) if memory_exhausted(memory, &store, limit) => (
+                            format!("module exceeded its memory limit of {} bytes", limit),
+                            Some("OOMKilled".to_string()),
+                            KILLED_EXIT_CODE,
+                        ),
+                        (Some(limit), _)
+                            if store.fuel_consumed().map_or(false, |used| used >= limit) =>
+                        {
+                            (
+                                format!("module exhausted its fuel limit of {}", limit),
+                                None,
+                                TRAP_EXIT_CODE,
+                            )
+                        }
+                        _ => (failure_message(&e), None, TRAP_EXIT_CODE),
+                    };
                     error!(error = %e, "{}", message);
                     send(
                         &status_sender,
                         &name,
                         Status::Terminated {
                             failed: true,
-                            message: message.into(),
+                            message: message.clone(),
                             timestamp: chrono::Utc::now(),
+                            reason,
+                            exit_code: Some(exit_code),
                         },
                     );
 
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                    // Keep the original error as the source so the exit
+                    // status of the module can still be found
+                    return Err(e.context(message));
                 }
             };
 
-            info!("module run complete");
+            let message = match *stop_request.lock().unwrap() {
+                StopRequest::None => "Module run completed",
+                _ => "Module run exited gracefully after being asked to stop",
+            };
+            info!("{}", message);
             send(
                 &status_sender,
                 &name,
                 Status::Terminated {
                     failed: false,
-                    message: "Module run completed".into(),
+                    message: message.into(),
                     timestamp: chrono::Utc::now(),
+                    reason: None,
+                    exit_code: Some(0),
                 },
             );
             Ok(())
@@ -335,6 +1267,112 @@ impl WasiRuntime {
     }
 }
 
+/// Runs a call into a module to completion on the current thread. Each time the module yields
+/// after a slice of fuel, the thread is paused for long enough that the module uses no more than
+/// `millicores` thousandths of a CPU. This is a coarse approximation of the limit, as the module
+/// only yields between slices
+fn run_throttled<F: Future>(call: F, millicores: u64) -> F::Output {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut call = Box::pin(call);
+    let pause_ratio = (1000 - millicores) as f64 / millicores as f64;
+    loop {
+        let resumed = Instant::now();
+        match call.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => {
+                let ran = resumed.elapsed();
+                let pause = ran.mul_f64(pause_ratio);
+                trace!(
+                    ?ran,
+                    ?pause,
+                    "Pausing module to keep it within its CPU limit"
+                );
+                std::thread::sleep(pause);
+            }
+        }
+    }
+}
+
+/// The directory capabilities given to read only mounts. These allow the module to walk and read
+/// the directory but not create, remove, rename or otherwise modify anything in it
+fn read_only_dir_caps() -> DirCaps {
+    DirCaps::OPEN
+        | DirCaps::READDIR
+        | DirCaps::READLINK
+        | DirCaps::PATH_FILESTAT_GET
+        | DirCaps::FILESTAT_GET
+}
+
+/// The file capabilities given to files opened from read only mounts
+fn read_only_file_caps() -> FileCaps {
+    FileCaps::READ
+        | FileCaps::SEEK
+        | FileCaps::TELL
+        | FileCaps::ADVISE
+        | FileCaps::FILESTAT_GET
+        | FileCaps::POLL_READWRITE
+}
+
+/// Returns true if the module's memory can't grow by another page without going over the limit,
+/// meaning a trap was most likely caused by a failed allocation
+fn memory_exhausted(memory: Option<Memory>, store: &Store<StoreData>, limit: u64) -> bool {
+    memory.map_or(false, |m| {
+        m.data_size(store) as u64 + WASM_PAGE_SIZE > limit
+    })
+}
+
+/// Describes why a module run failed. Traps are described by their reason (e.g. `wasm trap: out
+/// of bounds memory access`) and the functions the module was in when it trapped, innermost
+/// first
+fn failure_message(e: &anyhow::Error) -> String {
+    let trap = match e.downcast_ref::<wasmtime::Trap>() {
+        Some(trap) => trap,
+        None => return format!("unable to run module: {}", e),
+    };
+    // The trap's own description ends with a backtrace of unbounded length, so only its first
+    // line is kept
+    let reason = trap.to_string();
+    let mut message = reason.lines().next().unwrap_or_default().to_owned();
+    let frames = trap.trace();
+    if !frames.is_empty() {
+        message.push_str("\nwasm backtrace:");
+        for (i, frame) in frames.iter().take(MAX_BACKTRACE_FRAMES).enumerate() {
+            let func = match frame.func_name() {
+                Some(name) => name.to_owned(),
+                None => format!("<wasm function {}>", frame.func_index()),
+            };
+            message.push_str(&format!(
+                "\n  {}: {}!{}",
+                i,
+                frame.module_name().unwrap_or("<unknown>"),
+                func
+            ));
+        }
+        if frames.len() > MAX_BACKTRACE_FRAMES {
+            message.push_str(&format!(
+                "\n  ... {} more frames",
+                frames.len() - MAX_BACKTRACE_FRAMES
+            ));
+        }
+    }
+    message
+}
+
+/// Returns the status the module exited with through `proc_exit`, if that is how its run ended
+fn exit_status(e: &anyhow::Error) -> Option<i32> {
+    e.downcast_ref::<wasmtime::Trap>()
+        .and_then(|trap| trap.i32_exit_status())
+}
+
+/// Returns true if the error is the trap raised when the module was interrupted
+/// through its `InterruptHandle`
+fn is_interrupt(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<wasmtime::Trap>()
+        .and_then(|trap| trap.trap_code())
+        == Some(wasmtime::TrapCode::Interrupt)
+}
+
 #[instrument(level = "info", skip(sender, status))]
 fn send(sender: &Sender<Status>, name: &str, status: Status) {
     match sender.blocking_send(status) {
@@ -342,3 +1380,302 @@ fn send(sender: &Sender<Status>, name: &str, status: Status) {
         Ok(_) => debug!("send completed"),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn runtime(log_dir: &Path) -> (WasiRuntime, mpsc::Receiver<Status>) {
+        runtime_of(
+            log_dir,
+            r#"(module (func (export "_start")))"#,
+            WasiExecConfig::default(),
+        )
+        .await
+    }
+
+    async fn runtime_of(
+        log_dir: &Path,
+        module: &str,
+        exec_config: WasiExecConfig,
+    ) -> (WasiRuntime, mpsc::Receiver<Status>) {
+        let (status_sender, status_receiver) = mpsc::channel(8);
+        let runtime = WasiRuntime::new(
+            "test".to_owned(),
+            wat::parse_str(module).unwrap(),
+            HashMap::new(),
+            Vec::new(),
+            HashMap::new(),
+            log_dir.to_owned(),
+            status_sender,
+            WasiHttpConfig::default(),
+            exec_config,
+        )
+        .await
+        .unwrap();
+        (runtime, status_receiver)
+    }
+
+    /// Runs the module and returns the status it terminated with, or the error that kept it from
+    /// starting
+    async fn run_to_end(module: &str, exec_config: WasiExecConfig) -> Result<Status, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime, mut status_receiver) = runtime_of(dir.path(), module, exec_config).await;
+        let mut handle = runtime.start().await.map_err(|e| e.to_string())?;
+        let _ = handle.wait().await;
+        loop {
+            match status_receiver.recv().await {
+                Some(status @ Status::Terminated { .. }) => return Ok(status),
+                Some(_) => continue,
+                None => panic!("module stopped without a terminated status"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_code() {
+        let exits_with = |code: i32| {
+            format!(
+                r#"(module
+                    (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                    (memory (export "memory") 1)
+                    (func (export "_start") i32.const {} call $proc_exit))"#,
+                code
+            )
+        };
+        // The exit code, whether the run failed, and the reason of each module's run
+        let terminated = |module: String| async move {
+            match run_to_end(&module, WasiExecConfig::default())
+                .await
+                .unwrap()
+            {
+                Status::Terminated {
+                    exit_code,
+                    failed,
+                    reason,
+                    ..
+                } => (exit_code, failed, reason),
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(
+            (Some(0), false, None),
+            terminated(r#"(module (func (export "_start")))"#.to_owned()).await
+        );
+        assert_eq!((Some(0), false, None), terminated(exits_with(0)).await);
+        assert_eq!(
+            (Some(3), true, Some("Error".to_owned())),
+            terminated(exits_with(3)).await
+        );
+        assert_eq!(
+            (Some(TRAP_EXIT_CODE), true, None),
+            terminated(r#"(module (func (export "_start") unreachable))"#.to_owned()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_entrypoint() {
+        let module = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") unreachable)
+            (func (export "run"))
+            (func (export "add") (param i32)))"#;
+        // Whether the module ran to completion from the given entrypoint, or the error that
+        // kept it from starting
+        let run = |entrypoint: Option<&str>| {
+            let exec_config = WasiExecConfig {
+                entrypoint: entrypoint.map(str::to_owned),
+                ..Default::default()
+            };
+            async move {
+                run_to_end(module, exec_config)
+                    .await
+                    .map(|status| matches!(status, Status::Terminated { failed: false, .. }))
+            }
+        };
+
+        assert_eq!(Ok(false), run(None).await);
+        assert_eq!(Ok(true), run(Some("run")).await);
+        let e = run(Some("missing")).await.unwrap_err();
+        assert!(
+            e.contains("does not export an entrypoint function named missing"),
+            "{}",
+            e
+        );
+        let e = run(Some("add")).await.unwrap_err();
+        assert!(e.contains("takes parameters"), "{}", e);
+        let e = run(Some("memory")).await.unwrap_err();
+        assert!(e.contains("is not a function"), "{}", e);
+    }
+
+    #[test]
+    fn test_failure_message() {
+        // Runs the module's `_start` with the given argument and returns its error
+        let run = |module: &str, arg: i32| {
+            let engine = wasmtime::Engine::default();
+            let module = wasmtime::Module::new(&engine, module).unwrap();
+            let mut store = Store::new(&engine, ());
+            let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
+            let start = instance
+                .get_typed_func::<i32, (), _>(&mut store, "_start")
+                .unwrap();
+            anyhow::Error::from(start.call(&mut store, arg).unwrap_err())
+        };
+        let module = r#"(module $app
+            (func $recurse (param i32)
+                local.get 0
+                i32.eqz
+                if
+                    unreachable
+                end
+                local.get 0
+                i32.const 1
+                i32.sub
+                call $recurse)
+            (func $start (export "_start") (param i32)
+                local.get 0
+                call $recurse))"#;
+
+        assert_eq!(
+            "wasm trap: unreachable\nwasm backtrace:\n  0: app!recurse\n  1: app!recurse\n  2: app!start",
+            failure_message(&run(module, 1))
+        );
+
+        // Deep backtraces are cut short
+        let message = failure_message(&run(module, 20));
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(MAX_BACKTRACE_FRAMES + 3, lines.len(), "{}", message);
+        assert_eq!("  ... 6 more frames", lines[lines.len() - 1]);
+
+        assert_eq!(
+            "unable to run module: no memory export",
+            failure_message(&anyhow::anyhow!("no memory export"))
+        );
+    }
+
+    #[test]
+    fn test_thread_limit() {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, "(module (memory 1))").unwrap();
+        // The number of instances of the module a store with the config's limits can hold
+        let instances = |exec_config: WasiExecConfig| {
+            let mut store = Store::new(&engine, exec_config.store_limits());
+            store.limiter(|limits| limits);
+            (0..4)
+                .take_while(|_| wasmtime::Instance::new(&mut store, &module, &[]).is_ok())
+                .count()
+        };
+        assert_eq!(4, instances(WasiExecConfig::default()));
+        let single = WasiExecConfig {
+            max_threads: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(1, instances(single));
+        let audited = WasiExecConfig {
+            max_threads: Some(2),
+            audit_calls: true,
+            ..Default::default()
+        };
+        assert_eq!(3, instances(audited));
+    }
+
+    #[test]
+    fn test_throttle_slices() {
+        assert_eq!((THROTTLE_FUEL_SLICE, u32::MAX), throttle_slices(None));
+        assert_eq!((1, 0), throttle_slices(Some(1)));
+        assert_eq!(
+            (THROTTLE_FUEL_SLICE, 0),
+            throttle_slices(Some(THROTTLE_FUEL_SLICE))
+        );
+        assert_eq!(
+            (THROTTLE_FUEL_SLICE, 2),
+            throttle_slices(Some(3 * THROTTLE_FUEL_SLICE))
+        );
+        assert_eq!((5, 2), throttle_slices(Some(2 * THROTTLE_FUEL_SLICE + 5)));
+        let (first, refills) = throttle_slices(Some(u64::MAX));
+        assert_eq!(u32::MAX, refills);
+        assert_eq!(u64::MAX, first + refills as u64 * THROTTLE_FUEL_SLICE);
+    }
+
+    #[tokio::test]
+    async fn test_output_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime, _status_receiver) = runtime(dir.path()).await;
+        std::fs::write(
+            runtime.output.path(),
+            "stdout first\nstderr second\nstdout third\n",
+        )
+        .unwrap();
+        assert_eq!(
+            "first\nsecond\nthird",
+            runtime.output_tail(80, 2048).await.unwrap()
+        );
+        assert_eq!("second\nthird", runtime.output_tail(2, 2048).await.unwrap());
+        // The line the byte limit cuts into is left out
+        assert_eq!("third", runtime.output_tail(80, 20).await.unwrap());
+        // Unless the limit falls right at its start
+        assert_eq!("second\nthird", runtime.output_tail(80, 27).await.unwrap());
+        assert_eq!("", runtime.output_tail(80, 5).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_output_tail_of_json_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime, _status_receiver) = runtime(dir.path()).await;
+        let runtime = runtime.with_log_format(LogFormat::Json {
+            namespace: "default".to_owned(),
+            pod: "app".to_owned(),
+            container: "module".to_owned(),
+        });
+        let log: String = ["first", "second"]
+            .iter()
+            .map(|message| {
+                format!(
+                    "{}\n",
+                    serde_json::json!({"stream": "stderr", "message": message})
+                )
+            })
+            .collect();
+        std::fs::write(runtime.output.path(), log).unwrap();
+        assert_eq!(
+            "first\nsecond",
+            runtime.output_tail(80, 2048).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_timeout_excludes_waiting_to_compile() {
+        let dir = tempfile::tempdir().unwrap();
+        let permits = Arc::new(Semaphore::new(1));
+        let (runtime, _status_receiver) = runtime(dir.path()).await;
+        let runtime = runtime
+            .with_compile_permits(Some(permits.clone()))
+            .with_start_timeout(Some(Duration::from_secs(2)));
+
+        // Another module compiles for longer than the timeout
+        let compiling = permits.clone().acquire_owned().await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            drop(compiling);
+        });
+        let mut handle = runtime.start().await.unwrap();
+        handle.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let (runtime, _status_receiver) = runtime(dir.path()).await;
+        let runtime = runtime.with_start_timeout(Some(Duration::from_nanos(1)));
+        let e = runtime.start().await.err().unwrap();
+        assert!(
+            matches!(
+                e.downcast_ref::<ContainerError>(),
+                Some(ContainerError::StartTimedOut(_))
+            ),
+            "{}",
+            e
+        );
+    }
+}
@@ -2,10 +2,14 @@ use kubelet::config::Config;
 use kubelet::plugin_watcher::PluginRegistry;
 use kubelet::resources::DeviceManager;
 use kubelet::store::composite::ComposableStore;
+use kubelet::store::http::HttpStore;
 use kubelet::store::oci::FileStore;
 use kubelet::Kubelet;
+use opentelemetry::{sdk, KeyValue};
 use std::convert::TryFrom;
 use std::sync::Arc;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use wasi_provider::WasiProvider;
 
 #[tokio::main(flavor = "multi_thread")]
@@ -14,15 +18,22 @@ async fn main() -> anyhow::Result<()> {
     // a new Kubelet, all you need to implement is a provider.
     let config = Config::new_from_file_and_flags(env!("CARGO_PKG_VERSION"), None);
 
-    // Initialize the logger
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+    // Initialize the logger, also exporting spans if an OpenTelemetry collector is configured
+    let telemetry = match &config.otlp_endpoint {
+        Some(endpoint) => Some(
+            tracing_opentelemetry::layer().with_tracer(otlp_tracer(endpoint, &config.node_name)?),
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(telemetry)
         .init();
 
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
-    let store = make_store(&config);
+    let store = make_store(&config)?;
     let plugin_registry = Arc::new(PluginRegistry::new(&config.plugins_dir));
     let device_plugin_manager = Arc::new(DeviceManager::new(
         &config.device_plugins_dir,
@@ -39,20 +50,37 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
-    kubelet.start().await
+    let result = kubelet.start().await;
+    // Flush any spans that haven't been exported yet
+    opentelemetry::global::shutdown_tracer_provider();
+    result
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
+fn otlp_tracer(endpoint: &str, node_name: &str) -> anyhow::Result<sdk::trace::Tracer> {
+    let resource = sdk::Resource::new(vec![
+        KeyValue::new("service.name", "krustlet-wasi"),
+        KeyValue::new("host.name", node_name.to_owned()),
+    ]);
+    Ok(opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(sdk::trace::config().with_resource(resource))
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)?)
+}
+
+fn make_store(config: &Config) -> anyhow::Result<Arc<dyn kubelet::store::Store + Send + Sync>> {
     let client = oci_distribution::Client::from_source(config);
-    let mut store_path = config.data_dir.join(".oci");
-    store_path.push("modules");
-    let file_store = Arc::new(FileStore::new(client, &store_path));
+    let file_store = Arc::new(match config.module_cache_max_size {
+        Some(max_size) => FileStore::with_max_size(client, &config.module_cache_dir, max_size),
+        None => FileStore::new(client, &config.module_cache_dir),
+    });
 
-    if config.allow_local_modules {
+    let store: Arc<dyn kubelet::store::Store + Send + Sync> = if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
     } else {
         file_store
-    }
+    };
+    Ok(Arc::new(HttpStore::from_config(store, config)?))
 }
 
 fn notify_bootstrap(message: String) {
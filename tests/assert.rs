@@ -79,6 +79,35 @@ pub async fn pod_exited_with_failure(pods: &Api<Pod>, pod_name: &str) -> anyhow:
     Ok(())
 }
 
+pub async fn pod_condition_is(
+    pods: &Api<Pod>,
+    pod_name: &str,
+    condition_type: &str,
+    expected_status: &str,
+) -> anyhow::Result<()> {
+    let pod = pods.get(pod_name).await?;
+
+    let condition = (|| {
+        pod.status?
+            .conditions
+            .into_iter()
+            .find(|c| c.type_ == condition_type)
+    })()
+    .unwrap_or_else(|| panic!("Pod has no {} condition", condition_type));
+    assert_eq!(
+        condition.status, expected_status,
+        "Expected pod condition {} to be {}",
+        condition_type, expected_status
+    );
+    assert!(
+        condition.last_transition_time.is_some(),
+        "Pod condition {} has no transition time",
+        condition_type
+    );
+
+    Ok(())
+}
+
 pub async fn pod_reason_contains(
     pods: &Api<Pod>,
     pod_name: &str,
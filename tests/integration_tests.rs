@@ -89,6 +89,8 @@ const INITY_WASI_POD: &str = "hello-wasi-with-inits";
 const FAILY_INITS_POD: &str = "faily-inits-pod";
 const PRIVATE_REGISTRY_POD: &str = "private-registry-pod";
 const PROJECTED_VOLUME_POD: &str = "projected-volume-pod";
+const READ_ONLY_MOUNT_POD: &str = "read-only-mount-pod";
+const SHARED_EMPTY_DIR_POD: &str = "shared-empty-dir-pod";
 #[cfg(target_os = "linux")]
 const PVC_MOUNT_POD: &str = "pvc-mount-pod";
 #[cfg(target_os = "linux")]
@@ -109,6 +111,7 @@ async fn create_wasi_pod(
             "name": pod_name
         },
         "spec": {
+            "restartPolicy": "Never",
             "containers": [
                 {
                     "name": pod_name,
@@ -200,6 +203,7 @@ async fn create_experimental_wasi_http_pod(
             }
         },
         "spec": {
+            "restartPolicy": "Never",
             "containers": [
                 {
                     "name": pod_name,
@@ -253,6 +257,7 @@ async fn create_fancy_schmancy_wasi_pod(
             "name": pod_name
         },
         "spec": {
+            "restartPolicy": "Never",
             "containers": [
                 {
                     "name": pod_name,
@@ -322,11 +327,13 @@ async fn create_multi_mount_pod(
         WasmerciserVolumeSpec {
             volume_name: "multicm",
             mount_path: "/mcm",
+            read_only: false,
             source: WasmerciserVolumeSource::ConfigMap("multi-configmap"),
         },
         WasmerciserVolumeSpec {
             volume_name: "multisecret",
             mount_path: "/ms",
+            read_only: false,
             source: WasmerciserVolumeSource::Secret("multi-secret"),
         },
     ];
@@ -372,6 +379,7 @@ async fn create_multi_items_mount_pod(
         WasmerciserVolumeSpec {
             volume_name: "multicm",
             mount_path: "/mcm",
+            read_only: false,
             source: WasmerciserVolumeSource::ConfigMapItems(
                 "multi-configmap",
                 vec![("mcm1", "mcm1"), ("mcm5", "mcm-five")],
@@ -380,6 +388,7 @@ async fn create_multi_items_mount_pod(
         WasmerciserVolumeSpec {
             volume_name: "multisecret",
             mount_path: "/ms",
+            read_only: false,
             source: WasmerciserVolumeSource::SecretItems(
                 "multi-secret",
                 vec![("ms1", "ms1"), ("ms3", "ms-three")],
@@ -448,6 +457,36 @@ async fn create_faily_pod(
     .await
 }
 
+async fn create_read_only_mount_pod(
+    client: kube::Client,
+    pods: &Api<Pod>,
+    resource_manager: &mut TestResourceManager,
+) -> anyhow::Result<()> {
+    let pod_name = READ_ONLY_MOUNT_POD;
+
+    let containers = vec![WasmerciserContainerSpec::named(pod_name)
+        .with_args(&["write(lit:graffiti)to(file:/ro/tag.txt)"])];
+
+    let volumes = vec![WasmerciserVolumeSpec {
+        volume_name: "readonly",
+        mount_path: "/ro",
+        read_only: true,
+        source: WasmerciserVolumeSource::HostPath,
+    }];
+
+    wasmercise_wasi(
+        pod_name,
+        client,
+        pods,
+        vec![],
+        containers,
+        volumes,
+        OnFailure::Accept,
+        resource_manager,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn wasmercise_wasi<'a>(
     pod_name: &str,
@@ -494,6 +533,7 @@ async fn create_pod_with_init_containers(
     let volumes = vec![WasmerciserVolumeSpec {
         volume_name: "hostpath-test",
         mount_path: "/hp",
+        read_only: false,
         source: WasmerciserVolumeSource::HostPath,
     }];
 
@@ -510,6 +550,48 @@ async fn create_pod_with_init_containers(
     .await
 }
 
+async fn create_shared_empty_dir_pod(
+    client: kube::Client,
+    pods: &Api<Pod>,
+    resource_manager: &mut TestResourceManager,
+) -> anyhow::Result<()> {
+    let pod_name = SHARED_EMPTY_DIR_POD;
+
+    let inits = vec![WasmerciserContainerSpec::named("writer")
+        .with_args(&["write(lit:mittens)to(file:/scratch/note.txt)"])];
+
+    let containers = vec![
+        WasmerciserContainerSpec::named(pod_name).with_args(&[
+            "read(file:/scratch/note.txt)to(var:note)",
+            "assert_value(var:note)is(lit:mittens)",
+            "write(var:note)to(stm:stdout)",
+        ]),
+        WasmerciserContainerSpec::named("second-reader").with_args(&[
+            "read(file:/scratch/note.txt)to(var:note)",
+            "assert_value(var:note)is(lit:mittens)",
+        ]),
+    ];
+
+    let volumes = vec![WasmerciserVolumeSpec {
+        volume_name: "scratch",
+        mount_path: "/scratch",
+        read_only: false,
+        source: WasmerciserVolumeSource::EmptyDir,
+    }];
+
+    wasmercise_wasi(
+        pod_name,
+        client,
+        pods,
+        inits,
+        containers,
+        volumes,
+        OnFailure::Panic,
+        resource_manager,
+    )
+    .await
+}
+
 async fn create_pod_with_failing_init_container(
     client: kube::Client,
     pods: &Api<Pod>,
@@ -622,6 +704,10 @@ async fn test_pod_logs_and_mounts() -> anyhow::Result<()> {
     assert::pod_log_equals(&pods, SIMPLE_WASI_POD, "Hello, world!\n").await?;
 
     assert::pod_exited_successfully(&pods, SIMPLE_WASI_POD).await?;
+    assert::pod_condition_is(&pods, SIMPLE_WASI_POD, "PodScheduled", "True").await?;
+    assert::pod_condition_is(&pods, SIMPLE_WASI_POD, "Initialized", "True").await?;
+    assert::pod_condition_is(&pods, SIMPLE_WASI_POD, "ContainersReady", "False").await?;
+    assert::pod_condition_is(&pods, SIMPLE_WASI_POD, "Ready", "False").await?;
 
     assert::container_file_contains(
         SIMPLE_WASI_POD,
@@ -755,6 +841,8 @@ async fn test_module_exiting_with_error() -> anyhow::Result<()> {
 
     create_faily_pod(client.clone(), &pods, &mut resource_manager).await?;
     assert::main_container_exited_with_failure(&pods, FAILY_POD).await?;
+    assert::pod_exited_with_failure(&pods, FAILY_POD).await?;
+    assert::pod_condition_is(&pods, FAILY_POD, "Ready", "False").await?;
     assert::pod_log_contains(
         &pods,
         FAILY_POD,
@@ -765,6 +853,18 @@ async fn test_module_exiting_with_error() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_write_to_read_only_mount_fails() -> anyhow::Result<()> {
+    let test_ns = "wasi-e2e-read-only-mount";
+    let (client, pods, mut resource_manager) = set_up_test(test_ns).await?;
+
+    create_read_only_mount_pod(client.clone(), &pods, &mut resource_manager).await?;
+    assert::main_container_exited_with_failure(&pods, READ_ONLY_MOUNT_POD).await?;
+    assert::pod_log_contains(&pods, READ_ONLY_MOUNT_POD, r#"ERR: Failed with"#).await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_init_containers() -> anyhow::Result<()> {
     let test_ns = "wasi-e2e-init-containers";
@@ -789,6 +889,27 @@ async fn test_init_containers() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_containers_share_empty_dir() -> anyhow::Result<()> {
+    let test_ns = "wasi-e2e-shared-empty-dir";
+    let (client, pods, mut resource_manager) = set_up_test(test_ns).await?;
+
+    create_shared_empty_dir_pod(client.clone(), &pods, &mut resource_manager).await?;
+    assert::pod_log_contains(&pods, SHARED_EMPTY_DIR_POD, "mittens").await?;
+    assert_container_statuses(
+        &pods,
+        SHARED_EMPTY_DIR_POD,
+        vec![
+            ContainerStatusExpectation::InitTerminated("writer", "Module run completed"),
+            ContainerStatusExpectation::AppTerminated(SHARED_EMPTY_DIR_POD, "Module run completed"),
+            ContainerStatusExpectation::AppTerminated("second-reader", "Module run completed"),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_failing_init_containers() -> anyhow::Result<()> {
     let test_ns = "wasi-e2e-failing-init-containers";
@@ -884,6 +1005,7 @@ async fn create_projected_pod(
     let volumes = vec![WasmerciserVolumeSpec {
         volume_name: "projected",
         mount_path: "/projected",
+        read_only: false,
         source: WasmerciserVolumeSource::Projected(projected_sources),
     }];
 
@@ -938,6 +1060,7 @@ async fn create_pvc_mount_pod(
     let volumes = vec![WasmerciserVolumeSpec {
         volume_name: PVC_NAME,
         mount_path: "/sgc",
+        read_only: false,
         source: WasmerciserVolumeSource::Pvc(PVC_NAME),
     }];
 
@@ -36,11 +36,13 @@ impl<'a> WasmerciserContainerSpec<'a> {
 pub struct WasmerciserVolumeSpec<'a> {
     pub volume_name: &'a str,
     pub mount_path: &'a str,
+    pub read_only: bool,
     pub source: WasmerciserVolumeSource<'a>,
 }
 
 pub enum WasmerciserVolumeSource<'a> {
     HostPath,
+    EmptyDir,
     ConfigMap(&'a str),
     ConfigMapItems(&'a str, Vec<(&'a str, &'a str)>),
     Secret(&'a str),
@@ -80,7 +82,8 @@ fn wasmerciser_container(
 fn wasmerciser_volume_mount(spec: &WasmerciserVolumeSpec) -> anyhow::Result<VolumeMount> {
     let mount: VolumeMount = serde_json::from_value(json!({
         "mountPath": spec.mount_path,
-        "name": spec.volume_name
+        "name": spec.volume_name,
+        "readOnly": spec.read_only,
     }))?;
     Ok(mount)
 }
@@ -101,6 +104,14 @@ fn wasmerciser_volume(
 
             Ok((volume, Some(tempdir)))
         }
+        WasmerciserVolumeSource::EmptyDir => {
+            let volume: Volume = serde_json::from_value(json!({
+                "name": spec.volume_name,
+                "emptyDir": {},
+            }))?;
+
+            Ok((volume, None))
+        }
         WasmerciserVolumeSource::ConfigMap(name) => {
             let volume: Volume = serde_json::from_value(json!({
                 "name": spec.volume_name,
@@ -203,6 +214,7 @@ pub fn wasmerciser_pod(
             "name": pod_name
         },
         "spec": {
+            "restartPolicy": "Never",
             "initContainers": init_container_specs,
             "containers": app_container_specs,
             "tolerations": [